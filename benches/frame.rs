@@ -0,0 +1,123 @@
+//! Measures how fast incoming frames are decoded, by parsing and unmasking a stream of small
+//! masked frames as a server receives them from a client.
+//!
+//! The `baseline` variant decodes the frames the way `Frame::parse` did before it read headers in
+//! place, through `Read`, and unmasked them a byte at a time, so that the two can be compared.
+//!
+//! Run with `cargo bench --bench frame`.
+
+extern crate byteorder;
+#[macro_use]
+extern crate criterion;
+extern crate ws;
+
+use std::io::{Cursor, ErrorKind, Read};
+
+use byteorder::{BigEndian, ReadBytesExt};
+use criterion::{Criterion, Throughput};
+use ws::{Frame, OpCode};
+
+const FRAMES: usize = 100_000;
+const PAYLOAD: usize = 64;
+
+// A buffer of masked frames with 64 byte payloads, as a client would send them.
+fn stream() -> Vec<u8> {
+    let mut buf = Vec::with_capacity(FRAMES * (PAYLOAD + 6));
+    for i in 0..FRAMES {
+        let mut frame = Frame::message(vec![i as u8; PAYLOAD], OpCode::Binary, true);
+        frame.set_mask_key([0x37, 0xfa, 0x21, i as u8]);
+        frame.format(&mut buf).unwrap();
+    }
+    buf
+}
+
+// Parse the next frame of the buffer and unmask it like the decoder used to, leaving out the
+// checks that the stream of the benchmark doesn't need.
+fn baseline_parse(cursor: &mut Cursor<Vec<u8>>) -> Option<Frame> {
+    let size = cursor.get_ref().len() as u64 - cursor.position();
+    let initial = cursor.position();
+
+    let mut head = [0u8; 2];
+    if cursor.read(&mut head).unwrap() != 2 {
+        cursor.set_position(initial);
+        return None;
+    }
+    let finished = head[0] & 0x80 != 0;
+    let opcode = OpCode::from(head[0] & 0x0F);
+    let masked = head[1] & 0x80 != 0;
+
+    let mut header_length = 2;
+    let mut length = u64::from(head[1] & 0x7F);
+    if let Some(length_nbytes) = match length {
+        126 => Some(2),
+        127 => Some(8),
+        _ => None,
+    } {
+        match cursor.read_uint::<BigEndian>(length_nbytes) {
+            Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
+                cursor.set_position(initial);
+                return None;
+            }
+            Err(err) => panic!("{}", err),
+            Ok(read) => length = read,
+        }
+        header_length += length_nbytes as u64;
+    }
+
+    let mut mask = [0u8; 4];
+    if masked {
+        if cursor.read(&mut mask).unwrap() != 4 {
+            cursor.set_position(initial);
+            return None;
+        }
+        header_length += 4;
+    }
+
+    if size < length + header_length {
+        cursor.set_position(initial);
+        return None;
+    }
+
+    let mut data = vec![0; length as usize];
+    cursor.read_exact(&mut data).unwrap();
+    for (byte, &key) in data.iter_mut().zip(mask.iter().cycle()) {
+        *byte ^= key
+    }
+    Some(Frame::message(data, opcode, finished))
+}
+
+// Decode every frame of the stream, returning a sum of the payloads so the work isn't optimized
+// away.
+fn decode(buf: &mut Cursor<Vec<u8>>) -> u64 {
+    buf.set_position(0);
+    let mut sum = 0u64;
+    while let Some(mut frame) = Frame::parse(buf, u64::MAX).unwrap() {
+        frame.remove_mask();
+        sum += u64::from(frame.payload()[PAYLOAD - 1]);
+    }
+    sum
+}
+
+fn decode_baseline(buf: &mut Cursor<Vec<u8>>) -> u64 {
+    buf.set_position(0);
+    let mut sum = 0u64;
+    while let Some(frame) = baseline_parse(buf) {
+        sum += u64::from(frame.payload()[PAYLOAD - 1]);
+    }
+    sum
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut buf = Cursor::new(stream());
+    let expected = decode(&mut buf);
+    assert_eq!(decode_baseline(&mut buf), expected);
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(FRAMES as u64));
+    group.bench_function("baseline", |b| b.iter(|| decode_baseline(&mut buf)));
+    group.bench_function("current", |b| b.iter(|| decode(&mut buf)));
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode);
+criterion_main!(benches);
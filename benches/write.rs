@@ -0,0 +1,147 @@
+//! Counts the writes a server makes to push many queued messages, with the queued frames flushed
+//! by vectored writes and with the same output written one buffer at a time.
+//!
+//! Run with `cargo bench --bench write`.
+
+extern crate url;
+extern crate ws;
+
+#[cfg(unix)]
+mod bench {
+    use std::io::{self, IoSlice, Read, Write};
+    use std::os::unix::net::UnixStream;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Instant;
+
+    use url;
+    use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings, WebSocket};
+
+    const MESSAGES: usize = 10_000;
+    // a large message among every so many small ones, whose payload is written from the message
+    const LARGE_EVERY: usize = 10;
+
+    // A stream that counts the writes made to it, and that writes only the first buffer of a
+    // vectored write unless it is vectored.
+    struct Counted {
+        sock: UnixStream,
+        writes: Arc<AtomicUsize>,
+        vectored: bool,
+    }
+
+    impl Read for Counted {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.sock.read(buf)
+        }
+    }
+
+    impl Write for Counted {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.sock.write(buf)
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            if self.vectored {
+                self.sock.write_vectored(bufs)
+            } else {
+                let buf = bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |b| &b[..]);
+                self.sock.write(buf)
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.sock.flush()
+        }
+    }
+
+    struct Server {
+        out: Sender,
+    }
+
+    impl Handler for Server {
+        fn on_open(&mut self, _: Handshake) -> Result<()> {
+            for i in 0..MESSAGES {
+                if i % LARGE_EVERY == 0 {
+                    self.out.send(vec![0u8; 8192])?;
+                } else {
+                    self.out.send("a small message")?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    struct Client {
+        out: Sender,
+        received: usize,
+    }
+
+    impl Handler for Client {
+        fn on_message(&mut self, _: Message) -> Result<()> {
+            self.received += 1;
+            if self.received == MESSAGES {
+                self.out.close(CloseCode::Normal)?;
+            }
+            Ok(())
+        }
+    }
+
+    // Push the messages to a client, returning the number of writes the server made.
+    fn run(vectored: bool) -> usize {
+        let (server_end, client_end) = UnixStream::pair().unwrap();
+        let writes = Arc::new(AtomicUsize::new(0));
+        let stream = Counted {
+            sock: server_end,
+            writes: writes.clone(),
+            vectored,
+        };
+
+        let server = thread::spawn(move || {
+            // room in the queue for every message sent from `on_open`
+            Builder::new()
+                .with_settings(Settings {
+                    queue_size: MESSAGES,
+                    ..Settings::default()
+                })
+                .build(|out| Server { out })
+                .unwrap()
+                .serve_stream(stream)
+                .unwrap();
+        });
+        WebSocket::new(|out| Client { out, received: 0 })
+            .unwrap()
+            .connect_stream(client_end, url::Url::parse("ws://localhost/").unwrap())
+            .unwrap();
+        server.join().unwrap();
+        writes.load(Ordering::SeqCst)
+    }
+
+    pub fn main() {
+        for &vectored in &[true, false] {
+            let start = Instant::now();
+            let writes = run(vectored);
+            println!(
+                "{}: {} writes for {} messages in {:?}",
+                if vectored {
+                    "vectored writes"
+                } else {
+                    "one buffer per write"
+                },
+                writes,
+                MESSAGES,
+                start.elapsed()
+            );
+        }
+    }
+}
+
+#[cfg(unix)]
+fn main() {
+    bench::main()
+}
+
+#[cfg(not(unix))]
+fn main() {}
@@ -0,0 +1,60 @@
+#!/usr/bin/env bash
+# Run the Autobahn Testsuite against the autobahn-client and autobahn-server examples and fail if
+# any case has a result other than OK, NON-STRICT, INFORMATIONAL or UNIMPLEMENTED.
+#
+# The suite runs in the crossbario/autobahn-testsuite docker image, so docker and python3 are
+# required. Extra arguments are passed to cargo, for example `--features permessage-deflate`.
+# The reports are written to ./reports.
+set -euo pipefail
+
+cd "$(dirname "$0")/.."
+IMAGE=crossbario/autobahn-testsuite
+CARGO_ARGS=("$@")
+
+cargo build --release --example autobahn-client --example autobahn-server "${CARGO_ARGS[@]}"
+rm -rf reports
+mkdir -p reports
+
+cleanup() {
+    [ -n "${SERVER:-}" ] && kill "$SERVER" 2>/dev/null || true
+    docker rm -f ws-rs-fuzzingserver >/dev/null 2>&1 || true
+}
+trap cleanup EXIT
+
+# the client against the fuzzingserver
+docker run -d --rm --name ws-rs-fuzzingserver --network host \
+    -v "$PWD/tests:/config" -v "$PWD/reports:/reports" \
+    -w / "$IMAGE" wstest -m fuzzingserver -s /config/fuzzingserver.json >/dev/null
+sleep 5
+target/release/examples/autobahn-client
+docker rm -f ws-rs-fuzzingserver >/dev/null
+
+# the server against the fuzzingclient
+target/release/examples/autobahn-server &
+SERVER=$!
+sleep 2
+docker run --rm --network host \
+    -v "$PWD/tests:/config" -v "$PWD/reports:/reports" \
+    -w / "$IMAGE" wstest -m fuzzingclient -s /config/fuzzingclient.json
+
+python3 - reports/clients/index.json reports/servers/index.json <<'EOF'
+import json
+import sys
+
+PASSING = {"OK", "NON-STRICT", "INFORMATIONAL", "UNIMPLEMENTED"}
+
+failed = []
+for path in sys.argv[1:]:
+    with open(path) as index:
+        for agent, cases in json.load(index).items():
+            for case, result in sorted(cases.items()):
+                for key in ("behavior", "behaviorClose"):
+                    if result[key] not in PASSING:
+                        failed.append("%s %s %s: %s" % (path, agent, case, result[key]))
+
+for failure in failed:
+    print(failure)
+if failed:
+    sys.exit("%d Autobahn cases failed." % len(failed))
+print("All Autobahn cases passed.")
+EOF
@@ -0,0 +1,262 @@
+use std::fmt;
+use std::str::from_utf8;
+
+use handshake::{decode_base64, Request, Response};
+use result::Result;
+
+/// Decides whether a handshake request may open a connection, before the request is passed to
+/// `Handler::on_request`. An authenticator is installed with `Builder::with_authenticator` and
+/// is shared by all the connections of a server, so it has to be thread safe.
+///
+/// ```no_run
+/// use ws::{AuthOutcome, Authenticator, Builder, Request, Response, Result};
+///
+/// struct ApiKey;
+///
+/// impl Authenticator for ApiKey {
+///     fn authenticate(&self, req: &Request) -> Result<AuthOutcome> {
+///         match req.header("x-api-key") {
+///             Some(key) if &key[..] == b"secret" => Ok(AuthOutcome::Accept),
+///             _ => Ok(AuthOutcome::Reject(Response::new(
+///                 403,
+///                 "Forbidden",
+///                 b"Unknown API key.".to_vec(),
+///             ))),
+///         }
+///     }
+/// }
+///
+/// Builder::new()
+///     .with_authenticator(ApiKey)
+///     .build(|out: ws::Sender| move |msg| out.send(msg))
+///     .unwrap()
+///     .listen("127.0.0.1:3012")
+///     .unwrap();
+/// ```
+pub trait Authenticator: Send + Sync {
+    /// Authenticate a handshake request. Returning an error fails the connection as it would for
+    /// an error returned from `on_request`.
+    fn authenticate(&self, req: &Request) -> Result<AuthOutcome>;
+}
+
+impl fmt::Debug for dyn Authenticator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Authenticator")
+    }
+}
+
+/// The decision of an `Authenticator` about a handshake request.
+#[derive(Debug)]
+pub enum AuthOutcome {
+    /// Pass the request on to `Handler::on_request`.
+    Accept,
+    /// Answer the request with the given response, such as a 403 Forbidden, instead of opening
+    /// the connection.
+    Reject(Response),
+    /// Answer the request with a 401 Unauthorized response that asks for credentials with the
+    /// given `WWW-Authenticate` challenge, such as `Basic realm="chat"`.
+    Challenge(String),
+}
+
+impl AuthOutcome {
+    #[doc(hidden)]
+    pub fn into_rejection(self) -> Option<Response> {
+        match self {
+            AuthOutcome::Accept => None,
+            AuthOutcome::Reject(res) => Some(res),
+            AuthOutcome::Challenge(challenge) => {
+                let mut res = Response::new(401, "Unauthorized", b"Unauthorized.".to_vec());
+                res.headers_mut()
+                    .push(("WWW-Authenticate".into(), challenge.into_bytes()));
+                Some(res)
+            }
+        }
+    }
+}
+
+// Get the credentials of the Authorization header if they use the given scheme, which is
+// matched case-insensitively.
+fn credentials<'a>(req: &'a Request, scheme: &str) -> Option<&'a str> {
+    let value = from_utf8(req.header("authorization")?).ok()?.trim();
+    let split = value.find(' ')?;
+    if value[..split].eq_ignore_ascii_case(scheme) {
+        Some(value[split..].trim())
+    } else {
+        None
+    }
+}
+
+// Compare secrets in time that only depends on their length, so that the time it takes to reject
+// a guess doesn't reveal how much of it is right.
+fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Accepts requests with an `Authorization: Bearer <token>` header carrying one of the given
+/// tokens, challenging all other requests.
+pub struct BearerTokenAuthenticator {
+    tokens: Vec<String>,
+}
+
+impl BearerTokenAuthenticator {
+    /// Create an authenticator that accepts any of the tokens.
+    pub fn new(tokens: Vec<String>) -> BearerTokenAuthenticator {
+        BearerTokenAuthenticator { tokens }
+    }
+
+    fn accepts(&self, token: &str) -> bool {
+        self.tokens
+            .iter()
+            .any(|t| secure_eq(t.as_bytes(), token.as_bytes()))
+    }
+}
+
+impl Authenticator for BearerTokenAuthenticator {
+    fn authenticate(&self, req: &Request) -> Result<AuthOutcome> {
+        Ok(match credentials(req, "Bearer") {
+            Some(token) if self.accepts(token) => AuthOutcome::Accept,
+            Some(_) => AuthOutcome::Challenge("Bearer error=\"invalid_token\"".into()),
+            None => AuthOutcome::Challenge("Bearer".into()),
+        })
+    }
+}
+
+impl fmt::Debug for BearerTokenAuthenticator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BearerTokenAuthenticator {{ tokens: {} }}", self.tokens.len())
+    }
+}
+
+/// Accepts requests with HTTP Basic authentication credentials matching one of the given users,
+/// challenging all other requests.
+pub struct BasicAuthAuthenticator {
+    realm: String,
+    users: Vec<(String, String)>,
+}
+
+impl BasicAuthAuthenticator {
+    /// Create an authenticator for the realm, which browsers show when they ask for credentials,
+    /// that accepts the given usernames and passwords.
+    pub fn new<R>(realm: R, users: Vec<(String, String)>) -> BasicAuthAuthenticator
+    where
+        R: Into<String>,
+    {
+        BasicAuthAuthenticator {
+            realm: realm.into(),
+            users,
+        }
+    }
+
+    fn accepts(&self, credentials: &str) -> bool {
+        let decoded = match decode_base64(credentials.as_bytes()) {
+            Some(decoded) => decoded,
+            None => return false,
+        };
+        let split = match decoded.iter().position(|&c| c == b':') {
+            Some(split) => split,
+            None => return false,
+        };
+        let (user, password) = (&decoded[..split], &decoded[split + 1..]);
+        self.users.iter().any(|(u, p)| {
+            // check both to take the same time whether or not the username is known
+            secure_eq(u.as_bytes(), user) & secure_eq(p.as_bytes(), password)
+        })
+    }
+}
+
+impl Authenticator for BasicAuthAuthenticator {
+    fn authenticate(&self, req: &Request) -> Result<AuthOutcome> {
+        match credentials(req, "Basic") {
+            Some(credentials) if self.accepts(credentials) => Ok(AuthOutcome::Accept),
+            _ => Ok(AuthOutcome::Challenge(format!(
+                "Basic realm=\"{}\", charset=\"UTF-8\"",
+                self.realm.replace('\\', "\\\\").replace('"', "\\\"")
+            ))),
+        }
+    }
+}
+
+impl fmt::Debug for BasicAuthAuthenticator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "BasicAuthAuthenticator {{ realm: {:?}, users: {} }}",
+            self.realm,
+            self.users.len()
+        )
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    fn request(authorization: &str) -> Request {
+        let raw = format!(
+            "GET / HTTP/1.1\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Authorization: {}\r\n\r\n",
+            authorization
+        );
+        Request::parse(raw.as_bytes()).unwrap().unwrap()
+    }
+
+    fn challenge(outcome: AuthOutcome) -> String {
+        match outcome {
+            AuthOutcome::Challenge(challenge) => challenge,
+            outcome => panic!("Expected a challenge, got {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn bearer() {
+        let auth = BearerTokenAuthenticator::new(vec!["first".into(), "second".into()]);
+        assert!(match auth.authenticate(&request("Bearer second")).unwrap() {
+            AuthOutcome::Accept => true,
+            _ => false,
+        });
+        assert!(match auth.authenticate(&request("bearer  first ")).unwrap() {
+            AuthOutcome::Accept => true,
+            _ => false,
+        });
+        assert_eq!(
+            challenge(auth.authenticate(&request("Bearer third")).unwrap()),
+            "Bearer error=\"invalid_token\""
+        );
+        assert_eq!(
+            challenge(auth.authenticate(&request("Basic Zmlyc3Q=")).unwrap()),
+            "Bearer"
+        );
+    }
+
+    #[test]
+    fn basic() {
+        let users = vec![("alice".into(), "open sesame".into())];
+        let auth = BasicAuthAuthenticator::new("chat", users);
+        // alice:open sesame
+        assert!(match auth.authenticate(&request("Basic YWxpY2U6b3BlbiBzZXNhbWU=")).unwrap() {
+            AuthOutcome::Accept => true,
+            _ => false,
+        });
+        // alice:guess
+        assert_eq!(
+            challenge(auth.authenticate(&request("Basic YWxpY2U6Z3Vlc3M=")).unwrap()),
+            "Basic realm=\"chat\", charset=\"UTF-8\""
+        );
+        assert!(auth.authenticate(&request("Basic !!!!")).is_ok());
+        assert!(auth.authenticate(&request("Bearer YWxpY2U6b3BlbiBzZXNhbWU=")).is_ok());
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64(b"").unwrap(), b"");
+        assert_eq!(decode_base64(b"Zg==").unwrap(), b"f");
+        assert_eq!(decode_base64(b"Zm8=").unwrap(), b"fo");
+        assert_eq!(decode_base64(b"Zm9v").unwrap(), b"foo");
+        assert!(decode_base64(b"Zm9").is_none());
+        assert!(decode_base64(b"Z===").is_none());
+        assert!(decode_base64(b"Zg=v").is_none());
+        assert!(decode_base64(b"Zm9!").is_none());
+    }
+}
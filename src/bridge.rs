@@ -0,0 +1,133 @@
+//! An adapter from the event loop to the `Stream` and `Sink` traits of the futures crate.
+use std::borrow::Borrow;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures::{Sink, Stream};
+
+use communication::Sender;
+use handler::Handler;
+use message::Message;
+use protocol::CloseCode;
+use result::{Error, Result};
+
+/// Connect to a WebSocket server on a thread of its own, returning a sink for the outgoing
+/// messages and a stream of the incoming ones, so that the connection can be used from async
+/// code with combinators instead of a `Handler`.
+///
+/// Like `connect_with_sender`, this blocks until the connection has been created but not until
+/// the handshake has completed. Messages sent before then are queued. Errors of the connection,
+/// such as a failed handshake, are items of the stream, which ends once the connection is
+/// closed.
+///
+/// Only the items are passed through channels, neither side applies backpressure: the stream
+/// holds the messages that haven't been polled, and the sink accepts messages as fast as they
+/// can be queued for the event loop.
+///
+/// # Examples
+///
+/// ```no_run
+/// extern crate futures;
+/// extern crate ws;
+///
+/// use futures::executor::block_on;
+/// use futures::{SinkExt, StreamExt};
+///
+/// # fn main() {
+/// let (mut sink, mut stream) = ws::connect_async("ws://127.0.0.1:3012").unwrap();
+/// block_on(sink.send("Hello WebSocket".into())).unwrap();
+/// if let Some(msg) = block_on(stream.next()) {
+///     println!("Got message: {}", msg.unwrap());
+/// }
+/// block_on(sink.close()).unwrap();
+/// # }
+/// ```
+pub fn connect_async<U>(url: U) -> Result<(MessageSink, MessageStream)>
+where
+    U: Borrow<str>,
+{
+    let (tx, rx) = unbounded();
+    let mut events = Some(tx);
+    let (out, _) = ::connect_with_sender(url, move |_| Bridge {
+        events: events.take(),
+    })?;
+    Ok((MessageSink { out }, MessageStream { events: rx }))
+}
+
+/// The outgoing half of a connection made with `connect_async`. Closing the sink starts the
+/// closing handshake with a normal close code.
+#[derive(Debug, Clone)]
+pub struct MessageSink {
+    out: Sender,
+}
+
+impl MessageSink {
+    /// The Sender of the connection, for pings or closing with another code.
+    pub fn sender(&self) -> &Sender {
+        &self.out
+    }
+}
+
+impl Sink<Message> for MessageSink {
+    type Error = Error;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<()>> {
+        Poll::Ready(self.out.close(CloseCode::Normal))
+    }
+}
+
+/// The incoming half of a connection made with `connect_async`.
+#[derive(Debug)]
+pub struct MessageStream {
+    events: UnboundedReceiver<Result<Message>>,
+}
+
+impl Stream for MessageStream {
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Message>>> {
+        Pin::new(&mut self.events).poll_next(cx)
+    }
+}
+
+// Passes the events of the connection on to its stream.
+struct Bridge {
+    events: Option<UnboundedSender<Result<Message>>>,
+}
+
+impl Bridge {
+    fn push(&self, event: Result<Message>) {
+        if let Some(ref events) = self.events {
+            // nobody is listening once the stream is dropped
+            let _ = events.unbounded_send(event);
+        }
+    }
+}
+
+impl Handler for Bridge {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.push(Ok(msg));
+        Ok(())
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        self.events = None;
+    }
+
+    fn on_error(&mut self, err: Error) {
+        self.push(Err(err));
+    }
+}
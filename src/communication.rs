@@ -1,31 +1,488 @@
+use std::any::Any;
 use std::borrow::Cow;
 use std::convert::Into;
+use std::error::Error as StdError;
+use std::io;
+use std::mem::replace;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, ThreadId};
+use std::time::Duration;
 
 use mio;
+use mio::channel::TrySendError;
 use mio::Token;
 use mio_extras::timer::Timeout;
+#[cfg(feature = "json")]
+use serde::Serialize;
+#[cfg(feature = "json")]
+use serde_json;
 use url;
 
+use frame::Frame;
+use handshake::Response;
 use io::ALL;
 use message;
-use protocol::CloseCode;
-use result::{Error, Result};
+use protocol::{CloseCode, OpCode};
+use result::{Error, Kind, Result};
+use OverflowPolicy;
 use std::cmp::PartialEq;
 use std::hash::{Hash, Hasher};
 use std::fmt;
+use std::result::Result as StdResult;
 
 #[derive(Debug, Clone)]
 pub enum Signal {
-    Message(message::Message),
+    Message(message::Message, Option<OverflowPolicy>, Option<AckToken>),
+    // a message sent with a ServerHandle, which isn't counted as pending by any Sender
+    Routed(message::Message),
+    // a message sent to all connections by a Sender other than the broadcaster, which isn't
+    // counted as pending by the broadcaster
+    Broadcast(message::Message, Option<OverflowPolicy>, Filter),
+    Fragment(Frame),
+    Frame(Frame),
+    Handshake(Response),
     Close(CloseCode, Cow<'static, str>),
+    CloseWhere(Selector, CloseCode, Cow<'static, str>),
     Ping(Vec<u8>),
     Pong(Vec<u8>),
     Connect(url::Url),
     Shutdown,
+    ShutdownGraceful(Duration),
+    Drain(Duration),
     Timeout { delay: u64, token: Token },
     Cancel(Timeout),
 }
 
+// The payload of a control frame is at most 125 bytes.
+fn check_control_payload(data: &[u8]) -> Result<()> {
+    if data.len() > 125 {
+        return Err(Error::new(
+            Kind::Internal,
+            "Control frames can't carry more than 125 bytes.",
+        ));
+    }
+    Ok(())
+}
+
+/// Selects the connections that a broadcast is sent to by their token.
+#[derive(Clone)]
+pub struct Filter(Arc<dyn Fn(Token) -> bool + Send + Sync>);
+
+impl Filter {
+    fn all() -> Filter {
+        Filter(Arc::new(|_| true))
+    }
+
+    pub fn matches(&self, token: Token) -> bool {
+        (self.0)(token)
+    }
+}
+
+impl fmt::Debug for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Filter")
+    }
+}
+
+/// Selects the connections that are closed by `Sender::close_where`.
+#[derive(Clone)]
+pub struct Selector(Arc<dyn Fn(&ConnectionInfo) -> bool + Send + Sync>);
+
+impl Selector {
+    pub fn matches(&self, info: &ConnectionInfo) -> bool {
+        (self.0)(info)
+    }
+}
+
+impl fmt::Debug for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Selector")
+    }
+}
+
+type State = Arc<dyn Any + Send + Sync>;
+
+/// What is known about an open connection when selecting the connections to close with
+/// `Sender::close_where`.
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    token: Token,
+    connection_id: u32,
+    peer_addr: Option<SocketAddr>,
+    state: Option<State>,
+}
+
+impl ConnectionInfo {
+    #[doc(hidden)]
+    pub fn new(
+        token: Token,
+        connection_id: u32,
+        peer_addr: Option<SocketAddr>,
+        shared: &Shared,
+    ) -> ConnectionInfo {
+        ConnectionInfo {
+            token,
+            connection_id,
+            peer_addr,
+            state: shared.state(),
+        }
+    }
+
+    /// The token of the connection, see `Sender::token`.
+    pub fn token(&self) -> Token {
+        self.token
+    }
+
+    /// The id of the connection, see `Sender::connection_id`.
+    pub fn connection_id(&self) -> u32 {
+        self.connection_id
+    }
+
+    /// The address of the other endpoint, if it is known.
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.peer_addr
+    }
+
+    /// The state attached to the connection with `Sender::set_state`, if it is of type `T`.
+    pub fn state<T: Any>(&self) -> Option<&T> {
+        self.state.as_ref()?.downcast_ref()
+    }
+}
+
+/// A snapshot of the traffic of a connection, or of all the connections of a WebSocket.
+///
+/// Bytes are counted as they are read from and written to the stream of a connection, so they
+/// include the opening handshake and the framing overhead, but for encrypted connections they
+/// count the data before encryption.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct Stats {
+    /// The number of bytes read from the other endpoint.
+    pub bytes_read: u64,
+    /// The number of bytes written to the other endpoint.
+    pub bytes_written: u64,
+    /// The number of messages received.
+    pub messages_in: u64,
+    /// The number of messages sent.
+    pub messages_out: u64,
+    /// The number of frames received, including control frames.
+    pub frames_in: u64,
+    /// The number of frames sent, including control frames.
+    pub frames_out: u64,
+}
+
+/// The number of connections of a WebSocket in each state, see `WebSocket::connection_counts`.
+///
+/// Connections that have closed aren't counted.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct ConnectionCounts {
+    /// The number of connections whose opening handshake hasn't completed yet, including client
+    /// connections that are waiting to reconnect.
+    pub connecting: usize,
+    /// The number of open connections.
+    pub open: usize,
+    /// The number of connections that are going through the closing handshake.
+    pub closing: usize,
+}
+
+/// The counters behind `Stats` and `ConnectionCounts`.
+#[derive(Debug, Default)]
+pub struct Counters {
+    bytes_read: AtomicU64,
+    bytes_written: AtomicU64,
+    messages_in: AtomicU64,
+    messages_out: AtomicU64,
+    frames_in: AtomicU64,
+    frames_out: AtomicU64,
+    // the connections in each state, which are only counted in the totals
+    connecting: AtomicUsize,
+    open: AtomicUsize,
+    closing: AtomicUsize,
+}
+
+impl Counters {
+    pub fn stats(&self) -> Stats {
+        Stats {
+            bytes_read: self.bytes_read.load(Ordering::Relaxed),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            messages_in: self.messages_in.load(Ordering::Relaxed),
+            messages_out: self.messages_out.load(Ordering::Relaxed),
+            frames_in: self.frames_in.load(Ordering::Relaxed),
+            frames_out: self.frames_out.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        ConnectionCounts {
+            connecting: self.connecting.load(Ordering::Relaxed),
+            open: self.open.load(Ordering::Relaxed),
+            closing: self.closing.load(Ordering::Relaxed),
+        }
+    }
+
+    fn connections(&self, state: ConnectionState) -> Option<&AtomicUsize> {
+        match state {
+            ConnectionState::Connecting => Some(&self.connecting),
+            ConnectionState::Open => Some(&self.open),
+            ConnectionState::Closing => Some(&self.closing),
+            ConnectionState::Closed => None,
+        }
+    }
+}
+
+/// Identifies a message sent with `Sender::send_with_ack`. The token is handed to
+/// `Handler::on_sent` once the message has been written, tokens are unique to a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AckToken(u64);
+
+/// The state of a connection, see `Sender::connection_state`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ConnectionState {
+    /// The opening handshake hasn't completed yet, or a lost client connection is waiting to
+    /// reconnect.
+    Connecting,
+    /// The connection is open, so messages sent over it are delivered.
+    Open,
+    /// A close frame has been sent or received, messages sent now are dropped.
+    Closing,
+    /// The connection is gone, or its event loop has stopped.
+    Closed,
+}
+
+impl ConnectionState {
+    fn from_usize(state: usize) -> ConnectionState {
+        match state {
+            0 => ConnectionState::Connecting,
+            1 => ConnectionState::Open,
+            2 => ConnectionState::Closing,
+            _ => ConnectionState::Closed,
+        }
+    }
+}
+
+/// State shared between a connection and the senders that feed it.
+#[derive(Debug)]
+pub struct Shared {
+    high_water_mark: usize,
+    fragment_size: usize,
+    // bytes of messages sitting in the event loop queue
+    queued: AtomicUsize,
+    // bytes written to the output buffer but not yet to the socket
+    buffered: AtomicUsize,
+    // messages held back by the connection until earlier output has been written
+    held: AtomicUsize,
+    queue_limit: Option<usize>,
+    overflow_policy: OverflowPolicy,
+    // the thread of the event loop, on which waiting for room would never end
+    event_loop: ThreadId,
+    room: Condvar,
+    room_lock: Mutex<()>,
+    // the counters of this connection and those of the whole WebSocket
+    counters: Counters,
+    totals: Arc<Counters>,
+    // the state attached to the connection by its handler
+    state: Mutex<Option<State>>,
+    // the ConnectionState of the connection
+    status: AtomicUsize,
+    // the number of the next message sent with an acknowledgement
+    next_ack: AtomicU64,
+}
+
+impl Shared {
+    pub fn new(high_water_mark: usize, fragment_size: usize, totals: Arc<Counters>) -> Shared {
+        let shared = Shared {
+            high_water_mark,
+            fragment_size,
+            queued: AtomicUsize::new(0),
+            buffered: AtomicUsize::new(0),
+            held: AtomicUsize::new(0),
+            queue_limit: None,
+            overflow_policy: OverflowPolicy::Block,
+            event_loop: thread::current().id(),
+            room: Condvar::new(),
+            room_lock: Mutex::new(()),
+            counters: Counters::default(),
+            totals,
+            state: Mutex::new(None),
+            status: AtomicUsize::new(ConnectionState::Closed as usize),
+            next_ack: AtomicU64::new(0),
+        };
+        shared.set_status(ConnectionState::Connecting);
+        shared
+    }
+
+    /// Limit the messages held back by the connection, this must be created on the thread of the
+    /// event loop.
+    pub fn with_queue_limit(mut self, limit: Option<usize>, policy: OverflowPolicy) -> Shared {
+        self.queue_limit = limit;
+        self.overflow_policy = policy;
+        self
+    }
+
+    #[inline]
+    fn state(&self) -> Option<State> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    pub fn set_status(&self, status: ConnectionState) {
+        let old = ConnectionState::from_usize(self.status.swap(status as usize, Ordering::Relaxed));
+        if old != status {
+            if let Some(count) = self.totals.connections(old) {
+                count.fetch_sub(1, Ordering::Relaxed);
+            }
+            if let Some(count) = self.totals.connections(status) {
+                count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    #[inline]
+    fn status(&self) -> ConnectionState {
+        ConnectionState::from_usize(self.status.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    fn count(&self, counter: fn(&Counters) -> &AtomicU64, n: usize) {
+        counter(&self.counters).fetch_add(n as u64, Ordering::Relaxed);
+        counter(&self.totals).fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    /// Count the bytes of a read from the stream, passing its result through.
+    #[inline]
+    pub fn count_read(&self, read: Option<usize>) -> Option<usize> {
+        if let Some(len) = read {
+            self.count(|c| &c.bytes_read, len);
+        }
+        read
+    }
+
+    /// Count the bytes of a write to the stream, passing its result through.
+    #[inline]
+    pub fn count_written(&self, written: Option<usize>) -> Option<usize> {
+        if let Some(len) = written {
+            self.count(|c| &c.bytes_written, len);
+        }
+        written
+    }
+
+    #[inline]
+    pub fn count_message_in(&self) {
+        self.count(|c| &c.messages_in, 1);
+    }
+
+    #[inline]
+    pub fn count_message_out(&self) {
+        self.count(|c| &c.messages_out, 1);
+    }
+
+    #[inline]
+    pub fn count_frame_in(&self) {
+        self.count(|c| &c.frames_in, 1);
+    }
+
+    #[inline]
+    pub fn count_frame_out(&self) {
+        self.count(|c| &c.frames_out, 1);
+    }
+
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.queued
+            .load(Ordering::Relaxed)
+            .saturating_add(self.buffered.load(Ordering::Relaxed))
+    }
+
+    #[inline]
+    pub fn enqueue(&self, len: usize) {
+        self.queued.fetch_add(len, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn dequeue(&self, len: usize) {
+        self.queued.fetch_sub(len, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn set_buffered(&self, len: usize) {
+        self.buffered.store(len, Ordering::Relaxed);
+    }
+
+    pub fn set_held(&self, held: usize) {
+        self.held.store(held, Ordering::Relaxed);
+        if self.queue_limit.is_some() {
+            let _lock = self.room_lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            self.room.notify_all();
+        }
+    }
+
+    #[inline]
+    fn is_full(&self) -> bool {
+        self.queue_limit
+            .is_some_and(|limit| self.held.load(Ordering::Relaxed) >= limit)
+    }
+
+    // Block until the connection holds back fewer messages than its limit.
+    fn wait_for_room(&self) {
+        if thread::current().id() == self.event_loop {
+            return;
+        }
+        let mut lock = self.room_lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        while self.is_full() {
+            // wake up now and then in case a notification was missed
+            lock = self.room
+                .wait_timeout(lock, Duration::from_millis(100))
+                .unwrap_or_else(|poisoned| poisoned.into_inner())
+                .0;
+        }
+    }
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        // a connection that never got going is no longer counted either
+        self.set_status(ConnectionState::Closed);
+    }
+}
+
+/// The error returned by `Sender::try_send`.
+#[derive(Debug)]
+pub enum SendError {
+    /// The pending output of the connection is above `Settings::high_water_mark` or the event
+    /// loop queue is full. The message was not queued and is handed back so that it may be
+    /// retried later or dropped.
+    WouldBlock(message::Message),
+    /// The message could not be queued because of some other error.
+    Error(Error),
+}
+
+impl fmt::Display for SendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SendError::WouldBlock(_) => write!(f, "Sending would block the connection"),
+            SendError::Error(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl StdError for SendError {
+    fn cause(&self) -> Option<&dyn StdError> {
+        match *self {
+            SendError::WouldBlock(_) => None,
+            SendError::Error(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<Error> for SendError {
+    fn from(err: Error) -> SendError {
+        SendError::Error(err)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Command {
     token: Token,
@@ -49,11 +506,16 @@ impl Command {
 
 /// A representation of the output of the WebSocket connection. Use this to send messages to the
 /// other endpoint.
+///
+/// Senders compare equal and hash the same when they belong to the same connection, so a Sender
+/// or its `token` and `connection_id` may be used to key per-connection state.
 #[derive(Clone)]
 pub struct Sender {
     token: Token,
     channel: mio::channel::SyncSender<Command>,
     connection_id: u32,
+    shared: Arc<Shared>,
+    overflow_policy: Option<OverflowPolicy>,
 }
 
 impl fmt::Debug for Sender {
@@ -87,39 +549,344 @@ impl Sender {
         token: Token,
         channel: mio::channel::SyncSender<Command>,
         connection_id: u32,
+    ) -> Sender {
+        Sender::with_shared(
+            token,
+            channel,
+            connection_id,
+            Arc::new(Shared::new(
+                usize::MAX,
+                u16::MAX as usize,
+                Arc::new(Counters::default()),
+            )),
+        )
+    }
+
+    #[doc(hidden)]
+    #[inline]
+    pub fn with_shared(
+        token: Token,
+        channel: mio::channel::SyncSender<Command>,
+        connection_id: u32,
+        shared: Arc<Shared>,
     ) -> Sender {
         Sender {
             token,
             channel,
             connection_id,
+            shared,
+            overflow_policy: None,
         }
     }
 
     /// A Token identifying this sender within the WebSocket.
+    ///
+    /// Tokens are only unique among the connections that are currently open, the token of a
+    /// closed connection may be given to a new one. Use the `connection_id` as well to tell them
+    /// apart.
     #[inline]
     pub fn token(&self) -> Token {
         self.token
     }
 
     /// A connection_id identifying this sender within the WebSocket.
+    ///
+    /// Each new connection is given the next id, so unlike tokens, ids aren't reused until the
+    /// counter wraps around.
     #[inline]
     pub fn connection_id(&self) -> u32 {
         self.connection_id
     }
 
+    /// A copy of this sender whose messages are handled according to the given policy, instead
+    /// of `Settings::overflow_policy`, when `Settings::max_queued_messages` messages are already
+    /// held back by the connection.
+    pub fn with_overflow_policy(&self, policy: OverflowPolicy) -> Sender {
+        Sender {
+            overflow_policy: Some(policy),
+            ..self.clone()
+        }
+    }
+
+    #[inline]
+    fn overflow_policy(&self) -> OverflowPolicy {
+        self.overflow_policy.unwrap_or(self.shared.overflow_policy)
+    }
+
     /// Send a message over the connection.
+    ///
+    /// With `OverflowPolicy::Block`, this waits while the connection holds back
+    /// `Settings::max_queued_messages` messages, unless it is called from the thread of the event
+    /// loop.
     #[inline]
     pub fn send<M>(&self, msg: M) -> Result<()>
     where
         M: Into<message::Message>,
     {
+        self.send_message(msg.into(), None)
+    }
+
+    /// Serialize the value to JSON and send it as a text message. This fails with a `Kind::Json`
+    /// error if the value can't be serialized, such as a map with keys that aren't strings.
+    #[cfg(feature = "json")]
+    #[inline]
+    pub fn send_json<T>(&self, value: &T) -> Result<()>
+    where
+        T: Serialize + ?Sized,
+    {
+        self.send(serde_json::to_string(value)?)
+    }
+
+    /// Send a message over the connection and get a token that is handed to `Handler::on_sent`
+    /// once all of the message has been written to the socket, unlike `send`, which doesn't tell
+    /// when that happens. The token of a message that is dropped, or that is still waiting to be
+    /// written when the connection closes, is never handed back.
+    ///
+    /// The sender returned by `WebSocket::broadcaster` can't send messages with a token.
+    pub fn send_with_ack<M>(&self, msg: M) -> Result<AckToken>
+    where
+        M: Into<message::Message>,
+    {
+        if self.token == ALL {
+            return Err(Error::new(
+                Kind::Internal,
+                "Messages sent to all connections can't be acknowledged.",
+            ));
+        }
+        let ack = AckToken(self.shared.next_ack.fetch_add(1, Ordering::Relaxed));
+        self.send_message(msg.into(), Some(ack)).map(|()| ack)
+    }
+
+    fn send_message(&self, msg: message::Message, ack: Option<AckToken>) -> Result<()> {
+        if self.overflow_policy() == OverflowPolicy::Block {
+            self.shared.wait_for_room();
+        }
+        let len = msg.len();
+        // count the message before the event loop has a chance to see it
+        self.shared.enqueue(len);
         self.channel
             .send(Command {
                 token: self.token,
-                signal: Signal::Message(msg.into()),
+                signal: Signal::Message(msg, self.overflow_policy, ack),
                 connection_id: self.connection_id,
             })
-            .map_err(Error::from)
+            .map_err(|err| {
+                self.shared.dequeue(len);
+                Error::from(err)
+            })
+    }
+
+    /// Attempt to send a message over the connection without letting output pile up.
+    ///
+    /// If the bytes waiting to be written to the other endpoint exceed
+    /// `Settings::high_water_mark`, or if the event loop queue is full, the message is handed
+    /// back in `SendError::WouldBlock` instead of being queued. This allows the caller to drop or
+    /// throttle messages for slow consumers. The same happens with `OverflowPolicy::Block` while
+    /// the connection holds back `Settings::max_queued_messages` messages.
+    pub fn try_send<M>(&self, msg: M) -> StdResult<(), SendError>
+    where
+        M: Into<message::Message>,
+    {
+        let msg = msg.into();
+        if self.shared.pending() > self.shared.high_water_mark
+            || (self.overflow_policy() == OverflowPolicy::Block && self.shared.is_full())
+        {
+            return Err(SendError::WouldBlock(msg));
+        }
+
+        let len = msg.len();
+        self.shared.enqueue(len);
+        let res = self.channel.try_send(Command {
+            token: self.token,
+            signal: Signal::Message(msg, self.overflow_policy, None),
+            connection_id: self.connection_id,
+        });
+        if res.is_err() {
+            self.shared.dequeue(len);
+        }
+
+        match res {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(cmd)) => match cmd.into_signal() {
+                Signal::Message(msg, _, _) => Err(SendError::WouldBlock(msg)),
+                _ => unreachable!(),
+            },
+            Err(TrySendError::Disconnected(cmd)) => Err(SendError::Error(Error::from(
+                mio::channel::SendError::Disconnected(cmd),
+            ))),
+            Err(TrySendError::Io(err)) => Err(SendError::Error(Error::from(err))),
+        }
+    }
+
+    /// Start sending a message whose data is written incrementally.
+    ///
+    /// The returned writer sends the data written to it as the fragments of a single message,
+    /// each time enough has been written to fill `Settings::fragment_size`. Call
+    /// `MessageWriter::finish` to send the final fragment. Other messages sent over the
+    /// connection in the meantime are held back until the streamed message is finished, but
+    /// control frames such as pings are still sent right away.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the opcode is not `OpCode::Text` or `OpCode::Binary`.
+    pub fn stream(&self, opcode: OpCode) -> MessageWriter {
+        assert!(
+            opcode == OpCode::Text || opcode == OpCode::Binary,
+            "Only text and binary messages can be streamed."
+        );
+        MessageWriter {
+            sender: self.clone(),
+            opcode,
+            buffer: Vec::with_capacity(self.shared.fragment_size),
+            started: false,
+            finished: false,
+        }
+    }
+
+    /// Send a frame over the connection exactly as it was constructed, bypassing the encoding
+    /// of messages. The frame is still passed to `Handler::on_send_frame`, and it is masked if
+    /// this is a client connection.
+    ///
+    /// Frames sent this way are not tracked by the connection, so it is up to the caller to keep
+    /// the conversation valid. For example, sending a close frame doesn't close the connection,
+    /// and the fragments of a message may be interleaved with other messages.
+    #[inline]
+    pub fn send_frame(&self, frame: Frame) -> Result<()> {
+        let len = frame.payload().len();
+        self.shared.enqueue(len);
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Frame(frame),
+                connection_id: self.connection_id,
+            })
+            .map_err(|err| {
+                self.shared.dequeue(len);
+                Error::from(err)
+            })
+    }
+
+    /// Send a single frame with one of the opcodes that the protocol reserves for further frame
+    /// types, 3-7 for non-control frames and 11-15 for control frames. This is meant for
+    /// experimenting with protocol extensions; endpoints that don't know the opcode fail the
+    /// connection when they receive such a frame.
+    ///
+    /// Like the frames of `send_frame`, these frames are not tracked by the connection, and they
+    /// are sent without compression. This fails with an internal error for opcodes that aren't
+    /// reserved, and for control frames with payloads over 125 bytes.
+    pub fn send_custom(&self, opcode: u8, data: Vec<u8>) -> Result<()> {
+        let opcode = match opcode {
+            3..=7 | 11..=15 => OpCode::Reserved(opcode),
+            _ => {
+                return Err(Error::new(
+                    Kind::Internal,
+                    format!("Opcode {} is not a reserved opcode.", opcode),
+                ))
+            }
+        };
+        if opcode.is_control() {
+            check_control_payload(&data)?;
+        }
+        let mut frame = Frame::message(data, OpCode::Binary, true);
+        frame.set_opcode(opcode);
+        self.send_frame(frame)
+    }
+
+    fn send_fragment(&self, frame: Frame) -> Result<()> {
+        let len = frame.payload().len();
+        self.shared.enqueue(len);
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Fragment(frame),
+                connection_id: self.connection_id,
+            })
+            .map_err(|err| {
+                self.shared.dequeue(len);
+                Error::from(err)
+            })
+    }
+
+    /// The number of bytes sent over this connection that have not yet been written to the
+    /// other endpoint.
+    #[inline]
+    pub fn pending(&self) -> usize {
+        self.shared.pending()
+    }
+
+    /// The traffic of this connection so far.
+    ///
+    /// For the sender returned by `WebSocket::broadcaster`, this is the traffic of all the
+    /// connections of the WebSocket, including those that have already closed.
+    #[inline]
+    pub fn stats(&self) -> Stats {
+        if self.token == ALL {
+            self.shared.totals.stats()
+        } else {
+            self.shared.counters.stats()
+        }
+    }
+
+    /// The number of connections of the WebSocket that this sender belongs to in each state,
+    /// see `WebSocket::connection_counts`.
+    #[inline]
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        self.shared.totals.connection_counts()
+    }
+
+    /// The state of the connection, which tells whether messages sent now will be delivered.
+    /// Messages sent over a connection that isn't open are dropped, so this allows pruning
+    /// stored senders before sending to them. The state may change right after it is read.
+    ///
+    /// The sender returned by `WebSocket::broadcaster` isn't bound to a connection, its state is
+    /// always `Open`.
+    #[inline]
+    pub fn connection_state(&self) -> ConnectionState {
+        if self.token == ALL {
+            ConnectionState::Open
+        } else {
+            self.shared.status()
+        }
+    }
+
+    /// Whether the connection is open, see `connection_state`.
+    #[inline]
+    pub fn is_open(&self) -> bool {
+        self.connection_state() == ConnectionState::Open
+    }
+
+    /// Attach state to the connection, replacing any state that was attached before. The state
+    /// is offered to the selectors of `close_where`, so that connections can be picked by
+    /// something the handler knows about them, such as the room they have joined.
+    ///
+    /// The state is shared by all clones of this sender, so it can be read back with `state` in
+    /// later callbacks, or from other threads, without keeping a map of connections. For example,
+    /// a handler built by a closure can remember who authenticated:
+    ///
+    /// ```ignore
+    /// // in on_open, once the request is authenticated
+    /// self.out.set_state(User { id: 42 });
+    ///
+    /// // in on_message
+    /// let user = self.out.state::<User>().expect("Set when the connection opened.");
+    /// ```
+    pub fn set_state<T>(&self, state: T)
+    where
+        T: Any + Send + Sync,
+    {
+        *self.shared
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(Arc::new(state));
+    }
+
+    /// The state attached to the connection with `set_state`, if there is any and it is of type
+    /// `T`.
+    pub fn state<T>(&self) -> Option<Arc<T>>
+    where
+        T: Any + Send + Sync,
+    {
+        self.shared.state()?.downcast().ok()
     }
 
     /// Send a message to the endpoints of all connections.
@@ -129,15 +896,48 @@ impl Sender {
     /// send a copy of the message to each connected client. However, if you have a WebSocket that
     /// is listening for connections and is also connected to another WebSocket, this method will
     /// broadcast a copy of the message to all the clients connected and to that WebSocket server.
+    ///
+    /// The message is encoded once, and the same frames are written to every server connection
+    /// whose handler allows it, see `Handler::share_broadcasts`, rather than copying the payload
+    /// for each connection.
     #[inline]
     pub fn broadcast<M>(&self, msg: M) -> Result<()>
     where
         M: Into<message::Message>,
+    {
+        if self.token == ALL {
+            // the broadcaster counts the message as pending until the event loop sends it
+            return self.send_message(msg.into(), None);
+        }
+        self.channel
+            .send(Command {
+                token: ALL,
+                signal: Signal::Broadcast(msg.into(), self.overflow_policy, Filter::all()),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Send a message to the endpoints of the connections whose token is accepted by the
+    /// filter.
+    ///
+    /// This works like `broadcast`, except that connections for which the filter returns false
+    /// are skipped. For example, a chat server can relay a message to everyone but its author.
+    ///
+    /// ```ignore
+    /// let author = self.out.token();
+    /// self.out.broadcast_filter(msg, move |token| token != author)
+    /// ```
+    #[inline]
+    pub fn broadcast_filter<M, F>(&self, msg: M, filter: F) -> Result<()>
+    where
+        M: Into<message::Message>,
+        F: Fn(Token) -> bool + Send + Sync + 'static,
     {
         self.channel
             .send(Command {
                 token: ALL,
-                signal: Signal::Message(msg.into()),
+                signal: Signal::Broadcast(msg.into(), None, Filter(Arc::new(filter))),
                 connection_id: self.connection_id,
             })
             .map_err(Error::from)
@@ -155,7 +955,8 @@ impl Sender {
             .map_err(Error::from)
     }
 
-    /// Send a close code and provide a descriptive reason for closing.
+    /// Send a close code and provide a descriptive reason for closing. The reason reaches the
+    /// `on_close` of the other endpoint, truncated to the 123 bytes that fit in a close frame.
     #[inline]
     pub fn close_with_reason<S>(&self, code: CloseCode, reason: S) -> Result<()>
     where
@@ -170,9 +971,44 @@ impl Sender {
             .map_err(Error::from)
     }
 
-    /// Send a ping to the other endpoint with the given test data.
+    /// Close every open connection of the WebSocket that the selector returns true for, with the
+    /// given code and reason.
+    ///
+    /// The selector runs on the event loop thread, so each connection is either still open and
+    /// offered to the selector, or already closed and skipped. For example, a chat server can
+    /// close everyone in a room:
+    ///
+    /// ```ignore
+    /// // in on_message, after the user joined a room
+    /// self.out.set_state(Room(name));
+    ///
+    /// // from anywhere
+    /// out.close_where(
+    ///     |info| info.state::<Room>().map_or(false, |room| room.0 == "lobby"),
+    ///     CloseCode::Policy,
+    ///     "The lobby is closed.",
+    /// )
+    /// ```
+    #[inline]
+    pub fn close_where<F, S>(&self, selector: F, code: CloseCode, reason: S) -> Result<()>
+    where
+        F: Fn(&ConnectionInfo) -> bool + Send + Sync + 'static,
+        S: Into<Cow<'static, str>>,
+    {
+        self.channel
+            .send(Command {
+                token: ALL,
+                signal: Signal::CloseWhere(Selector(Arc::new(selector)), code, reason.into()),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Send a ping to the other endpoint with the given test data. This fails with an internal
+    /// error for data over 125 bytes, which doesn't fit in a control frame.
     #[inline]
     pub fn ping(&self, data: Vec<u8>) -> Result<()> {
+        check_control_payload(&data)?;
         self.channel
             .send(Command {
                 token: self.token,
@@ -182,9 +1018,11 @@ impl Sender {
             .map_err(Error::from)
     }
 
-    /// Send a pong to the other endpoint responding with the given test data.
+    /// Send a pong to the other endpoint responding with the given test data. Like `ping`, this
+    /// fails for data over 125 bytes.
     #[inline]
     pub fn pong(&self, data: Vec<u8>) -> Result<()> {
+        check_control_payload(&data)?;
         self.channel
             .send(Command {
                 token: self.token,
@@ -194,6 +1032,20 @@ impl Sender {
             .map_err(Error::from)
     }
 
+    /// Complete a handshake that was deferred by returning `Response::pending` from
+    /// `Handler::on_request`. The handshake is accepted or failed depending on the status of the
+    /// response, as if it had been returned from `on_request`.
+    #[inline]
+    pub fn complete_handshake(&self, response: Response) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::Handshake(response),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
     /// Queue a new connection on this WebSocket to the specified URL.
     #[inline]
     pub fn connect(&self, url: url::Url) -> Result<()> {
@@ -218,6 +1070,23 @@ impl Sender {
             .map_err(Error::from)
     }
 
+    /// Request that the WebSocket stop running once all connections have closed cleanly.
+    ///
+    /// The WebSocket will stop accepting new connections and send a close frame to every open
+    /// connection after any messages that were already queued for it. The event loop exits once
+    /// the other endpoints have completed the closing handshake, or when `timeout` elapses,
+    /// whichever comes first.
+    #[inline]
+    pub fn shutdown_graceful(&self, timeout: Duration) -> Result<()> {
+        self.channel
+            .send(Command {
+                token: self.token,
+                signal: Signal::ShutdownGraceful(timeout),
+                connection_id: self.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
     /// Schedule a `token` to be sent to the WebSocket Handler's `on_timeout` method
     /// after `ms` milliseconds
     #[inline]
@@ -247,3 +1116,349 @@ impl Sender {
             .map_err(Error::from)
     }
 }
+
+/// A handle for sending to and closing individual connections of a WebSocket by their token,
+/// from any thread, see `WebSocket::handle`. This lets the connections that a message is routed
+/// to be decided elsewhere, such as in a pool of workers, without holding on to the Sender of
+/// each connection.
+///
+/// Connections are addressed by their token together with their connection id, as given by
+/// `Sender::token` and `Sender::connection_id`. Tokens are given to new connections once the
+/// connections that held them have closed, but the id tells them apart, so nothing sent to a
+/// connection that has closed reaches a newer one.
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    sender: Sender,
+}
+
+impl ServerHandle {
+    #[doc(hidden)]
+    pub fn new(sender: Sender) -> ServerHandle {
+        ServerHandle { sender }
+    }
+
+    /// Send a message to the connection with the given token and connection id. Nothing is sent
+    /// if there is no such connection by the time the event loop handles the message.
+    #[inline]
+    pub fn send_to<M>(&self, token: Token, connection_id: u32, msg: M) -> Result<()>
+    where
+        M: Into<message::Message>,
+    {
+        self.sender
+            .channel
+            .send(Command {
+                token,
+                signal: Signal::Routed(msg.into()),
+                connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Close the connection with the given token and connection id with a close code.
+    #[inline]
+    pub fn close(&self, token: Token, connection_id: u32, code: CloseCode) -> Result<()> {
+        self.sender
+            .channel
+            .send(Command {
+                token,
+                signal: Signal::Close(code, "".into()),
+                connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// Start draining the WebSocket, for example ahead of a rolling deploy. New handshakes are
+    /// answered with a 503 response, while the open connections carry on undisturbed. The
+    /// WebSocket shuts down once the last of them has closed. If some are still open after `max`
+    /// has passed, they are closed with the Away close code and given as long again to finish
+    /// closing, as with `Sender::shutdown_graceful`.
+    ///
+    /// `Factory::on_drain_start` is called when draining starts.
+    #[inline]
+    pub fn start_draining(&self, max: Duration) -> Result<()> {
+        self.sender
+            .channel
+            .send(Command {
+                token: ALL,
+                signal: Signal::Drain(max),
+                connection_id: self.sender.connection_id,
+            })
+            .map_err(Error::from)
+    }
+
+    /// A Sender for all the connections, like `WebSocket::broadcaster`.
+    #[inline]
+    pub fn broadcaster(&self) -> &Sender {
+        &self.sender
+    }
+}
+
+/// Writes a message to a connection incrementally, see `Sender::stream`.
+///
+/// Dropping the writer finishes the message as well, but any error is lost.
+pub struct MessageWriter {
+    sender: Sender,
+    opcode: OpCode,
+    buffer: Vec<u8>,
+    started: bool,
+    finished: bool,
+}
+
+impl MessageWriter {
+    /// Send the data that is still buffered as the final fragment of the message.
+    pub fn finish(mut self) -> Result<()> {
+        self.finished = true;
+        self.send_fragment(true)
+    }
+
+    fn send_fragment(&mut self, finished: bool) -> Result<()> {
+        let opcode = if self.started {
+            OpCode::Continue
+        } else {
+            self.opcode
+        };
+        self.started = true;
+        let capacity = self.sender.shared.fragment_size;
+        let data = replace(&mut self.buffer, Vec::with_capacity(capacity));
+        self.sender.send_fragment(Frame::message(data, opcode, finished))
+    }
+}
+
+impl io::Write for MessageWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let space = self.sender.shared.fragment_size - self.buffer.len();
+        let len = buf.len().min(space);
+        self.buffer.extend_from_slice(&buf[..len]);
+        if self.buffer.len() >= self.sender.shared.fragment_size {
+            self.send_fragment(false).map_err(io::Error::other)?;
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            self.send_fragment(false).map_err(io::Error::other)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for MessageWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.send_fragment(true);
+        }
+    }
+}
+
+impl fmt::Debug for MessageWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "MessageWriter {{ sender: {:?}, opcode: {:?}, buffered: {} }}",
+            self.sender,
+            self.opcode,
+            self.buffer.len()
+        )
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use mio;
+
+    #[test]
+    fn try_send_high_water_mark() {
+        let (chn, rx) = mio::channel::sync_channel(42);
+        let shared = Arc::new(Shared::new(10, 16, Arc::new(Counters::default())));
+        let sender = Sender::with_shared(Token(0), chn, 0, shared.clone());
+
+        assert!(sender.try_send("0123456789").is_ok());
+        assert_eq!(sender.pending(), 10);
+        assert!(sender.try_send("x").is_ok());
+
+        match sender.try_send("blocked") {
+            Err(SendError::WouldBlock(msg)) => assert_eq!(msg.as_text().unwrap(), "blocked"),
+            other => panic!("{:?}", other),
+        }
+
+        shared.dequeue(11);
+        assert_eq!(sender.pending(), 0);
+        assert!(sender.try_send("unblocked").is_ok());
+    }
+
+    #[test]
+    fn send_custom() {
+        let (chn, rx) = mio::channel::sync_channel(42);
+        let sender = Sender::new(Token(0), chn, 0);
+
+        sender.send_custom(3, b"custom".to_vec()).unwrap();
+        sender.send_custom(15, vec![0; 125]).unwrap();
+        assert!(sender.send_custom(2, Vec::new()).is_err());
+        assert!(sender.send_custom(8, Vec::new()).is_err());
+        assert!(sender.send_custom(16, Vec::new()).is_err());
+        assert!(sender.send_custom(11, vec![0; 126]).is_err());
+        assert!(sender.ping(vec![0; 126]).is_err());
+        assert!(sender.pong(vec![0; 126]).is_err());
+
+        let mut formatted = Vec::new();
+        match rx.try_recv().unwrap().into_signal() {
+            Signal::Frame(mut frame) => {
+                assert_eq!(frame.opcode(), OpCode::Reserved(3));
+                assert!(!frame.is_control());
+                frame.format(&mut formatted).unwrap();
+            }
+            other => panic!("{:?}", other),
+        }
+        // FIN and the reserved opcode
+        assert_eq!(formatted[0], 0x83);
+        match rx.try_recv().unwrap().into_signal() {
+            Signal::Frame(frame) => assert!(frame.is_control()),
+            other => panic!("{:?}", other),
+        }
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn send_waits_for_room() {
+        let (chn, rx) = mio::channel::sync_channel(42);
+        // this thread takes the place of the event loop
+        let shared = Arc::new(
+            Shared::new(usize::MAX, 16, Arc::new(Counters::default()))
+                .with_queue_limit(Some(1), OverflowPolicy::Block),
+        );
+        let sender = Sender::with_shared(Token(0), chn, 0, shared.clone());
+        shared.set_held(1);
+
+        // the event loop never waits
+        sender.send("looped").unwrap();
+        assert!(rx.try_recv().is_ok());
+        match sender.try_send("full") {
+            Err(SendError::WouldBlock(_)) => (),
+            other => panic!("{:?}", other),
+        }
+
+        let other = sender.clone();
+        let waiting = ::std::thread::spawn(move || other.send("waited").unwrap());
+        ::std::thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+
+        shared.set_held(0);
+        waiting.join().unwrap();
+        assert!(rx.try_recv().is_ok());
+
+        // messages that may be dropped are sent right away
+        shared.set_held(1);
+        let dropping = sender.with_overflow_policy(OverflowPolicy::DropNewest);
+        ::std::thread::spawn(move || dropping.send("dropped").unwrap())
+            .join()
+            .unwrap();
+        match rx.try_recv().unwrap().into_signal() {
+            Signal::Message(_, policy, _) => assert_eq!(policy, Some(OverflowPolicy::DropNewest)),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn broadcast_filter() {
+        let (chn, rx) = mio::channel::sync_channel(1);
+        let sender = Sender::new(Token(1), chn, 0);
+        let author = sender.token();
+        sender
+            .broadcast_filter("hi", move |token| token != author)
+            .unwrap();
+
+        let cmd = rx.try_recv().unwrap();
+        assert_eq!(cmd.token(), ALL);
+        match cmd.into_signal() {
+            Signal::Broadcast(msg, _, filter) => {
+                assert_eq!(msg.as_text().unwrap(), "hi");
+                assert!(!filter.matches(Token(1)));
+                assert!(filter.matches(Token(2)));
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn sender_identity() {
+        let (chn, rx) = mio::channel::sync_channel(1);
+        let sender = Sender::new(Token(0), chn.clone(), 0);
+        let reused = Sender::new(Token(0), chn, 1);
+
+        assert_eq!(sender, sender.clone());
+        assert!(sender != reused);
+
+        let mut ids = ::std::collections::HashSet::new();
+        ids.insert((sender.token(), sender.connection_id()));
+        assert!(ids.contains(&(sender.token(), sender.connection_id())));
+        assert!(!ids.contains(&(reused.token(), reused.connection_id())));
+    }
+
+    #[test]
+    fn connection_state() {
+        #[derive(Debug, PartialEq)]
+        struct User(u32);
+
+        let (chn, _) = mio::channel::sync_channel(1);
+        let sender = Sender::new(Token(0), chn, 0);
+        let clone = sender.clone();
+        assert!(sender.state::<User>().is_none());
+
+        clone.set_state(User(42));
+        assert_eq!(sender.state::<User>().as_ref().map(|user| &**user), Some(&User(42)));
+        // state of another type is not found
+        assert!(sender.state::<String>().is_none());
+
+        sender.set_state("replaced".to_string());
+        assert!(clone.state::<User>().is_none());
+        assert_eq!(clone.state::<String>().unwrap().as_str(), "replaced");
+    }
+
+    #[test]
+    fn try_send_full_queue() {
+        let (chn, rx) = mio::channel::sync_channel(1);
+        let sender = Sender::new(Token(0), chn, 0);
+
+        assert!(sender.try_send("first").is_ok());
+        match sender.try_send("second") {
+            Err(SendError::WouldBlock(msg)) => assert_eq!(msg.as_text().unwrap(), "second"),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn stream_fragments() {
+        use std::io::Write;
+
+        let (chn, rx) = mio::channel::sync_channel(42);
+        let shared = Arc::new(Shared::new(usize::MAX, 4, Arc::new(Counters::default())));
+        let sender = Sender::with_shared(Token(0), chn, 0, shared.clone());
+
+        let mut writer = sender.stream(OpCode::Text);
+        writer.write_all(b"hello world").unwrap();
+        assert_eq!(sender.pending(), 8);
+        writer.finish().unwrap();
+
+        let mut frames = Vec::new();
+        while let Ok(cmd) = rx.try_recv() {
+            match cmd.into_signal() {
+                Signal::Fragment(frame) => frames.push(frame),
+                other => panic!("{:?}", other),
+            }
+        }
+        let frames: Vec<_> = frames
+            .iter()
+            .map(|frame| (frame.opcode(), frame.is_final(), frame.payload().clone()))
+            .collect();
+        assert_eq!(
+            frames,
+            vec![
+                (OpCode::Text, false, b"hell".to_vec()),
+                (OpCode::Continue, false, b"o wo".to_vec()),
+                (OpCode::Continue, true, b"rld".to_vec()),
+            ]
+        );
+    }
+}
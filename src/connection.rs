@@ -1,13 +1,18 @@
 use std::borrow::Borrow;
+use std::cmp;
 use std::collections::VecDeque;
-use std::io::{Cursor, Read, Seek, SeekFrom, Write};
+use std::io::{self, Cursor, IoSlice, Read, Write};
 use std::mem::replace;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::from_utf8;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::vec::Drain;
 
 use mio::tcp::TcpStream;
-use mio::{Ready, Token};
+use mio::{Evented, Ready, Token};
 use mio_extras::timer::Timeout;
+use rand;
 use url;
 
 #[cfg(feature = "nativetls")]
@@ -15,18 +20,34 @@ use native_tls::HandshakeError;
 #[cfg(feature = "ssl")]
 use openssl::ssl::HandshakeError;
 
-use frame::Frame;
-use handler::Handler;
-use handshake::{Handshake, Request, Response};
-use message::Message;
+use auth::Authenticator;
+use communication::{AckToken, ConnectionState, Shared};
+use frame::{FragmentState, Frame};
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+use handler::with_tls_settings;
+use handler::{Handler, PingAction};
+use handshake::{origin_matches, with_random_source, proxy_request, Handshake, Request, Response};
+use message::{Message, MessageMetadata, MessageRef};
 use protocol::{CloseCode, OpCode};
+use proxy_protocol::ProxyHeader;
 use result::{Error, Kind, Result};
+use output::{OutputBuffer, SharedFrames};
 use stream::{Stream, TryReadBuf, TryWriteBuf};
+use utf8::Utf8Validator;
 
 use self::Endpoint::*;
 use self::State::*;
 
-use super::Settings;
+use super::{ClientSettings, MaskingKeySource, OverflowPolicy, RateLimitAction, Settings};
+
+// Timeout events reserved for timers that are managed by the connection itself
+const PING: Token = Token(usize::MAX - 7);
+const IDLE: Token = Token(usize::MAX - 9);
+const HANDSHAKE: Token = Token(usize::MAX - 10);
+const CLOSE: Token = Token(usize::MAX - 11);
+
+// The most buffers of queued output that are handed to a single write
+const MAX_WRITE_SLICES: usize = 64;
 
 #[derive(Debug)]
 pub enum State {
@@ -57,7 +78,6 @@ impl State {
         }
     }
 
-    #[allow(dead_code)]
     #[inline]
     pub fn is_open(&self) -> bool {
         match *self {
@@ -75,6 +95,130 @@ impl State {
     }
 }
 
+// A CONNECT request to a proxy, which has to succeed before the handshake can start.
+struct Tunnel {
+    req: Cursor<Vec<u8>>,
+    res: Vec<u8>,
+}
+
+fn origin_allowed(request: &Request, allowed: &Option<Arc<Vec<String>>>) -> Result<bool> {
+    if let Some(ref allowed) = *allowed {
+        if let Some(origin) = request.origin()? {
+            return Ok(allowed.iter().any(|pattern| origin_matches(pattern, origin)));
+        }
+    }
+    Ok(true)
+}
+
+// Prepare a response that doesn't switch protocols, after which the connection is closed, so
+// that the client knows where the body ends and not to reuse the connection. The headers of a
+// response built with `Response::from_request` that only belong to an upgrade are dropped.
+fn close_after(response: &mut Response) {
+    response.headers_mut().retain(|(name, _)| {
+        !name.eq_ignore_ascii_case("upgrade")
+            && !name.eq_ignore_ascii_case("connection")
+            && !name.eq_ignore_ascii_case("sec-websocket-accept")
+    });
+    if response.header("content-length").is_none() {
+        let len = response.body().len().to_string();
+        response
+            .headers_mut()
+            .push(("Content-Length".into(), len.into_bytes()));
+    }
+    response
+        .headers_mut()
+        .push(("Connection".into(), b"close".to_vec()));
+}
+
+// The length of the HTTP head at the start of the buffer, if all of it has arrived.
+fn handshake_end(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|w| w == b"\r\n\r\n").map(|pos| pos + 4)
+}
+
+// Whether any of the headers with the name lists the token, such as `keep-alive, Upgrade` does
+// for the Connection header. Both are matched case-insensitively.
+fn has_token(request: &Request, header: &str, token: &str) -> bool {
+    request
+        .headers()
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case(header))
+        .filter_map(|(_, value)| from_utf8(value).ok())
+        .any(|value| value.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+}
+
+// The number of extension offers, their parameters and the subprotocols listed by the request.
+fn handshake_tokens(request: &Request) -> usize {
+    request
+        .headers()
+        .iter()
+        .filter(|(name, _)| {
+            name.eq_ignore_ascii_case("sec-websocket-extensions")
+                || name.eq_ignore_ascii_case("sec-websocket-protocol")
+        })
+        .map(|(_, value)| value.split(|&b| b == b',' || b == b';').count())
+        .sum()
+}
+
+// Whether a request asks to upgrade the connection to the WebSocket protocol, rather than being a
+// plain HTTP request.
+fn is_upgrade(request: &Request) -> bool {
+    has_token(request, "upgrade", "websocket") && has_token(request, "connection", "upgrade")
+}
+
+fn authenticate(
+    request: &Request,
+    authenticator: &Option<Arc<dyn Authenticator>>,
+) -> Result<Option<Response>> {
+    match *authenticator {
+        Some(ref authenticator) => Ok(authenticator.authenticate(request)?.into_rejection()),
+        None => Ok(None),
+    }
+}
+
+fn reconnect_delay(settings: &ClientSettings, attempts: u32) -> Duration {
+    let backoff = 1u32
+        .checked_shl(attempts)
+        .and_then(|factor| settings.reconnect_delay.checked_mul(factor))
+        .map_or(settings.max_reconnect_delay, |delay| {
+            cmp::min(delay, settings.max_reconnect_delay)
+        });
+    let fraction = match settings.random_source {
+        Some(ref source) => {
+            let mut bytes = [0; 8];
+            source.fill(&mut bytes);
+            // the top 53 bits, as many as an f64 holds, as a fraction in [0, 1)
+            (u64::from_be_bytes(bytes) >> 11) as f64 / (1u64 << 53) as f64
+        }
+        None => rand::random::<f64>(),
+    };
+    backoff + settings.reconnect_jitter.mul_f64(fraction)
+}
+
+// The next key of a deterministic sequence, the first four bytes of splitmix64.
+fn next_masking_key(state: &mut u64) -> [u8; 4] {
+    *state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^= z >> 31;
+    [(z >> 24) as u8, (z >> 16) as u8, (z >> 8) as u8, z as u8]
+}
+
+// The url that a client connection is encrypted for, the server name is taken from its host.
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+fn tls_url(url: &url::Url, settings: &ClientSettings) -> Result<url::Url> {
+    let mut url = url.clone();
+    if let Some(ref name) = settings.sni_hostname {
+        url.set_host(Some(name)).map_err(|err| {
+            Error::new(
+                Kind::Protocol,
+                format!("Invalid SNI hostname {}: {}", name, err),
+            )
+        })?;
+    }
+    Ok(url)
+}
+
 pub struct Connection<H>
 where
     H: Handler,
@@ -86,9 +230,18 @@ where
     events: Ready,
 
     fragments: VecDeque<Frame>,
+    fragments_len: usize,
+    fragment_state: FragmentState,
+    utf8: Utf8Validator,
+    // the opcode of an incoming message being streamed to the handler, and whether it was
+    // dropped
+    receiving: Option<OpCode>,
+    receive_dropped: bool,
+    // the input can no longer be parsed, after a frame that didn't fit in the input buffer
+    discard_input: bool,
 
     in_buffer: Cursor<Vec<u8>>,
-    out_buffer: Cursor<Vec<u8>>,
+    out_buffer: OutputBuffer,
 
     handler: H,
 
@@ -96,6 +249,60 @@ where
 
     settings: Settings,
     connection_id: u32,
+    shared: Arc<Shared>,
+    allowed_origins: Option<Arc<Vec<String>>>,
+    trusted_proxies: Option<Arc<Vec<IpAddr>>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    // A plain HTTP request that the factory has to answer.
+    non_upgrade: Option<Request>,
+    proxy_header_read: bool,
+    proxied_addr: Option<SocketAddr>,
+    #[cfg(feature = "tracing")]
+    span: ::tracing::Span,
+    over_capacity: bool,
+    draining: bool,
+    pending_response: Option<Response>,
+    client_settings: ClientSettings,
+    tunnel: Option<Tunnel>,
+    encrypt_tunnel: bool,
+
+    timers: Vec<(Token, Duration)>,
+    handshake_deadline: Option<Instant>,
+    ping_nonce: u64,
+    missed_pongs: usize,
+    last_activity: Instant,
+
+    // the token bucket of the message rate limit
+    rate_tokens: f64,
+    rate_refilled: Instant,
+
+    // messages sent while a streamed message is in progress wait until it is finished
+    streaming: bool,
+    delayed: VecDeque<(Message, Option<OverflowPolicy>, Option<AckToken>)>,
+
+    // messages held back while earlier output is waiting to be written, when there is a limit
+    held: VecDeque<(Message, Option<AckToken>)>,
+
+    // the bytes buffered and written since the connection was established, and the number of
+    // bytes after which each message that is waiting to be acknowledged has been written
+    bytes_buffered: u64,
+    bytes_written: u64,
+    acks: VecDeque<(u64, AckToken)>,
+    // the offsets in the same count at which the frames that aren't written yet end, and where
+    // the last of the pings and pongs that skipped ahead of them ends
+    frame_ends: VecDeque<u64>,
+    priority_end: u64,
+
+    // the time at which output held back to be written together with what follows is written
+    // anyway, and whether output is being written until the buffer is empty
+    flush_at: Option<Instant>,
+    flushing: bool,
+
+    local_close: bool,
+    reconnecting: bool,
+    reconnect_attempts: u32,
+    // the state of the sequence of deterministic masking keys
+    masking_keys: u64,
 }
 
 impl<H> Connection<H>
@@ -104,14 +311,15 @@ where
 {
     pub fn new(
         tok: Token,
-        sock: TcpStream,
+        sock: Stream,
         handler: H,
         settings: Settings,
         connection_id: u32,
+        shared: Arc<Shared>,
     ) -> Connection<H> {
         Connection {
             token: tok,
-            socket: Stream::tcp(sock),
+            socket: sock,
             state: Connecting(
                 Cursor::new(Vec::with_capacity(2048)),
                 Cursor::new(Vec::with_capacity(2048)),
@@ -119,41 +327,169 @@ where
             endpoint: Endpoint::Server,
             events: Ready::empty(),
             fragments: VecDeque::with_capacity(settings.fragments_capacity),
-            in_buffer: Cursor::new(Vec::with_capacity(settings.in_buffer_capacity)),
-            out_buffer: Cursor::new(Vec::with_capacity(settings.out_buffer_capacity)),
+            fragments_len: 0,
+            fragment_state: FragmentState::default(),
+            utf8: Utf8Validator::default(),
+            receiving: None,
+            receive_dropped: false,
+            discard_input: false,
+            in_buffer: Cursor::new(Vec::with_capacity(cmp::min(
+                settings.in_buffer_capacity,
+                settings.in_buffer_max_capacity,
+            ))),
+            out_buffer: OutputBuffer::with_capacity(cmp::min(
+                settings.out_buffer_capacity,
+                settings.out_buffer_max_capacity,
+            )),
             handler,
             addresses: Vec::new(),
             settings,
             connection_id,
+            shared,
+            allowed_origins: None,
+            trusted_proxies: None,
+            authenticator: None,
+            non_upgrade: None,
+            proxy_header_read: false,
+            proxied_addr: None,
+            #[cfg(feature = "tracing")]
+            span: debug_span!(
+                "connection",
+                token = tok.0,
+                id = connection_id,
+                peer = ::tracing::field::Empty
+            ),
+            over_capacity: false,
+            draining: false,
+            pending_response: None,
+            client_settings: ClientSettings::default(),
+            tunnel: None,
+            encrypt_tunnel: false,
+            timers: Vec::new(),
+            handshake_deadline: None,
+            ping_nonce: 0,
+            missed_pongs: 0,
+            last_activity: Instant::now(),
+            rate_tokens: settings.max_messages_per_second.unwrap_or(0) as f64,
+            rate_refilled: Instant::now(),
+            streaming: false,
+            delayed: VecDeque::new(),
+            held: VecDeque::new(),
+            bytes_buffered: 0,
+            bytes_written: 0,
+            acks: VecDeque::new(),
+            frame_ends: VecDeque::new(),
+            priority_end: 0,
+            flush_at: None,
+            flushing: false,
+            local_close: false,
+            reconnecting: false,
+            reconnect_attempts: 0,
+            masking_keys: match settings.masking_key_source {
+                MaskingKeySource::InsecureDeterministic(seed) => seed,
+                MaskingKeySource::Random => 0,
+            },
         }
     }
 
-    pub fn as_server(&mut self) -> Result<()> {
+    pub fn as_server(
+        &mut self,
+        allowed_origins: Option<Arc<Vec<String>>>,
+        trusted_proxies: Option<Arc<Vec<IpAddr>>>,
+        authenticator: Option<Arc<dyn Authenticator>>,
+    ) -> Result<()> {
+        self.allowed_origins = allowed_origins;
+        self.trusted_proxies = trusted_proxies;
+        self.authenticator = authenticator;
         self.events.insert(Ready::readable());
+        self.start_handshake_timer(self.settings.handshake_timeout);
         Ok(())
     }
 
-    pub fn as_client(&mut self, url: url::Url, addrs: Vec<SocketAddr>) -> Result<()> {
+    // Answer the handshake request with a 503 response because the server is at capacity.
+    pub fn reject_over_capacity(&mut self) {
+        self.over_capacity = true;
+    }
+
+    // Answer the handshake request with a 503 response because the server is draining.
+    pub fn reject_draining(&mut self) {
+        self.draining = true;
+    }
+
+    pub fn as_client(
+        &mut self,
+        url: url::Url,
+        addrs: Vec<SocketAddr>,
+        client_settings: ClientSettings,
+    ) -> Result<()> {
+        self.client_settings = client_settings;
+        let req = self.build_request(&url)?;
         if let Connecting(ref mut req_buf, _) = self.state {
-            let req = self.handler.build_request(&url)?;
             self.addresses = addrs;
             self.events.insert(Ready::writable());
             self.endpoint = Endpoint::Client(url);
-            req.format(req_buf.get_mut())
+            req.format(req_buf.get_mut())?;
+            self.start_handshake_timer(self.settings.connect_timeout);
         } else {
-            Err(Error::new(
+            return Err(Error::new(
                 Kind::Internal,
                 "Tried to set connection to client while not connecting.",
-            ))
+            ));
         }
+        self.start_tunnel()
+    }
+
+    // The handshake request of the handler, with a key from the random source if there is one.
+    fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+        let handler = &mut self.handler;
+        with_random_source(self.client_settings.random_source.as_ref(), || {
+            handler.build_request(url)
+        })
+    }
+
+    fn start_tunnel(&mut self) -> Result<()> {
+        self.tunnel = None;
+        if let Some(ref proxy) = self.client_settings.proxy {
+            if let Client(ref url) = self.endpoint {
+                let mut req = Vec::with_capacity(256);
+                proxy_request(url, proxy)?.format(&mut req)?;
+                self.tunnel = Some(Tunnel {
+                    req: Cursor::new(req),
+                    res: Vec::with_capacity(1024),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Whether the connection has just been tunneled through a proxy and needs to be encrypted
+    // before the handshake can be sent.
+    pub fn take_tunnel_encryption(&mut self) -> bool {
+        replace(&mut self.encrypt_tunnel, false)
     }
 
-    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
     pub fn encrypt(&mut self) -> Result<()> {
-        let sock = self.socket().try_clone()?;
+        let sock = match self.socket.tcp_stream() {
+            Some(sock) => sock.try_clone()?,
+            None => {
+                return Err(Error::new(
+                    Kind::Internal,
+                    "Encryption is only supported for tcp connections.",
+                ))
+            }
+        };
+        let handler = &mut self.handler;
         let ssl_stream = match self.endpoint {
-            Server => self.handler.upgrade_ssl_server(sock),
-            Client(ref url) => self.handler.upgrade_ssl_client(sock, url),
+            Server => with_tls_settings(&self.settings, &self.client_settings, || {
+                handler.upgrade_ssl_server(sock)
+            }),
+            Client(ref url) => {
+                let url = tls_url(url, &self.client_settings)?;
+                with_tls_settings(&self.settings, &self.client_settings, || {
+                    handler.upgrade_ssl_client(sock, &url)
+                })
+            }
         };
 
         match ssl_stream {
@@ -195,37 +531,69 @@ where
         self.token
     }
 
-    pub fn socket(&self) -> &TcpStream {
+    pub fn socket(&self) -> &dyn Evented {
         self.socket.evented()
     }
 
+    pub fn allow_read(&mut self) {
+        self.socket.allow_read()
+    }
+
+    pub fn socket_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.peer_addr()
+    }
+
     pub fn connection_id(&self) -> u32 {
         self.connection_id
     }
 
-    fn peer_addr(&self) -> String {
-        if let Ok(addr) = self.socket.peer_addr() {
+    pub fn shared(&self) -> &Shared {
+        &self.shared
+    }
+
+    #[cfg(feature = "tracing")]
+    pub fn span(&self) -> &::tracing::Span {
+        &self.span
+    }
+
+    pub fn peer_addr(&self) -> String {
+        if let Some(addr) = self.remote_addr() {
             addr.to_string()
         } else {
             "UNKNOWN".into()
         }
     }
 
+    // The address of the client as told by a PROXY protocol header, or else of the peer.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.proxied_addr.or_else(|| self.socket.peer_addr().ok())
+    }
+
     // Resetting may be necessary in order to try all possible addresses for a server
-    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
     pub fn reset(&mut self) -> Result<()> {
         // if self.is_client() {
         if let Client(ref url) = self.endpoint {
             if let Connecting(ref mut req, ref mut res) = self.state {
                 req.set_position(0);
                 res.set_position(0);
+                if let Some(ref mut tunnel) = self.tunnel {
+                    tunnel.req.set_position(0);
+                    tunnel.res.clear();
+                }
                 self.events.remove(Ready::readable());
                 self.events.insert(Ready::writable());
 
                 if let Some(ref addr) = self.addresses.pop() {
                     let sock = TcpStream::connect(addr)?;
-                    if self.socket.is_tls() {
-                        let ssl_stream = self.handler.upgrade_ssl_client(sock, url);
+                    // a tunneled connection is encrypted once the proxy has opened the tunnel
+                    if self.socket.is_tls() && self.tunnel.is_none() {
+                        let url = tls_url(url, &self.client_settings)?;
+                        let handler = &mut self.handler;
+                        let ssl_stream =
+                            with_tls_settings(&self.settings, &self.client_settings, || {
+                                handler.upgrade_ssl_client(sock, &url)
+                            });
                         match ssl_stream {
                             Ok(stream) => {
                                 self.socket = Stream::tls_live(stream);
@@ -283,12 +651,16 @@ where
         }
     }
 
-    #[cfg(not(any(feature = "ssl", feature = "nativetls")))]
+    #[cfg(not(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls")))]
     pub fn reset(&mut self) -> Result<()> {
         if self.is_client() {
             if let Connecting(ref mut req, ref mut res) = self.state {
                 req.set_position(0);
                 res.set_position(0);
+                if let Some(ref mut tunnel) = self.tunnel {
+                    tunnel.req.set_position(0);
+                    tunnel.res.clear();
+                }
                 self.events.remove(Ready::readable());
                 self.events.insert(Ready::writable());
 
@@ -316,10 +688,81 @@ where
         }
     }
 
+    pub fn is_reconnecting(&self) -> bool {
+        self.reconnecting
+    }
+
+    // Prepare a lost client connection to be reestablished, returning the delay to wait before
+    // calling `reconnect`, or None if the connection should be dropped instead.
+    pub fn schedule_reconnect(&mut self) -> Option<Duration> {
+        if !self.client_settings.auto_reconnect
+            || !self.is_client()
+            || self.local_close
+            || self.reconnect_attempts >= self.client_settings.max_retries
+        {
+            return None;
+        }
+
+        self.reconnecting = true;
+        self.state = Connecting(
+            Cursor::new(Vec::with_capacity(2048)),
+            Cursor::new(Vec::with_capacity(2048)),
+        );
+        self.shared.set_status(ConnectionState::Connecting);
+        self.events = Ready::empty();
+        self.fragments.clear();
+        self.fragments_len = 0;
+        self.fragment_state = FragmentState::default();
+        self.utf8 = Utf8Validator::default();
+        self.receiving = None;
+        self.discard_input = false;
+        self.in_buffer.get_mut().clear();
+        self.in_buffer.set_position(0);
+        self.out_buffer.clear();
+        self.held.clear();
+        self.update_held();
+        self.bytes_buffered = 0;
+        self.bytes_written = 0;
+        self.acks.clear();
+        self.frame_ends.clear();
+        self.priority_end = 0;
+        self.flush_at = None;
+        self.flushing = false;
+        self.missed_pongs = 0;
+        self.streaming = false;
+        self.delayed.clear();
+
+        Some(reconnect_delay(&self.client_settings, self.reconnect_attempts))
+    }
+
+    pub fn reconnect<R>(&mut self, resolve: R) -> Result<()>
+    where
+        R: FnOnce(&url::Url) -> Result<Vec<SocketAddr>>,
+    {
+        self.reconnecting = false;
+        self.reconnect_attempts += 1;
+        self.handler.on_reconnect_attempt(self.reconnect_attempts);
+
+        if let Client(url) = self.endpoint.clone() {
+            let req = self.build_request(&url)?;
+            if let Connecting(ref mut req_buf, _) = self.state {
+                req.format(req_buf.get_mut())?;
+            }
+            self.addresses = resolve(&url)?;
+        }
+        self.start_handshake_timer(self.settings.connect_timeout);
+        self.start_tunnel()?;
+        self.reset()
+    }
+
     pub fn events(&self) -> Ready {
         self.events
     }
 
+    pub fn is_connecting(&self) -> bool {
+        self.state.is_connecting()
+    }
+
     pub fn is_client(&self) -> bool {
         match self.endpoint {
             Client(_) => true,
@@ -349,7 +792,144 @@ where
 
     #[inline]
     pub fn timeout_triggered(&mut self, event: Token) -> Result<()> {
-        self.handler.on_timeout(event)
+        match event {
+            PING => self.heartbeat(),
+            IDLE => self.check_idle(),
+            HANDSHAKE => self.check_handshake(),
+            CLOSE => {
+                self.check_close();
+                Ok(())
+            }
+            _ => self.handler.on_timeout(event),
+        }
+    }
+
+    /// Internal timers that the connection would like to have scheduled on the event loop.
+    #[inline]
+    pub fn timers(&mut self) -> Drain<'_, (Token, Duration)> {
+        self.timers.drain(..)
+    }
+
+    // Enter a state of the closing handshake.
+    fn set_state(&mut self, state: State) {
+        self.state = state;
+        self.shared.set_status(ConnectionState::Closing);
+    }
+
+    fn opened(&mut self) {
+        #[cfg(feature = "tracing")]
+        self.span
+            .record("peer", ::tracing::field::display(self.peer_addr()));
+        self.handshake_deadline = None;
+        self.reconnect_attempts = 0;
+        self.last_activity = Instant::now();
+        self.schedule_ping();
+        if let Some(timeout) = self.settings.idle_timeout {
+            self.timers.push((IDLE, timeout));
+        }
+    }
+
+    fn start_handshake_timer(&mut self, timeout: Option<Duration>) {
+        if let Some(timeout) = timeout {
+            self.handshake_deadline = Some(Instant::now() + timeout);
+            self.timers.push((HANDSHAKE, timeout));
+        }
+    }
+
+    fn check_handshake(&mut self) -> Result<()> {
+        let deadline = match self.handshake_deadline {
+            Some(deadline) if self.state.is_connecting() => deadline,
+            _ => return Ok(()),
+        };
+
+        // the timer may have been started by an earlier attempt to reconnect
+        let now = Instant::now();
+        if now < deadline {
+            self.timers.push((HANDSHAKE, deadline - now));
+            return Ok(());
+        }
+
+        debug!(
+            "Handshake with {} did not complete in time, disconnecting.",
+            self.peer_addr()
+        );
+        Err(Error::new(
+            Kind::Timeout,
+            "The WebSocket handshake did not complete in time.",
+        ))
+    }
+
+    fn check_close(&mut self) {
+        match self.state {
+            AwaitingClose => {
+                debug!(
+                    "Connection to {} did not answer the close frame in time, disconnecting.",
+                    self.peer_addr()
+                );
+                self.disconnect()
+            }
+            // The closing handshake is done, but the server hasn't ended the TCP connection
+            FinishedClose => self.disconnect(),
+            _ => (),
+        }
+    }
+
+    fn schedule_ping(&mut self) {
+        if let Some(interval) = self.settings.ping_interval {
+            self.timers.push((PING, interval));
+        }
+    }
+
+    fn heartbeat(&mut self) -> Result<()> {
+        if !self.state.is_open() {
+            return Ok(());
+        }
+
+        if self.missed_pongs >= self.settings.max_missed_pongs {
+            debug!(
+                "Connection to {} missed {} pongs, disconnecting.",
+                self.peer_addr(),
+                self.missed_pongs
+            );
+            self.disconnect();
+            return Ok(());
+        }
+
+        self.missed_pongs += 1;
+        self.ping_nonce = self.ping_nonce.wrapping_add(1);
+        let data = self.ping_nonce.to_be_bytes().to_vec();
+        self.send_ping(data)?;
+        self.schedule_ping();
+        Ok(())
+    }
+
+    fn check_idle(&mut self) -> Result<()> {
+        let timeout = match self.settings.idle_timeout {
+            Some(timeout) => timeout,
+            None => return Ok(()),
+        };
+
+        let idle = self.last_activity.elapsed();
+        if idle < timeout {
+            self.timers.push((IDLE, timeout - idle));
+            return Ok(());
+        }
+
+        match self.state {
+            Open => {
+                debug!(
+                    "Connection to {} has been idle for {:?}, closing.",
+                    self.peer_addr(),
+                    idle
+                );
+                self.send_close(CloseCode::Away, "Idle timeout.")?;
+                self.timers.push((IDLE, timeout));
+            }
+            // The other endpoint didn't answer the closing handshake either
+            AwaitingClose => self.disconnect(),
+            _ => (),
+        }
+        Ok(())
     }
 
     pub fn error(&mut self, err: Error) {
@@ -360,7 +940,7 @@ where
                     self.handler.on_error(err);
                     self.events = Ready::empty();
                 }
-                Kind::Io(_) => {
+                Kind::Io(_) | Kind::Timeout => {
                     self.handler.on_error(err);
                     self.events = Ready::empty();
                 }
@@ -459,6 +1039,18 @@ where
                         error!("Disconnecting WebSocket.");
                         self.disconnect()
                     }
+                    #[cfg(feature = "json")]
+                    Kind::Json(_) => {
+                        let reason = format!("{}", err);
+
+                        self.handler.on_error(err);
+                        if let Some(code) = self.settings.json_close_code {
+                            if let Err(err) = self.send_close(code, reason) {
+                                self.handler.on_error(err);
+                                self.disconnect()
+                            }
+                        }
+                    }
                     Kind::Custom(_) => {
                         self.handler.on_error(err);
                     }
@@ -491,6 +1083,9 @@ where
     }
 
     pub fn consume(self) -> H {
+        // don't leave senders waiting for room that will never be made
+        self.shared.set_held(0);
+        self.shared.set_status(ConnectionState::Closed);
         self.handler
     }
 
@@ -499,7 +1094,8 @@ where
             match self.endpoint {
                 Server => {
                     let mut done = false;
-                    if self.socket.try_write_buf(res)?.is_some() {
+                    let written = self.socket.try_write_buf(res)?;
+                    if self.shared.count_written(written).is_some() {
                         if res.position() as usize == res.get_ref().len() {
                             done = true
                         }
@@ -509,7 +1105,8 @@ where
                     }
                 }
                 Client(_) => {
-                    if self.socket.try_write_buf(req)?.is_some() {
+                    let written = self.socket.try_write_buf(req)?;
+                    if self.shared.count_written(written).is_some() {
                         if req.position() as usize == req.get_ref().len() {
                             trace!(
                                 "Finished writing handshake request to {}",
@@ -533,12 +1130,17 @@ where
                 self.peer_addr()
             );
 
+            // a client may send its first frames along with the request
+            if let Some(end) = handshake_end(req.get_ref()) {
+                self.in_buffer.get_mut().extend(&req.get_ref()[end..]);
+            }
+
             let request = match Request::parse(req.get_ref()) {
                 Ok(Some(req)) => req,
                 _ => {
                     // An error should already have been sent for the first time it failed to
                     // parse. We don't call disconnect here because `on_open` hasn't been called yet.
-                    self.state = FinishedClose;
+                    self.set_state(FinishedClose);
                     self.events = Ready::empty();
                     return Ok(());
                 }
@@ -555,14 +1157,28 @@ where
                 self.events = Ready::empty();
                 return Ok(());
             } else {
-                self.handler.on_open(Handshake {
+                // senders may be used from on_open
+                self.shared.set_status(ConnectionState::Open);
+                let mut shake = Handshake::new(
                     request,
                     response,
-                    peer_addr: self.socket.peer_addr().ok(),
-                    local_addr: self.socket.local_addr().ok(),
-                })?;
+                    self.remote_addr(),
+                    self.socket.local_addr().ok(),
+                );
+                if let Some(ref proxies) = self.trusted_proxies {
+                    if self.settings.trust_forwarded_for {
+                        shake.set_trusted_proxies(proxies.to_vec());
+                    }
+                }
+                shake.set_tls_info(self.socket.tls_info());
+                self.handler.on_open(shake)?;
                 debug!("Connection to {} is now open.", self.peer_addr());
+                self.opened();
                 self.events.insert(Ready::readable());
+
+                if !self.in_buffer.get_ref().is_empty() {
+                    self.read_frames()?;
+                }
                 self.check_events();
                 return Ok(());
             }
@@ -574,18 +1190,162 @@ where
         }
     }
 
+    pub fn take_non_upgrade(&mut self) -> Option<Request> {
+        self.non_upgrade.take()
+    }
+
+    // Answer a plain HTTP request, closing the connection once the response is sent.
+    pub fn respond_non_upgrade(&mut self, mut response: Response) -> Result<()> {
+        if response.status() == 101 {
+            return Err(Error::new(
+                Kind::Internal,
+                "Tried to switch protocols in response to a request that isn't a handshake.",
+            ));
+        }
+        close_after(&mut response);
+        if let Connecting(_, ref mut res) = self.state {
+            response.format(res.get_mut())?;
+            self.events.insert(Ready::writable());
+        }
+        Ok(())
+    }
+
+    pub fn complete_handshake(&mut self, mut response: Response) -> Result<()> {
+        let pending = self.pending_response.take().ok_or_else(|| {
+            Error::new(
+                Kind::Internal,
+                "Tried to complete a handshake that isn't pending.",
+            )
+        })?;
+        if let Connecting(_, ref mut res) = self.state {
+            if response.status() == 101 {
+                // keep the headers added by wrapping handlers, such as negotiated extensions
+                for (key, val) in pending.headers() {
+                    if response.header(key).is_none() {
+                        response.headers_mut().push((key.clone(), val.clone()));
+                    }
+                }
+            } else {
+                close_after(&mut response);
+            }
+            response.format(res.get_mut())?;
+            self.events.remove(Ready::readable());
+            self.events.insert(Ready::writable());
+        }
+        Ok(())
+    }
+
     fn read_handshake(&mut self) -> Result<()> {
         if let Connecting(ref mut req, ref mut res) = self.state {
             match self.endpoint {
                 Server => {
-                    if let Some(read) = self.socket.try_read_buf(req.get_mut())? {
+                    let read = self.socket.try_read_buf(req.get_mut())?;
+                    if let Some(read) = self.shared.count_read(read) {
                         if read == 0 {
                             self.events = Ready::empty();
                             return Ok(());
                         }
-                        if let Some(ref request) = Request::parse(req.get_ref())? {
+                        if self.pending_response.is_some() {
+                            // the request has already been handled
+                            return Ok(());
+                        }
+                        if self.settings.accept_proxy_protocol && !self.proxy_header_read {
+                            match ProxyHeader::parse(req.get_ref())? {
+                                Some(header) => {
+                                    trace!("PROXY protocol header received: {:?}", header);
+                                    req.get_mut().drain(..header.len);
+                                    self.proxy_header_read = true;
+                                    self.proxied_addr = header.source;
+                                }
+                                None => return Ok(()),
+                            }
+                        }
+                        if let Some(request) = Request::parse(req.get_ref())? {
                             trace!("Handshake request received: \n{}", request);
-                            let response = self.handler.on_request(request)?;
+                            if !is_upgrade(&request) {
+                                debug!("Received a request that isn't a WebSocket handshake.");
+                                self.non_upgrade = Some(request);
+                                self.events.remove(Ready::readable());
+                                return Ok(());
+                            }
+                            self.handler.on_handshake_request(&request);
+                            let mut response = if self.over_capacity {
+                                debug!("Rejecting handshake because the server is at capacity.");
+                                Response::new(
+                                    503,
+                                    "Service Unavailable",
+                                    b"Too many connections.".to_vec(),
+                                )
+                            } else if self.draining {
+                                debug!("Rejecting handshake because the server is draining.");
+                                Response::new(
+                                    503,
+                                    "Service Unavailable",
+                                    b"The server is draining.".to_vec(),
+                                )
+                            } else if request.version().ok().map(str::trim) != Some("13") {
+                                debug!("Rejecting handshake for an unsupported protocol version.");
+                                let mut response = Response::new(
+                                    426,
+                                    "Upgrade Required",
+                                    b"Only version 13 of the WebSocket protocol is supported."
+                                        .to_vec(),
+                                );
+                                response
+                                    .headers_mut()
+                                    .push(("Sec-WebSocket-Version".into(), b"13".to_vec()));
+                                response
+                            } else if handshake_tokens(&request)
+                                > self.settings.max_handshake_tokens
+                            {
+                                debug!("Rejecting handshake that lists too many extensions.");
+                                Response::new(
+                                    400,
+                                    "Bad Request",
+                                    b"Too many extensions or protocols.".to_vec(),
+                                )
+                            } else if !origin_allowed(&request, &self.allowed_origins)? {
+                                debug!("Rejecting handshake from disallowed origin.");
+                                Response::new(403, "Forbidden", b"Origin not allowed.".to_vec())
+                            } else if self.settings.require_client_cert
+                                && !self.socket.has_peer_certificate()
+                            {
+                                debug!("Rejecting handshake without a client certificate.");
+                                Response::new(
+                                    403,
+                                    "Forbidden",
+                                    b"Client certificate required.".to_vec(),
+                                )
+                            } else if let Some(rejection) =
+                                authenticate(&request, &self.authenticator)?
+                            {
+                                debug!("Rejecting handshake that failed authentication.");
+                                rejection
+                            } else {
+                                self.handler.on_request(&request)?
+                            };
+                            if response.is_pending() {
+                                debug!("Deferring the handshake response.");
+                                self.pending_response = Some(response);
+                                return Ok(());
+                            }
+                            if response.status() != 101 {
+                                close_after(&mut response);
+                            }
+                            response.format(res.get_mut())?;
+                            self.events.remove(Ready::readable());
+                            self.events.insert(Ready::writable());
+                        } else if req.get_ref().len() > self.settings.max_handshake_size {
+                            debug!(
+                                "Rejecting handshake request larger than {} bytes.",
+                                self.settings.max_handshake_size
+                            );
+                            let mut response = Response::new(
+                                431,
+                                "Request Header Fields Too Large",
+                                b"Handshake request too large.".to_vec(),
+                            );
+                            close_after(&mut response);
                             response.format(res.get_mut())?;
                             self.events.remove(Ready::readable());
                             self.events.insert(Ready::writable());
@@ -594,17 +1354,23 @@ where
                     return Ok(());
                 }
                 Client(_) => {
-                    if self.socket.try_read_buf(res.get_mut())?.is_some() {
-                        // TODO: see if this can be optimized with drain
+                    let read = self.socket.try_read_buf(res.get_mut())?;
+                    if self.shared.count_read(read).is_some() {
                         let end = {
                             let data = res.get_ref();
-                            let end = data.iter()
-                                .enumerate()
-                                .take_while(|&(ind, _)| !data[..ind].ends_with(b"\r\n\r\n"))
-                                .count();
-                            if !data[..end].ends_with(b"\r\n\r\n") {
-                                return Ok(());
-                            }
+                            let end = match handshake_end(data) {
+                                Some(end) => end,
+                                None if data.len() > self.settings.max_handshake_size => {
+                                    return Err(Error::new(
+                                        Kind::Protocol,
+                                        format!(
+                                            "Handshake response is larger than {} bytes.",
+                                            self.settings.max_handshake_size
+                                        ),
+                                    ));
+                                }
+                                None => return Ok(()),
+                            };
                             self.in_buffer.get_mut().extend(&data[end..]);
                             end
                         };
@@ -649,7 +1415,7 @@ where
 
             if self.settings.key_strict {
                 let req_key = request.hashed_key()?;
-                let res_key = from_utf8(response.key()?)?;
+                let res_key = from_utf8(response.key()?)?.trim();
                 if req_key != res_key {
                     return Err(Error::new(
                         Kind::Protocol,
@@ -662,12 +1428,16 @@ where
             }
 
             self.handler.on_response(&response)?;
-            self.handler.on_open(Handshake {
+            self.shared.set_status(ConnectionState::Open);
+            let mut shake = Handshake::new(
                 request,
                 response,
-                peer_addr: self.socket.peer_addr().ok(),
-                local_addr: self.socket.local_addr().ok(),
-            })?;
+                self.socket.peer_addr().ok(),
+                self.socket.local_addr().ok(),
+            );
+            shake.set_tls_info(self.socket.tls_info());
+            self.handler.on_open(shake)?;
+            self.opened();
 
             // check to see if there is anything to read already
             if !self.in_buffer.get_ref().is_empty() {
@@ -689,7 +1459,10 @@ where
             self.socket.clear_negotiating()?;
             self.write()
         } else {
-            let res = if self.state.is_connecting() {
+            let res = if self.tunnel.is_some() {
+                trace!("Ready to read proxy response from {}.", self.peer_addr());
+                self.read_tunnel()
+            } else if self.state.is_connecting() {
                 trace!("Ready to read handshake from {}.", self.peer_addr());
                 self.read_handshake()
             } else {
@@ -712,41 +1485,148 @@ where
                 self.events.remove(Ready::readable());
                 self.events.insert(Ready::writable());
             }
+            if self.socket.wants_write() && res.is_ok() {
+                self.events.insert(Ready::writable());
+            }
             res
         }
     }
 
+    fn read_tunnel(&mut self) -> Result<()> {
+        if let Some(ref mut tunnel) = self.tunnel {
+            let read = self.socket.try_read_buf(&mut tunnel.res)?;
+            match self.shared.count_read(read) {
+                Some(0) => {
+                    return Err(Error::new(
+                        Kind::Protocol,
+                        "Proxy closed the connection before opening a tunnel.",
+                    ))
+                }
+                Some(_) => (),
+                // NOTE: wait to be polled again; response not ready.
+                None => return Ok(()),
+            }
+            match Response::parse(&tunnel.res)? {
+                Some(ref response) if response.status() == 200 => (),
+                Some(response) => {
+                    return Err(Error::new(
+                        Kind::Protocol,
+                        format!(
+                            "Proxy refused to open a tunnel: {} {}",
+                            response.status(),
+                            response.reason()
+                        ),
+                    ))
+                }
+                None => return Ok(()),
+            }
+        }
+
+        debug!("Proxy at {} opened a tunnel.", self.peer_addr());
+        self.tunnel = None;
+        if let Client(ref url) = self.endpoint {
+            self.encrypt_tunnel = url.scheme() == "wss";
+        }
+        self.events.remove(Ready::readable());
+        self.events.insert(Ready::writable());
+        Ok(())
+    }
+
+    fn write_tunnel(&mut self) -> Result<()> {
+        if let Some(ref mut tunnel) = self.tunnel {
+            let written = self.socket.try_write_buf(&mut tunnel.req)?;
+            if self.shared.count_written(written).is_some()
+                && tunnel.req.position() as usize == tunnel.req.get_ref().len()
+            {
+                self.events.remove(Ready::writable());
+                self.events.insert(Ready::readable());
+            }
+        }
+        Ok(())
+    }
+
     fn read_frames(&mut self) -> Result<()> {
         let max_size = self.settings.max_fragment_size as u64;
         while let Some(mut frame) = Frame::parse(&mut self.in_buffer, max_size)? {
+            self.shared.count_frame_in();
+            self.last_activity = Instant::now();
             match self.state {
                 // Ignore data received after receiving close frame
                 RespondingClose | FinishedClose => continue,
                 _ => (),
             }
 
-            if self.settings.masking_strict {
-                if frame.is_masked() {
-                    if self.is_client() {
-                        return Err(Error::new(
-                            Kind::Protocol,
-                            "Received masked frame from a server endpoint.",
-                        ));
-                    }
-                } else {
-                    if self.is_server() {
-                        return Err(Error::new(
-                            Kind::Protocol,
-                            "Received unmasked frame from a client endpoint.",
-                        ));
-                    }
+            // Ignore the rest of a message that was rejected before we started closing
+            if self.state.is_closing()
+                && self.fragments.is_empty()
+                && frame.opcode() == OpCode::Continue
+            {
+                continue;
+            }
+
+            if frame.is_masked() {
+                if self.is_client() && !self.settings.allow_server_masking {
+                    return Err(Error::new(
+                        Kind::Protocol,
+                        "Received masked frame from a server endpoint.",
+                    ));
                 }
+            } else if self.settings.masking_strict && self.is_server() {
+                return Err(Error::new(
+                    Kind::Protocol,
+                    "Received unmasked frame from a client endpoint.",
+                ));
             }
 
             // This is safe whether or not a frame is masked.
             frame.remove_mask();
 
-            if let Some(frame) = self.handler.on_frame(frame)? {
+            if self.settings.reject_client_data && self.is_server() && !frame.is_control() {
+                debug!("Closing {} for sending data to a push only server.", self.peer_addr());
+                self.fragments.clear();
+                self.fragments_len = 0;
+                self.send_close(CloseCode::Policy, "This server does not accept messages.")?;
+                continue;
+            }
+
+            // the handler sees the state before the frame, as it arrived over the wire
+            let state = self.fragment_state;
+            self.fragment_state.advance(&frame);
+            // how the message of a final data frame arrived, before any extension changes it
+            let metadata = if state.in_message() {
+                MessageMetadata::new(
+                    state.opcode().unwrap_or(frame.opcode()),
+                    state.frames() + 1,
+                    state.payload_len() + frame.payload().len(),
+                    state.is_compressed(),
+                )
+            } else {
+                MessageMetadata::new(frame.opcode(), 1, frame.payload().len(), frame.has_rsv1())
+            };
+
+            if let Some(limit) = self.settings.max_continuation_frames {
+                // the frames received so far include the first one
+                if frame.opcode() == OpCode::Continue && state.frames() > limit {
+                    debug!(
+                        "Closing {} for sending a message in too many fragments.",
+                        self.peer_addr()
+                    );
+                    self.fragments.clear();
+                    self.fragments_len = 0;
+                    self.send_close(CloseCode::Policy, "Message has too many fragments.")?;
+                    continue;
+                }
+            }
+
+            if let Some(frame) = self.handler.on_frame_with_state(frame, state)? {
+                if !frame.is_control() {
+                    self.check_message_size(frame.payload().len())?;
+                    if self.settings.stream_messages {
+                        self.stream_frame(frame)?;
+                        continue;
+                    }
+                }
+
                 if frame.is_final() {
                     match frame.opcode() {
                         // singleton data frames
@@ -759,7 +1639,7 @@ where
                             }
                             let msg = Message::text(String::from_utf8(frame.into_data())
                                 .map_err(|err| err.utf8_error())?);
-                            self.handler.on_message(msg)?;
+                            self.on_message(msg, metadata)?;
                         }
                         OpCode::Binary => {
                             trace!("Received binary frame {:?}", frame);
@@ -769,7 +1649,7 @@ where
                                 return Err(Error::new(Kind::Protocol, "Received unfragmented binary frame while processing fragmented message."));
                             }
                             let data = frame.into_data();
-                            self.handler.on_message(Message::binary(data))?;
+                            self.on_message(Message::binary(data), metadata)?;
                         }
                         // control frames
                         OpCode::Close => {
@@ -785,7 +1665,7 @@ where
                                 }
                             } else {
                                 // Starting handshake, will send the responding close frame
-                                self.state = RespondingClose;
+                                self.set_state(RespondingClose);
                             }
 
                             let mut close_code = [0u8; 2];
@@ -800,24 +1680,14 @@ where
                                     close_code
                                 );
                                 let named = CloseCode::from(raw_code);
-                                if let CloseCode::Other(code) = named {
-                                    if code < 1000 ||
-                                            code >= 5000 ||
-                                            code == 1004 ||
-                                            code == 1014 ||
-                                            code == 1016 || // these below are here to pass the autobahn test suite
-                                            code == 1100 || // we shouldn't need them later
-                                            code == 2000
-                                        || code == 2999
-                                    {
-                                        return Err(Error::new(
-                                            Kind::Protocol,
-                                            format!(
-                                                "Received invalid close code from endpoint: {}",
-                                                code
-                                            ),
-                                        ));
-                                    }
+                                if !named.is_allowed() {
+                                    return Err(Error::new(
+                                        Kind::Protocol,
+                                        format!(
+                                            "Received invalid close code from endpoint: {}",
+                                            raw_code
+                                        ),
+                                    ));
                                 }
                                 let has_reason = {
                                     if let Ok(reason) = from_utf8(&data.get_ref()[2..]) {
@@ -829,42 +1699,23 @@ where
                                     }
                                 };
 
-                                if let CloseCode::Abnormal = named {
-                                    return Err(Error::new(
-                                        Kind::Protocol,
-                                        "Received abnormal close code from endpoint.",
-                                    ));
-                                } else if let CloseCode::Status = named {
-                                    return Err(Error::new(
-                                        Kind::Protocol,
-                                        "Received no status close code from endpoint.",
-                                    ));
-                                } else if let CloseCode::Restart = named {
-                                    return Err(Error::new(
-                                        Kind::Protocol,
-                                        "Restart close code is not supported.",
-                                    ));
-                                } else if let CloseCode::Again = named {
-                                    return Err(Error::new(
-                                        Kind::Protocol,
-                                        "Try again later close code is not supported.",
-                                    ));
-                                } else if let CloseCode::Tls = named {
-                                    return Err(Error::new(
-                                        Kind::Protocol,
-                                        "Received TLS close code outside of TLS handshake.",
-                                    ));
-                                } else {
-                                    if !self.state.is_closing() {
-                                        if has_reason {
-                                            self.send_close(named, "")?; // note this drops any extra close data
-                                        } else {
-                                            self.send_close(CloseCode::Invalid, "")?;
-                                        }
+                                if !self.state.is_closing() {
+                                    if !self.settings.auto_close_response {
+                                        trace!("Leaving the close response to the handler.");
+                                    } else if has_reason {
+                                        self.send_close(named, "")?; // note this drops any extra close data
                                     } else {
-                                        self.state = FinishedClose;
+                                        self.send_close(CloseCode::Invalid, "")?;
                                     }
+                                } else {
+                                    self.set_state(FinishedClose);
                                 }
+                            } else if !data.get_ref().is_empty() {
+                                // a close code takes two bytes
+                                return Err(Error::new(
+                                    Kind::Protocol,
+                                    "Received close frame with a truncated close code.",
+                                ));
                             } else {
                                 // This is not an error. It is allowed behavior in the
                                 // protocol, so we don't trigger an error.
@@ -872,31 +1723,40 @@ where
                                 // _The WebSocket Connection Close Reason_ is the empty string."
                                 self.handler.on_close(CloseCode::Status, "");
                                 if !self.state.is_closing() {
-                                    self.send_close(CloseCode::Empty, "")?;
+                                    if self.settings.auto_close_response {
+                                        self.send_close(CloseCode::Empty, "")?;
+                                    }
                                 } else {
-                                    self.state = FinishedClose;
+                                    self.set_state(FinishedClose);
                                 }
                             }
                         }
                         OpCode::Ping => {
                             trace!("Received ping frame {:?}", frame);
-                            self.send_pong(frame.into_data())?;
+                            match self.handler.on_ping(frame.payload())? {
+                                PingAction::Pong(data) => self.send_pong(data)?,
+                                PingAction::None => trace!("Not answering ping."),
+                                PingAction::Default => self.send_pong(frame.into_data())?,
+                            }
                         }
                         OpCode::Pong => {
                             trace!("Received pong frame {:?}", frame);
-                            // no ping validation for now
+                            if frame.payload()[..] == self.ping_nonce.to_be_bytes()[..] {
+                                self.missed_pongs = 0;
+                            }
+                            self.handler.on_pong(frame.payload())?;
                         }
                         // last fragment
                         OpCode::Continue => {
                             trace!("Received final fragment {:?}", frame);
                             if let Some(first) = self.fragments.pop_front() {
-                                let size = self.fragments.iter().fold(
-                                    first.payload().len() + frame.payload().len(),
-                                    |len, frame| len + frame.payload().len(),
-                                );
+                                let size = self.fragments_len + frame.payload().len();
+                                self.fragments_len = 0;
                                 match first.opcode() {
                                     OpCode::Text => {
                                         trace!("Constructing text message from fragments: {:?} -> {:?} -> {:?}", first, self.fragments.iter().collect::<Vec<&Frame>>(), frame);
+                                        self.utf8.feed(frame.payload())?;
+                                        self.utf8.finish()?;
                                         let mut data = Vec::with_capacity(size);
                                         data.extend(first.into_data());
                                         while let Some(frame) = self.fragments.pop_front() {
@@ -911,7 +1771,7 @@ where
                                             "Calling handler with constructed message: {:?}",
                                             string
                                         );
-                                        self.handler.on_message(Message::text(string))?;
+                                        self.on_message(Message::text(string), metadata)?;
                                     }
                                     OpCode::Binary => {
                                         trace!("Constructing binary message from fragments: {:?} -> {:?} -> {:?}", first, self.fragments.iter().collect::<Vec<&Frame>>(), frame);
@@ -928,7 +1788,7 @@ where
                                             "Calling handler with constructed message: {:?}",
                                             data
                                         );
-                                        self.handler.on_message(Message::binary(data))?;
+                                        self.on_message(Message::binary(data), metadata)?;
                                     }
                                     _ => {
                                         return Err(Error::new(
@@ -959,6 +1819,12 @@ where
                         {
                             return Err(Error::new(Kind::Capacity, "Exceeded max fragments."));
                         } else {
+                            // text is validated as it arrives to reject invalid messages early
+                            let first = self.fragments.front().unwrap_or(&frame);
+                            if first.opcode() == OpCode::Text {
+                                self.utf8.feed(frame.payload())?;
+                            }
+                            self.fragments_len += frame.payload().len();
                             self.fragments.push_back(frame)
                         }
                     }
@@ -968,13 +1834,135 @@ where
         Ok(())
     }
 
+    // Hand the payload of a data frame to the streaming handler methods instead of reassembling
+    // the message.
+    fn stream_frame(&mut self, frame: Frame) -> Result<()> {
+        match (frame.opcode(), self.receiving) {
+            (OpCode::Text, None) | (OpCode::Binary, None) => {
+                trace!("Streaming message starting with {:?}", frame);
+                self.receiving = Some(frame.opcode());
+                self.receive_dropped = !self.admit_message()?;
+                if !self.receive_dropped {
+                    self.handler.on_message_start(frame.opcode())?;
+                }
+            }
+            (OpCode::Continue, Some(_)) => trace!("Streaming fragment {:?}", frame),
+            (OpCode::Continue, None) => {
+                return Err(Error::new(
+                    Kind::Protocol,
+                    "Received a continuation frame without a message to continue.",
+                ))
+            }
+            (OpCode::Text, Some(_)) | (OpCode::Binary, Some(_)) => {
+                return Err(Error::new(
+                    Kind::Protocol,
+                    "Received a new message while processing fragmented message.",
+                ))
+            }
+            _ => return Err(Error::new(Kind::Protocol, "Encountered invalid opcode.")),
+        }
+
+        let text = self.receiving == Some(OpCode::Text);
+        if text {
+            self.utf8.feed(frame.payload())?;
+        }
+        if frame.is_final() {
+            if text {
+                self.utf8.finish()?;
+            }
+            self.receiving = None;
+            self.fragments_len = 0;
+        } else {
+            self.fragments_len += frame.payload().len();
+        }
+
+        if self.receive_dropped {
+            return Ok(());
+        }
+        self.handler.on_message_chunk(frame.payload())?;
+        if frame.is_final() {
+            self.handler.on_message_end()?;
+        }
+        Ok(())
+    }
+
+    // Offer the message to the borrowing handler method before handing over ownership.
+    fn on_message(&mut self, msg: Message, metadata: MessageMetadata) -> Result<()> {
+        if !self.admit_message()? {
+            return Ok(());
+        }
+        if self.handler.on_message_ref(MessageRef::from(&msg))? {
+            return Ok(());
+        }
+        self.handler.on_message_with_metadata(msg, metadata)
+    }
+
+    // Count an incoming message and apply the rate limit to it, returning whether the message is
+    // passed to the handler.
+    fn admit_message(&mut self) -> Result<bool> {
+        self.shared.count_message_in();
+        if self.take_rate_token() {
+            return Ok(true);
+        }
+        match self.settings.rate_limit_action {
+            RateLimitAction::Close => {
+                debug!("Closing {} for exceeding the message rate.", self.peer_addr());
+                self.send_close(CloseCode::Policy, "Message rate exceeded.")?;
+            }
+            RateLimitAction::Drop => {
+                debug!("Dropping message from {} over the rate limit.", self.peer_addr());
+            }
+        }
+        Ok(false)
+    }
+
+    // Take a token from the bucket of the message rate limit, after refilling it for the time
+    // that has passed since the last message.
+    fn take_rate_token(&mut self) -> bool {
+        let rate = match self.settings.max_messages_per_second {
+            Some(rate) => f64::from(rate),
+            None => return true,
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.rate_refilled);
+        self.rate_refilled = now;
+        self.rate_tokens = (self.rate_tokens + elapsed.as_secs_f64() * rate).min(rate);
+        if self.rate_tokens >= 1.0 {
+            self.rate_tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // The size limit applies to the whole message, including any fragments received so far.
+    #[inline]
+    fn check_message_size(&mut self, len: usize) -> Result<()> {
+        if self.fragments_len.saturating_add(len) > self.settings.max_message_size {
+            self.fragments.clear();
+            self.fragments_len = 0;
+            Err(Error::new(
+                Kind::Capacity,
+                format!(
+                    "Rejected message with length exceeding defined max: {}.",
+                    self.settings.max_message_size
+                ),
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn write(&mut self) -> Result<()> {
         if self.socket.is_negotiating() {
             trace!("Performing TLS negotiation on {}.", self.peer_addr());
             self.socket.clear_negotiating()?;
             self.read()
         } else {
-            let res = if self.state.is_connecting() {
+            let res = if self.tunnel.is_some() {
+                trace!("Ready to write proxy request to {}.", self.peer_addr());
+                self.write_tunnel()
+            } else if self.state.is_connecting() {
                 trace!("Ready to write handshake to {}.", self.peer_addr());
                 self.write_handshake()
             } else {
@@ -983,20 +1971,37 @@ where
                 // Start out assuming that this write will clear the whole buffer
                 self.events.remove(Ready::writable());
 
-                if let Some(len) = self.socket.try_write_buf(&mut self.out_buffer)? {
+                // The queued frames go out in one vectored write. A partial write advances the
+                // buffer past the bytes that went out, so the next writable event resumes with
+                // the first byte that didn't.
+                let mut slices = [IoSlice::new(&[]); MAX_WRITE_SLICES];
+                let count = self.out_buffer.slices(&mut slices);
+                let written = self.socket.try_write_vectored(&slices[..count])?;
+                if let Some(len) = self.shared.count_written(written) {
                     trace!("Wrote {} bytes to {}", len, self.peer_addr());
-                    let finished = len == 0
-                        || self.out_buffer.position() == self.out_buffer.get_ref().len() as u64;
+                    self.out_buffer.advance(len);
+                    self.update_buffered();
+                    self.bytes_written += len as u64;
+                    self.forget_written_frames();
+                    self.acknowledge();
+                    let finished = len == 0 || self.out_buffer.is_empty();
                     if finished {
+                        self.flushing = false;
                         match self.state {
                             // we are are a server that is closing and just wrote out our confirming
                             // close frame, let's disconnect
-                            FinishedClose if self.is_server() => {
+                            FinishedClose if self.is_server() && !self.socket.wants_write() => {
                                 self.events = Ready::empty();
                                 return Ok(());
                             }
                             _ => (),
                         }
+                        if let Some((msg, ack)) = self.held.pop_front() {
+                            self.update_held();
+                            self.buffer_message(msg, ack)?;
+                        } else if len > 0 {
+                            self.handler.on_buffer_drained();
+                        }
                     }
                 }
 
@@ -1009,11 +2014,19 @@ where
                 self.events.remove(Ready::writable());
                 self.events.insert(Ready::readable());
             }
+            if self.socket.wants_write() && res.is_ok() {
+                self.events.insert(Ready::writable());
+            }
             res
         }
     }
 
-    pub fn send_message(&mut self, msg: Message) -> Result<()> {
+    pub fn send_message(
+        &mut self,
+        msg: Message,
+        policy: Option<OverflowPolicy>,
+        ack: Option<AckToken>,
+    ) -> Result<()> {
         if self.state.is_closing() {
             trace!(
                 "Connection is closing. Ignoring request to send message {:?} to {}.",
@@ -1023,6 +2036,58 @@ where
             return Ok(());
         }
 
+        if self.streaming {
+            trace!("Delaying message until the streamed message is finished.");
+            self.delayed.push_back((msg, policy, ack));
+            return Ok(());
+        }
+
+        if let Some(limit) = self.settings.max_queued_messages {
+            if !self.held.is_empty() || self.is_writing() {
+                if self.held.len() >= limit {
+                    match policy.unwrap_or(self.settings.overflow_policy) {
+                        OverflowPolicy::Block => (),
+                        OverflowPolicy::DropOldest => {
+                            debug!(
+                                "Dropping the oldest message held back for {}.",
+                                self.peer_addr()
+                            );
+                            self.held.pop_front();
+                        }
+                        OverflowPolicy::DropNewest => {
+                            debug!(
+                                "Dropping a message for {} over the queue limit.",
+                                self.peer_addr()
+                            );
+                            return Ok(());
+                        }
+                        OverflowPolicy::CloseConnection => {
+                            debug!(
+                                "Closing connection to {} over the queue limit.",
+                                self.peer_addr()
+                            );
+                            self.held.clear();
+                            self.update_held();
+                            return self.send_close(
+                                CloseCode::Policy,
+                                "Too many messages are waiting to be sent.",
+                            );
+                        }
+                    }
+                }
+                trace!("Holding back message until earlier output is written.");
+                self.held.push_back((msg, ack));
+                self.update_held();
+                return Ok(());
+            }
+        }
+
+        self.buffer_message(msg, ack)
+    }
+
+    fn buffer_message(&mut self, msg: Message, ack: Option<AckToken>) -> Result<()> {
+        self.last_activity = Instant::now();
+        self.shared.count_message_out();
         let opcode = msg.opcode();
         trace!("Message opcode {:?}", opcode);
         let data = msg.into_data();
@@ -1030,49 +2095,158 @@ where
         if let Some(frame) = self.handler
             .on_send_frame(Frame::message(data, opcode, true))?
         {
-            if frame.payload().len() > self.settings.fragment_size {
-                trace!("Chunking at {:?}.", self.settings.fragment_size);
-                // note this copies the data, so it's actually somewhat expensive to fragment
-                let mut chunks = frame
-                    .payload()
-                    .chunks(self.settings.fragment_size)
-                    .peekable();
-                let chunk = chunks.next().expect("Unable to get initial chunk!");
-
-                let mut first = Frame::message(Vec::from(chunk), opcode, false);
-
-                // Match reserved bits from original to keep extension status intact
-                first.set_rsv1(frame.has_rsv1());
-                first.set_rsv2(frame.has_rsv2());
-                first.set_rsv3(frame.has_rsv3());
-
-                self.buffer_frame(first)?;
-
-                while let Some(chunk) = chunks.next() {
-                    if chunks.peek().is_some() {
-                        self.buffer_frame(Frame::message(
-                            Vec::from(chunk),
-                            OpCode::Continue,
-                            false,
-                        ))?;
-                    } else {
-                        self.buffer_frame(Frame::message(
-                            Vec::from(chunk),
-                            OpCode::Continue,
-                            true,
-                        ))?;
-                    }
-                }
-            } else {
-                trace!("Sending unfragmented message frame.");
-                // true means that the message is done
-                self.buffer_frame(frame)?;
+            self.buffer_message_frame(frame)?;
+        }
+        if let Some(ack) = ack {
+            self.acks.push_back((self.bytes_buffered, ack));
+            // nothing may have been buffered, if an extension swallowed the message
+            self.acknowledge();
+        }
+        self.check_events();
+        Ok(())
+    }
+
+    // Write a broadcast message that was encoded once for all connections, returning false if
+    // this connection has to encode the message itself with `send_message`.
+    pub fn send_shared(&mut self, frames: &SharedFrames) -> Result<bool> {
+        let held_back = self.settings.max_queued_messages.is_some()
+            && (!self.held.is_empty() || self.is_writing());
+        if !self.is_server()
+            || self.state.is_closing()
+            || self.streaming
+            || held_back
+            || !self.handler.share_broadcasts()
+        {
+            return Ok(false);
+        }
+        self.check_buffer_out(frames.len())?;
+
+        trace!("Buffering shared message for {}.", self.peer_addr());
+        self.last_activity = Instant::now();
+        self.shared.count_message_out();
+        for _ in 0..frames.frames() {
+            self.shared.count_frame_out();
+        }
+        if self.frame_ends.is_empty() {
+            // the start of the first frame
+            self.frame_ends.push_back(self.bytes_buffered);
+        }
+        // control frames can't go ahead of the frames of the message, only before or after
+        self.out_buffer.push_shared(frames);
+        self.bytes_buffered += frames.len() as u64;
+        self.frame_ends.push_back(self.bytes_buffered);
+        self.update_buffered();
+        self.check_events();
+        Ok(true)
+    }
+
+    // Hand the tokens of the messages that have been written in full back to the handler.
+    fn acknowledge(&mut self) {
+        while self
+            .acks
+            .front()
+            .is_some_and(|&(end, _)| end <= self.bytes_written)
+        {
+            if let Some((_, ack)) = self.acks.pop_front() {
+                self.handler.on_sent(ack);
+            }
+        }
+    }
+
+    pub fn send_fragment(&mut self, frame: Frame) -> Result<()> {
+        if self.state.is_closing() {
+            trace!(
+                "Connection is closing. Ignoring request to send fragment {:?} to {}.",
+                frame,
+                self.peer_addr()
+            );
+            return Ok(());
+        }
+
+        if frame.opcode() == OpCode::Continue {
+            if !self.streaming {
+                // the start of the message was lost, most likely to a reconnection
+                trace!("Ignoring fragment of a streamed message that is not in progress.");
+                return Ok(());
+            }
+        } else if self.streaming {
+            return Err(Error::new(
+                Kind::Internal,
+                "Started a streamed message before the previous one was finished.",
+            ));
+        }
+
+        self.last_activity = Instant::now();
+        self.streaming = !frame.is_final();
+
+        if let Some(frame) = self.handler.on_send_frame(frame)? {
+            self.buffer_message_frame(frame)?;
+        }
+
+        if !self.streaming {
+            self.shared.count_message_out();
+            while let Some((msg, policy, ack)) = self.delayed.pop_front() {
+                self.send_message(msg, policy, ack)?;
             }
         }
         self.check_events();
         Ok(())
     }
 
+    pub fn send_frame(&mut self, frame: Frame) -> Result<()> {
+        if self.state.is_closing() {
+            trace!(
+                "Connection is closing. Ignoring request to send frame {:?} to {}.",
+                frame,
+                self.peer_addr()
+            );
+            return Ok(());
+        }
+        trace!("Sending frame to {}.", self.peer_addr());
+
+        self.last_activity = Instant::now();
+        if let Some(frame) = self.handler.on_send_frame(frame)? {
+            self.buffer_frame(frame)?;
+        }
+        self.check_events();
+        Ok(())
+    }
+
+    fn buffer_message_frame(&mut self, frame: Frame) -> Result<()> {
+        if frame.payload().len() > self.settings.fragment_size {
+            trace!("Chunking at {:?}.", self.settings.fragment_size);
+            // note this copies the data, so it's actually somewhat expensive to fragment
+            let mut chunks = frame
+                .payload()
+                .chunks(self.settings.fragment_size)
+                .peekable();
+            let chunk = chunks.next().expect("Unable to get initial chunk!");
+
+            let mut first = Frame::message(Vec::from(chunk), frame.opcode(), false);
+
+            // Match reserved bits from original to keep extension status intact
+            first.set_rsv1(frame.has_rsv1());
+            first.set_rsv2(frame.has_rsv2());
+            first.set_rsv3(frame.has_rsv3());
+
+            self.buffer_frame(first)?;
+
+            while let Some(chunk) = chunks.next() {
+                // the last chunk only finishes the message if the original frame did
+                let finished = chunks.peek().is_none() && frame.is_final();
+                self.buffer_frame(Frame::message(
+                    Vec::from(chunk),
+                    OpCode::Continue,
+                    finished,
+                ))?;
+            }
+            Ok(())
+        } else {
+            trace!("Sending unfragmented message frame.");
+            self.buffer_frame(frame)
+        }
+    }
+
     #[inline]
     pub fn send_ping(&mut self, data: Vec<u8>) -> Result<()> {
         if self.state.is_closing() {
@@ -1086,7 +2260,7 @@ where
         trace!("Sending ping to {}.", self.peer_addr());
 
         if let Some(frame) = self.handler.on_send_frame(Frame::ping(data))? {
-            self.buffer_frame(frame)?;
+            self.buffer_priority_frame(frame)?;
         }
         self.check_events();
         Ok(())
@@ -1105,7 +2279,7 @@ where
         trace!("Sending pong to {}.", self.peer_addr());
 
         if let Some(frame) = self.handler.on_send_frame(Frame::pong(data))? {
-            self.buffer_frame(frame)?;
+            self.buffer_priority_frame(frame)?;
         }
         self.check_events();
         Ok(())
@@ -1119,7 +2293,7 @@ where
         match self.state {
             // We are responding to a close frame the other endpoint, when this frame goes out, we
             // are done.
-            RespondingClose => self.state = FinishedClose,
+            RespondingClose => self.set_state(FinishedClose),
             // Multiple close frames are being sent from our end, ignore the later frames
             AwaitingClose | FinishedClose => {
                 trace!(
@@ -1132,11 +2306,24 @@ where
                 return Ok(());
             }
             // We are initiating a closing handshake.
-            Open => self.state = AwaitingClose,
+            Open => {
+                self.set_state(AwaitingClose);
+                self.local_close = true;
+            }
+            // The connection was lost and is waiting to reconnect, so give up on it instead.
+            Connecting(_, _) if self.reconnecting => {
+                self.reconnecting = false;
+                self.local_close = true;
+                self.events = Ready::empty();
+                return Ok(());
+            }
             Connecting(_, _) => {
                 debug_assert!(false, "Attempted to close connection while not yet open.")
             }
         }
+        if let Some(timeout) = self.settings.close_timeout {
+            self.timers.push((CLOSE, timeout));
+        }
 
         trace!(
             "Sending close {:?} -- {:?} to {}.",
@@ -1145,10 +2332,28 @@ where
             self.peer_addr()
         );
 
+        let ahead = self.settings.prioritize_close_frames;
+        if ahead {
+            // nothing may follow the close frame, so the messages held back are dropped
+            self.held.clear();
+            self.update_held();
+        } else if let AwaitingClose = self.state {
+            // the messages held back go out before the close frame
+            while let Some((msg, ack)) = self.held.pop_front() {
+                self.buffer_message(msg, ack)?;
+            }
+            self.update_held();
+        }
+
         if let Some(frame) = self.handler
             .on_send_frame(Frame::close(code, reason.borrow()))?
         {
-            self.buffer_frame(frame)?;
+            if ahead && frame.is_control() {
+                self.queue_frame(frame, true)?;
+                self.drop_output_after(self.priority_end);
+            } else {
+                self.buffer_frame(frame)?;
+            }
         }
 
         trace!("Connection to {} is now closing.", self.peer_addr());
@@ -1160,67 +2365,222 @@ where
     fn check_events(&mut self) {
         if !self.state.is_connecting() {
             self.events.insert(Ready::readable());
-            if self.out_buffer.position() < self.out_buffer.get_ref().len() as u64 {
+            if self.is_writing() && !self.coalescing() {
                 self.events.insert(Ready::writable());
             }
         }
     }
 
-    fn buffer_frame(&mut self, mut frame: Frame) -> Result<()> {
-        self.check_buffer_out(&frame)?;
+    // Whether the output is held back to be written together with what follows.
+    fn coalescing(&mut self) -> bool {
+        let window = match self.settings.coalesce_window {
+            Some(window) if !self.flushing => window,
+            _ => return false,
+        };
+        let now = Instant::now();
+        if now < *self.flush_at.get_or_insert(now + window) {
+            return true;
+        }
+        self.flush_at = None;
+        self.flushing = true;
+        false
+    }
+
+    /// The time at which the output that is held back should be written.
+    #[inline]
+    pub fn flush_at(&self) -> Option<Instant> {
+        self.flush_at
+    }
+
+    /// Write the output that is held back.
+    pub fn flush(&mut self) {
+        if self.flush_at.take().is_some() {
+            self.flushing = true;
+            self.check_events();
+        }
+    }
+
+    fn buffer_frame(&mut self, frame: Frame) -> Result<()> {
+        self.queue_frame(frame, false)
+    }
+
+    // Buffer a ping or pong, ahead of the data that is waiting to be written if so configured.
+    fn buffer_priority_frame(&mut self, frame: Frame) -> Result<()> {
+        let ahead = self.settings.prioritize_control_frames && frame.is_control();
+        self.queue_frame(frame, ahead)
+    }
+
+    fn queue_frame(&mut self, mut frame: Frame, ahead: bool) -> Result<()> {
+        if frame.is_control() && frame.payload().len() > 125 {
+            return Err(Error::new(
+                Kind::Internal,
+                "Control frames can't carry more than 125 bytes.",
+            ));
+        }
+        self.check_buffer_out(frame.len())?;
 
         if self.is_client() {
-            frame.set_mask();
+            match self.settings.masking_key_source {
+                MaskingKeySource::Random => match self.client_settings.random_source {
+                    Some(ref source) => {
+                        let mut key = [0; 4];
+                        source.fill(&mut key);
+                        frame.set_mask_key(key)
+                    }
+                    None => frame.set_mask(),
+                },
+                MaskingKeySource::InsecureDeterministic(_) => {
+                    frame.set_mask_key(next_masking_key(&mut self.masking_keys))
+                }
+            };
         }
 
         trace!("Buffering frame to {}:\n{}", self.peer_addr(), frame);
+        self.shared.count_frame_out();
+        if frame.is_control() {
+            // control frames are never held back
+            self.flush_at = None;
+            self.flushing = true;
+        }
+
+        if ahead {
+            return self.insert_frame(frame);
+        }
+
+        if self.frame_ends.is_empty() {
+            // the start of the first frame
+            self.frame_ends.push_back(self.bytes_buffered);
+        }
+        self.bytes_buffered += self.out_buffer.push(frame)? as u64;
+        self.frame_ends.push_back(self.bytes_buffered);
+        self.update_buffered();
+        Ok(())
+    }
+
+    // Put a control frame ahead of the output that is waiting to be written, after the frame
+    // that is being written and the control frames that skipped ahead before.
+    fn insert_frame(&mut self, frame: Frame) -> Result<()> {
+        self.forget_written_frames();
+        let current = self.frame_ends.front().cloned().unwrap_or(self.bytes_written);
+        let at = cmp::max(current, cmp::max(self.priority_end, self.bytes_written));
+
+        let len = self
+            .out_buffer
+            .insert((at - self.bytes_written) as usize, frame)? as u64;
+
+        // everything after the frame is written later by as much
+        for end in self.frame_ends.iter_mut().filter(|end| **end > at) {
+            *end += len;
+        }
+        for ack in self.acks.iter_mut().filter(|ack| ack.0 > at) {
+            ack.0 += len;
+        }
+        let pos = self.frame_ends.iter().take_while(|&&end| end <= at).count();
+        self.frame_ends.insert(pos, at + len);
+        self.priority_end = at + len;
+        self.bytes_buffered += len;
+        self.update_buffered();
+        Ok(())
+    }
+
+    // Drop the output that was buffered after the given end of a frame, along with the
+    // acknowledgements of the messages in it.
+    fn drop_output_after(&mut self, end: u64) {
+        self.out_buffer.truncate((end - self.bytes_written) as usize);
+        self.bytes_buffered = end;
+        while self.frame_ends.back().is_some_and(|&frame_end| frame_end > end) {
+            self.frame_ends.pop_back();
+        }
+        while self.acks.back().is_some_and(|&(ack_end, _)| ack_end > end) {
+            self.acks.pop_back();
+        }
+        self.update_buffered();
+    }
 
-        let pos = self.out_buffer.position();
-        self.out_buffer.seek(SeekFrom::End(0))?;
-        frame.format(&mut self.out_buffer)?;
-        self.out_buffer.seek(SeekFrom::Start(pos))?;
+    // Drop the ends of the frames that have been written, except for one that ends where the
+    // output has been written to, at which a control frame can still go ahead of the rest.
+    fn forget_written_frames(&mut self) {
+        while self
+            .frame_ends
+            .front()
+            .is_some_and(|&end| end < self.bytes_written)
+        {
+            self.frame_ends.pop_front();
+        }
+    }
+
+    #[inline]
+    fn is_writing(&self) -> bool {
+        !self.out_buffer.is_empty()
+    }
+
+    #[inline]
+    fn update_buffered(&self) {
+        let len = self.out_buffer.len();
+        let held: usize = self.held.iter().map(|(msg, _)| msg.len()).sum();
+        self.shared.set_buffered(len + held);
+    }
+
+    fn update_held(&self) {
+        self.shared.set_held(self.held.len());
+        self.update_buffered();
+    }
+
+    fn check_buffer_out(&self, needed: usize) -> Result<()> {
+        let capacity = cmp::min(
+            self.settings.out_buffer_capacity,
+            self.settings.out_buffer_max_capacity,
+        );
+        let len = self.out_buffer.len();
+        if (len >= capacity && !self.settings.out_buffer_grow)
+            || len + needed > self.settings.out_buffer_max_capacity
+        {
+            return Err(Error::new(
+                Kind::Capacity,
+                "Maxed out output buffer for connection.",
+            ));
+        }
         Ok(())
     }
 
-    fn check_buffer_out(&mut self, frame: &Frame) -> Result<()> {
-        if self.out_buffer.get_ref().capacity() <= self.out_buffer.get_ref().len() + frame.len() {
+    fn buffer_in(&mut self) -> Result<Option<usize>> {
+        trace!("Reading buffer for connection to {}.", self.peer_addr());
+        // Make room before reading rather than after, the frames that filled the buffer have been
+        // handled by now, so only the start of a frame that doesn't fit is left.
+        if !self.discard_input
+            && self.in_buffer.get_ref().len() == self.in_buffer.get_ref().capacity()
+        {
             // extend
-            let mut new = Vec::with_capacity(self.out_buffer.get_ref().capacity());
-            new.extend(&self.out_buffer.get_ref()[self.out_buffer.position() as usize..]);
+            let mut new = Vec::with_capacity(self.in_buffer.get_ref().capacity());
+            new.extend(&self.in_buffer.get_ref()[self.in_buffer.position() as usize..]);
             if new.len() == new.capacity() {
-                if self.settings.out_buffer_grow {
-                    new.reserve(self.settings.out_buffer_capacity)
+                let room = self.settings.in_buffer_max_capacity.saturating_sub(new.capacity());
+                if self.settings.in_buffer_grow && room > 0 {
+                    let grow = cmp::max(new.capacity(), self.settings.in_buffer_capacity);
+                    new.reserve_exact(cmp::min(grow, room));
                 } else {
-                    return Err(Error::new(
+                    // Nothing after the frame can be parsed either, including the answer to our
+                    // close frame, so stop waiting for it and drop the rest of the input.
+                    self.discard_input = true;
+                    self.in_buffer = Cursor::new(Vec::new());
+                    self.error(Error::new(
                         Kind::Capacity,
-                        "Maxed out output buffer for connection.",
+                        "Maxed out input buffer for connection.",
                     ));
+                    if let AwaitingClose = self.state {
+                        self.set_state(FinishedClose);
+                    }
+                    return Ok(None);
                 }
             }
-            self.out_buffer = Cursor::new(new);
+            self.in_buffer = Cursor::new(new);
         }
-        Ok(())
-    }
-
-    fn buffer_in(&mut self) -> Result<Option<usize>> {
-        trace!("Reading buffer for connection to {}.", self.peer_addr());
-        if let Some(len) = self.socket.try_read_buf(self.in_buffer.get_mut())? {
+        let read = self.socket.try_read_buf(self.in_buffer.get_mut())?;
+        if let Some(len) = self.shared.count_read(read) {
             trace!("Buffered {}.", len);
-            if self.in_buffer.get_ref().len() == self.in_buffer.get_ref().capacity() {
-                // extend
-                let mut new = Vec::with_capacity(self.in_buffer.get_ref().capacity());
-                new.extend(&self.in_buffer.get_ref()[self.in_buffer.position() as usize..]);
-                if new.len() == new.capacity() {
-                    if self.settings.in_buffer_grow {
-                        new.reserve(self.settings.in_buffer_capacity);
-                    } else {
-                        return Err(Error::new(
-                            Kind::Capacity,
-                            "Maxed out input buffer for connection.",
-                        ));
-                    }
-                }
-                self.in_buffer = Cursor::new(new);
+            if self.discard_input {
+                self.in_buffer.get_mut().clear();
+                self.in_buffer.set_position(0);
             }
             Ok(Some(len))
         } else {
@@ -1,13 +1,59 @@
+use std::cell::Cell;
+use std::cmp;
 use std::mem;
+use std::ptr;
 use std::slice;
 
 use super::ffi;
-use super::libc::{c_char, c_int, c_uint};
+use super::libc::{self, c_char, c_int, c_uint, c_void, size_t};
 
 use result::{Error, Kind, Result};
 
+use super::extension::DeflateStrategy;
+
 const ZLIB_VERSION: &'static str = "1.2.8\0";
 
+// The largest sliding window used by deflate.
+const MAX_WINDOW: usize = 1 << 15;
+
+extern "C" {
+    // Part of zlib since 1.2.7.1, but not exposed by libz-sys.
+    fn inflateGetDictionary(
+        strm: *mut ffi::z_stream,
+        dictionary: *mut u8,
+        dict_length: *mut c_uint,
+    ) -> c_int;
+}
+
+// zlib allows passing null allocation functions to fall back on its defaults, but the function
+// pointers in libz-sys are not nullable, so we provide equivalent ones ourselves.
+extern "C" fn zalloc(_opaque: *mut c_void, items: c_uint, size: c_uint) -> *mut c_void {
+    unsafe { libc::calloc(items as size_t, size as size_t) }
+}
+
+extern "C" fn zfree(_opaque: *mut c_void, address: *mut c_void) {
+    unsafe { libc::free(address) }
+}
+
+fn new_stream() -> Box<ffi::z_stream> {
+    Box::new(ffi::z_stream {
+        next_in: ptr::null_mut(),
+        avail_in: 0,
+        total_in: 0,
+        next_out: ptr::null_mut(),
+        avail_out: 0,
+        total_out: 0,
+        msg: ptr::null_mut(),
+        state: ptr::null_mut(),
+        zalloc,
+        zfree,
+        opaque: ptr::null_mut(),
+        data_type: 0,
+        adler: 0,
+        reserved: 0,
+    })
+}
+
 trait Context {
     fn stream(&mut self) -> &mut ffi::z_stream;
 
@@ -28,7 +74,8 @@ trait Context {
             output_size = output.len();
 
             if output_size == output.capacity() {
-                output.reserve(input.len())
+                // even empty input produces some output, so always make room for it
+                output.reserve(cmp::max(input.len(), 64))
             }
 
             let out_slice = unsafe {
@@ -59,27 +106,64 @@ pub struct Compressor {
     // Box the z_stream to ensure it isn't moved. Moving the z_stream
     // causes zlib to fail, because it maintains internal pointers.
     stream: Box<ffi::z_stream>,
+    // the preset dictionary, which is set again whenever the context is reset
+    dictionary: Vec<u8>,
 }
 
 impl Compressor {
-    pub fn new(window_bits: i8) -> Compressor {
+    pub fn new(window_bits: i8, level: u8, mem_level: u8, strategy: DeflateStrategy) -> Compressor {
         debug_assert!(window_bits >= 9, "Received too small window size.");
         debug_assert!(window_bits <= 15, "Received too large window size.");
+        debug_assert!(level <= 9, "Received too large compression level.");
+        debug_assert!(mem_level >= 1, "Received too small memory level.");
+        debug_assert!(mem_level <= 9, "Received too large memory level.");
+
+        let strategy = match strategy {
+            DeflateStrategy::Default => ffi::Z_DEFAULT_STRATEGY,
+            DeflateStrategy::Filtered => ffi::Z_FILTERED,
+            DeflateStrategy::HuffmanOnly => ffi::Z_HUFFMAN_ONLY,
+            DeflateStrategy::Rle => ffi::Z_RLE,
+        };
 
         unsafe {
-            let mut stream: Box<ffi::z_stream> = Box::new(mem::zeroed());
+            let mut stream = new_stream();
             let result = ffi::deflateInit2_(
                 stream.as_mut(),
-                9,
+                level as c_int,
                 ffi::Z_DEFLATED,
                 -window_bits as c_int,
-                9,
-                ffi::Z_DEFAULT_STRATEGY,
+                c_int::from(mem_level),
+                strategy,
                 ZLIB_VERSION.as_ptr() as *const c_char,
                 mem::size_of::<ffi::z_stream>() as c_int,
             );
             assert!(result == ffi::Z_OK, "Failed to initialize compresser.");
-            Compressor { stream: stream }
+            Compressor {
+                stream: stream,
+                dictionary: Vec::new(),
+            }
+        }
+    }
+
+    pub fn with_dictionary(mut self, dictionary: Vec<u8>) -> Compressor {
+        self.dictionary = dictionary;
+        assert!(
+            self.set_dictionary() == ffi::Z_OK,
+            "Failed to set compression dictionary."
+        );
+        self
+    }
+
+    fn set_dictionary(&mut self) -> c_int {
+        if self.dictionary.is_empty() {
+            return ffi::Z_OK;
+        }
+        unsafe {
+            ffi::deflateSetDictionary(
+                self.stream.as_mut(),
+                self.dictionary.as_ptr(),
+                self.dictionary.len() as c_uint,
+            )
         }
     }
 
@@ -102,7 +186,11 @@ impl Compressor {
     }
 
     pub fn reset(&mut self) -> Result<()> {
-        match unsafe { ffi::deflateReset(self.stream.as_mut()) } {
+        let code = match unsafe { ffi::deflateReset(self.stream.as_mut()) } {
+            ffi::Z_OK => self.set_dictionary(),
+            code => code,
+        };
+        match code {
             ffi::Z_OK => Ok(()),
             code => Err(Error::new(
                 Kind::Protocol,
@@ -131,6 +219,8 @@ impl Drop for Compressor {
 
 pub struct Decompressor {
     stream: Box<ffi::z_stream>,
+    // the preset dictionary, which is set again whenever the context is reset
+    dictionary: Vec<u8>,
 }
 
 impl Decompressor {
@@ -139,7 +229,7 @@ impl Decompressor {
         debug_assert!(window_bits <= 15, "Received too large window size.");
 
         unsafe {
-            let mut stream: Box<ffi::z_stream> = Box::new(mem::zeroed());
+            let mut stream = new_stream();
             let result = ffi::inflateInit2_(
                 stream.as_mut(),
                 -window_bits as c_int,
@@ -147,11 +237,38 @@ impl Decompressor {
                 mem::size_of::<ffi::z_stream>() as c_int,
             );
             assert!(result == ffi::Z_OK, "Failed to initialize decompresser.");
-            Decompressor { stream: stream }
+            Decompressor {
+                stream: stream,
+                dictionary: Vec::new(),
+            }
+        }
+    }
+
+    pub fn with_dictionary(mut self, dictionary: Vec<u8>) -> Decompressor {
+        self.dictionary = dictionary;
+        assert!(
+            self.set_dictionary() == ffi::Z_OK,
+            "Failed to set decompression dictionary."
+        );
+        self
+    }
+
+    // A raw inflate stream takes its dictionary up front, rather than when the data asks for it.
+    fn set_dictionary(&mut self) -> c_int {
+        if self.dictionary.is_empty() {
+            return ffi::Z_OK;
+        }
+        unsafe {
+            ffi::inflateSetDictionary(
+                self.stream.as_mut(),
+                self.dictionary.as_ptr(),
+                self.dictionary.len() as c_uint,
+            )
         }
     }
 
     pub fn decompress(&mut self, input: &[u8], output: &mut Vec<u8>) -> Result<()> {
+        let ended = Cell::new(false);
         self.stream_apply(input, output, |stream| unsafe {
             match ffi::inflate(stream, ffi::Z_SYNC_FLUSH) {
                 ffi::Z_OK | ffi::Z_BUF_ERROR => {
@@ -161,16 +278,55 @@ impl Decompressor {
                         None
                     }
                 }
+                // The message ended with a block that has BFINAL set, anything after it is the
+                // empty block appended to every message.
+                ffi::Z_STREAM_END => {
+                    ended.set(true);
+                    Some(Ok(()))
+                }
                 code => Some(Err(Error::new(
                     Kind::Protocol,
                     format!("Failed to perform decompression: {}", code),
                 ))),
             }
-        })
+        })?;
+        if ended.get() {
+            self.restart()?
+        }
+        Ok(())
+    }
+
+    // Start a new deflate stream after one was finished, keeping the sliding window for the
+    // messages that still refer to it.
+    fn restart(&mut self) -> Result<()> {
+        let mut window = vec![0; MAX_WINDOW];
+        let mut len = 0;
+        let code = unsafe {
+            match inflateGetDictionary(self.stream.as_mut(), window.as_mut_ptr(), &mut len) {
+                ffi::Z_OK => match ffi::inflateReset(self.stream.as_mut()) {
+                    ffi::Z_OK => {
+                        ffi::inflateSetDictionary(self.stream.as_mut(), window.as_ptr(), len)
+                    }
+                    code => code,
+                },
+                code => code,
+            }
+        };
+        match code {
+            ffi::Z_OK => Ok(()),
+            code => Err(Error::new(
+                Kind::Protocol,
+                format!("Failed to restart decompression context: {}", code),
+            )),
+        }
     }
 
     pub fn reset(&mut self) -> Result<()> {
-        match unsafe { ffi::inflateReset(self.stream.as_mut()) } {
+        let code = match unsafe { ffi::inflateReset(self.stream.as_mut()) } {
+            ffi::Z_OK => self.set_dictionary(),
+            code => code,
+        };
+        match code {
             ffi::Z_OK => Ok(()),
             code => Err(Error::new(
                 Kind::Protocol,
@@ -214,7 +370,7 @@ mod test {
             let mut compressed = Vec::with_capacity(data.len());
             let mut decompressed = Vec::with_capacity(data.len());
 
-            let com = Compressor::new(i);
+            let com = Compressor::new(i, 9, 9, DeflateStrategy::Default);
             let mut moved_com = com;
 
             moved_com
@@ -244,7 +400,7 @@ mod test {
         let mut decompressed2 = Vec::with_capacity(data2.len());
         let mut decompressed2_ind = Vec::with_capacity(data2.len());
 
-        let mut com = Compressor::new(9);
+        let mut com = Compressor::new(9, 9, 9, DeflateStrategy::Default);
 
         com.compress(&data1, &mut compressed1).unwrap();
         com.compress(&data2, &mut compressed2).unwrap();
@@ -265,4 +421,131 @@ mod test {
         assert!(compressed2 != compressed2_ind);
         assert!(compressed2.len() < compressed2_ind.len());
     }
+
+    #[test]
+    fn dictionary() {
+        let dictionary = br#"{"type":"message","room":"","text":""}"#.to_vec();
+        let data = br#"{"type":"message","room":"lobby","text":"hi"}"#;
+        let mut plain = Vec::new();
+        Compressor::new(15, 9, 9, DeflateStrategy::Default)
+            .compress(data, &mut plain)
+            .unwrap();
+
+        let mut com =
+            Compressor::new(15, 9, 9, DeflateStrategy::Default).with_dictionary(dictionary.clone());
+        let mut dec = Decompressor::new(15).with_dictionary(dictionary.clone());
+        for _ in 0..2 {
+            let mut compressed = Vec::new();
+            let mut decompressed = Vec::new();
+            com.compress(data, &mut compressed).unwrap();
+            assert!(compressed.len() < plain.len());
+            dec.decompress(&compressed, &mut decompressed).unwrap();
+            assert_eq!(&decompressed[..], &data[..]);
+
+            // the dictionary outlasts a reset of the contexts
+            com.reset().unwrap();
+            dec.reset().unwrap();
+        }
+
+        // without the dictionary the data can't be decompressed
+        let mut compressed = Vec::new();
+        com.compress(data, &mut compressed).unwrap();
+        let mut decompressed = Vec::new();
+        let result = Decompressor::new(15).decompress(&compressed, &mut decompressed);
+        assert!(result.is_err() || &decompressed[..] != &data[..]);
+    }
+
+    #[test]
+    fn levels() {
+        let data = "HI THERE HI THERE HI THERE HI THERE".as_bytes();
+        for level in 0..10 {
+            let mut compressed = Vec::with_capacity(data.len());
+            let mut decompressed = Vec::with_capacity(data.len());
+
+            let mut com = Compressor::new(15, level, 9, DeflateStrategy::Default);
+            com.compress(&data, &mut compressed).unwrap();
+
+            let mut dec = Decompressor::new(15);
+            dec.decompress(&compressed, &mut decompressed).unwrap();
+
+            assert_eq!(data, &decompressed[..]);
+        }
+    }
+
+    #[test]
+    fn tuning() {
+        let data = "HI THERE HI THERE HI THERE HI THERE".as_bytes();
+        let strategies = [
+            DeflateStrategy::Default,
+            DeflateStrategy::Filtered,
+            DeflateStrategy::HuffmanOnly,
+            DeflateStrategy::Rle,
+        ];
+        for &strategy in &strategies {
+            for mem_level in 1..10 {
+                let mut compressed = Vec::with_capacity(data.len());
+                let mut decompressed = Vec::with_capacity(data.len());
+
+                let mut com = Compressor::new(15, 6, mem_level, strategy);
+                com.compress(&data, &mut compressed).unwrap();
+
+                let mut dec = Decompressor::new(15);
+                dec.decompress(&compressed, &mut decompressed).unwrap();
+
+                assert_eq!(data, &decompressed[..]);
+            }
+        }
+    }
+
+    // Decompress a message payload the way the extension does, with the empty block appended.
+    fn inflate(dec: &mut Decompressor, payload: &[u8]) -> Vec<u8> {
+        let mut input = payload.to_vec();
+        input.extend(&[0, 0, 0xff, 0xff]);
+        let mut output = Vec::new();
+        dec.decompress(&input, &mut output).unwrap();
+        output
+    }
+
+    // The payloads of the examples in RFC 7692, section 7.2.3, which browsers produce as well.
+    #[test]
+    fn rfc_examples() {
+        let mut dec = Decompressor::new(15);
+        assert_eq!(inflate(&mut dec, &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00]), b"Hello");
+        // refers to the previous message through the shared sliding window
+        assert_eq!(inflate(&mut dec, &[0xf2, 0x00, 0x11, 0x00, 0x00]), b"Hello");
+
+        let mut dec = Decompressor::new(15);
+        let stored = [0x00, 0x05, 0x00, 0xfa, 0xff, 0x48, 0x65, 0x6c, 0x6c, 0x6f, 0x00];
+        assert_eq!(inflate(&mut dec, &stored), b"Hello");
+
+        let mut dec = Decompressor::new(15);
+        let two_blocks = [
+            0xf2, 0x48, 0x05, 0x00, 0x00, 0x00, 0xff, 0xff, 0xca, 0xc9, 0xc9, 0x07, 0x00,
+        ];
+        assert_eq!(inflate(&mut dec, &two_blocks), b"Hello");
+
+        // a block with BFINAL set ends the deflate stream, but not the sliding window
+        let mut dec = Decompressor::new(15);
+        let last = [0xf3, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00, 0x00];
+        assert_eq!(inflate(&mut dec, &last), b"Hello");
+        assert_eq!(inflate(&mut dec, &[0xf2, 0x00, 0x11, 0x00, 0x00]), b"Hello");
+        assert_eq!(inflate(&mut dec, &last), b"Hello");
+    }
+
+    #[test]
+    fn empty_block() {
+        let mut com = Compressor::new(15, 9, 9, DeflateStrategy::Default);
+        let expected = [
+            &[0xf2, 0x48, 0xcd, 0xc9, 0xc9, 0x07, 0x00][..],
+            &[0xf2, 0x00, 0x11, 0x00, 0x00],
+        ];
+        for expected in &expected {
+            let mut compressed = Vec::new();
+            com.compress(b"Hello", &mut compressed).unwrap();
+            // every flushed message ends with the empty block that the extension strips
+            assert!(compressed.ends_with(&[0x00, 0x00, 0xff, 0xff]));
+            compressed.truncate(compressed.len() - 4);
+            assert_eq!(&compressed[..], *expected);
+        }
+    }
 }
@@ -4,27 +4,55 @@ use std::mem::replace;
 use openssl::ssl::SslStream;
 #[cfg(feature = "nativetls")]
 use native_tls::TlsStream as SslStream;
+#[cfg(feature = "tls-rustls")]
+use rustls::{ClientConfig, ServerConfig};
+#[cfg(feature = "tls-rustls")]
+use std::sync::Arc;
 use url;
 
-use frame::Frame;
-use handler::Handler;
+use communication::AckToken;
+use frame::{FragmentState, Frame};
+use handler::{Handler, PingAction};
 use handshake::{Handshake, Request, Response};
-use message::Message;
+use message::{Message, MessageMetadata, MessageRef};
 use protocol::{CloseCode, OpCode};
 use result::{Error, Kind, Result};
-#[cfg(any(feature = "ssl", feature = "nativetls"))]
+#[cfg(feature = "tls-rustls")]
+use stream::RustlsStream as SslStream;
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
 use util::TcpStream;
 use util::{Timeout, Token};
 
 use super::context::{Compressor, Decompressor};
 
 /// Deflate Extension Handler Settings
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct DeflateSettings {
     /// The max size of the sliding window. If the other endpoint selects a smaller size, that size
     /// will be used instead. This must be an integer between 9 and 15 inclusive.
+    ///
+    /// Together with `mem_level`, the window decides the memory that each connection spends on
+    /// compression. zlib uses about `2 ^ (max_window_bits + 2) + 2 ^ (mem_level + 9)` bytes for
+    /// compressing and `2 ^ max_window_bits` bytes for decompressing, and a smaller window
+    /// compresses messages less well. A server with a smaller
+    /// window limits both `server_max_window_bits` and `client_max_window_bits` in its response,
+    /// whatever the client offers, and declines offers from clients that don't allow their
+    /// window to be limited.
     /// Default: 15
     pub max_window_bits: u8,
+    /// The zlib compression level used for outgoing messages. Lower levels trade bandwidth for
+    /// cpu time. This must be an integer between 0 and 9 inclusive, where 0 disables compression.
+    /// Default: 9
+    pub compression_level: u8,
+    /// The amount of memory zlib uses for the internal compression state of outgoing messages.
+    /// Higher levels use more memory for faster and better compression. This must be an integer
+    /// between 1 and 9 inclusive.
+    /// Default: 9
+    pub mem_level: u8,
+    /// The zlib strategy used to compress outgoing messages, which tunes the compression to the
+    /// kind of data that is sent.
+    /// Default: DeflateStrategy::Default
+    pub strategy: DeflateStrategy,
     /// Indicates whether to ask the other endpoint to reset the sliding window for each message.
     /// Default: false
     pub request_no_context_takeover: bool,
@@ -33,6 +61,19 @@ pub struct DeflateSettings {
     /// will fail if this endpoint is a client and the server requests no context takeover.
     /// Default: true
     pub accept_no_context_takeover: bool,
+    /// Whether the client resets its sliding window for each message it compresses, so that it
+    /// doesn't keep a compression context between messages. A client with this set does so and
+    /// says so in its offer, and a server with this set requires the client to do so in its
+    /// response. Unlike with `request_no_context_takeover`, the request isn't left to the
+    /// endpoint's role.
+    /// Default: false
+    pub client_no_context_takeover: bool,
+    /// Whether the server resets its sliding window for each message it compresses, so that it
+    /// doesn't keep a compression context between messages. A server with this set does so and
+    /// says so in its response, and a client with this set asks the server to do so in its offer,
+    /// failing the handshake if the server doesn't agree.
+    /// Default: false
+    pub server_no_context_takeover: bool,
     /// The number of WebSocket frames to store when defragmenting an incoming fragmented
     /// compressed message.
     /// This setting may be different from the `fragments_capacity` setting of the WebSocket in order to
@@ -43,23 +84,127 @@ pub struct DeflateSettings {
     /// exceeded. If this is not true, a capacity error will be triggered instead.
     /// Default: true
     pub fragments_grow: bool,
+    /// When set, a message sent in a single frame is sent uncompressed if compressing it saves
+    /// fewer than this many bytes, as happens with data that is already compressed or
+    /// encrypted. `Some(0)` only sends messages uncompressed when compression would make them
+    /// larger. The compression context is reset after such a message, since the other endpoint
+    /// doesn't see the data that was compressed.
+    /// Default: None
+    pub skip_incompressible_threshold: Option<usize>,
+    /// A preset dictionary that primes both the compression and the decompression contexts, so
+    /// that even the first messages can refer back to it. Messages of a protocol with a known
+    /// vocabulary, such as the keys of JSON objects, compress much better against a dictionary
+    /// that contains it. The extension doesn't negotiate the dictionary, so both endpoints have
+    /// to agree on it out-of-band, otherwise messages fail to decompress. Only the last 32KB of
+    /// the dictionary, or less with a smaller window, are used.
+    /// Default: None
+    pub dictionary: Option<Vec<u8>>,
 }
 
 impl Default for DeflateSettings {
     fn default() -> DeflateSettings {
         DeflateSettings {
             max_window_bits: 15,
+            compression_level: 9,
+            mem_level: 9,
+            strategy: DeflateStrategy::Default,
             request_no_context_takeover: false,
             accept_no_context_takeover: true,
+            client_no_context_takeover: false,
+            server_no_context_takeover: false,
             fragments_capacity: 10,
             fragments_grow: true,
+            skip_incompressible_threshold: None,
+            dictionary: None,
+        }
+    }
+}
+
+impl DeflateSettings {
+    /// Check that the settings are within the ranges that zlib accepts.
+    pub fn validate(&self) -> Result<()> {
+        if self.max_window_bits < 9 || self.max_window_bits > 15 {
+            return Err(Error::new(
+                Kind::Internal,
+                format!(
+                    "The max window bits must be between 9 and 15, not {}.",
+                    self.max_window_bits
+                ),
+            ));
+        }
+        if self.compression_level > 9 {
+            return Err(Error::new(
+                Kind::Internal,
+                format!(
+                    "The compression level must be between 0 and 9, not {}.",
+                    self.compression_level
+                ),
+            ));
+        }
+        if self.mem_level < 1 || self.mem_level > 9 {
+            return Err(Error::new(
+                Kind::Internal,
+                format!(
+                    "The memory level must be between 1 and 9, not {}.",
+                    self.mem_level
+                ),
+            ));
+        }
+        Ok(())
+    }
+
+    fn compressor(&self, window_bits: i8) -> Compressor {
+        let com = Compressor::new(
+            window_bits,
+            self.compression_level,
+            self.mem_level,
+            self.strategy,
+        );
+        match self.dictionary {
+            Some(ref dictionary) => com.with_dictionary(dictionary.clone()),
+            None => com,
+        }
+    }
+
+    fn decompressor(&self, window_bits: i8) -> Decompressor {
+        let dec = Decompressor::new(window_bits);
+        match self.dictionary {
+            Some(ref dictionary) => dec.with_dictionary(dictionary.clone()),
+            None => dec,
         }
     }
 }
 
+/// The zlib compression strategies, see the documentation of `deflateInit2` in zlib.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeflateStrategy {
+    /// Compression suited to most data.
+    Default,
+    /// Compression suited to data with small values of a somewhat random distribution, which
+    /// favors Huffman coding over string matching.
+    Filtered,
+    /// Huffman coding only, without string matching.
+    HuffmanOnly,
+    /// String matching limited to runs of the same byte, which is almost as fast as Huffman
+    /// coding only but compresses data such as images better.
+    Rle,
+}
+
+// The value of a window bits parameter of an offer, which may be missing or quoted. None if the
+// value is not a window size between 9 and 15.
+fn window_bits(param: &str) -> Option<Option<i8>> {
+    match param.split_once('=') {
+        Some((_, value)) => match value.trim().trim_matches('"').parse() {
+            Ok(window_bits) if window_bits >= 9 && window_bits <= 15 => Some(Some(window_bits)),
+            _ => None,
+        },
+        None => Some(None),
+    }
+}
+
 /// Utility for applying the permessage-deflate extension to a handler with particular deflate
 /// settings.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub struct DeflateBuilder {
     settings: DeflateSettings,
 }
@@ -79,15 +224,22 @@ impl DeflateBuilder {
     }
 
     /// Wrap another handler in with a deflate handler as configured.
+    ///
+    /// # Panics
+    ///
+    /// If the settings are invalid, see `DeflateSettings::validate`.
     pub fn build<H: Handler>(&self, handler: H) -> DeflateHandler<H> {
+        if let Err(err) = self.settings.validate() {
+            panic!("Invalid deflate settings: {}", err);
+        }
         DeflateHandler {
-            com: Compressor::new(self.settings.max_window_bits as i8),
-            dec: Decompressor::new(self.settings.max_window_bits as i8),
+            com: self.settings.compressor(self.settings.max_window_bits as i8),
+            dec: self.settings.decompressor(self.settings.max_window_bits as i8),
             fragments: Vec::with_capacity(self.settings.fragments_capacity),
             compress_reset: false,
             decompress_reset: false,
             pass: false,
-            settings: self.settings,
+            settings: self.settings.clone(),
             inner: handler,
         }
     }
@@ -116,8 +268,8 @@ impl<H: Handler> DeflateHandler<H> {
         trace!("Using permessage-deflate handler.");
         let settings = DeflateSettings::default();
         DeflateHandler {
-            com: Compressor::new(settings.max_window_bits as i8),
-            dec: Decompressor::new(settings.max_window_bits as i8),
+            com: settings.compressor(settings.max_window_bits as i8),
+            dec: settings.decompressor(settings.max_window_bits as i8),
             fragments: Vec::with_capacity(settings.fragments_capacity),
             compress_reset: false,
             decompress_reset: false,
@@ -135,6 +287,68 @@ impl<H: Handler> DeflateHandler<H> {
         res.remove_extension("permessage-deflate");
         Ok(res)
     }
+
+    // Decompress the frames of compressed messages, the fragments of a compressed message are
+    // held back until the final one arrives.
+    fn decompress(&mut self, mut frame: Frame) -> Result<Option<Frame>> {
+        if !self.pass && !frame.is_control() {
+            if !self.fragments.is_empty() || frame.has_rsv1() {
+                frame.set_rsv1(false);
+
+                if !frame.is_final() {
+                    self.fragments.push(frame);
+                    return Ok(None);
+                } else {
+                    if frame.opcode() == OpCode::Continue {
+                        if self.fragments.is_empty() {
+                            return Err(Error::new(
+                                Kind::Protocol,
+                                "Unable to reconstruct fragmented message. No first frame.",
+                            ));
+                        } else {
+                            if !self.settings.fragments_grow
+                                && self.settings.fragments_capacity == self.fragments.len()
+                            {
+                                return Err(Error::new(Kind::Capacity, "Exceeded max fragments."));
+                            } else {
+                                self.fragments.push(frame);
+                            }
+
+                            // it's safe to unwrap because of the above check for empty
+                            let opcode = self.fragments.first().unwrap().opcode();
+                            let size = self.fragments
+                                .iter()
+                                .fold(0, |len, frame| len + frame.payload().len());
+                            let mut compressed = Vec::with_capacity(size);
+                            let mut decompressed = Vec::with_capacity(size * 2);
+                            for frag in replace(
+                                &mut self.fragments,
+                                Vec::with_capacity(self.settings.fragments_capacity),
+                            ) {
+                                compressed.extend(frag.into_data())
+                            }
+
+                            compressed.extend(&[0, 0, 255, 255]);
+                            self.dec.decompress(&compressed, &mut decompressed)?;
+                            frame = Frame::message(decompressed, opcode, true);
+                        }
+                    } else {
+                        let mut decompressed = Vec::with_capacity(frame.payload().len() * 2);
+                        frame.payload_mut().extend(&[0, 0, 255, 255]);
+
+                        self.dec.decompress(frame.payload(), &mut decompressed)?;
+
+                        *frame.payload_mut() = decompressed;
+                    }
+
+                    if self.decompress_reset {
+                        self.dec.reset()?
+                    }
+                }
+            }
+        }
+        Ok(Some(frame))
+    }
 }
 
 impl<H: Handler> Handler for DeflateHandler<H> {
@@ -150,36 +364,59 @@ impl<H: Handler> Handler for DeflateHandler<H> {
         } else {
             req_ext.push_str("; client_max_window_bits")
         }
-        if self.settings.request_no_context_takeover {
+        if self.settings.request_no_context_takeover || self.settings.server_no_context_takeover {
             req_ext.push_str("; server_no_context_takeover")
         }
+        if self.settings.client_no_context_takeover {
+            self.compress_reset = true;
+            req_ext.push_str("; client_no_context_takeover")
+        }
         req.add_extension(&req_ext);
         Ok(req)
     }
 
+    #[inline]
+    fn on_handshake_request(&mut self, req: &Request) {
+        self.inner.on_handshake_request(req)
+    }
+
     fn on_request(&mut self, req: &Request) -> Result<Response> {
         let mut res = self.inner.on_request(req)?;
 
-        'ext: for req_ext in req.extensions()?
+        let offers = match req.extensions() {
+            Ok(offers) => offers,
+            // a broken header is no reason to fail the handshake, only to go without compression
+            Err(_) => return self.decline(res),
+        };
+
+        // Offers are listed in order of preference, an offer that can't be accepted is skipped
+        // in favor of the next one, and only the accepted one changes how we compress.
+        'ext: for req_ext in offers
             .iter()
-            .filter(|&&ext| ext.contains("permessage-deflate"))
+            .filter(|&&ext| ext.split(';').next().map(str::trim) == Some("permessage-deflate"))
         {
             let mut res_ext = String::with_capacity(req_ext.len());
             let mut s_takeover = false;
             let mut c_takeover = false;
             let mut s_max = false;
             let mut c_max = false;
+            let mut compress_reset = false;
+            let mut decompress_reset = false;
+            let mut com_bits = None;
+            let mut dec_bits = None;
 
             for param in req_ext.split(';') {
                 match param.trim() {
                     "permessage-deflate" => res_ext.push_str("permessage-deflate"),
                     "server_no_context_takeover" => {
                         if s_takeover {
-                            return self.decline(res);
+                            continue 'ext;
                         } else {
                             s_takeover = true;
-                            if self.settings.accept_no_context_takeover {
-                                self.compress_reset = true;
+                            if self.settings.accept_no_context_takeover
+                                || self.settings.server_no_context_takeover
+                            {
+                                compress_reset = true;
                                 res_ext.push_str("; server_no_context_takeover");
                             } else {
                                 continue 'ext;
@@ -188,59 +425,51 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                     }
                     "client_no_context_takeover" => {
                         if c_takeover {
-                            return self.decline(res);
+                            continue 'ext;
                         } else {
                             c_takeover = true;
-                            self.decompress_reset = true;
+                            decompress_reset = true;
                             res_ext.push_str("; client_no_context_takeover");
                         }
                     }
                     param if param.starts_with("server_max_window_bits") => {
                         if s_max {
-                            return self.decline(res);
+                            continue 'ext;
                         } else {
                             s_max = true;
-                            let mut param_iter = param.split('=');
-                            param_iter.next(); // we already know the name
-                            if let Some(window_bits_str) = param_iter.next() {
-                                if let Ok(window_bits) = window_bits_str.trim().parse() {
-                                    if window_bits >= 9 && window_bits <= 15 {
-                                        if window_bits < self.settings.max_window_bits as i8 {
-                                            self.com = Compressor::new(window_bits);
-                                            res_ext.push_str("; ");
-                                            res_ext.push_str(param)
-                                        }
-                                    } else {
-                                        return self.decline(res);
-                                    }
-                                } else {
-                                    return self.decline(res);
+                            match window_bits(param) {
+                                Some(Some(window_bits))
+                                    if window_bits < self.settings.max_window_bits as i8 =>
+                                {
+                                    com_bits = Some(window_bits);
+                                    res_ext.push_str(&format!(
+                                        "; server_max_window_bits={}",
+                                        window_bits
+                                    ))
                                 }
+                                Some(_) => (),
+                                None => continue 'ext,
                             }
                         }
                     }
                     param if param.starts_with("client_max_window_bits") => {
                         if c_max {
-                            return self.decline(res);
+                            continue 'ext;
                         } else {
                             c_max = true;
-                            let mut param_iter = param.split('=');
-                            param_iter.next(); // we already know the name
-                            if let Some(window_bits_str) = param_iter.next() {
-                                if let Ok(window_bits) = window_bits_str.trim().parse() {
-                                    if window_bits >= 9 && window_bits <= 15 {
-                                        if window_bits < self.settings.max_window_bits as i8 {
-                                            self.dec = Decompressor::new(window_bits);
-                                            res_ext.push_str("; ");
-                                            res_ext.push_str(param);
-                                            continue;
-                                        }
-                                    } else {
-                                        return self.decline(res);
-                                    }
-                                } else {
-                                    return self.decline(res);
+                            match window_bits(param) {
+                                Some(Some(window_bits))
+                                    if window_bits < self.settings.max_window_bits as i8 =>
+                                {
+                                    dec_bits = Some(window_bits);
+                                    res_ext.push_str(&format!(
+                                        "; client_max_window_bits={}",
+                                        window_bits
+                                    ));
+                                    continue;
                                 }
+                                Some(_) => (),
+                                None => continue 'ext,
                             }
                             res_ext.push_str("; ");
                             res_ext.push_str(&format!(
@@ -250,19 +479,26 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                         }
                     }
                     _ => {
-                        // decline all extension offers because we got a bad parameter
-                        return self.decline(res);
+                        // skip the offer because we got a bad parameter
+                        continue 'ext;
                     }
                 }
             }
-
             if !res_ext.contains("client_no_context_takeover")
-                && self.settings.request_no_context_takeover
+                && (self.settings.request_no_context_takeover
+                    || self.settings.client_no_context_takeover)
             {
-                self.decompress_reset = true;
+                decompress_reset = true;
                 res_ext.push_str("; client_no_context_takeover");
             }
 
+            if !res_ext.contains("server_no_context_takeover")
+                && self.settings.server_no_context_takeover
+            {
+                compress_reset = true;
+                res_ext.push_str("; server_no_context_takeover");
+            }
+
             if !res_ext.contains("server_max_window_bits") {
                 res_ext.push_str("; ");
                 res_ext.push_str(&format!(
@@ -275,6 +511,14 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                 continue;
             }
 
+            self.compress_reset = compress_reset;
+            self.decompress_reset = decompress_reset;
+            if let Some(window_bits) = com_bits {
+                self.com = self.settings.compressor(window_bits);
+            }
+            if let Some(window_bits) = dec_bits {
+                self.dec = self.settings.decompressor(window_bits);
+            }
             res.add_extension(&res_ext);
             return Ok(res);
         }
@@ -323,7 +567,9 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                             ));
                         } else {
                             c_takeover = true;
-                            if self.settings.accept_no_context_takeover {
+                            if self.settings.accept_no_context_takeover
+                                || self.settings.client_no_context_takeover
+                            {
                                 self.compress_reset = true;
                             } else {
                                 return Err(Error::new(
@@ -347,7 +593,7 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                                 if let Ok(window_bits) = window_bits_str.trim().parse() {
                                     if window_bits >= 9 && window_bits <= 15 {
                                         if window_bits as u8 != self.settings.max_window_bits {
-                                            self.dec = Decompressor::new(window_bits);
+                                            self.dec = self.settings.decompressor(window_bits);
                                         }
                                     } else {
                                         return Err(Error::new(
@@ -384,7 +630,7 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                                 if let Ok(window_bits) = window_bits_str.trim().parse() {
                                     if window_bits >= 9 && window_bits <= 15 {
                                         if window_bits as u8 != self.settings.max_window_bits {
-                                            self.com = Compressor::new(window_bits);
+                                            self.com = self.settings.compressor(window_bits);
                                         }
                                     } else {
                                         return Err(Error::new(
@@ -416,6 +662,13 @@ impl<H: Handler> Handler for DeflateHandler<H> {
                     }
                 }
             }
+
+            if self.settings.server_no_context_takeover && !s_takeover {
+                return Err(Error::new(
+                    Kind::Protocol,
+                    "The server requires context takeover.",
+                ));
+            }
         } else {
             self.pass = true
         }
@@ -423,86 +676,57 @@ impl<H: Handler> Handler for DeflateHandler<H> {
         Ok(())
     }
 
-    fn on_frame(&mut self, mut frame: Frame) -> Result<Option<Frame>> {
-        if !self.pass && !frame.is_control() {
-            if !self.fragments.is_empty() || frame.has_rsv1() {
-                frame.set_rsv1(false);
-
-                if !frame.is_final() {
-                    self.fragments.push(frame);
-                    return Ok(None);
-                } else {
-                    if frame.opcode() == OpCode::Continue {
-                        if self.fragments.is_empty() {
-                            return Err(Error::new(
-                                Kind::Protocol,
-                                "Unable to reconstruct fragmented message. No first frame.",
-                            ));
-                        } else {
-                            if !self.settings.fragments_grow
-                                && self.settings.fragments_capacity == self.fragments.len()
-                            {
-                                return Err(Error::new(Kind::Capacity, "Exceeded max fragments."));
-                            } else {
-                                self.fragments.push(frame);
-                            }
-
-                            // it's safe to unwrap because of the above check for empty
-                            let opcode = self.fragments.first().unwrap().opcode();
-                            let size = self.fragments
-                                .iter()
-                                .fold(0, |len, frame| len + frame.payload().len());
-                            let mut compressed = Vec::with_capacity(size);
-                            let mut decompressed = Vec::with_capacity(size * 2);
-                            for frag in replace(
-                                &mut self.fragments,
-                                Vec::with_capacity(self.settings.fragments_capacity),
-                            ) {
-                                compressed.extend(frag.into_data())
-                            }
-
-                            compressed.extend(&[0, 0, 255, 255]);
-                            self.dec.decompress(&compressed, &mut decompressed)?;
-                            frame = Frame::message(decompressed, opcode, true);
-                        }
-                    } else {
-                        let mut decompressed = Vec::with_capacity(frame.payload().len() * 2);
-                        frame.payload_mut().extend(&[0, 0, 255, 255]);
-
-                        self.dec.decompress(frame.payload(), &mut decompressed)?;
-
-                        *frame.payload_mut() = decompressed;
-                    }
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        match self.decompress(frame)? {
+            Some(frame) => self.inner.on_frame(frame),
+            None => Ok(None),
+        }
+    }
 
-                    if self.decompress_reset {
-                        self.dec.reset()?
-                    }
-                }
-            }
+    fn on_frame_with_state(
+        &mut self,
+        frame: Frame,
+        state: FragmentState,
+    ) -> Result<Option<Frame>> {
+        match self.decompress(frame)? {
+            Some(frame) => self.inner.on_frame_with_state(frame, state),
+            None => Ok(None),
         }
-        self.inner.on_frame(frame)
     }
 
     fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
         if let Some(mut frame) = self.inner.on_send_frame(frame)? {
-            if !self.pass && !frame.is_control() {
-                debug_assert!(
-                    frame.is_final(),
-                    "Received non-final frame from upstream handler!"
-                );
-                debug_assert!(
-                    frame.opcode() != OpCode::Continue,
-                    "Received continue frame from upstream handler!"
-                );
-
-                frame.set_rsv1(true);
+            // frames with reserved opcodes are sent as they are
+            if !self.pass && !frame.is_control() && !frame.opcode().is_reserved() {
                 let mut compressed = Vec::with_capacity(frame.payload().len());
                 self.com.compress(frame.payload(), &mut compressed)?;
-                let len = compressed.len();
-                compressed.truncate(len - 4);
+
+                // the flush marker is only dropped from the end of the message, an empty
+                // final fragment of a streamed message doesn't produce one
+                if frame.is_final() && compressed.ends_with(&[0, 0, 0xff, 0xff]) {
+                    let len = compressed.len();
+                    compressed.truncate(len - 4);
+                }
+
+                // only a message that fits in one frame can be sent uncompressed, the first
+                // frame of a fragmented message has already claimed compression
+                let single = frame.is_final() && frame.opcode() != OpCode::Continue;
+                let threshold = self.settings.skip_incompressible_threshold;
+                if let (true, Some(threshold)) = (single, threshold) {
+                    if compressed.len() + threshold > frame.payload().len() {
+                        trace!("Sending incompressible message uncompressed.");
+                        self.com.reset()?;
+                        return Ok(Some(frame));
+                    }
+                }
+
+                // only the first frame of a streamed message carries the compression bit
+                if frame.opcode() != OpCode::Continue {
+                    frame.set_rsv1(true);
+                }
                 *frame.payload_mut() = compressed;
 
-                if self.compress_reset {
+                if frame.is_final() && self.compress_reset {
                     self.com.reset()?
                 }
             }
@@ -512,6 +736,12 @@ impl<H: Handler> Handler for DeflateHandler<H> {
         }
     }
 
+    // Messages are compressed for each connection on its own.
+    #[inline]
+    fn share_broadcasts(&self) -> bool {
+        self.pass && self.inner.share_broadcasts()
+    }
+
     #[inline]
     fn on_shutdown(&mut self) {
         self.inner.on_shutdown()
@@ -527,11 +757,61 @@ impl<H: Handler> Handler for DeflateHandler<H> {
         self.inner.on_message(msg)
     }
 
+    #[inline]
+    fn on_message_ref(&mut self, msg: MessageRef) -> Result<bool> {
+        self.inner.on_message_ref(msg)
+    }
+
+    #[inline]
+    fn on_message_with_metadata(&mut self, msg: Message, metadata: MessageMetadata) -> Result<()> {
+        self.inner.on_message_with_metadata(msg, metadata)
+    }
+
+    #[inline]
+    fn on_message_start(&mut self, opcode: OpCode) -> Result<()> {
+        self.inner.on_message_start(opcode)
+    }
+
+    #[inline]
+    fn on_message_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.inner.on_message_chunk(data)
+    }
+
+    #[inline]
+    fn on_message_end(&mut self) -> Result<()> {
+        self.inner.on_message_end()
+    }
+
     #[inline]
     fn on_close(&mut self, code: CloseCode, reason: &str) {
         self.inner.on_close(code, reason)
     }
 
+    #[inline]
+    fn on_ping(&mut self, data: &[u8]) -> Result<PingAction> {
+        self.inner.on_ping(data)
+    }
+
+    #[inline]
+    fn on_pong(&mut self, data: &[u8]) -> Result<()> {
+        self.inner.on_pong(data)
+    }
+
+    #[inline]
+    fn on_buffer_drained(&mut self) {
+        self.inner.on_buffer_drained()
+    }
+
+    #[inline]
+    fn on_sent(&mut self, ack: AckToken) {
+        self.inner.on_sent(ack)
+    }
+
+    #[inline]
+    fn on_reconnect_attempt(&mut self, attempt: u32) {
+        self.inner.on_reconnect_attempt(attempt)
+    }
+
     #[inline]
     fn on_error(&mut self, err: Error) {
         self.inner.on_error(err)
@@ -548,7 +828,7 @@ impl<H: Handler> Handler for DeflateHandler<H> {
     }
 
     #[inline]
-    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
     fn upgrade_ssl_client(
         &mut self,
         stream: TcpStream,
@@ -558,8 +838,20 @@ impl<H: Handler> Handler for DeflateHandler<H> {
     }
 
     #[inline]
-    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
     fn upgrade_ssl_server(&mut self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
         self.inner.upgrade_ssl_server(stream)
     }
+
+    #[inline]
+    #[cfg(feature = "tls-rustls")]
+    fn build_client_tls_config(&mut self) -> Result<Arc<ClientConfig>> {
+        self.inner.build_client_tls_config()
+    }
+
+    #[inline]
+    #[cfg(feature = "tls-rustls")]
+    fn build_server_tls_config(&mut self) -> Result<Arc<ServerConfig>> {
+        self.inner.build_server_tls_config()
+    }
 }
@@ -6,4 +6,4 @@ extern crate libz_sys as ffi;
 mod context;
 mod extension;
 
-pub use self::extension::{DeflateBuilder, DeflateHandler, DeflateSettings};
+pub use self::extension::{DeflateBuilder, DeflateHandler, DeflateSettings, DeflateStrategy};
@@ -1,5 +1,7 @@
 use communication::Sender;
 use handler::Handler;
+use handshake::{Request, Response};
+use result::Error;
 
 /// A trait for creating new WebSocket handlers.
 pub trait Factory {
@@ -14,6 +16,14 @@ pub trait Factory {
         debug!("Factory received WebSocket shutdown request.");
     }
 
+    /// Called when the WebSocket starts draining with `ServerHandle::start_draining`, before any
+    /// new handshake is turned away. This is the place to ask the clients that are still
+    /// connected to move elsewhere, for example with a broadcast.
+    #[inline]
+    fn on_drain_start(&mut self) {
+        debug!("Factory received WebSocket drain request.");
+    }
+
     /// Called when a new connection is established for a client endpoint.
     /// This method can be used to differentiate a client aspect for a handler.
     ///
@@ -99,6 +109,72 @@ pub trait Factory {
     /// state that was not internally tracked by the handler.
     #[inline]
     fn connection_lost(&mut self, _: Self::Handler) {}
+
+    /// Called when a server connection arrives while `Settings::max_connections` connections
+    /// are already open, before it is turned away according to
+    /// `Settings::connection_limit_action`.
+    ///
+    /// The default implementation is a noop. You can use this to alert when the server is at
+    /// capacity.
+    #[inline]
+    fn on_connection_limit(&mut self) {}
+
+    /// Called when accepting a new connection fails, for example because the process ran out of
+    /// file descriptors. The server stops accepting connections for a moment and then tries
+    /// again, so such errors don't stop it from accepting connections for good.
+    ///
+    /// The default implementation is a noop. You can use this to alert when the server can't
+    /// accept connections.
+    #[inline]
+    fn on_accept_error(&mut self, _: &Error) {}
+
+    /// Called when a server connection receives a plain HTTP request instead of a WebSocket
+    /// handshake, such as a health check from a load balancer. The response is sent and the
+    /// connection is closed, without a handler ever seeing the request. A `Content-Length` and a
+    /// `Connection: close` header are added to the response unless it already has them.
+    ///
+    /// The default implementation answers with 426 Upgrade Required.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use ws::{Factory, Handler, Request, Response, Sender};
+    ///
+    /// struct Server;
+    ///
+    /// impl Handler for Server {}
+    ///
+    /// struct Healthy;
+    ///
+    /// impl Factory for Healthy {
+    ///     type Handler = Server;
+    ///
+    ///     fn connection_made(&mut self, _: Sender) -> Server {
+    ///         Server
+    ///     }
+    ///
+    ///     fn on_non_upgrade(&mut self, req: &Request) -> Response {
+    ///         if req.resource() == "/health" {
+    ///             Response::new(200, "OK", b"OK".to_vec())
+    ///         } else {
+    ///             Response::new(404, "Not Found", Vec::new())
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    #[inline]
+    fn on_non_upgrade(&mut self, _: &Request) -> Response {
+        let mut res = Response::new(
+            426,
+            "Upgrade Required",
+            b"This server only accepts WebSocket connections.".to_vec(),
+        );
+        res.headers_mut()
+            .push(("Upgrade".into(), b"websocket".to_vec()));
+        res.headers_mut()
+            .push(("Sec-WebSocket-Version".into(), b"13".to_vec()));
+        res
+    }
 }
 
 impl<F, H> Factory for F
@@ -1,17 +1,28 @@
+use std::convert::TryInto;
 use std::default::Default;
 use std::fmt;
-use std::io::{Cursor, ErrorKind, Read, Write};
+use std::io::{Cursor, Write};
 
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
 use rand;
 
 use protocol::{CloseCode, OpCode};
 use result::{Error, Kind, Result};
-use stream::TryReadBuf;
+
+// The payload of a control frame is at most 125 bytes, two of which hold the close code.
+const MAX_CLOSE_REASON_LEN: usize = 123;
 
 fn apply_mask(buf: &mut [u8], mask: &[u8; 4]) {
-    let iter = buf.iter_mut().zip(mask.iter().cycle());
-    for (byte, &key) in iter {
+    // eight bytes at a time, which keeps the mask lined up with the start of the payload
+    let word = u64::from_ne_bytes([
+        mask[0], mask[1], mask[2], mask[3], mask[0], mask[1], mask[2], mask[3],
+    ]);
+    let mut chunks = buf.chunks_exact_mut(8);
+    for chunk in &mut chunks {
+        let masked = u64::from_ne_bytes(chunk.try_into().unwrap()) ^ word;
+        chunk.copy_from_slice(&masked.to_ne_bytes());
+    }
+    for (byte, &key) in chunks.into_remainder().iter_mut().zip(mask.iter().cycle()) {
         *byte ^= key
     }
 }
@@ -171,6 +182,14 @@ impl Frame {
         self
     }
 
+    // Store the given mask for this frame, like `set_mask` does with a random one.
+    #[doc(hidden)]
+    #[inline]
+    pub fn set_mask_key(&mut self, mask: [u8; 4]) -> &mut Frame {
+        self.mask = Some(mask);
+        self
+    }
+
     // This method unmasks the payload and should only be called on frames that are actually
     // masked. In other words, those frames that have just been received from a client endpoint.
     #[doc(hidden)]
@@ -226,7 +245,8 @@ impl Frame {
         }
     }
 
-    /// Create a new Close control frame.
+    /// Create a new Close control frame. Reasons longer than the 123 bytes that fit in a control
+    /// frame after the close code are truncated.
     #[inline]
     pub fn close(code: CloseCode, reason: &str) -> Frame {
         let payload = if let CloseCode::Empty = code {
@@ -234,7 +254,11 @@ impl Frame {
         } else {
             let u: u16 = code.into();
             let raw = [(u >> 8) as u8, u as u8];
-            [&raw, reason.as_bytes()].concat()
+            let mut end = reason.len().min(MAX_CLOSE_REASON_LEN);
+            while !reason.is_char_boundary(end) {
+                end -= 1;
+            }
+            [&raw, &reason.as_bytes()[..end]].concat()
         };
 
         Frame {
@@ -244,21 +268,19 @@ impl Frame {
     }
 
     /// Parse the input stream into a frame.
+    ///
+    /// The header is read in place from the buffer, and the payload is copied out of it with a
+    /// single allocation.
     pub fn parse(cursor: &mut Cursor<Vec<u8>>, max_payload_length: u64) -> Result<Option<Frame>> {
-        let size = cursor.get_ref().len() as u64 - cursor.position();
         let initial = cursor.position();
         trace!("Position in buffer {}", initial);
+        let buf = &cursor.get_ref()[initial as usize..];
 
-        let mut head = [0u8; 2];
-        if cursor.read(&mut head)? != 2 {
-            cursor.set_position(initial);
+        if buf.len() < 2 {
             return Ok(None);
         }
-
-        trace!("Parsed headers {:?}", head);
-
-        let first = head[0];
-        let second = head[1];
+        let first = buf[0];
+        let second = buf[1];
         trace!("First: {:b}", first);
         trace!("Second: {:b}", second);
 
@@ -274,34 +296,19 @@ impl Frame {
         let masked = second & 0x80 != 0;
         trace!("Masked: {:?}", masked);
 
-        let mut header_length = 2;
-
-        let mut length = u64::from(second & 0x7F);
-
-        if let Some(length_nbytes) = match length {
-            126 => Some(2),
-            127 => Some(8),
-            _ => None,
-        } {
-            match cursor.read_uint::<BigEndian>(length_nbytes) {
-                Err(ref err) if err.kind() == ErrorKind::UnexpectedEof => {
-                    cursor.set_position(initial);
-                    return Ok(None);
-                }
-                Err(err) => {
-                    return Err(Error::from(err));
-                }
-                Ok(read) => {
-                    length = read;
-                }
-            };
-            header_length += length_nbytes as u64;
-        }
+        let (length, mut header_length) = match second & 0x7F {
+            126 if buf.len() < 4 => return Ok(None),
+            126 => (u64::from(BigEndian::read_u16(&buf[2..4])), 4),
+            127 if buf.len() < 10 => return Ok(None),
+            127 => (BigEndian::read_u64(&buf[2..10]), 10),
+            length => (u64::from(length), 2),
+        };
         trace!("Payload length: {}", length);
 
         if length > max_payload_length {
+            cursor.set_position(initial + header_length as u64);
             return Err(Error::new(
-                Kind::Protocol,
+                Kind::Capacity,
                 format!(
                     "Rejected frame with payload length exceeding defined max: {}.",
                     max_payload_length
@@ -310,33 +317,25 @@ impl Frame {
         }
 
         let mask = if masked {
-            let mut mask_bytes = [0u8; 4];
-            if cursor.read(&mut mask_bytes)? != 4 {
-                cursor.set_position(initial);
+            if buf.len() < header_length + 4 {
                 return Ok(None);
-            } else {
-                header_length += 4;
-                Some(mask_bytes)
             }
+            let mut mask_bytes = [0u8; 4];
+            mask_bytes.copy_from_slice(&buf[header_length..header_length + 4]);
+            header_length += 4;
+            Some(mask_bytes)
         } else {
             None
         };
 
-        match length.checked_add(header_length) {
-            Some(l) if size < l => {
-                cursor.set_position(initial);
-                return Ok(None);
-            }
-            Some(_) => (),
+        let end = match length.checked_add(header_length as u64) {
+            Some(l) if (buf.len() as u64) < l => return Ok(None),
+            Some(l) => l as usize,
             None => return Ok(None),
         };
 
-        let mut data = Vec::with_capacity(length as usize);
-        if length > 0 {
-            if let Some(read) = cursor.try_read_buf(&mut data)? {
-                debug_assert!(read == length as usize, "Read incorrect payload length!");
-            }
-        }
+        let data = buf[header_length..end].to_vec();
+        cursor.set_position(initial + end as u64);
 
         // Disallow bad opcode
         if let OpCode::Bad = opcode {
@@ -352,7 +351,7 @@ impl Frame {
                 return Err(Error::new(
                     Kind::Protocol,
                     format!(
-                        "Rejected WebSocket handshake.Received control frame with length: {}.",
+                        "Received control frame with payload length exceeding 125: {}.",
                         length
                     ),
                 ))
@@ -382,6 +381,18 @@ impl Frame {
 
     /// Write a frame out to a buffer
     pub fn format<W>(&mut self, w: &mut W) -> Result<()>
+    where
+        W: Write,
+    {
+        self.format_head(w)?;
+        w.write_all(&self.payload)?;
+        Ok(())
+    }
+
+    // Write out the header of the frame and mask the payload, leaving the payload to be written
+    // after the header by the caller.
+    #[doc(hidden)]
+    pub fn format_head<W>(&mut self, w: &mut W) -> Result<()>
     where
         W: Write,
     {
@@ -432,8 +443,6 @@ impl Frame {
             apply_mask(&mut self.payload, &mask);
             w.write_all(&mask)?;
         }
-
-        w.write_all(&self.payload)?;
         Ok(())
     }
 }
@@ -452,6 +461,88 @@ impl Default for Frame {
     }
 }
 
+/// Builds a frame with any combination of the FIN bit, the reserved bits and the opcode, which is
+/// useful for developing and testing extensions. The frame can be sent with `Sender::send_frame`,
+/// or returned from `Handler::on_frame` and `Handler::on_send_frame`. Nothing checks that the
+/// frame is valid, so it is up to the other endpoint to expect the combination of bits.
+///
+/// Unless set otherwise, the frame is a final binary frame without reserved bits or payload.
+///
+/// ```
+/// use ws::{FrameBuilder, OpCode};
+///
+/// let frame = FrameBuilder::new()
+///     .opcode(OpCode::Text)
+///     .rsv1(true)
+///     .payload(b"compressed".to_vec())
+///     .finish();
+/// assert!(frame.is_final());
+/// assert!(frame.has_rsv1());
+/// ```
+#[derive(Debug, Clone)]
+pub struct FrameBuilder {
+    frame: Frame,
+}
+
+impl FrameBuilder {
+    /// Create a new FrameBuilder.
+    pub fn new() -> FrameBuilder {
+        FrameBuilder {
+            frame: Frame {
+                opcode: OpCode::Binary,
+                ..Frame::default()
+            },
+        }
+    }
+
+    /// Set whether the frame is the final frame of its message.
+    pub fn fin(&mut self, fin: bool) -> &mut FrameBuilder {
+        self.frame.finished = fin;
+        self
+    }
+
+    /// Set the first reserved bit.
+    pub fn rsv1(&mut self, rsv1: bool) -> &mut FrameBuilder {
+        self.frame.rsv1 = rsv1;
+        self
+    }
+
+    /// Set the second reserved bit.
+    pub fn rsv2(&mut self, rsv2: bool) -> &mut FrameBuilder {
+        self.frame.rsv2 = rsv2;
+        self
+    }
+
+    /// Set the third reserved bit.
+    pub fn rsv3(&mut self, rsv3: bool) -> &mut FrameBuilder {
+        self.frame.rsv3 = rsv3;
+        self
+    }
+
+    /// Set the opcode, which may be one of the reserved opcodes.
+    pub fn opcode(&mut self, opcode: OpCode) -> &mut FrameBuilder {
+        self.frame.opcode = opcode;
+        self
+    }
+
+    /// Set the payload.
+    pub fn payload(&mut self, payload: Vec<u8>) -> &mut FrameBuilder {
+        self.frame.payload = payload;
+        self
+    }
+
+    /// Build the frame. The builder can be used again to build more frames like it.
+    pub fn finish(&self) -> Frame {
+        self.frame.clone()
+    }
+}
+
+impl Default for FrameBuilder {
+    fn default() -> FrameBuilder {
+        FrameBuilder::new()
+    }
+}
+
 impl fmt::Display for Frame {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -481,15 +572,173 @@ payload: 0x{}
     }
 }
 
+/// The progress of the fragmented message that is being received, see
+/// `Handler::on_frame_with_state`.
+///
+/// This describes the frames that have arrived from the other endpoint before the current one,
+/// as they were sent over the wire. Control frames don't change the state.
+#[derive(Debug, Default, Eq, PartialEq, Clone, Copy)]
+pub struct FragmentState {
+    opcode: Option<OpCode>,
+    frames: usize,
+    len: usize,
+    compressed: bool,
+}
+
+impl FragmentState {
+    /// Whether a fragmented message is in progress, in which case the current frame is either a
+    /// control frame or a continuation of that message.
+    #[inline]
+    pub fn in_message(&self) -> bool {
+        self.opcode.is_some()
+    }
+
+    /// The opcode of the first frame of the message in progress.
+    #[inline]
+    pub fn opcode(&self) -> Option<OpCode> {
+        self.opcode
+    }
+
+    /// The number of frames of the message in progress received so far.
+    #[inline]
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// The combined payload length of the frames of the message in progress.
+    #[inline]
+    pub fn payload_len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether the first frame of the message in progress has the RSV1 bit set, which the
+    /// permessage-deflate extension uses to mark compressed messages.
+    #[inline]
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+
+    /// Advance the state past a received frame.
+    pub fn advance(&mut self, frame: &Frame) {
+        if frame.is_control() {
+            return;
+        }
+
+        if frame.is_final() {
+            *self = FragmentState::default();
+        } else {
+            if self.opcode.is_none() {
+                self.opcode = Some(frame.opcode());
+                self.compressed = frame.has_rsv1();
+            }
+            self.frames += 1;
+            self.len += frame.payload().len();
+        }
+    }
+}
+
 mod test {
     #![allow(unused_imports, unused_variables, dead_code)]
     use super::*;
     use protocol::OpCode;
 
+    #[test]
+    fn build_frame() {
+        let mut builder = FrameBuilder::new();
+        let mut frame = builder
+            .fin(false)
+            .rsv2(true)
+            .opcode(OpCode::Text)
+            .payload(b"hi".to_vec())
+            .finish();
+        assert!(!frame.is_final() && frame.has_rsv2() && !frame.has_rsv1() && !frame.has_rsv3());
+        let mut buf = Vec::new();
+        frame.format(&mut buf).unwrap();
+        assert_eq!(buf, vec![0x21, 0x02, b'h', b'i']);
+
+        let mut frame = builder
+            .fin(true)
+            .rsv1(true)
+            .rsv3(true)
+            .opcode(OpCode::Reserved(3))
+            .finish();
+        let mut buf = Vec::new();
+        frame.format(&mut buf).unwrap();
+        // the second reserved bit is still set from the first frame
+        assert_eq!(buf[0], 0xf3);
+    }
+
+    #[test]
+    fn mask() {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        for len in 0..20 {
+            let mut buf = (0..len as u8).collect::<Vec<_>>();
+            apply_mask(&mut buf, &mask);
+            for (i, &byte) in buf.iter().enumerate() {
+                assert_eq!(byte, i as u8 ^ mask[i % 4]);
+            }
+        }
+    }
+
+    #[test]
+    fn parse_partial_frame() {
+        let mut frame = Frame::message(vec![7; 300], OpCode::Binary, true);
+        frame.set_mask_key([1, 2, 3, 4]);
+        let mut buf = vec![0x89, 0x00];
+        frame.format(&mut buf).unwrap();
+
+        // an empty ping ahead of the frame is parsed on its own
+        let mut cursor = Cursor::new(Vec::new());
+        for (i, &byte) in buf.iter().enumerate() {
+            cursor.get_mut().push(byte);
+            match Frame::parse(&mut cursor, u64::MAX).unwrap() {
+                Some(frame) if i == 1 => assert_eq!(frame.opcode(), OpCode::Ping),
+                Some(mut frame) => {
+                    assert_eq!(i, buf.len() - 1);
+                    assert!(frame.is_masked());
+                    assert_eq!(frame.remove_mask().payload(), &vec![7; 300][..]);
+                }
+                None => assert!(i != 1 && i != buf.len() - 1),
+            }
+        }
+        assert_eq!(cursor.position(), buf.len() as u64);
+    }
+
     #[test]
     fn display_frame() {
         let f = Frame::message("hi there".into(), OpCode::Text, true);
         let view = format!("{}", f);
         view.contains("payload:");
     }
+
+    #[test]
+    fn fragment_state() {
+        let mut state = FragmentState::default();
+        assert!(!state.in_message());
+
+        state.advance(&Frame::message(vec![0; 3], OpCode::Binary, false));
+        state.advance(&Frame::ping(vec![0; 8]));
+        state.advance(&Frame::message(vec![0; 2], OpCode::Continue, false));
+        assert!(state.in_message());
+        assert_eq!(state.opcode(), Some(OpCode::Binary));
+        assert_eq!(state.frames(), 2);
+        assert_eq!(state.payload_len(), 5);
+
+        state.advance(&Frame::message(vec![0; 4], OpCode::Continue, true));
+        assert_eq!(state, FragmentState::default());
+    }
+
+    #[test]
+    fn close_reason_truncated() {
+        let frame = Frame::close(CloseCode::Normal, &"a".repeat(200));
+        assert_eq!(frame.payload().len(), 125);
+
+        // the reason is cut before a character that doesn't fit whole
+        let reason = format!("{}\u{e9}", "a".repeat(122));
+        let frame = Frame::close(CloseCode::Normal, &reason);
+        assert_eq!(&frame.payload()[2..], "a".repeat(122).as_bytes());
+
+        let frame = Frame::close(CloseCode::Away, "bye");
+        assert_eq!(&frame.payload()[2..], b"bye");
+    }
 }
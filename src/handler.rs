@@ -1,19 +1,87 @@
+#[cfg(not(feature = "tracing"))]
 use log::Level::Error as ErrorLevel;
 #[cfg(feature = "nativetls")]
-use native_tls::{TlsConnector, TlsStream as SslStream};
+use native_tls::{Identity, Protocol, TlsConnector, TlsStream as SslStream};
 #[cfg(feature = "ssl")]
-use openssl::ssl::{SslConnector, SslMethod, SslStream};
+use openssl::error::ErrorStack;
+#[cfg(feature = "ssl")]
+use openssl::pkey::PKey;
+#[cfg(feature = "ssl")]
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVersion};
+#[cfg(feature = "ssl")]
+use openssl::x509::X509;
+#[cfg(feature = "tls-rustls")]
+use rustls::crypto::ring;
+#[cfg(feature = "tls-rustls")]
+use rustls::pki_types::ServerName;
+#[cfg(feature = "tls-rustls")]
+use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, ServerConnection,
+             SupportedProtocolVersion};
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+use std::cell::RefCell;
+#[cfg(feature = "tls-rustls")]
+use std::convert::TryFrom;
+#[cfg(feature = "tls-rustls")]
+use std::net::IpAddr;
+#[cfg(feature = "tls-rustls")]
+use std::sync::Arc;
 use url;
+#[cfg(feature = "tls-rustls")]
+use webpki_roots;
 
-use frame::Frame;
+use communication::AckToken;
+use frame::{FragmentState, Frame};
 use handshake::{Handshake, Request, Response};
-use message::Message;
-use protocol::CloseCode;
+use message::{Message, MessageMetadata, MessageRef};
+use protocol::{CloseCode, OpCode};
 use result::{Error, Kind, Result};
 use util::{Timeout, Token};
 
-#[cfg(any(feature = "ssl", feature = "nativetls"))]
+#[cfg(feature = "tls-rustls")]
+use stream::RustlsStream as SslStream;
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
 use util::TcpStream;
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+use super::{ClientSettings, Settings, TlsVersion};
+
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+thread_local! {
+    // the settings of the connection that is being encrypted
+    static TLS_SETTINGS: RefCell<Option<(Settings, ClientSettings)>> =
+        const { RefCell::new(None) };
+}
+
+/// Call `f` with the settings of a connection made available to the default implementations of
+/// the methods that encrypt it, which don't take the settings as a parameter.
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+pub fn with_tls_settings<F, T>(settings: &Settings, client_settings: &ClientSettings, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let current = Some((*settings, client_settings.clone()));
+    let outer = TLS_SETTINGS.with(|cell| cell.replace(current));
+    let res = f();
+    TLS_SETTINGS.with(|cell| cell.replace(outer));
+    res
+}
+
+// The settings of the connection that is being encrypted, or the default settings when the
+// methods are called outside of a connection.
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+fn tls_settings() -> (Settings, ClientSettings) {
+    TLS_SETTINGS.with(|cell| cell.borrow().clone()).unwrap_or_default()
+}
+
+/// How to answer a ping, as decided by `Handler::on_ping`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PingAction {
+    /// Answer with a pong carrying this data instead of the data of the ping.
+    Pong(Vec<u8>),
+    /// Don't answer the ping.
+    None,
+    /// Answer with a pong carrying the data of the ping.
+    Default,
+}
 
 /// The core trait of this library.
 /// Implementing this trait provides the business logic of the WebSocket application.
@@ -43,6 +111,52 @@ pub trait Handler {
         Ok(())
     }
 
+    /// Called on incoming messages before `on_message`, with a view of the message that is only
+    /// valid for the duration of the call. Return `true` if the message has been handled, in
+    /// which case the message is not passed to `on_message`.
+    ///
+    /// Handlers that only need to read message payloads can implement this method instead of
+    /// `on_message`, the default implementation leaves every message to `on_message`.
+    #[inline]
+    fn on_message_ref(&mut self, _: MessageRef) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Called on incoming messages along with how they arrived over the wire: the number of
+    /// frames, their combined payload length before decompression, and whether they were
+    /// compressed. This helps to diagnose endpoints that fragment messages in unexpected ways.
+    ///
+    /// By default this method calls `on_message`, which is the one to implement when the
+    /// metadata is not needed.
+    #[inline]
+    fn on_message_with_metadata(&mut self, msg: Message, _: MessageMetadata) -> Result<()> {
+        self.on_message(msg)
+    }
+
+    /// Called when the first frame of an incoming message arrives, with the opcode of the
+    /// message, if `Settings::stream_messages` is enabled. The payload follows through
+    /// `on_message_chunk` as the frames arrive, and `on_message_end` is called after the last
+    /// frame. Such messages are not passed to `on_message`.
+    #[inline]
+    fn on_message_start(&mut self, _: OpCode) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called with the payload of each frame of an incoming message, if
+    /// `Settings::stream_messages` is enabled. The chunks of a text message are valid UTF-8
+    /// together, but a chunk may end in the middle of a character.
+    #[inline]
+    fn on_message_chunk(&mut self, _: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called after the last chunk of an incoming message, if `Settings::stream_messages` is
+    /// enabled.
+    #[inline]
+    fn on_message_end(&mut self) -> Result<()> {
+        Ok(())
+    }
+
     /// Called any time this endpoint receives a close control frame.
     /// This may be because the other endpoint is initiating a closing handshake,
     /// or it may be the other endpoint confirming the handshake initiated by this endpoint.
@@ -50,6 +164,47 @@ pub trait Handler {
         debug!("Connection closing due to ({:?}) {}", code, reason);
     }
 
+    /// Called when a ping arrives from the other endpoint, to decide how to answer it.
+    ///
+    /// By default the ping is answered with a pong carrying the same data, as the protocol
+    /// requires. Suppressing the pong should be reserved for answering the ping some other way,
+    /// such as with an unsolicited pong sent later.
+    #[inline]
+    fn on_ping(&mut self, _: &[u8]) -> Result<PingAction> {
+        Ok(PingAction::Default)
+    }
+
+    /// Called when a pong arrives from the other endpoint, whether it answers a ping sent by this
+    /// endpoint or is unsolicited. The data can be correlated with the data of the pings sent with
+    /// `Sender::ping`, for instance to measure the round trip time.
+    #[inline]
+    fn on_pong(&mut self, _: &[u8]) -> Result<()> {
+        Ok(())
+    }
+
+    /// Called each time a write empties the output buffer of the connection, meaning that
+    /// everything sent over the connection so far has been handed to the socket. Together with `Sender::pending` and
+    /// `Sender::try_send`, this allows a producer to pause when the output piles up and to resume
+    /// once it has drained, instead of polling.
+    #[inline]
+    fn on_buffer_drained(&mut self) {}
+
+    /// Called once all of a message sent with `Sender::send_with_ack` has been written to the
+    /// socket, with the token that was returned for it. Messages are written in the order they
+    /// were sent, so their tokens are handed back in that order as well. Being written doesn't
+    /// mean the message has reached the other endpoint or its handler, only that it has left
+    /// this one.
+    #[inline]
+    fn on_sent(&mut self, _: AckToken) {}
+
+    /// Called before a lost client connection is reestablished, when automatic reconnection is
+    /// enabled in the `ClientSettings`. The attempt number starts at 1 and is reset once a
+    /// reconnection succeeds.
+    #[inline]
+    fn on_reconnect_attempt(&mut self, attempt: u32) {
+        debug!("Attempting to reconnect ({})", attempt);
+    }
+
     /// Called when an error occurs on the WebSocket.
     fn on_error(&mut self, err: Error) {
         // Ignore connection reset errors by default, but allow library clients to see them by
@@ -61,7 +216,13 @@ pub trait Handler {
         }
 
         error!("{:?}", err);
-        if !log_enabled!(ErrorLevel) {
+        #[cfg(not(feature = "tracing"))]
+        let logging = log_enabled!(ErrorLevel);
+        // without a subscriber, tracing events are passed on to the log crate
+        #[cfg(feature = "tracing")]
+        let logging =
+            enabled!(::tracing::Level::ERROR) || ::log::log_enabled!(::log::Level::Error);
+        if !logging {
             println!(
                 "Encountered an error: {}\nEnable a logger to see more information.",
                 err
@@ -71,6 +232,15 @@ pub trait Handler {
 
     // handshake events
 
+    /// Called when a WebSocket handshake request arrives, before it is answered. Unlike
+    /// `on_request`, this method only observes the request, which makes it a place for logging
+    /// and metrics that leaves the response to `on_request`. It is also called for requests that
+    /// the server rejects itself, such as those from disallowed origins.
+    ///
+    /// This method will not be called when the handler represents a client endpoint.
+    #[inline]
+    fn on_handshake_request(&mut self, _: &Request) {}
+
     /// A method for handling the low-level workings of the request portion of the WebSocket
     /// handshake.
     ///
@@ -81,6 +251,22 @@ pub trait Handler {
     /// the WebSocket protocol, and implementors should use the `Response::from_request` method and
     /// then modify the resulting response as necessary in order to maintain conformance.
     ///
+    /// To decide on the response without blocking the event loop, return `Response::pending`
+    /// and later pass the response to `Sender::complete_handshake`.
+    ///
+    /// To reject the handshake, return a response with a status other than 101. It is written
+    /// to the client with its body before the connection is closed, which is the only way to
+    /// tell a browser why the handshake failed. Returning an error instead answers with a bare
+    /// 400 or 500 response that only carries the description of the error.
+    ///
+    /// ```ignore
+    /// if req.header("authorization").is_none() {
+    ///     let mut res = Response::new(401, "Unauthorized", br#"{"error": "no token"}"#.to_vec());
+    ///     res.add_header("Content-Type", "application/json")?;
+    ///     return Ok(res);
+    /// }
+    /// ```
+    ///
     /// This method will not be called when the handler represents a client endpoint. Use
     /// `build_request` to provide an initial handshake request.
     ///
@@ -228,6 +414,20 @@ pub trait Handler {
         }
     }
 
+    /// A method for handling incoming frames along with the progress of the message they belong
+    /// to.
+    ///
+    /// The state tells whether a fragmented message is in progress, its opcode, and how many
+    /// frames and payload bytes have been received for it before this frame. This saves
+    /// extensions that work on whole messages from tracking the fin bits and opcodes themselves.
+    ///
+    /// By default this method calls `on_frame`, which is the one to implement when the state is
+    /// not needed.
+    #[inline]
+    fn on_frame_with_state(&mut self, frame: Frame, _: FragmentState) -> Result<Option<Frame>> {
+        self.on_frame(frame)
+    }
+
     /// A method for handling outgoing frames.
     ///
     /// This method provides very low-level access to the details of the WebSocket protocol. It may
@@ -240,7 +440,10 @@ pub trait Handler {
     ///
     /// For messages, this method will be called with a single complete, final frame before any
     /// fragmentation is performed. Automatic fragmentation will be performed on the returned
-    /// frame, if any, based on the `fragment_size` setting.
+    /// frame, if any, based on the `fragment_size` setting. Messages written with
+    /// `Sender::stream` are the exception, they are passed here one fragment at a time, starting
+    /// with a text or binary frame and followed by continuation frames until the final one.
+    /// Frames sent with `Sender::send_frame` are passed here as they were constructed.
     ///
     /// By default this method simply ensures that no reserved bits are set.
     #[inline]
@@ -257,6 +460,18 @@ pub trait Handler {
         }
     }
 
+    /// Whether the messages broadcast to every connection may be written to this connection as
+    /// they were encoded once for all of them, instead of being copied and encoded for this
+    /// connection alone. The frames of shared messages don't pass through `on_send_frame`, so
+    /// return false if this handler needs to see or change every outgoing frame. Client
+    /// connections always encode their own frames, which are masked.
+    ///
+    /// By default broadcast messages are shared.
+    #[inline]
+    fn share_broadcasts(&self) -> bool {
+        true
+    }
+
     // constructors
 
     /// A method for creating the initial handshake request for WebSocket clients.
@@ -283,7 +498,9 @@ pub trait Handler {
     /// A method for wrapping a client TcpStream with Ssl Authentication machinery
     ///
     /// Override this method to customize how the connection is encrypted. By default
-    /// this will use the Server Name Indication extension in conformance with RFC6455.
+    /// this will use the Server Name Indication extension in conformance with RFC6455. The host
+    /// of the url is replaced by `ClientSettings::sni_hostname` when it is set, and no TLS version
+    /// older than `Settings::min_tls_version` is negotiated.
     #[inline]
     #[cfg(feature = "ssl")]
     fn upgrade_ssl_client(
@@ -295,14 +512,38 @@ pub trait Handler {
             Kind::Protocol,
             format!("Unable to parse domain from {}. Needed for SSL.", url),
         ))?;
-        let connector = SslConnector::builder(SslMethod::tls())
-            .map_err(|e| {
-                Error::new(
-                    Kind::Internal,
-                    format!("Failed to upgrade client to SSL: {}", e),
-                )
-            })?
-            .build();
+        let failed = |e: ErrorStack| {
+            Error::new(
+                Kind::Internal,
+                format!("Failed to upgrade client to SSL: {}", e),
+            )
+        };
+        let mut builder = SslConnector::builder(SslMethod::tls()).map_err(failed)?;
+        let (settings, client_settings) = tls_settings();
+        let version = match settings.min_tls_version {
+            TlsVersion::Tls10 => SslVersion::TLS1,
+            TlsVersion::Tls11 => SslVersion::TLS1_1,
+            TlsVersion::Tls12 => SslVersion::TLS1_2,
+            TlsVersion::Tls13 => SslVersion::TLS1_3,
+        };
+        builder
+            .set_min_proto_version(Some(version))
+            .map_err(failed)?;
+        if let Some(ref identity) = client_settings.identity {
+            let mut chain = X509::stack_from_pem(&identity.cert_chain)
+                .map_err(failed)?
+                .into_iter();
+            let cert = chain.next().ok_or_else(|| {
+                Error::new(Kind::Internal, "The TLS identity has no certificate.")
+            })?;
+            builder.set_certificate(&cert).map_err(failed)?;
+            for cert in chain {
+                builder.add_extra_chain_cert(cert).map_err(failed)?;
+            }
+            let key = PKey::private_key_from_pem(&identity.key).map_err(failed)?;
+            builder.set_private_key(&key).map_err(failed)?;
+        }
+        let connector = builder.build();
         connector.connect(domain, stream).map_err(Error::from)
     }
 
@@ -318,7 +559,19 @@ pub trait Handler {
             format!("Unable to parse domain from {}. Needed for SSL.", url),
         ))?;
 
-        let connector = TlsConnector::new().map_err(|e| {
+        let (settings, client_settings) = tls_settings();
+        let version = match settings.min_tls_version {
+            TlsVersion::Tls10 => Protocol::Tlsv10,
+            TlsVersion::Tls11 => Protocol::Tlsv11,
+            TlsVersion::Tls12 => Protocol::Tlsv12,
+            TlsVersion::Tls13 => Protocol::Tlsv13,
+        };
+        let mut builder = TlsConnector::builder();
+        builder.min_protocol_version(Some(version));
+        if let Some(ref identity) = client_settings.identity {
+            builder.identity(Identity::from_pkcs8(&identity.cert_chain, &identity.key)?);
+        }
+        let connector = builder.build().map_err(|e| {
             Error::new(
                 Kind::Internal,
                 format!("Failed to upgrade client to SSL: {}", e),
@@ -327,6 +580,68 @@ pub trait Handler {
 
         connector.connect(domain, stream).map_err(Error::from)
     }
+
+    #[inline]
+    #[cfg(feature = "tls-rustls")]
+    fn upgrade_ssl_client(
+        &mut self,
+        stream: TcpStream,
+        url: &url::Url,
+    ) -> Result<SslStream<TcpStream>> {
+        let name = match url.host() {
+            Some(url::Host::Domain(domain)) => {
+                ServerName::try_from(domain.to_owned()).map_err(|e| {
+                    Error::new(
+                        Kind::Protocol,
+                        format!("Invalid server name in {}: {}", url, e),
+                    )
+                })?
+            }
+            Some(url::Host::Ipv4(ip)) => ServerName::from(IpAddr::V4(ip)),
+            Some(url::Host::Ipv6(ip)) => ServerName::from(IpAddr::V6(ip)),
+            None => {
+                return Err(Error::new(
+                    Kind::Protocol,
+                    format!("Unable to parse domain from {}. Needed for SSL.", url),
+                ))
+            }
+        };
+
+        let config = self.build_client_tls_config()?;
+        let conn = ClientConnection::new(config, name)?;
+        Ok(SslStream::new(conn, stream))
+    }
+
+    /// A method for building the rustls configuration used to encrypt client connections.
+    ///
+    /// Override this method to customize the trusted certificates. By default the Mozilla root
+    /// certificates are trusted, TLS 1.2 and 1.3 are supported unless `Settings::min_tls_version`
+    /// requires TLS 1.3, and the `ClientSettings::identity` is presented to servers that ask for
+    /// a client certificate.
+    #[inline]
+    #[cfg(feature = "tls-rustls")]
+    fn build_client_tls_config(&mut self) -> Result<Arc<ClientConfig>> {
+        let mut roots = RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        // rustls doesn't implement the versions before TLS 1.2
+        let (settings, client_settings) = tls_settings();
+        let versions: &[&SupportedProtocolVersion] = match settings.min_tls_version {
+            TlsVersion::Tls13 => &[&rustls::version::TLS13],
+            _ => rustls::DEFAULT_VERSIONS,
+        };
+        let builder = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_protocol_versions(versions)?
+            .with_root_certificates(roots);
+        let config = match client_settings.identity {
+            Some(ref identity) => {
+                let (chain, key) = identity.to_rustls()?;
+                builder.with_client_auth_cert(chain, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+
     /// A method for wrapping a server TcpStream with Ssl Authentication machinery
     ///
     /// Override this method to customize how the connection is encrypted. By default
@@ -336,6 +651,27 @@ pub trait Handler {
     fn upgrade_ssl_server(&mut self, _: TcpStream) -> Result<SslStream<TcpStream>> {
         unimplemented!()
     }
+
+    #[inline]
+    #[cfg(feature = "tls-rustls")]
+    fn upgrade_ssl_server(&mut self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        let config = self.build_server_tls_config()?;
+        let conn = ServerConnection::new(config)?;
+        Ok(SslStream::new(conn, stream))
+    }
+
+    /// A method for building the rustls configuration used to encrypt server connections.
+    ///
+    /// Override this method to provide the certificate chain and private key of the server. By
+    /// default no configuration is available and encrypted connections are refused.
+    #[inline]
+    #[cfg(feature = "tls-rustls")]
+    fn build_server_tls_config(&mut self) -> Result<Arc<ServerConfig>> {
+        Err(Error::new(
+            Kind::Internal,
+            "No TLS configuration has been provided for server connections.",
+        ))
+    }
 }
 
 impl<F> Handler for F
@@ -398,12 +734,7 @@ mod test {
         let url = url::Url::parse("wss://127.0.0.1:3012").unwrap();
         let req = Request::from_url(&url).unwrap();
         let res = Response::from_request(&req).unwrap();
-        h.on_open(Handshake {
-            request: req,
-            response: res,
-            peer_addr: None,
-            local_addr: None,
-        }).unwrap();
+        h.on_open(Handshake::new(req, res, None, None)).unwrap();
         h.on_message(message::Message::Text("testme".to_owned()))
             .unwrap();
         h.on_close(CloseCode::Normal, "");
@@ -420,4 +751,24 @@ mod test {
             .on_message(message::Message::Binary(vec![1, 2, 3]))
             .unwrap();
     }
+
+    #[test]
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+    fn tls_settings_scope() {
+        let settings = Settings {
+            min_tls_version: TlsVersion::Tls13,
+            ..Settings::default()
+        };
+        let client_settings = ClientSettings {
+            sni_hostname: Some("localhost".into()),
+            ..ClientSettings::default()
+        };
+        let (inner, inner_client) = with_tls_settings(&settings, &client_settings, tls_settings);
+        assert_eq!(inner.min_tls_version, TlsVersion::Tls13);
+        assert_eq!(inner_client.sni_hostname, Some("localhost".into()));
+
+        let (outer, outer_client) = tls_settings();
+        assert_eq!(outer.min_tls_version, TlsVersion::Tls12);
+        assert_eq!(outer_client.sni_hostname, None);
+    }
 }
@@ -1,6 +1,9 @@
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
 use std::fmt;
 use std::io::Write;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::str::from_utf8;
 
 use httparse;
@@ -9,13 +12,38 @@ use sha1::{self, Digest};
 use url;
 
 use result::{Error, Kind, Result};
+use super::{ProxyConfig, RandomSource};
 
 static WS_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
 static BASE64: &'static [u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
 const MAX_HEADERS: usize = 124;
 
+thread_local! {
+    // the random source of the connection whose handshake request is being built
+    static RANDOM_SOURCE: RefCell<Option<RandomSource>> = const { RefCell::new(None) };
+}
+
+/// Call `f` with the random source of a connection made available to `Request::from_url`, which
+/// doesn't take the settings as a parameter.
+pub fn with_random_source<F, T>(source: Option<&RandomSource>, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    let outer = RANDOM_SOURCE.with(|cell| cell.replace(source.cloned()));
+    let res = f();
+    RANDOM_SOURCE.with(|cell| cell.replace(outer));
+    res
+}
+
 fn generate_key() -> String {
-    let key: [u8; 16] = rand::random();
+    let key = RANDOM_SOURCE.with(|cell| match *cell.borrow() {
+        Some(ref source) => {
+            let mut key = [0; 16];
+            source.fill(&mut key);
+            key
+        }
+        None => rand::random(),
+    });
     encode_base64(&key)
 }
 
@@ -28,6 +56,81 @@ pub fn hash_key(key: &[u8]) -> String {
     encode_base64(&hasher.result())
 }
 
+// Build the request asking a proxy to open a tunnel to the host of the given url.
+pub fn proxy_request(url: &url::Url, proxy: &ProxyConfig) -> Result<Request> {
+    let authority = format!(
+        "{}:{}",
+        url.host_str().ok_or_else(|| Error::new(
+            Kind::Internal,
+            "No host passed for WebSocket connection.",
+        ))?,
+        url.port_or_known_default().unwrap_or(80)
+    );
+
+    let mut headers = vec![("Host".into(), authority.clone().into())];
+    if let Some((ref username, ref password)) = proxy.credentials {
+        let basic = encode_base64(format!("{}:{}", username, password).as_bytes());
+        headers.push((
+            "Proxy-Authorization".into(),
+            format!("Basic {}", basic).into(),
+        ))
+    }
+
+    Ok(Request {
+        path: authority,
+        method: "CONNECT".to_owned(),
+        headers,
+    })
+}
+
+// Check an origin such as `https://www.example.com` against an allowed origin pattern.
+pub fn origin_matches(pattern: &str, origin: &str) -> bool {
+    let pattern = pattern.trim().to_lowercase();
+    let origin = origin.trim().to_lowercase();
+
+    if pattern == "*" {
+        return true;
+    }
+
+    let (pattern_scheme, pattern_host) = split_origin(&pattern);
+    let (origin_scheme, origin_host) = split_origin(&origin);
+
+    if pattern_scheme.is_some() && pattern_scheme != origin_scheme {
+        return false;
+    }
+
+    // only compare ports if the pattern asks for one
+    let origin_host = if pattern_host.contains(':') {
+        origin_host
+    } else {
+        origin_host.split(':').next().unwrap_or("")
+    };
+
+    if pattern_host.starts_with("*.") {
+        origin_host.ends_with(&pattern_host[1..])
+    } else {
+        pattern_host == origin_host
+    }
+}
+
+fn split_origin(origin: &str) -> (Option<&str>, &str) {
+    if let Some(idx) = origin.find("://") {
+        (Some(&origin[..idx]), &origin[idx + 3..])
+    } else {
+        (None, origin)
+    }
+}
+
+// A 16 byte value encodes to 22 base64 characters followed by two padding characters.
+fn is_valid_key(key: &[u8]) -> bool {
+    key.len() == 24 && key.ends_with(b"==") && key[..22].iter().all(|c| BASE64.contains(c))
+}
+
+// The characters allowed in an HTTP header name (RFC 7230, section 3.2.6).
+fn is_token(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
 // This code is based on rustc_serialize base64 STANDARD
 fn encode_base64(data: &[u8]) -> String {
     let len = data.len();
@@ -70,6 +173,39 @@ fn encode_base64(data: &[u8]) -> String {
     String::from_utf8(encoded).unwrap()
 }
 
+/// Decode padded standard base64, returning `None` if the data isn't valid base64.
+pub fn decode_base64(data: &[u8]) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(4) {
+        return None;
+    }
+    let padding = data.iter().rev().take_while(|&&c| c == b'=').count();
+    if padding > 2 {
+        return None;
+    }
+
+    let mut decoded = Vec::with_capacity(data.len() / 4 * 3);
+    for chunk in data.chunks(4) {
+        let mut g24 = 0u32;
+        for &c in chunk {
+            let val = match c {
+                b'=' => 0,
+                _ => BASE64.iter().position(|&b| b == c)? as u32,
+            };
+            g24 = g24 << 6 | val;
+        }
+        decoded.push((g24 >> 16) as u8);
+        decoded.push((g24 >> 8) as u8);
+        decoded.push(g24 as u8);
+    }
+    // padding is only allowed at the very end
+    if data[..data.len() - padding].contains(&b'=') {
+        return None;
+    }
+    let len = decoded.len() - padding;
+    decoded.truncate(len);
+    Some(decoded)
+}
+
 /// A struct representing the two halves of the WebSocket handshake.
 #[derive(Debug)]
 pub struct Handshake {
@@ -82,9 +218,33 @@ pub struct Handshake {
     pub peer_addr: Option<SocketAddr>,
     /// The socket address of this endpoint.
     pub local_addr: Option<SocketAddr>,
+    // the parameters captured from the path of the request by the route of a `Router`
+    params: HashMap<String, String>,
+    // the reverse proxies whose forwarded headers are trusted by `client_addr`
+    trusted_proxies: Vec<IpAddr>,
+    // the details of the TLS session of an encrypted connection
+    tls: Option<TlsInfo>,
 }
 
 impl Handshake {
+    /// Create a handshake from its request and response, and the addresses of the endpoints.
+    pub fn new(
+        request: Request,
+        response: Response,
+        peer_addr: Option<SocketAddr>,
+        local_addr: Option<SocketAddr>,
+    ) -> Handshake {
+        Handshake {
+            request,
+            response,
+            peer_addr,
+            local_addr,
+            params: HashMap::new(),
+            trusted_proxies: Vec::new(),
+            tls: None,
+        }
+    }
+
     /// Get the IP address of the remote connection.
     ///
     /// This is the preferred method of obtaining the client's IP address.
@@ -107,6 +267,106 @@ impl Handshake {
             }
         }))
     }
+
+    /// Get the IP address of the client behind any trusted reverse proxies.
+    ///
+    /// Unlike `Handshake::remote_addr`, the request headers are only honored when the peer is
+    /// one of the proxies given to `Builder::with_trusted_proxies`, and
+    /// `Settings::trust_forwarded_for` is enabled on the server. The `X-Forwarded-For` header is
+    /// then read from the right, skipping the addresses of other trusted proxies, and the first
+    /// address that isn't one is the client. Requests without that header may name the client
+    /// with `X-Real-IP` instead. Otherwise this is the address of the peer.
+    pub fn client_addr(&self) -> Option<IpAddr> {
+        let peer = self.peer_addr?.ip();
+        if !self.trusted_proxies.contains(&peer) {
+            return Some(peer);
+        }
+
+        let mut client = peer;
+        if let Some(forwarded) = self.request.header("x-forwarded-for") {
+            if let Ok(forwarded) = from_utf8(forwarded) {
+                for addr in forwarded.rsplit(',').map(|addr| addr.trim().parse()) {
+                    match addr {
+                        Ok(addr) => client = addr,
+                        // an address we can't make sense of can't be trusted any further
+                        Err(_) => break,
+                    }
+                    if !self.trusted_proxies.contains(&client) {
+                        break;
+                    }
+                }
+            }
+        } else if let Some(real_ip) = self.request.header("x-real-ip") {
+            if let Some(addr) = from_utf8(real_ip).ok().and_then(|ip| ip.trim().parse().ok()) {
+                client = addr;
+            }
+        }
+        Some(client)
+    }
+
+    /// Get the negotiated TLS version and cipher suite of an encrypted connection. This is None
+    /// for connections that are not encrypted, and for those encrypted with `nativetls`, which
+    /// doesn't expose the session.
+    #[inline]
+    pub fn tls_info(&self) -> Option<&TlsInfo> {
+        self.tls.as_ref()
+    }
+
+    #[doc(hidden)]
+    pub fn set_tls_info(&mut self, tls: Option<TlsInfo>) {
+        self.tls = tls;
+    }
+
+    /// Get a parameter captured from the path of the request by the route of a `Router`. There
+    /// are no parameters for connections that were not routed.
+    #[inline]
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params.get(name).map(String::as_str)
+    }
+
+    #[doc(hidden)]
+    pub fn set_params(&mut self, params: HashMap<String, String>) {
+        self.params = params;
+    }
+
+    #[doc(hidden)]
+    pub fn set_trusted_proxies(&mut self, proxies: Vec<IpAddr>) {
+        self.trusted_proxies = proxies;
+    }
+
+    /// Get the subprotocol that was agreed upon in the handshake, if any.
+    pub fn negotiated_protocol(&self) -> Option<&str> {
+        self.response.protocol().ok().flatten()
+    }
+
+    /// Get the names of the extensions that were agreed upon in the handshake, such as
+    /// `permessage-deflate`. The parameters of the extensions are left out, they can be read
+    /// from `Response::extensions`.
+    pub fn negotiated_extensions(&self) -> Vec<&str> {
+        self.response
+            .extensions()
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|ext| ext.split(';').next())
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .collect()
+    }
+}
+
+/// The details of the TLS session of an encrypted connection, as negotiated in the TLS handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlsInfo {
+    /// The protocol version, such as `TLSv1.3`.
+    pub version: String,
+    /// The name of the cipher suite, in the form used by the TLS library. OpenSSL uses names such
+    /// as `ECDHE-RSA-AES256-GCM-SHA384`, while rustls uses the IANA names, such as
+    /// `TLS13_AES_256_GCM_SHA384`.
+    pub cipher: String,
+    /// The subject of the certificate presented by the other endpoint, such as
+    /// `CN=example.com, O=Example`. This is None if the other endpoint didn't present a
+    /// certificate, and always None with rustls, which doesn't parse certificates.
+    pub peer_subject: Option<String>,
 }
 
 /// The handshake request.
@@ -119,18 +379,20 @@ pub struct Request {
 
 impl Request {
     /// Get the value of the first instance of an HTTP header.
+    /// Header names are matched case-insensitively.
     pub fn header(&self, header: &str) -> Option<&Vec<u8>> {
         self.headers
             .iter()
-            .find(|&&(ref key, _)| key.to_lowercase() == header.to_lowercase())
+            .find(|&&(ref key, _)| key.eq_ignore_ascii_case(header))
             .map(|&(_, ref val)| val)
     }
 
     /// Edit the value of the first instance of an HTTP header.
+    /// Header names are matched case-insensitively.
     pub fn header_mut(&mut self, header: &str) -> Option<&mut Vec<u8>> {
         self.headers
             .iter_mut()
-            .find(|&&mut (ref key, _)| key.to_lowercase() == header.to_lowercase())
+            .find(|&&mut (ref key, _)| key.eq_ignore_ascii_case(header))
             .map(|&mut (_, ref mut val)| val)
     }
 
@@ -164,9 +426,17 @@ impl Request {
             .ok_or_else(|| Error::new(Kind::Protocol, "Unable to parse WebSocket key."))
     }
 
-    /// Get the hashed WebSocket key from this request.
+    /// Get the hashed WebSocket key from this request, ignoring surrounding whitespace. This
+    /// fails if the key is not the base64 encoding of 16 bytes.
     pub fn hashed_key(&self) -> Result<String> {
-        Ok(hash_key(self.key()?))
+        let key = self.key()?.trim_ascii();
+        if !is_valid_key(key) {
+            return Err(Error::new(
+                Kind::Protocol,
+                "The WebSocket key is not the base64 encoding of 16 bytes.",
+            ));
+        }
+        Ok(hash_key(key))
     }
 
     /// Get the WebSocket protocol version from the request (should be 13).
@@ -195,17 +465,39 @@ impl Request {
         &self.path
     }
 
-    /// Get the possible protocols for the WebSocket connection.
+    /// Get the parameters of the query string of the resource, in the order in which they appear.
+    /// Names and values are percent-decoded, and names that appear more than once are yielded
+    /// once for each of their values.
+    pub fn query_pairs(&self) -> impl Iterator<Item = (Cow<'_, str>, Cow<'_, str>)> {
+        let query = self.path.split_once('?').map_or("", |(_, query)| query);
+        url::form_urlencoded::parse(query.as_bytes())
+    }
+
+    /// Get the possible protocols for the WebSocket connection, in the order of preference of
+    /// the client. The protocols of all the `Sec-WebSocket-Protocol` headers are included.
     #[allow(dead_code)]
     pub fn protocols(&self) -> Result<Vec<&str>> {
-        if let Some(protos) = self.header("sec-websocket-protocol") {
-            Ok(from_utf8(protos)?
-                .split(',')
-                .map(|proto| proto.trim())
-                .collect())
-        } else {
-            Ok(Vec::new())
+        let mut protocols = Vec::new();
+        for (key, val) in &self.headers {
+            if key.eq_ignore_ascii_case("sec-websocket-protocol") {
+                protocols.extend(
+                    from_utf8(val)?
+                        .split(',')
+                        .map(|proto| proto.trim())
+                        .filter(|proto| !proto.is_empty()),
+                );
+            }
         }
+        Ok(protocols)
+    }
+
+    /// Select the first of the protocols offered by the client that is also supported by the
+    /// server. Protocol names are compared case-sensitively.
+    pub fn select_protocol<'a>(&self, supported: &[&'a str]) -> Result<Option<&'a str>> {
+        let protocols = self.protocols()?;
+        Ok(protocols
+            .into_iter()
+            .find_map(|offered| supported.iter().find(|&&proto| proto == offered).copied()))
     }
 
     /// Add a possible protocol to this request.
@@ -420,12 +712,13 @@ impl fmt::Display for Request {
 }
 
 /// The handshake response.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Response {
     status: u16,
     reason: String,
     headers: Vec<(String, Vec<u8>)>,
     body: Vec<u8>,
+    pending: bool,
 }
 
 impl Response {
@@ -441,44 +734,124 @@ impl Response {
             reason: reason.into(),
             headers: vec![("Content-Length".into(), body.len().to_string().into())],
             body,
+            pending: false,
+        }
+    }
+
+    /// Construct a response that defers the handshake, for when deciding whether to accept it
+    /// requires work that shouldn't block the event loop, such as validating a token with an
+    /// external service. Return this from `Handler::on_request`, and once the work is done, pass
+    /// the real response to `Sender::complete_handshake`. The connection is kept open meanwhile.
+    ///
+    /// Headers added to the pending response, such as the extensions negotiated by a wrapping
+    /// handler, are added to the completing response if it accepts the handshake.
+    pub fn pending() -> Response {
+        Response {
+            status: 101,
+            reason: "Switching Protocols".into(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            pending: true,
         }
     }
 
+    /// Whether this response defers the handshake.
+    #[inline]
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
     /// Get the response body.
     #[inline]
     pub fn body(&self) -> &[u8] {
         &self.body
     }
 
+    /// Replace the response body, updating the Content-Length header to match.
+    pub fn set_body(&mut self, body: Vec<u8>) {
+        let len = body.len().to_string().into_bytes();
+        match self.header_mut("content-length") {
+            Some(val) => *val = len,
+            None => self.headers.push(("Content-Length".into(), len)),
+        }
+        self.body = body;
+    }
+
     /// Get the value of the first instance of an HTTP header.
-    fn header(&self, header: &str) -> Option<&Vec<u8>> {
+    /// Header names are matched case-insensitively.
+    pub fn header(&self, header: &str) -> Option<&Vec<u8>> {
         self.headers
             .iter()
-            .find(|&&(ref key, _)| key.to_lowercase() == header.to_lowercase())
+            .find(|&&(ref key, _)| key.eq_ignore_ascii_case(header))
             .map(|&(_, ref val)| val)
     }
+
     /// Edit the value of the first instance of an HTTP header.
+    /// Header names are matched case-insensitively.
     pub fn header_mut(&mut self, header: &str) -> Option<&mut Vec<u8>> {
         self.headers
             .iter_mut()
-            .find(|&&mut (ref key, _)| key.to_lowercase() == header.to_lowercase())
+            .find(|&&mut (ref key, _)| key.eq_ignore_ascii_case(header))
             .map(|&mut (_, ref mut val)| val)
     }
 
-    /// Access the request headers.
+    /// Access the response headers.
     #[allow(dead_code)]
     #[inline]
     pub fn headers(&self) -> &Vec<(String, Vec<u8>)> {
         &self.headers
     }
 
-    /// Edit the request headers.
+    /// Edit the response headers.
     #[allow(dead_code)]
     #[inline]
     pub fn headers_mut(&mut self) -> &mut Vec<(String, Vec<u8>)> {
         &mut self.headers
     }
 
+    /// Add an HTTP header to the response, keeping any headers of the same name, as is needed
+    /// to set several cookies. This is how a handler customizes the response returned from
+    /// `on_request`:
+    ///
+    /// ```
+    /// use ws::{Handler, Request, Response, Result};
+    ///
+    /// struct Server;
+    ///
+    /// impl Handler for Server {
+    ///     fn on_request(&mut self, req: &Request) -> Result<Response> {
+    ///         let mut res = Response::from_request(req)?;
+    ///         res.add_header("Set-Cookie", "session=1234; HttpOnly; Secure")?;
+    ///         Ok(res)
+    ///     }
+    /// }
+    /// ```
+    ///
+    /// This fails if the name isn't a valid header name or the value contains a line break,
+    /// which would otherwise allow the value to inject headers of its own.
+    pub fn add_header<N, V>(&mut self, name: N, value: V) -> Result<()>
+    where
+        N: Into<String>,
+        V: Into<Vec<u8>>,
+    {
+        let name = name.into();
+        let value = value.into();
+        if name.is_empty() || !name.bytes().all(is_token) {
+            return Err(Error::new(
+                Kind::Internal,
+                format!("Invalid header name {:?}.", name),
+            ));
+        }
+        if value.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+            return Err(Error::new(
+                Kind::Internal,
+                format!("The value of the {} header contains a line break.", name),
+            ));
+        }
+        self.headers.push((name, value));
+        Ok(())
+    }
+
     /// Get the HTTP status code.
     #[allow(dead_code)]
     #[inline]
@@ -487,7 +860,6 @@ impl Response {
     }
 
     /// Set the HTTP status code.
-    #[allow(dead_code)]
     #[inline]
     pub fn set_status(&mut self, status: u16) {
         self.status = status
@@ -501,7 +873,6 @@ impl Response {
     }
 
     /// Set the HTTP status reason.
-    #[allow(dead_code)]
     #[inline]
     pub fn set_reason<R>(&mut self, reason: R)
     where
@@ -601,6 +972,7 @@ impl Response {
                     .map(|h| (h.name.into(), h.value.into()))
                     .collect(),
                 body: Vec::new(),
+                pending: false,
             }))
         } else {
             Ok(None)
@@ -609,7 +981,8 @@ impl Response {
 
     /// Construct a new WebSocket handshake HTTP response from a request.
     /// This will create a response that ignores protocols and extensions. Edit this response to
-    /// accept a protocol and extensions as necessary.
+    /// accept a protocol and extensions as necessary, or use `from_request_with_protocols` to
+    /// negotiate the protocol.
     pub fn from_request(req: &Request) -> Result<Response> {
         let res = Response {
             status: 101,
@@ -620,12 +993,42 @@ impl Response {
                 ("Upgrade".into(), "websocket".into()),
             ],
             body: Vec::new(),
+            pending: false,
         };
 
         debug!("Built response from request:\n{}", res);
         Ok(res)
     }
 
+    /// Construct a new WebSocket handshake HTTP response from a request, accepting the first of
+    /// the protocols offered by the client that is also in `supported`.
+    ///
+    /// If the client doesn't offer any of the supported protocols, the response doesn't accept a
+    /// protocol, unless `required` is true, in which case the handshake is failed with a 400
+    /// response.
+    pub fn from_request_with_protocols(
+        req: &Request,
+        supported: &[&str],
+        required: bool,
+    ) -> Result<Response> {
+        match req.select_protocol(supported)? {
+            Some(protocol) => {
+                let mut res = Response::from_request(req)?;
+                res.set_protocol(protocol);
+                Ok(res)
+            }
+            None if required => {
+                debug!("None of the supported protocols {:?} were offered.", supported);
+                Ok(Response::new(
+                    400,
+                    "Bad Request",
+                    b"No supported protocol was offered.".to_vec(),
+                ))
+            }
+            None => Response::from_request(req),
+        }
+    }
+
     /// Write a response out to a buffer
     pub fn format<W>(&self, w: &mut W) -> Result<()>
     where
@@ -668,6 +1071,63 @@ mod test {
     use std::net::SocketAddr;
     use std::str::FromStr;
 
+    #[test]
+    fn proxy_connect_request() {
+        let url = url::Url::parse("wss://example.com/chat").unwrap();
+        let proxy = ProxyConfig::new("proxy:8080").with_credentials("user", "pass");
+        let mut buf = Vec::new();
+        proxy_request(&url, &proxy).unwrap().format(&mut buf).unwrap();
+        assert_eq!(
+            from_utf8(&buf).unwrap(),
+            "CONNECT example.com:443 HTTP/1.1\r\n\
+             Host: example.com:443\r\n\
+             Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn origin_patterns() {
+        assert!(origin_matches("*", "https://anything.com"));
+        assert!(origin_matches("https://example.com", "https://example.com"));
+        assert!(origin_matches("example.com", "http://EXAMPLE.com:8080"));
+        assert!(origin_matches("*.example.com", "https://www.example.com"));
+        assert!(origin_matches("https://*.example.com", "https://a.b.example.com"));
+        assert!(origin_matches("example.com:8080", "http://example.com:8080"));
+
+        assert!(!origin_matches("https://example.com", "http://example.com"));
+        assert!(!origin_matches("*.example.com", "https://example.com"));
+        assert!(!origin_matches("*.example.com", "https://badexample.com"));
+        assert!(!origin_matches("example.com", "https://example.com.evil.com"));
+        assert!(!origin_matches("example.com:8080", "http://example.com:9090"));
+        assert!(!origin_matches("example.com", "null"));
+    }
+
+    #[test]
+    fn headers_case_insensitive() {
+        let mut buf = Vec::with_capacity(2048);
+        write!(
+            &mut buf,
+            "GET / HTTP/1.1\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Authorization: Bearer token\r\n\
+             X-Custom-Header: custom\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n"
+        ).unwrap();
+
+        let req = Request::parse(&buf).unwrap().unwrap();
+        assert_eq!(req.header("authorization").unwrap(), b"Bearer token");
+        assert_eq!(req.header("x-custom-header").unwrap(), b"custom");
+        assert_eq!(req.header("X-CUSTOM-HEADER").unwrap(), b"custom");
+        assert!(req.header("cookie").is_none());
+
+        let mut res = Response::from_request(&req).unwrap();
+        res.headers_mut().push(("Set-Cookie".into(), b"a=b".to_vec()));
+        assert_eq!(res.header("upgrade").unwrap(), b"websocket");
+        assert_eq!(res.header("set-cookie").unwrap(), b"a=b");
+    }
+
     #[test]
     fn remote_addr() {
         let mut buf = Vec::with_capacity(2048);
@@ -682,12 +1142,8 @@ mod test {
 
         let req = Request::parse(&buf).unwrap().unwrap();
         let res = Response::from_request(&req).unwrap();
-        let shake = Handshake {
-            request: req,
-            response: res,
-            peer_addr: Some(SocketAddr::from_str("127.0.0.1:8888").unwrap()),
-            local_addr: None,
-        };
+        let peer_addr = SocketAddr::from_str("127.0.0.1:8888").unwrap();
+        let shake = Handshake::new(req, res, Some(peer_addr), None);
         assert_eq!(shake.remote_addr().unwrap().unwrap(), "127.0.0.1");
     }
 
@@ -706,12 +1162,7 @@ mod test {
 
         let req = Request::parse(&buf).unwrap().unwrap();
         let res = Response::from_request(&req).unwrap();
-        let shake = Handshake {
-            request: req,
-            response: res,
-            peer_addr: None,
-            local_addr: None,
-        };
+        let shake = Handshake::new(req, res, None, None);
         assert_eq!(shake.remote_addr().unwrap().unwrap(), "192.168.1.1");
     }
 
@@ -729,12 +1180,185 @@ mod test {
             .unwrap();
         let req = Request::parse(&buf).unwrap().unwrap();
         let res = Response::from_request(&req).unwrap();
-        let shake = Handshake {
-            request: req,
-            response: res,
-            peer_addr: None,
-            local_addr: None,
-        };
+        let shake = Handshake::new(req, res, None, None);
         assert_eq!(shake.remote_addr().unwrap().unwrap(), "192.0.2.43");
     }
+
+    #[test]
+    fn client_addr_trusted_proxies() {
+        let shake = |peer: &str, header: &str| {
+            let mut buf = Vec::with_capacity(2048);
+            write!(
+                &mut buf,
+                "GET / HTTP/1.1\r\n\
+                 Connection: Upgrade\r\n\
+                 Upgrade: websocket\r\n\
+                 {}\r\n\
+                 Sec-WebSocket-Version: 13\r\n\
+                 Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n",
+                header
+            ).unwrap();
+            let req = Request::parse(&buf).unwrap().unwrap();
+            let res = Response::from_request(&req).unwrap();
+            let peer_addr = SocketAddr::from_str(peer).unwrap();
+            let mut shake = Handshake::new(req, res, Some(peer_addr), None);
+            let proxies = vec!["10.0.0.1".parse().unwrap(), "10.0.0.2".parse().unwrap()];
+            shake.set_trusted_proxies(proxies);
+            shake.client_addr().unwrap().to_string()
+        };
+
+        let forwarded = "X-Forwarded-For: 1.1.1.1, 192.168.1.1, 10.0.0.2";
+        assert_eq!(shake("10.0.0.1:80", forwarded), "192.168.1.1");
+        // headers from peers that aren't trusted are ignored
+        assert_eq!(shake("192.168.1.9:80", forwarded), "192.168.1.9");
+        assert_eq!(
+            shake("10.0.0.1:80", "X-Forwarded-For: 10.0.0.2, 10.0.0.1"),
+            "10.0.0.2"
+        );
+        assert_eq!(
+            shake("10.0.0.1:80", "X-Forwarded-For: 1.1.1.1, unknown, 10.0.0.2"),
+            "10.0.0.2"
+        );
+        assert_eq!(shake("10.0.0.1:80", "X-Real-IP: 2001:db8::17"), "2001:db8::17");
+        assert_eq!(shake("10.0.0.1:80", "X-Other: 1.1.1.1"), "10.0.0.1");
+    }
+
+    #[test]
+    fn query_pairs() {
+        let mut buf = Vec::with_capacity(2048);
+        write!(
+            &mut buf,
+            "GET /ws?room=a%20b&token=xyz&room=c+d HTTP/1.1\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n"
+        ).unwrap();
+
+        let req = Request::parse(&buf).unwrap().unwrap();
+        let pairs: Vec<(String, String)> = req.query_pairs()
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        assert_eq!(
+            pairs,
+            vec![
+                ("room".into(), "a b".into()),
+                ("token".into(), "xyz".into()),
+                ("room".into(), "c d".into()),
+            ]
+        );
+
+        let req = Request::from_url(&url::Url::parse("ws://127.0.0.1:3012/ws").unwrap()).unwrap();
+        assert_eq!(req.query_pairs().count(), 0);
+    }
+
+    #[test]
+    fn protocol_negotiation() {
+        let mut buf = Vec::with_capacity(2048);
+        write!(
+            &mut buf,
+            "GET / HTTP/1.1\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Protocol: chat.v2, ,chat.v1\r\n\
+             Sec-WebSocket-Protocol: json\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n"
+        ).unwrap();
+
+        let req = Request::parse(&buf).unwrap().unwrap();
+        assert_eq!(req.protocols().unwrap(), vec!["chat.v2", "chat.v1", "json"]);
+        // the preference of the client wins
+        assert_eq!(req.select_protocol(&["json", "chat.v1"]).unwrap(), Some("chat.v1"));
+        assert_eq!(req.select_protocol(&["Chat.v1"]).unwrap(), None);
+
+        let res = Response::from_request_with_protocols(&req, &["json"], true).unwrap();
+        assert_eq!(res.status(), 101);
+        assert_eq!(res.protocol().unwrap(), Some("json"));
+
+        let res = Response::from_request_with_protocols(&req, &["xml"], false).unwrap();
+        assert_eq!(res.status(), 101);
+        assert_eq!(res.protocol().unwrap(), None);
+
+        let res = Response::from_request_with_protocols(&req, &["xml"], true).unwrap();
+        assert_eq!(res.status(), 400);
+    }
+
+    #[test]
+    fn accept_key() {
+        // the example from RFC 6455
+        assert_eq!(
+            hash_key(b"dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let request = |key: &str| {
+            let mut buf = Vec::with_capacity(2048);
+            write!(
+                &mut buf,
+                "GET / HTTP/1.1\r\n\
+                 Connection: Upgrade\r\n\
+                 Upgrade: websocket\r\n\
+                 Sec-WebSocket-Version: 13\r\n\
+                 sec-websocket-key: {}\r\n\r\n",
+                key
+            ).unwrap();
+            Request::parse(&buf).unwrap().unwrap()
+        };
+
+        let res = Response::from_request(&request("dGhlIHNhbXBsZSBub25jZQ== \t")).unwrap();
+        assert_eq!(res.key().unwrap(), b"s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        assert!(Response::from_request(&request("dGhlIHNhbXBsZSBub25jZQ")).is_err());
+        assert!(Response::from_request(&request("dGhlIHNhbXBsZSBub25jZSE=")).is_err());
+        assert!(Response::from_request(&request("dGhlIHNhbXBsZSBub25j!Q==")).is_err());
+        assert!(Response::from_request(&request("")).is_err());
+    }
+
+    #[test]
+    fn negotiated() {
+        let mut buf = Vec::with_capacity(2048);
+        write!(
+            &mut buf,
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Protocol: chat.v1\r\n\
+             Sec-WebSocket-Extensions: permessage-deflate; client_max_window_bits=10, x-test\r\n\
+             Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"
+        ).unwrap();
+
+        let url = url::Url::parse("ws://127.0.0.1:3012").unwrap();
+        let mut handshake = Handshake::new(
+            Request::from_url(&url).unwrap(),
+            Response::parse(&buf).unwrap().unwrap(),
+            None,
+            None,
+        );
+        assert_eq!(handshake.negotiated_protocol(), Some("chat.v1"));
+        assert_eq!(
+            handshake.negotiated_extensions(),
+            vec!["permessage-deflate", "x-test"]
+        );
+
+        handshake.response = Response::new(101, "Switching Protocols", Vec::new());
+        assert_eq!(handshake.negotiated_protocol(), None);
+        assert!(handshake.negotiated_extensions().is_empty());
+    }
+
+    #[test]
+    fn key_from_random_source() {
+        let url = url::Url::parse("ws://127.0.0.1:3012").unwrap();
+        let source = RandomSource::new(|dest: &mut [u8]| {
+            for byte in dest {
+                *byte = 0xab;
+            }
+        });
+        let req = with_random_source(Some(&source), || Request::from_url(&url)).unwrap();
+        assert_eq!(req.key().unwrap(), b"q6urq6urq6urq6urq6urqw==");
+
+        // the source only applies within the call
+        let req = Request::from_url(&url).unwrap();
+        assert_ne!(req.key().unwrap(), b"q6urq6urq6urq6urq6urqw==");
+    }
 }
@@ -1,25 +1,36 @@
 use std::borrow::Borrow;
 use std::io::{Error as IoError, ErrorKind};
-use std::net::{SocketAddr, ToSocketAddrs};
-use std::time::Duration;
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::usize;
 
 use mio;
 use mio::tcp::{TcpListener, TcpStream};
-use mio::{Poll, PollOpt, Ready, Token};
+use net2::TcpBuilder;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
 use mio_extras;
+#[cfg(unix)]
+use mio_uds::UnixListener;
+#[cfg(unix)]
+use std::path::Path;
 
 use url::Url;
 
 #[cfg(feature = "native_tls")]
 use native_tls::Error as SslError;
 
-use super::Settings;
-use communication::{Command, Sender, Signal};
+use super::{ClientSettings, ConnectionLimitAction, OverflowPolicy, Settings};
+use auth::Authenticator;
+use communication::{Command, ConnectionCounts, ConnectionInfo, ConnectionState, Counters, Filter,
+                    Sender, Shared, Signal, Stats};
 use connection::Connection;
 use factory::Factory;
+use message::Message;
+use output::SharedFrames;
 use slab::Slab;
 use result::{Error, Kind, Result};
+use stream::Stream;
 
 
 const QUEUE: Token = Token(usize::MAX - 3);
@@ -27,6 +38,15 @@ const TIMER: Token = Token(usize::MAX - 4);
 pub const ALL: Token = Token(usize::MAX - 5);
 const SYSTEM: Token = Token(usize::MAX - 6);
 
+// Timeout event for the deadline of a graceful shutdown
+const SHUTDOWN: Token = Token(usize::MAX - 7);
+// Timeout event for reestablishing a lost client connection
+const RECONNECT: Token = Token(usize::MAX - 8);
+// Timeout event for accepting connections again after an accept error
+const ACCEPT: Token = Token(usize::MAX - 9);
+// Timeout event for the deadline of draining
+const DRAIN: Token = Token(usize::MAX - 10);
+
 type Conn<F> = Connection<<F as Factory>::Handler>;
 
 const MAX_EVENTS: usize = 1024;
@@ -34,13 +54,14 @@ const MESSAGES_PER_TICK: usize = 256;
 const TIMER_TICK_MILLIS: u64 = 100;
 const TIMER_WHEEL_SIZE: usize = 1024;
 const TIMER_CAPACITY: usize = 65_536;
+const ACCEPT_BACKOFF_MILLIS: u64 = 100;
 
 #[cfg(not(windows))]
 const CONNECTION_REFUSED: i32 = 111;
 #[cfg(windows)]
 const CONNECTION_REFUSED: i32 = 61;
 
-fn url_to_addrs(url: &Url) -> Result<Vec<SocketAddr>> {
+fn url_host(url: &Url) -> Result<&str> {
     let host = url.host_str();
     if host.is_none() || (url.scheme() != "ws" && url.scheme() != "wss") {
         return Err(Error::new(
@@ -48,119 +69,268 @@ fn url_to_addrs(url: &Url) -> Result<Vec<SocketAddr>> {
             format!("Not a valid websocket url: {}", url),
         ));
     }
-    let host = host.unwrap();
+    Ok(host.unwrap())
+}
+
+fn url_to_addrs(url: &Url) -> Result<Vec<SocketAddr>> {
+    let host = url_host(url)?;
 
     let port = url.port_or_known_default().unwrap_or(80);
-    let mut addrs = (&host[..], port)
+    // IPv6 hosts are written in brackets, which can't be resolved
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let mut addrs = (host, port)
         .to_socket_addrs()?
         .collect::<Vec<SocketAddr>>();
     addrs.dedup();
     Ok(addrs)
 }
 
+// The addresses to connect to for a url, which are those of the proxy if one is configured.
+fn connect_addrs(url: &Url, settings: &ClientSettings) -> Result<Vec<SocketAddr>> {
+    if let Some(ref proxy) = settings.proxy {
+        url_host(url)?;
+        let mut addrs = proxy
+            .addr
+            .to_socket_addrs()?
+            .collect::<Vec<SocketAddr>>();
+        addrs.dedup();
+        Ok(addrs)
+    } else {
+        url_to_addrs(url)
+    }
+}
+
+// Applies the socket options from the settings to a newly accepted or connected socket.
+fn configure_tcp(sock: &TcpStream, settings: &Settings) -> Result<()> {
+    if settings.tcp_nodelay {
+        sock.set_nodelay(true)?
+    }
+    if settings.tcp_keepalive.is_some() {
+        sock.set_keepalive(settings.tcp_keepalive)?
+    }
+    if let Some(size) = settings.tcp_send_buffer_size {
+        sock.set_send_buffer_size(size)?
+    }
+    if let Some(size) = settings.tcp_recv_buffer_size {
+        sock.set_recv_buffer_size(size)?
+    }
+    Ok(())
+}
+
 enum State {
     Active,
+    // Waiting for connections to close before shutting down
+    Draining,
     Inactive,
 }
 
 impl State {
     fn is_active(&self) -> bool {
         match *self {
-            State::Active => true,
+            State::Active | State::Draining => true,
             State::Inactive => false,
         }
     }
 }
 
+enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    fn evented(&self) -> &dyn Evented {
+        match *self {
+            Listener::Tcp(ref listener) => listener,
+            #[cfg(unix)]
+            Listener::Unix(ref listener) => listener,
+        }
+    }
+
+    fn accept(&self) -> ::std::io::Result<Option<Stream>> {
+        match *self {
+            Listener::Tcp(ref listener) => match listener.accept() {
+                Ok((sock, addr)) => {
+                    info!("Accepted a new tcp connection from {}.", addr);
+                    Ok(Some(Stream::tcp(sock)))
+                }
+                Err(ref err) if err.kind() == ErrorKind::WouldBlock => Ok(None),
+                Err(err) => Err(err),
+            },
+            #[cfg(unix)]
+            Listener::Unix(ref listener) => match listener.accept()? {
+                Some((sock, _)) => {
+                    info!("Accepted a new unix socket connection.");
+                    Ok(Some(Stream::unix(sock)))
+                }
+                None => Ok(None),
+            },
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Timeout {
     connection: Token,
     event: Token,
+    connection_id: u32,
 }
 
 pub struct Handler<F>
 where
     F: Factory,
 {
-    listener: Option<TcpListener>,
+    listeners: Vec<Listener>,
+    accept_paused: bool,
+    // whether new handshakes are turned away while the open connections finish
+    draining: bool,
+    // the longest time to drain, until it is over
+    drain_timeout: Option<Duration>,
     connections: Slab<Conn<F>>,
     factory: F,
     settings: Settings,
+    client_settings: ClientSettings,
     state: State,
     queue_tx: mio::channel::SyncSender<Command>,
     queue_rx: mio::channel::Receiver<Command>,
     timer: mio_extras::timer::Timer<Timeout>,
     next_connection_id: u32,
+    allowed_origins: Option<Arc<Vec<String>>>,
+    trusted_proxies: Option<Arc<Vec<IpAddr>>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    totals: Arc<Counters>,
+    // shared by every broadcaster, so that broadcasts leave its pending count
+    broadcast: Arc<Shared>,
 }
 
 impl<F> Handler<F>
 where
     F: Factory,
 {
-    pub fn new(factory: F, settings: Settings) -> Handler<F> {
+    pub fn new(
+        factory: F,
+        settings: Settings,
+        client_settings: ClientSettings,
+        allowed_origins: Option<Arc<Vec<String>>>,
+        trusted_proxies: Option<Arc<Vec<IpAddr>>>,
+        authenticator: Option<Arc<dyn Authenticator>>,
+    ) -> Handler<F> {
         let (tx, rx) = mio::channel::sync_channel(settings.max_connections * settings.queue_size);
         let timer = mio_extras::timer::Builder::default()
             .tick_duration(Duration::from_millis(TIMER_TICK_MILLIS))
             .num_slots(TIMER_WHEEL_SIZE)
             .capacity(TIMER_CAPACITY)
             .build();
+        let totals = Arc::new(Counters::default());
+        let broadcast = Shared::new(usize::MAX, settings.fragment_size, totals.clone());
+        // the broadcaster isn't a connection, so it isn't counted as one
+        broadcast.set_status(ConnectionState::Closed);
         Handler {
-            listener: None,
+            listeners: Vec::new(),
+            accept_paused: false,
+            draining: false,
+            drain_timeout: None,
             connections: Slab::with_capacity(settings.max_connections),
             factory,
             settings,
+            client_settings,
             state: State::Inactive,
             queue_tx: tx,
             queue_rx: rx,
             timer,
             next_connection_id: 0,
+            allowed_origins,
+            trusted_proxies,
+            authenticator,
+            totals,
+            broadcast: Arc::new(broadcast),
         }
     }
 
     pub fn sender(&self) -> Sender {
-        Sender::new(ALL, self.queue_tx.clone(), 0)
+        Sender::with_shared(ALL, self.queue_tx.clone(), 0, self.broadcast.clone())
     }
 
-    pub fn listen(&mut self, poll: &mut Poll, addr: &SocketAddr) -> Result<&mut Handler<F>> {
-        debug_assert!(
-            self.listener.is_none(),
-            "Attempted to listen for connections from two addresses on the same websocket."
-        );
+    pub fn stats(&self) -> Stats {
+        self.totals.stats()
+    }
+
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        self.totals.connection_counts()
+    }
 
-        let tcp = TcpListener::bind(addr)?;
-        // TODO: consider net2 in order to set reuse_addr
-        poll.register(&tcp, ALL, Ready::readable(), PollOpt::level())?;
-        self.listener = Some(tcp);
+    // Every listener is registered with the ALL token, a readable event accepts from each of
+    // them in turn. The listeners are registered once the handler starts, with the poll it runs
+    // on.
+    pub fn listen(&mut self, addr: &SocketAddr) -> Result<&mut Handler<F>> {
+        let builder = match *addr {
+            SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+            SocketAddr::V6(_) => {
+                let builder = TcpBuilder::new_v6()?;
+                builder.only_v6(self.settings.ipv6_only)?;
+                builder
+            }
+        };
+        // like mio, don't reuse addresses on windows where it has different semantics
+        #[cfg(unix)]
+        builder.reuse_address(true)?;
+        let tcp = TcpListener::from_std(builder.bind(addr)?.listen(1024)?)?;
+        self.listeners.push(Listener::Tcp(tcp));
+        Ok(self)
+    }
+
+    #[cfg(unix)]
+    pub fn listen_unix(&mut self, path: &Path) -> Result<&mut Handler<F>> {
+        let uds = UnixListener::bind(path)?;
+        self.listeners.push(Listener::Unix(uds));
         Ok(self)
     }
 
     pub fn local_addr(&self) -> ::std::io::Result<SocketAddr> {
-        if let Some(ref listener) = self.listener {
-            listener.local_addr()
-        } else {
-            Err(IoError::new(ErrorKind::NotFound, "Not a listening socket"))
-        }
+        self.local_addrs()?
+            .into_iter()
+            .next()
+            .ok_or_else(|| IoError::new(ErrorKind::NotFound, "Not a listening socket"))
+    }
+
+    pub fn local_addrs(&self) -> ::std::io::Result<Vec<SocketAddr>> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| match *listener {
+                Listener::Tcp(ref listener) => Some(listener.local_addr()),
+                #[cfg(unix)]
+                Listener::Unix(_) => None,
+            })
+            .collect()
     }
 
-    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
     pub fn connect(&mut self, poll: &mut Poll, url: Url) -> Result<()> {
         let settings = self.settings;
 
         let (tok, addresses) = {
-            let (tok, entry, connection_id, handler) =
+            let (tok, entry, connection_id, shared, handler) =
                 if self.connections.len() < settings.max_connections {
                     let entry = self.connections.vacant_entry();
                     let tok = Token(entry.key());
                     let connection_id = self.next_connection_id;
                     self.next_connection_id = self.next_connection_id.wrapping_add(1);
+                    let shared = Arc::new(Shared::new(
+                        settings.high_water_mark,
+                        settings.fragment_size,
+                        self.totals.clone(),
+                    ).with_queue_limit(settings.max_queued_messages, settings.overflow_policy));
                     (
                         tok,
                         entry,
                         connection_id,
-                        self.factory.client_connected(Sender::new(
+                        shared.clone(),
+                        self.factory.client_connected(Sender::with_shared(
                             tok,
                             self.queue_tx.clone(),
                             connection_id,
+                            shared,
                         )),
                     )
                 } else {
@@ -170,7 +340,7 @@ where
                     ));
                 };
 
-            let mut addresses = match url_to_addrs(&url) {
+            let mut addresses = match connect_addrs(&url, &self.client_settings) {
                 Ok(addresses) => addresses,
                 Err(err) => {
                     self.factory.connection_lost(handler);
@@ -181,11 +351,16 @@ where
             loop {
                 if let Some(addr) = addresses.pop() {
                     if let Ok(sock) = TcpStream::connect(&addr) {
-                        if settings.tcp_nodelay {
-                            sock.set_nodelay(true)?
-                        }
+                        configure_tcp(&sock, &settings)?;
                         addresses.push(addr); // Replace the first addr in case ssl fails and we fallback
-                        entry.insert(Connection::new(tok, sock, handler, settings, connection_id));
+                        entry.insert(Connection::new(
+                            tok,
+                            Stream::tcp(sock),
+                            handler,
+                            settings,
+                            connection_id,
+                            shared,
+                        ));
                         break;
                     }
                 } else {
@@ -200,13 +375,17 @@ where
             (tok, addresses)
         };
 
-        let will_encrypt = url.scheme() == "wss";
+        // tunneled connections are encrypted once the proxy has opened the tunnel
+        let will_encrypt = url.scheme() == "wss" && self.client_settings.proxy.is_none();
 
-        if let Err(error) = self.connections[tok.into()].as_client(url, addresses) {
+        if let Err(error) =
+            self.connections[tok.into()].as_client(url, addresses, self.client_settings.clone())
+        {
             let handler = self.connections.remove(tok.into()).consume();
             self.factory.connection_lost(handler);
             return Err(error);
         }
+        self.schedule_timers(tok);
 
         if will_encrypt {
             while let Err(ssl_error) = self.connections[tok.into()].encrypt() {
@@ -264,25 +443,32 @@ where
             })
     }
 
-    #[cfg(not(any(feature = "ssl", feature = "nativetls")))]
+    #[cfg(not(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls")))]
     pub fn connect(&mut self, poll: &mut Poll, url: Url) -> Result<()> {
         let settings = self.settings;
 
         let (tok, addresses) = {
-            let (tok, entry, connection_id, handler) =
+            let (tok, entry, connection_id, shared, handler) =
                 if self.connections.len() < settings.max_connections {
                     let entry = self.connections.vacant_entry();
                     let tok = Token(entry.key());
                     let connection_id = self.next_connection_id;
                     self.next_connection_id = self.next_connection_id.wrapping_add(1);
+                    let shared = Arc::new(Shared::new(
+                        settings.high_water_mark,
+                        settings.fragment_size,
+                        self.totals.clone(),
+                    ).with_queue_limit(settings.max_queued_messages, settings.overflow_policy));
                     (
                         tok,
                         entry,
                         connection_id,
-                        self.factory.client_connected(Sender::new(
+                        shared.clone(),
+                        self.factory.client_connected(Sender::with_shared(
                             tok,
                             self.queue_tx.clone(),
                             connection_id,
+                            shared,
                         )),
                     )
                 } else {
@@ -292,7 +478,7 @@ where
                     ));
                 };
 
-            let mut addresses = match url_to_addrs(&url) {
+            let mut addresses = match connect_addrs(&url, &self.client_settings) {
                 Ok(addresses) => addresses,
                 Err(err) => {
                     self.factory.connection_lost(handler);
@@ -303,10 +489,15 @@ where
             loop {
                 if let Some(addr) = addresses.pop() {
                     if let Ok(sock) = TcpStream::connect(&addr) {
-                        if settings.tcp_nodelay {
-                            sock.set_nodelay(true)?
-                        }
-                        entry.insert(Connection::new(tok, sock, handler, settings, connection_id));
+                        configure_tcp(&sock, &settings)?;
+                        entry.insert(Connection::new(
+                            tok,
+                            Stream::tcp(sock),
+                            handler,
+                            settings,
+                            connection_id,
+                            shared,
+                        ));
                         break;
                     }
                 } else {
@@ -331,11 +522,14 @@ where
             return Err(error);
         }
 
-        if let Err(error) = self.connections[tok.into()].as_client(url, addresses) {
+        if let Err(error) =
+            self.connections[tok.into()].as_client(url, addresses, self.client_settings.clone())
+        {
             let handler = self.connections.remove(tok.into()).consume();
             self.factory.connection_lost(handler);
             return Err(error);
         }
+        self.schedule_timers(tok);
 
         poll.register(
             self.connections[tok.into()].socket(),
@@ -354,39 +548,83 @@ where
             })
     }
 
-    #[cfg(any(feature = "ssl", feature = "nativetls"))]
-    pub fn accept(&mut self, poll: &mut Poll, sock: TcpStream) -> Result<()> {
+    // Check whether there is room for another server connection. A connection that doesn't fit
+    // is either refused with an error, or accepted to have its handshake rejected, in which case
+    // this returns true.
+    fn check_capacity(&mut self) -> Result<bool> {
+        let settings = self.settings;
+        if self.connections.len() < settings.max_connections {
+            return Ok(false);
+        }
+
+        warn!(
+            "Reached the limit of {} connections.",
+            settings.max_connections
+        );
+        self.factory.on_connection_limit();
+        if settings.connection_limit_action == ConnectionLimitAction::Reject
+            && self.connections.len() < settings.max_connections.saturating_mul(2)
+        {
+            Ok(true)
+        } else {
+            Err(Error::new(
+                Kind::Capacity,
+                "Unable to add another connection to the event loop.",
+            ))
+        }
+    }
+
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+    pub fn accept(&mut self, poll: &mut Poll, sock: Stream) -> Result<()> {
+        let over_capacity = self.check_capacity()?;
         let factory = &mut self.factory;
         let settings = self.settings;
 
-        if settings.tcp_nodelay {
-            sock.set_nodelay(true)?
+        if let Some(tcp) = sock.tcp_stream() {
+            configure_tcp(tcp, &settings)?;
         }
 
         let tok = {
-            if self.connections.len() < settings.max_connections {
-                let entry = self.connections.vacant_entry();
-                let tok = Token(entry.key());
-                let connection_id = self.next_connection_id;
-                self.next_connection_id = self.next_connection_id.wrapping_add(1);
-                let handler = factory.server_connected(Sender::new(
-                    tok,
-                    self.queue_tx.clone(),
-                    connection_id,
-                ));
-                entry.insert(Connection::new(tok, sock, handler, settings, connection_id));
-                tok
-            } else {
-                return Err(Error::new(
-                    Kind::Capacity,
-                    "Unable to add another connection to the event loop.",
-                ));
-            }
+            let entry = self.connections.vacant_entry();
+            let tok = Token(entry.key());
+            let connection_id = self.next_connection_id;
+            self.next_connection_id = self.next_connection_id.wrapping_add(1);
+            let shared = Arc::new(Shared::new(
+                settings.high_water_mark,
+                settings.fragment_size,
+                self.totals.clone(),
+            ).with_queue_limit(settings.max_queued_messages, settings.overflow_policy));
+            let handler = factory.server_connected(Sender::with_shared(
+                tok,
+                self.queue_tx.clone(),
+                connection_id,
+                shared.clone(),
+            ));
+            entry.insert(Connection::new(
+                tok,
+                sock,
+                handler,
+                settings,
+                connection_id,
+                shared,
+            ));
+            tok
         };
 
-        let conn = &mut self.connections[tok.into()];
+        self.connections[tok.into()].as_server(
+            self.allowed_origins.clone(),
+            self.trusted_proxies.clone(),
+            self.authenticator.clone(),
+        )?;
+        self.schedule_timers(tok);
 
-        conn.as_server()?;
+        let conn = &mut self.connections[tok.into()];
+        if over_capacity {
+            conn.reject_over_capacity();
+        }
+        if self.draining {
+            conn.reject_draining();
+        }
         if settings.encrypt_server {
             conn.encrypt()?
         }
@@ -410,39 +648,57 @@ where
             })
     }
 
-    #[cfg(not(any(feature = "ssl", feature = "nativetls")))]
-    pub fn accept(&mut self, poll: &mut Poll, sock: TcpStream) -> Result<()> {
+    #[cfg(not(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls")))]
+    pub fn accept(&mut self, poll: &mut Poll, sock: Stream) -> Result<()> {
+        let over_capacity = self.check_capacity()?;
         let factory = &mut self.factory;
         let settings = self.settings;
 
-        if settings.tcp_nodelay {
-            sock.set_nodelay(true)?
+        if let Some(tcp) = sock.tcp_stream() {
+            configure_tcp(tcp, &settings)?;
         }
 
         let tok = {
-            if self.connections.len() < settings.max_connections {
-                let entry = self.connections.vacant_entry();
-                let tok = Token(entry.key());
-                let connection_id = self.next_connection_id;
-                self.next_connection_id = self.next_connection_id.wrapping_add(1);
-                let handler = factory.server_connected(Sender::new(
-                    tok,
-                    self.queue_tx.clone(),
-                    connection_id,
-                ));
-                entry.insert(Connection::new(tok, sock, handler, settings, connection_id));
-                tok
-            } else {
-                return Err(Error::new(
-                    Kind::Capacity,
-                    "Unable to add another connection to the event loop.",
-                ));
-            }
+            let entry = self.connections.vacant_entry();
+            let tok = Token(entry.key());
+            let connection_id = self.next_connection_id;
+            self.next_connection_id = self.next_connection_id.wrapping_add(1);
+            let shared = Arc::new(Shared::new(
+                settings.high_water_mark,
+                settings.fragment_size,
+                self.totals.clone(),
+            ).with_queue_limit(settings.max_queued_messages, settings.overflow_policy));
+            let handler = factory.server_connected(Sender::with_shared(
+                tok,
+                self.queue_tx.clone(),
+                connection_id,
+                shared.clone(),
+            ));
+            entry.insert(Connection::new(
+                tok,
+                sock,
+                handler,
+                settings,
+                connection_id,
+                shared,
+            ));
+            tok
         };
 
-        let conn = &mut self.connections[tok.into()];
+        self.connections[tok.into()].as_server(
+            self.allowed_origins.clone(),
+            self.trusted_proxies.clone(),
+            self.authenticator.clone(),
+        )?;
+        self.schedule_timers(tok);
 
-        conn.as_server()?;
+        let conn = &mut self.connections[tok.into()];
+        if over_capacity {
+            conn.reject_over_capacity();
+        }
+        if self.draining {
+            conn.reject_draining();
+        }
         if settings.encrypt_server {
             return Err(Error::new(
                 Kind::Protocol,
@@ -471,6 +727,7 @@ where
 
     pub fn run(&mut self, poll: &mut Poll) -> Result<()> {
         trace!("Running event loop");
+        self.register_listeners(poll)?;
         poll.register(
             &self.queue_rx,
             QUEUE,
@@ -482,18 +739,198 @@ where
         self.state = State::Active;
         let result = self.event_loop(poll);
         self.state = State::Inactive;
+        self.abandon_connections();
 
         result
+            .and(self.deregister_listeners(poll))
             .and(poll.deregister(&self.timer).map_err(Error::from))
             .and(poll.deregister(&self.queue_rx).map_err(Error::from))
     }
 
+    // Run a single connection over a stream provided by the user, as a client of the url if one is
+    // given and otherwise as a server.
+    pub fn run_stream(&mut self, poll: &mut Poll, sock: Stream, url: Option<Url>) -> Result<()> {
+        trace!("Running connection over a stream");
+        poll.register(
+            &self.queue_rx,
+            QUEUE,
+            Ready::readable(),
+            PollOpt::edge() | PollOpt::oneshot(),
+        )?;
+        poll.register(&self.timer, TIMER, Ready::readable(), PollOpt::edge())?;
+
+        self.state = State::Active;
+        let result = match url {
+            Some(url) => self.connect_stream(url, sock),
+            None => self.accept(poll, sock),
+        }.and_then(|()| self.stream_loop(poll));
+        self.state = State::Inactive;
+        self.abandon_connections();
+
+        result
+            .and(poll.deregister(&self.timer).map_err(Error::from))
+            .and(poll.deregister(&self.queue_rx).map_err(Error::from))
+    }
+
+    // The connections that are left once the event loop stops can't be reached by their senders.
+    fn abandon_connections(&self) {
+        for (_, conn) in self.connections.iter() {
+            conn.shared().set_status(ConnectionState::Closed);
+        }
+    }
+
+    fn register_listeners(&mut self, poll: &mut Poll) -> Result<()> {
+        self.accept_paused = false;
+        for listener in &self.listeners {
+            poll.register(listener.evented(), ALL, Ready::readable(), PollOpt::level())?;
+        }
+        Ok(())
+    }
+
+    fn deregister_listeners(&mut self, poll: &mut Poll) -> Result<()> {
+        if !self.accept_paused {
+            for listener in &self.listeners {
+                poll.deregister(listener.evented())?;
+            }
+        }
+        self.accept_paused = true;
+        Ok(())
+    }
+
+    // Start handling the events of a poll owned by the caller instead of running the event loop.
+    pub fn register(&mut self, poll: &mut Poll) -> Result<()> {
+        trace!("Registering with an external poll");
+        self.register_listeners(poll)?;
+        poll.register(
+            &self.queue_rx,
+            QUEUE,
+            Ready::readable(),
+            PollOpt::edge() | PollOpt::oneshot(),
+        )?;
+        poll.register(&self.timer, TIMER, Ready::readable(), PollOpt::edge())?;
+
+        self.state = State::Active;
+        Ok(())
+    }
+
+    // Handle the events of an external poll that belong to this handler, like a single iteration
+    // of the event loop. Returns whether the handler is still running.
+    pub fn ready(&mut self, poll: &mut Poll, events: &mio::Events) -> bool {
+        for evt in events.iter() {
+            if self.owns(evt.token()) {
+                self.handle_event(poll, evt.token(), evt.kind());
+            }
+        }
+
+        self.flush_due(poll);
+        self.check_count();
+        self.state.is_active()
+    }
+
+    // The longest an external poll may wait before `ready` has to be called again.
+    pub fn poll_timeout(&self) -> Option<Duration> {
+        self.next_flush()
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn deregister(&mut self, poll: &mut Poll) -> Result<()> {
+        trace!("Deregistering from an external poll");
+        self.state = State::Inactive;
+        self.abandon_connections();
+        self.deregister_listeners(poll)?;
+        for (_, conn) in self.connections.iter() {
+            // the socket of a connection that is waiting to reconnect isn't registered
+            let _ = poll.deregister(conn.socket());
+        }
+        poll.deregister(&self.timer)?;
+        poll.deregister(&self.queue_rx)?;
+        Ok(())
+    }
+
+    fn owns(&self, token: Token) -> bool {
+        token == QUEUE || token == TIMER || token == ALL || self.connections.contains(token.into())
+    }
+
+    fn connect_stream(&mut self, url: Url, sock: Stream) -> Result<()> {
+        let settings = self.settings;
+
+        let tok = {
+            let entry = self.connections.vacant_entry();
+            let tok = Token(entry.key());
+            let connection_id = self.next_connection_id;
+            self.next_connection_id = self.next_connection_id.wrapping_add(1);
+            let shared = Arc::new(Shared::new(
+                settings.high_water_mark,
+                settings.fragment_size,
+                self.totals.clone(),
+            ).with_queue_limit(settings.max_queued_messages, settings.overflow_policy));
+            let handler = self.factory.client_connected(Sender::with_shared(
+                tok,
+                self.queue_tx.clone(),
+                connection_id,
+                shared.clone(),
+            ));
+            entry.insert(Connection::new(
+                tok,
+                sock,
+                handler,
+                settings,
+                connection_id,
+                shared,
+            ));
+            tok
+        };
+
+        // there are no addresses to reconnect to
+        if let Err(error) =
+            self.connections[tok.into()].as_client(url, Vec::new(), self.client_settings.clone())
+        {
+            let handler = self.connections.remove(tok.into()).consume();
+            self.factory.connection_lost(handler);
+            return Err(error);
+        }
+        self.schedule_timers(tok);
+        Ok(())
+    }
+
+    // The stream can't be polled, so handle the queue and the timers without waiting, and then
+    // write to the stream, or block reading from it when there is nothing to write.
+    fn stream_loop(&mut self, poll: &mut Poll) -> Result<()> {
+        let mut events = mio::Events::with_capacity(MAX_EVENTS);
+        while self.state.is_active() {
+            poll.poll(&mut events, Some(Duration::from_millis(0)))?;
+            for evt in events.iter() {
+                self.handle_event(poll, evt.token(), evt.kind());
+            }
+
+            // nothing can be written while blocked on a read, so output isn't held back
+            let (token, ready) = match self.connections.iter_mut().next() {
+                Some((_, conn)) => {
+                    conn.flush();
+                    (conn.token(), conn.events())
+                }
+                None => break,
+            };
+            if ready.is_writable() {
+                self.handle_event(poll, token, Ready::writable());
+            } else if ready.is_readable() {
+                self.connections[token.into()].allow_read();
+                self.handle_event(poll, token, Ready::readable());
+            } else {
+                break;
+            }
+        }
+        Ok(())
+    }
+
     #[inline]
     fn event_loop(&mut self, poll: &mut Poll) -> Result<()> {
         let mut events = mio::Events::with_capacity(MAX_EVENTS);
         while self.state.is_active() {
             trace!("Waiting for event");
-            let nevents = match poll.poll(&mut events, None) {
+            let timeout = self.next_flush()
+                .map(|at| at.saturating_duration_since(Instant::now()));
+            let nevents = match poll.poll(&mut events, timeout) {
                 Ok(nevents) => nevents,
                 Err(err) => {
                     if err.kind() == ErrorKind::Interrupted {
@@ -516,19 +953,46 @@ where
                 self.handle_event(poll, evt.token(), evt.kind());
             }
 
+            self.flush_due(poll);
             self.check_count();
         }
         Ok(())
     }
 
+    // The earliest time at which a connection writes the output it holds back.
+    fn next_flush(&self) -> Option<Instant> {
+        self.settings.coalesce_window?;
+        self.connections
+            .iter()
+            .filter_map(|(_, conn)| conn.flush_at())
+            .min()
+    }
+
+    fn flush_due(&mut self, poll: &mut Poll) {
+        if self.settings.coalesce_window.is_none() {
+            return;
+        }
+        let now = Instant::now();
+        let due = self.connections
+            .iter()
+            .filter(|&(_, conn)| conn.flush_at().is_some_and(|at| at <= now))
+            .map(|(_, conn)| conn.token())
+            .collect::<Vec<Token>>();
+        for token in due {
+            let active = {
+                let conn = &mut self.connections[token.into()];
+                conn.flush();
+                conn.events().is_readable() || conn.events().is_writable()
+            };
+            self.check_active(poll, active, token);
+        }
+    }
+
     #[inline]
     fn schedule(&self, poll: &mut Poll, conn: &Conn<F>) -> Result<()> {
         trace!(
             "Scheduling connection to {} as {:?}",
-            conn.socket()
-                .peer_addr()
-                .map(|addr| addr.to_string())
-                .unwrap_or_else(|_| "UNKNOWN".into()),
+            conn.peer_addr(),
             conn.events()
         );
         poll.reregister(
@@ -540,6 +1004,22 @@ where
         Ok(())
     }
 
+    #[inline]
+    fn schedule_timers(&mut self, token: Token) {
+        let conn = &mut self.connections[token.into()];
+        let connection_id = conn.connection_id();
+        for (event, delay) in conn.timers() {
+            self.timer.set_timeout(
+                delay,
+                Timeout {
+                    connection: token,
+                    event,
+                    connection_id,
+                },
+            );
+        }
+    }
+
     fn shutdown(&mut self) {
         debug!("Received shutdown signal. WebSocket is attempting to shut down.");
         for (_, conn) in self.connections.iter_mut() {
@@ -552,13 +1032,83 @@ where
         }
     }
 
+    fn shutdown_graceful(&mut self, poll: &mut Poll, timeout: Duration) {
+        // shutting down ends draining
+        self.drain_timeout = None;
+        debug!("Received graceful shutdown signal. WebSocket is closing all connections.");
+        if !self.accept_paused {
+            for listener in &self.listeners {
+                if let Err(err) = poll.deregister(listener.evented()) {
+                    error!("Unable to stop listening for new connections: {}", err);
+                }
+            }
+        }
+        self.listeners.clear();
+
+        let tokens = self.connections
+            .iter()
+            .map(|(_, conn)| conn.token())
+            .collect::<Vec<Token>>();
+        for token in tokens {
+            let active = {
+                let conn = &mut self.connections[token.into()];
+                if conn.is_connecting() {
+                    conn.disconnect();
+                } else {
+                    conn.shutdown();
+                }
+                conn.events().is_readable() || conn.events().is_writable()
+            };
+            self.check_active(poll, active, token);
+        }
+
+        self.factory.on_shutdown();
+        self.timer.set_timeout(
+            timeout,
+            Timeout {
+                connection: SYSTEM,
+                event: SHUTDOWN,
+                connection_id: 0,
+            },
+        );
+        self.state = State::Draining;
+        if self.settings.panic_on_shutdown {
+            panic!("Panicking on shutdown as per setting.")
+        }
+    }
+
+    fn start_draining(&mut self, max: Duration) {
+        match self.state {
+            State::Active => (),
+            // already draining or shutting down
+            State::Draining | State::Inactive => return,
+        }
+        debug!("Received drain signal. WebSocket is turning away new connections.");
+        self.draining = true;
+        self.drain_timeout = Some(max);
+        self.factory.on_drain_start();
+        self.timer.set_timeout(
+            max,
+            Timeout {
+                connection: SYSTEM,
+                event: DRAIN,
+                connection_id: 0,
+            },
+        );
+        self.state = State::Draining;
+        self.check_count();
+    }
+
     #[inline]
     fn check_active(&mut self, poll: &mut Poll, active: bool, token: Token) {
         // NOTE: Closing state only applies after a ws connection was successfully
         // established. It's possible that we may go inactive while in a connecting
         // state if the handshake fails.
         if !active {
-            if let Ok(addr) = self.connections[token.into()].socket().peer_addr() {
+            if self.schedule_reconnect(token) {
+                return;
+            }
+            if let Ok(addr) = self.connections[token.into()].socket_addr() {
                 debug!("WebSocket connection to {} disconnected.", addr);
             } else {
                 trace!("WebSocket connection to token={:?} disconnected.", token);
@@ -566,6 +1116,7 @@ where
             let handler = self.connections.remove(token.into()).consume();
             self.factory.connection_lost(handler);
         } else {
+            self.schedule_timers(token);
             self.schedule(poll, &self.connections[token.into()])
                 .or_else(|err| {
                     // This will be an io error, so disconnect will already be called
@@ -578,9 +1129,110 @@ where
         }
     }
 
+    // Returns true if the connection is kept around to be reconnected.
+    fn schedule_reconnect(&mut self, token: Token) -> bool {
+        if let State::Active = self.state {
+            let conn = &mut self.connections[token.into()];
+            if conn.is_reconnecting() {
+                return true;
+            }
+            if let Some(delay) = conn.schedule_reconnect() {
+                debug!("Reconnecting client connection in {:?}.", delay);
+                self.timer.set_timeout(
+                    delay,
+                    Timeout {
+                        connection: token,
+                        event: RECONNECT,
+                        connection_id: conn.connection_id(),
+                    },
+                );
+                return true;
+            }
+        }
+        false
+    }
+
+    fn reconnect(&mut self, poll: &mut Poll, token: Token) {
+        let active = {
+            let client_settings = &self.client_settings;
+            let conn = &mut self.connections[token.into()];
+            let result = conn
+                .reconnect(|url| connect_addrs(url, client_settings))
+                .and_then(|_| {
+                    poll.register(
+                        conn.socket(),
+                        conn.token(),
+                        conn.events(),
+                        PollOpt::edge() | PollOpt::oneshot(),
+                    ).map_err(Error::from)
+                });
+            if let Err(err) = result {
+                conn.error(err);
+            }
+            conn.events().is_readable() || conn.events().is_writable()
+        };
+        self.check_active(poll, active, token);
+    }
+
+    // The socket is replaced when it is encrypted, so it has to be registered again.
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+    fn encrypt_tunnel(&mut self, poll: &mut Poll, token: Token) -> Result<()> {
+        let conn = &mut self.connections[token.into()];
+        poll.deregister(conn.socket())?;
+        conn.encrypt()?;
+        poll.register(
+            conn.socket(),
+            conn.token(),
+            conn.events(),
+            PollOpt::edge() | PollOpt::oneshot(),
+        )?;
+        Ok(())
+    }
+
+    #[cfg(not(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls")))]
+    fn encrypt_tunnel(&mut self, _: &mut Poll, _: Token) -> Result<()> {
+        Err(Error::new(
+            Kind::Protocol,
+            "The ssl feature is not enabled. Please enable it to use wss urls.",
+        ))
+    }
+
+    // Errors like running out of file descriptors leave the connection waiting on the listener,
+    // so stop listening for a moment rather than retrying right away.
+    fn pause_accept(&mut self, poll: &mut Poll) {
+        for listener in &self.listeners {
+            if let Err(err) = poll.deregister(listener.evented()) {
+                error!("Unable to stop listening for new connections: {}", err);
+            }
+        }
+        self.accept_paused = true;
+        self.timer.set_timeout(
+            Duration::from_millis(ACCEPT_BACKOFF_MILLIS),
+            Timeout {
+                connection: SYSTEM,
+                event: ACCEPT,
+                connection_id: 0,
+            },
+        );
+    }
+
+    fn resume_accept(&mut self, poll: &mut Poll) {
+        if !self.accept_paused {
+            return;
+        }
+        self.accept_paused = false;
+        for listener in &self.listeners {
+            if let Err(err) =
+                poll.register(listener.evented(), ALL, Ready::readable(), PollOpt::level())
+            {
+                error!("Unable to resume listening for new connections: {}", err);
+            }
+        }
+    }
+
     #[inline]
     fn is_client(&self) -> bool {
-        self.listener.is_none()
+        self.listeners.is_empty()
     }
 
     #[inline]
@@ -589,6 +1241,9 @@ where
         if self.connections.is_empty() {
             if !self.state.is_active() {
                 debug!("Shutting down websocket server.");
+            } else if let State::Draining = self.state {
+                debug!("All connections closed. Shutting down websocket.");
+                self.state = State::Inactive;
             } else if self.is_client() {
                 debug!("Shutting down websocket client.");
                 self.factory.on_shutdown();
@@ -597,6 +1252,15 @@ where
         }
     }
 
+    // Enters the span of a connection, so that the events logged while handling it carry the
+    // token and address of the connection.
+    #[cfg(feature = "tracing")]
+    fn enter_span(&self, token: Token) -> Option<::tracing::span::EnteredSpan> {
+        self.connections
+            .get(token.into())
+            .map(|conn| conn.span().clone().entered())
+    }
+
     fn handle_event(&mut self, poll: &mut Poll, token: Token, events: Ready) {
         match token {
             SYSTEM => {
@@ -605,24 +1269,31 @@ where
             }
             ALL => {
                 if events.is_readable() {
-                    match self.listener
-                        .as_ref()
-                        .expect("No listener provided for server websocket connections")
-                        .accept()
-                    {
-                        Ok((sock, addr)) => {
-                            info!("Accepted a new tcp connection from {}.", addr);
-                            if let Err(err) = self.accept(poll, sock) {
-                                error!("Unable to build WebSocket connection {:?}", err);
-                                if self.settings.panic_on_new_connection {
-                                    panic!("Unable to build WebSocket connection {:?}", err);
+                    debug_assert!(
+                        !self.listeners.is_empty(),
+                        "No listener provided for server websocket connections"
+                    );
+                    for i in 0..self.listeners.len() {
+                        match self.listeners[i].accept() {
+                            Ok(None) => (),
+                            Ok(Some(sock)) => {
+                                if let Err(err) = self.accept(poll, sock) {
+                                    error!("Unable to build WebSocket connection {:?}", err);
+                                    if self.settings.panic_on_new_connection {
+                                        panic!("Unable to build WebSocket connection {:?}", err);
+                                    }
                                 }
                             }
+                            Err(err) => {
+                                error!(
+                                    "Encountered an error {:?} while accepting tcp connection.",
+                                    err
+                                );
+                                self.factory.on_accept_error(&Error::from(err));
+                                self.pause_accept(poll);
+                                break;
+                            }
                         }
-                        Err(err) => error!(
-                            "Encountered an error {:?} while accepting tcp connection.",
-                            err
-                        ),
                     }
                 }
             }
@@ -644,6 +1315,8 @@ where
                 );
             }
             _ => {
+                #[cfg(feature = "tracing")]
+                let _span = self.enter_span(token);
                 let active = {
                     let conn_events = self.connections[token.into()].events();
 
@@ -684,6 +1357,14 @@ where
                         }
                     }
 
+                    if let Some(request) = self.connections[token.into()].take_non_upgrade() {
+                        let response = self.factory.on_non_upgrade(&request);
+                        if let Err(err) = self.connections[token.into()].respond_non_upgrade(response)
+                        {
+                            self.connections[token.into()].error(err)
+                        }
+                    }
+
                     let conn_events = self.connections[token.into()].events();
 
                     if (events & conn_events).is_writable() {
@@ -723,6 +1404,12 @@ where
                         }
                     }
 
+                    if self.connections[token.into()].take_tunnel_encryption() {
+                        if let Err(err) = self.encrypt_tunnel(poll, token) {
+                            self.connections[token.into()].error(err)
+                        }
+                    }
+
                     // connection events may have changed
                     self.connections[token.into()].events().is_readable()
                         || self.connections[token.into()].events().is_writable()
@@ -733,6 +1420,47 @@ where
         }
     }
 
+    // Send a message to every connection that the filter accepts. The message is encoded once
+    // and the same frames are written to each server connection, client connections and the
+    // connections that can't take shared frames get a copy of the message to encode themselves.
+    fn broadcast_message(
+        &mut self,
+        msg: Message,
+        policy: Option<OverflowPolicy>,
+        filter: Option<Filter>,
+        dead: &mut Vec<(Token, Error)>,
+    ) {
+        let shared = if self.connections.iter().any(|(_, conn)| conn.is_server()) {
+            match SharedFrames::encode(&msg, self.settings.fragment_size) {
+                Ok(frames) => Some(frames),
+                Err(err) => {
+                    error!("Unable to encode broadcast message: {}", err);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        for (_, conn) in self.connections.iter_mut() {
+            if filter.as_ref().is_some_and(|filter| !filter.matches(conn.token())) {
+                continue;
+            }
+            let sent = match shared {
+                Some(ref frames) => conn.send_shared(frames),
+                None => Ok(false),
+            };
+            let res = match sent {
+                Ok(true) => Ok(()),
+                Ok(false) => conn.send_message(msg.clone(), policy, None),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = res {
+                dead.push((conn.token(), err))
+            }
+        }
+    }
+
     fn handle_queue(&mut self, poll: &mut Poll, cmd: Command) {
         match cmd.token() {
             SYSTEM => {
@@ -742,14 +1470,41 @@ where
                 let mut dead = Vec::with_capacity(self.connections.len());
 
                 match cmd.into_signal() {
-                    Signal::Message(msg) => {
+                    Signal::Message(msg, policy, _) => {
                         trace!("Broadcasting message: {:?}", msg);
+                        self.broadcast.dequeue(msg.len());
+                        self.broadcast_message(msg, policy, None, &mut dead);
+                    }
+                    Signal::Broadcast(msg, policy, filter) => {
+                        trace!("Broadcasting message to selected connections: {:?}", msg);
+                        self.broadcast_message(msg, policy, Some(filter), &mut dead);
+                    }
+                    Signal::Fragment(frame) => {
+                        trace!("Broadcasting fragment: {:?}", frame);
+                        self.broadcast.dequeue(frame.payload().len());
+                        for (_, conn) in self.connections.iter_mut() {
+                            if let Err(err) = conn.send_fragment(frame.clone()) {
+                                dead.push((conn.token(), err))
+                            }
+                        }
+                    }
+                    Signal::Frame(frame) => {
+                        trace!("Broadcasting frame: {:?}", frame);
+                        self.broadcast.dequeue(frame.payload().len());
                         for (_, conn) in self.connections.iter_mut() {
-                            if let Err(err) = conn.send_message(msg.clone()) {
+                            if let Err(err) = conn.send_frame(frame.clone()) {
                                 dead.push((conn.token(), err))
                             }
                         }
                     }
+                    Signal::Handshake(_) => {
+                        error!("Unable to complete the handshake of every connection at once.");
+                        return;
+                    }
+                    Signal::Routed(_) => {
+                        error!("Unable to route a message to every connection at once.");
+                        return;
+                    }
                     Signal::Close(code, reason) => {
                         trace!("Broadcasting close: {:?} - {}", code, reason);
                         for (_, conn) in self.connections.iter_mut() {
@@ -758,6 +1513,23 @@ where
                             }
                         }
                     }
+                    Signal::CloseWhere(selector, code, reason) => {
+                        trace!("Closing selected connections: {:?} - {}", code, reason);
+                        for (_, conn) in self.connections.iter_mut() {
+                            let info = ConnectionInfo::new(
+                                conn.token(),
+                                conn.connection_id(),
+                                conn.remote_addr(),
+                                conn.shared(),
+                            );
+                            if !selector.matches(&info) {
+                                continue;
+                            }
+                            if let Err(err) = conn.send_close(code, reason.borrow()) {
+                                dead.push((conn.token(), err))
+                            }
+                        }
+                    }
                     Signal::Ping(data) => {
                         trace!("Broadcasting ping");
                         for (_, conn) in self.connections.iter_mut() {
@@ -784,6 +1556,14 @@ where
                         return;
                     }
                     Signal::Shutdown => self.shutdown(),
+                    Signal::ShutdownGraceful(timeout) => {
+                        self.shutdown_graceful(poll, timeout);
+                        return;
+                    }
+                    Signal::Drain(max) => {
+                        self.start_draining(max);
+                        return;
+                    }
                     Signal::Timeout {
                         delay,
                         token: event,
@@ -793,6 +1573,7 @@ where
                             Timeout {
                                 connection: ALL,
                                 event,
+                                connection_id: 0,
                             },
                         );
                         for (_, conn) in self.connections.iter_mut() {
@@ -819,12 +1600,15 @@ where
                 }
             }
             token => {
+                #[cfg(feature = "tracing")]
+                let _span = self.enter_span(token);
                 let connection_id = cmd.connection_id();
                 match cmd.into_signal() {
-                    Signal::Message(msg) => {
+                    Signal::Message(msg, policy, ack) => {
                         if let Some(conn) = self.connections.get_mut(token.into()) {
                             if conn.connection_id() == connection_id {
-                                if let Err(err) = conn.send_message(msg) {
+                                conn.shared().dequeue(msg.len());
+                                if let Err(err) = conn.send_message(msg, policy, ack) {
                                     conn.error(err)
                                 }
                             } else {
@@ -836,6 +1620,73 @@ where
                             )
                         }
                     }
+                    Signal::Routed(msg) => {
+                        if let Some(conn) = self.connections.get_mut(token.into()) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.send_message(msg, None, None) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while a message was waiting in the queue.")
+                            }
+                        } else {
+                            trace!(
+                                "Connection disconnected while a message was waiting in the queue."
+                            )
+                        }
+                    }
+                    Signal::Fragment(frame) => {
+                        if let Some(conn) = self.connections.get_mut(token.into()) {
+                            if conn.connection_id() == connection_id {
+                                conn.shared().dequeue(frame.payload().len());
+                                if let Err(err) = conn.send_fragment(frame) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!(
+                                    "Connection disconnected while a fragment was waiting in the queue."
+                                )
+                            }
+                        } else {
+                            trace!(
+                                "Connection disconnected while a fragment was waiting in the queue."
+                            )
+                        }
+                    }
+                    Signal::Frame(frame) => {
+                        if let Some(conn) = self.connections.get_mut(token.into()) {
+                            if conn.connection_id() == connection_id {
+                                conn.shared().dequeue(frame.payload().len());
+                                if let Err(err) = conn.send_frame(frame) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while a frame was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while a frame was waiting in the queue.")
+                        }
+                    }
+                    Signal::Handshake(response) => {
+                        if let Some(conn) = self.connections.get_mut(token.into()) {
+                            if conn.connection_id() == connection_id {
+                                if let Err(err) = conn.complete_handshake(response) {
+                                    conn.error(err)
+                                }
+                            } else {
+                                trace!("Connection disconnected while a handshake response was waiting in the queue.")
+                            }
+                        } else {
+                            trace!("Connection disconnected while a handshake response was waiting in the queue.")
+                        }
+                    }
+                    Signal::Broadcast(..) | Signal::CloseWhere(..) => {
+                        debug_assert!(
+                            false,
+                            "Broadcast sent to a single connection. This is a bug!"
+                        );
+                        error!("Broadcast sent to a single connection. This is a bug!");
+                    }
                     Signal::Close(code, reason) => {
                         if let Some(conn) = self.connections.get_mut(token.into()) {
                             if conn.connection_id() == connection_id {
@@ -889,6 +1740,14 @@ where
                         return;
                     }
                     Signal::Shutdown => self.shutdown(),
+                    Signal::ShutdownGraceful(timeout) => {
+                        self.shutdown_graceful(poll, timeout);
+                        return;
+                    }
+                    Signal::Drain(max) => {
+                        self.start_draining(max);
+                        return;
+                    }
                     Signal::Timeout {
                         delay,
                         token: event,
@@ -898,6 +1757,7 @@ where
                             Timeout {
                                 connection: token,
                                 event,
+                                connection_id,
                             },
                         );
                         if let Some(conn) = self.connections.get_mut(token.into()) {
@@ -924,9 +1784,49 @@ where
         }
     }
 
-    fn handle_timeout(&mut self, poll: &mut Poll, Timeout { connection, event }: Timeout) {
+    fn handle_timeout(
+        &mut self,
+        poll: &mut Poll,
+        Timeout {
+            connection,
+            event,
+            connection_id,
+        }: Timeout,
+    ) {
+        if connection == SYSTEM {
+            if event == SHUTDOWN {
+                debug!("Graceful shutdown timed out. Shutting down websocket.");
+                self.state = State::Inactive;
+            } else if event == ACCEPT {
+                debug!("Resuming accepting new connections.");
+                self.resume_accept(poll);
+            } else if event == DRAIN {
+                if let Some(max) = self.drain_timeout.take() {
+                    debug!("Draining timed out. Closing the remaining connections.");
+                    self.shutdown_graceful(poll, max);
+                }
+            }
+            return;
+        }
+
+        if event == RECONNECT {
+            match self.connections.get(connection.into()) {
+                Some(conn) if conn.connection_id() == connection_id && conn.is_reconnecting() => {
+                    self.reconnect(poll, connection)
+                }
+                _ => trace!("Connection closed while waiting to reconnect."),
+            }
+            return;
+        }
+
+        #[cfg(feature = "tracing")]
+        let _span = self.enter_span(connection);
         let active = {
             if let Some(conn) = self.connections.get_mut(connection.into()) {
+                if conn.connection_id() != connection_id {
+                    trace!("Connection disconnected while timeout was waiting.");
+                    return;
+                }
                 if let Err(err) = conn.timeout_triggered(event) {
                     conn.error(err)
                 }
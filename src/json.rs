@@ -0,0 +1,243 @@
+use std::marker::PhantomData;
+
+#[cfg(feature = "ssl")]
+use openssl::ssl::SslStream;
+#[cfg(feature = "nativetls")]
+use native_tls::TlsStream as SslStream;
+#[cfg(feature = "tls-rustls")]
+use rustls::{ClientConfig, ServerConfig};
+use serde::de::DeserializeOwned;
+#[cfg(feature = "tls-rustls")]
+use std::sync::Arc;
+use url;
+
+use communication::AckToken;
+use frame::{FragmentState, Frame};
+use handler::{Handler, PingAction};
+use handshake::{Handshake, Request, Response};
+use message::{Message, MessageRef};
+use protocol::{CloseCode, OpCode};
+use result::{Error, Result};
+#[cfg(feature = "tls-rustls")]
+use stream::RustlsStream as SslStream;
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+use util::TcpStream;
+use util::{Timeout, Token};
+
+/// A handler of the messages of a connection parsed as JSON of the type `T`, which is wrapped in
+/// a `JsonHandler` to receive them.
+pub trait OnJson<T: DeserializeOwned>: Handler {
+    /// Called on incoming messages with their payload parsed as JSON, whether they are text or
+    /// binary messages.
+    fn on_json(&mut self, value: T) -> Result<()>;
+}
+
+/// A WebSocket handler that parses incoming messages as JSON of the type `T` and passes the
+/// values to the `on_json` method of the child handler.
+///
+/// All other handler methods are proxied to the child handler, whose `on_message` and
+/// `on_message_with_metadata` are not called. A message that isn't JSON of the type `T` fails
+/// with a `Kind::Json` error, which closes the connection with `Settings::json_close_code`.
+///
+/// ```no_run
+/// #[macro_use]
+/// extern crate serde_derive;
+/// extern crate ws;
+///
+/// use ws::{Handler, JsonHandler, OnJson, Result, Sender};
+///
+/// #[derive(Deserialize)]
+/// struct Add {
+///     a: u32,
+///     b: u32,
+/// }
+///
+/// struct Server {
+///     out: Sender,
+/// }
+///
+/// impl Handler for Server {}
+///
+/// impl OnJson<Add> for Server {
+///     fn on_json(&mut self, add: Add) -> Result<()> {
+///         self.out.send((add.a + add.b).to_string())
+///     }
+/// }
+///
+/// fn main() {
+///     ws::listen("127.0.0.1:3012", |out| JsonHandler::new(Server { out })).unwrap();
+/// }
+/// ```
+pub struct JsonHandler<T, H> {
+    inner: H,
+    value: PhantomData<fn() -> T>,
+}
+
+impl<T, H> JsonHandler<T, H>
+where
+    T: DeserializeOwned,
+    H: OnJson<T>,
+{
+    /// Wrap a child handler to receive the messages of the connection as JSON values.
+    pub fn new(handler: H) -> JsonHandler<T, H> {
+        JsonHandler {
+            inner: handler,
+            value: PhantomData,
+        }
+    }
+}
+
+impl<T, H> Handler for JsonHandler<T, H>
+where
+    T: DeserializeOwned,
+    H: OnJson<T>,
+{
+    #[inline]
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let value = msg.json()?;
+        self.inner.on_json(value)
+    }
+
+    #[inline]
+    fn on_shutdown(&mut self) {
+        self.inner.on_shutdown()
+    }
+
+    #[inline]
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_message_ref(&mut self, msg: MessageRef) -> Result<bool> {
+        self.inner.on_message_ref(msg)
+    }
+
+    #[inline]
+    fn on_message_start(&mut self, opcode: OpCode) -> Result<()> {
+        self.inner.on_message_start(opcode)
+    }
+
+    #[inline]
+    fn on_message_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.inner.on_message_chunk(data)
+    }
+
+    #[inline]
+    fn on_message_end(&mut self) -> Result<()> {
+        self.inner.on_message_end()
+    }
+
+    #[inline]
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.inner.on_close(code, reason)
+    }
+
+    #[inline]
+    fn on_ping(&mut self, data: &[u8]) -> Result<PingAction> {
+        self.inner.on_ping(data)
+    }
+
+    #[inline]
+    fn on_pong(&mut self, data: &[u8]) -> Result<()> {
+        self.inner.on_pong(data)
+    }
+
+    #[inline]
+    fn on_buffer_drained(&mut self) {
+        self.inner.on_buffer_drained()
+    }
+
+    #[inline]
+    fn on_sent(&mut self, ack: AckToken) {
+        self.inner.on_sent(ack)
+    }
+
+    #[inline]
+    fn on_reconnect_attempt(&mut self, attempt: u32) {
+        self.inner.on_reconnect_attempt(attempt)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: Error) {
+        self.inner.on_error(err)
+    }
+
+    #[inline]
+    fn on_handshake_request(&mut self, req: &Request) {
+        self.inner.on_handshake_request(req)
+    }
+
+    #[inline]
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        self.inner.on_request(req)
+    }
+
+    #[inline]
+    fn on_response(&mut self, res: &Response) -> Result<()> {
+        self.inner.on_response(res)
+    }
+
+    #[inline]
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        self.inner.on_timeout(event)
+    }
+
+    #[inline]
+    fn on_new_timeout(&mut self, tok: Token, timeout: Timeout) -> Result<()> {
+        self.inner.on_new_timeout(tok, timeout)
+    }
+
+    #[inline]
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.inner.on_frame(frame)
+    }
+
+    #[inline]
+    fn on_frame_with_state(&mut self, frame: Frame, state: FragmentState) -> Result<Option<Frame>> {
+        self.inner.on_frame_with_state(frame, state)
+    }
+
+    #[inline]
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.inner.on_send_frame(frame)
+    }
+
+    #[inline]
+    fn share_broadcasts(&self) -> bool {
+        self.inner.share_broadcasts()
+    }
+
+    #[inline]
+    fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+        self.inner.build_request(url)
+    }
+
+    #[inline]
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+    fn upgrade_ssl_client(
+        &mut self,
+        stream: TcpStream,
+        url: &url::Url,
+    ) -> Result<SslStream<TcpStream>> {
+        self.inner.upgrade_ssl_client(stream, url)
+    }
+
+    #[inline]
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
+    fn upgrade_ssl_server(&mut self, stream: TcpStream) -> Result<SslStream<TcpStream>> {
+        self.inner.upgrade_ssl_server(stream)
+    }
+
+    #[inline]
+    #[cfg(feature = "tls-rustls")]
+    fn build_client_tls_config(&mut self) -> Result<Arc<ClientConfig>> {
+        self.inner.build_client_tls_config()
+    }
+
+    #[inline]
+    #[cfg(feature = "tls-rustls")]
+    fn build_server_tls_config(&mut self) -> Result<Arc<ServerConfig>> {
+        self.inner.build_server_tls_config()
+    }
+}
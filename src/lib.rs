@@ -5,20 +5,40 @@
 
 extern crate byteorder;
 extern crate bytes;
+#[cfg(feature = "futures")]
+extern crate futures;
 extern crate httparse;
-extern crate mio;
+extern crate iovec;
+pub extern crate mio;
 extern crate mio_extras;
+#[cfg(unix)]
+extern crate mio_uds;
+extern crate net2;
 #[cfg(feature = "ssl")]
 extern crate openssl;
 #[cfg(feature = "nativetls")]
 extern crate native_tls;
 extern crate rand;
+#[cfg(feature = "tls-rustls")]
+extern crate rustls;
+#[cfg(feature = "json")]
+extern crate serde;
+#[cfg(feature = "json")]
+extern crate serde_json;
 extern crate sha1;
 extern crate slab;
 extern crate url;
-#[macro_use]
+#[cfg(feature = "tls-rustls")]
+extern crate webpki_roots;
+#[cfg_attr(not(feature = "tracing"), macro_use)]
 extern crate log;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
 
+mod auth;
+#[cfg(feature = "futures")]
+mod bridge;
 mod communication;
 mod connection;
 mod factory;
@@ -26,33 +46,63 @@ mod frame;
 mod handler;
 mod handshake;
 mod io;
+#[cfg(feature = "json")]
+mod json;
 mod message;
+mod output;
+mod pool;
 mod protocol;
+mod proxy_protocol;
 mod result;
+mod router;
+mod rpc;
 mod stream;
+mod utf8;
 
 #[cfg(feature = "permessage-deflate")]
 pub mod deflate;
 
+pub mod test;
 pub mod util;
 
+pub use auth::{AuthOutcome, Authenticator, BasicAuthAuthenticator, BearerTokenAuthenticator};
+#[cfg(feature = "futures")]
+pub use bridge::{connect_async, MessageSink, MessageStream};
 pub use factory::Factory;
-pub use handler::Handler;
+pub use handler::{Handler, PingAction};
 
-pub use communication::Sender;
-pub use frame::Frame;
-pub use handshake::{Handshake, Request, Response};
-pub use message::Message;
+pub use communication::{AckToken, ConnectionCounts, ConnectionInfo, ConnectionState, MessageWriter,
+                        SendError, Sender, ServerHandle, Stats};
+pub use frame::{FragmentState, Frame, FrameBuilder};
+pub use handshake::{Handshake, Request, Response, TlsInfo};
+#[cfg(feature = "json")]
+pub use json::{JsonHandler, OnJson};
+pub use message::{Message, MessageMetadata, MessageRef};
+pub use pool::{ClientPool, PooledConnection};
 pub use protocol::{CloseCode, OpCode};
 pub use result::Kind as ErrorKind;
 pub use result::{Error, Result};
+pub use router::{Router, RouterHandler};
+pub use rpc::{Reply, RpcSender};
 
-use std::borrow::Borrow;
+use std::borrow::{Borrow, Cow};
 use std::default::Default;
 use std::fmt;
-use std::net::{SocketAddr, ToSocketAddrs};
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
 
-use mio::Poll;
+use mio::{Events, Poll};
+
+use stream::Stream;
+#[cfg(feature = "tls-rustls")]
+use rustls::pki_types::pem::{Error as PemError, PemObject};
+#[cfg(feature = "tls-rustls")]
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
 
 /// A utility function for setting up a WebSocket server.
 ///
@@ -125,13 +175,77 @@ where
     Ok(())
 }
 
+/// A utility function for setting up a WebSocket client on a thread of its own, returning the
+/// Sender of the connection as soon as the connection is created, along with the handle of the
+/// thread running the event loop.
+///
+/// Messages sent before the handshake completes are queued and sent once the connection is
+/// open. If the connection can't be created, the error of the event loop is returned.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ws::{connect_with_sender, CloseCode};
+///
+/// let (out, client) = connect_with_sender("ws://127.0.0.1:3012", |_| {
+///     |msg| {
+///         println!("Got message: {}", msg);
+///         Ok(())
+///     }
+/// }).unwrap();
+/// out.send("Hello WebSocket").unwrap();
+/// out.close(CloseCode::Normal).unwrap();
+/// client.join().unwrap().unwrap();
+/// ```
+pub fn connect_with_sender<U, F, H>(
+    url: U,
+    mut factory: F,
+) -> Result<(Sender, thread::JoinHandle<Result<()>>)>
+where
+    U: Borrow<str>,
+    F: FnMut(Sender) -> H + Send + 'static,
+    H: Handler,
+{
+    let parsed = url::Url::parse(url.borrow()).map_err(|err| {
+        Error::new(
+            ErrorKind::Internal,
+            format!("Unable to parse {} as url due to {:?}", url.borrow(), err),
+        )
+    })?;
+    let (tx, rx) = mpsc::channel();
+    let client = thread::spawn(move || {
+        let mut tx = Some(tx);
+        let mut ws = WebSocket::new(move |out: Sender| {
+            if let Some(tx) = tx.take() {
+                let _ = tx.send(out.clone());
+            }
+            factory(out)
+        })?;
+        ws.connect(parsed)?;
+        ws.run()?;
+        Ok(())
+    });
+
+    match rx.recv() {
+        Ok(out) => Ok((out, client)),
+        Err(_) => match client.join() {
+            Ok(Err(err)) => Err(err),
+            _ => Err(Error::new(
+                ErrorKind::Internal,
+                "The client stopped before creating the connection.",
+            )),
+        },
+    }
+}
+
 /// WebSocket settings
 #[derive(Debug, Clone, Copy)]
 pub struct Settings {
     /// The maximum number of connections that this WebSocket will support.
     /// The default setting is low and should be increased when expecting more
     /// connections because this is a hard limit and no new connections beyond
-    /// this limit can be made until an old connection is dropped.
+    /// this limit can be made until an old connection is dropped. How connections beyond
+    /// the limit are turned away is decided by `connection_limit_action`.
     /// Default: 100
     pub max_connections: usize,
     /// The number of events anticipated per connection. The event loop queue size will
@@ -158,9 +272,33 @@ pub struct Settings {
     /// The maximum length of outgoing frames. Messages longer than this will be fragmented.
     /// Default: 65,535
     pub fragment_size: usize,
-    /// The maximum length of acceptable incoming frames. Messages longer than this will be rejected.
+    /// The maximum length of acceptable incoming frames. Frames longer than this will be rejected
+    /// with a Capacity error, which closes the connection with a Size (1009) close code.
     /// Default: unlimited
     pub max_fragment_size: usize,
+    /// The maximum length of acceptable incoming messages. Unlike `max_fragment_size`, this limit
+    /// applies to the combined payload of all the frames of a fragmented message. Messages longer
+    /// than this will be rejected with a Capacity error, which closes the connection with a Size
+    /// (1009) close code.
+    /// Default: unlimited
+    pub max_message_size: usize,
+    /// The maximum number of continuation frames of an incoming fragmented message, which bounds
+    /// the number of frames a message is received in regardless of their size. A message
+    /// sent in more fragments than this closes the connection with a Policy (1008) close code,
+    /// which keeps the other endpoint from making this one handle an endless number of tiny
+    /// frames.
+    /// Default: None
+    pub max_continuation_frames: Option<usize>,
+    /// Whether a server only pushes messages to its clients and never receives any. A client
+    /// that sends a data frame is then closed with a Policy (1008) close code before the frame
+    /// reaches the handler. Control frames, such as pings, are still accepted.
+    /// Default: false
+    pub reject_client_data: bool,
+    /// Whether to hand incoming messages to `Handler::on_message_start`, `on_message_chunk` and
+    /// `on_message_end` frame by frame as they arrive, instead of reassembling them for
+    /// `on_message`. The `max_message_size` limit still applies to the whole message.
+    /// Default: false
+    pub stream_messages: bool,
     /// The size of the incoming buffer. A larger buffer uses more memory but will allow for fewer
     /// reallocations.
     /// Default: 2048
@@ -169,6 +307,14 @@ pub struct Settings {
     /// false, a Capacity error will be triggered instead.
     /// Default: true
     pub in_buffer_grow: bool,
+    /// The largest capacity the incoming buffer may grow to. A full buffer is emptied by handling
+    /// the frames in it before more is read, leaving the rest of the data waiting in the socket,
+    /// so this bounds the memory of each connection at the cost of more reads for large
+    /// messages. A frame that doesn't fit in the buffer triggers a Capacity error, which closes
+    /// the connection with a Size (1009) close code, so this should be larger than
+    /// `max_fragment_size`.
+    /// Default: unlimited
+    pub in_buffer_max_capacity: usize,
     /// The size of the outgoing buffer. A larger buffer uses more memory but will allow for fewer
     /// reallocations.
     /// Default: 2048
@@ -177,6 +323,54 @@ pub struct Settings {
     /// false, a Capacity error will be triggered instead.
     /// Default: true
     pub out_buffer_grow: bool,
+    /// The largest capacity the outgoing buffer may grow to. A frame that doesn't fit next to
+    /// the output still waiting to be written triggers a Capacity error, so a connection that
+    /// sends faster than the other endpoint reads should also limit `max_queued_messages`, which
+    /// holds messages back until the buffer has been written.
+    /// Default: unlimited
+    pub out_buffer_max_capacity: usize,
+    /// The number of bytes that may be waiting to be written to the other endpoint before
+    /// `Sender::try_send` starts refusing messages with `SendError::WouldBlock`. This counts both
+    /// messages queued on the event loop and data in the outgoing buffer. It does not affect
+    /// `Sender::send`.
+    /// Default: unlimited
+    pub high_water_mark: usize,
+    /// The maximum number of outgoing messages that a connection holds back while earlier output
+    /// is still waiting to be written to the other endpoint. What happens to messages beyond the
+    /// limit is decided by `overflow_policy`. When there is no limit, messages are written to the
+    /// outgoing buffer right away, which keeps growing for a slow consumer unless
+    /// `out_buffer_grow` is false.
+    ///
+    /// Default: None
+    pub max_queued_messages: Option<usize>,
+    /// What to do with an outgoing message when `max_queued_messages` messages are already held
+    /// back. This can be overridden for the messages of a particular `Sender` with
+    /// `Sender::with_overflow_policy`.
+    ///
+    /// Default: OverflowPolicy::Block
+    pub overflow_policy: OverflowPolicy,
+    /// How long outgoing data frames may be held back so that frames sent in quick succession
+    /// are written to the socket together, trading a little latency for fewer system calls.
+    /// Control frames such as pongs and close frames are written right away, together with the
+    /// output held back before them.
+    ///
+    /// Default: None
+    pub coalesce_window: Option<Duration>,
+    /// Whether pings and pongs, including the pongs that answer pings, skip ahead of the data
+    /// frames that are waiting to be written, to go out right after the frame that is being
+    /// written. This keeps a connection that is saturated with messages answering pings in time
+    /// instead of being dropped as dead. The frames sent with `Sender::send_frame` keep their
+    /// place, and so do close frames unless `prioritize_close_frames` is set.
+    ///
+    /// Default: true
+    pub prioritize_control_frames: bool,
+    /// Whether the close frames sent by this endpoint skip ahead of the data frames that are
+    /// waiting to be written, the way `prioritize_control_frames` lets pings and pongs skip
+    /// ahead. Nothing may follow a close frame, so the data frames it skips and the messages
+    /// held back are dropped instead of being delivered.
+    ///
+    /// Default: false
+    pub prioritize_close_frames: bool,
     /// Whether to panic when an Internal error is encountered. Internal errors should generally
     /// not occur, so this setting defaults to true as a debug measure, whereas production
     /// applications should consider setting it to false.
@@ -206,8 +400,21 @@ pub struct Settings {
     /// The WebSocket protocol requires frames sent from client endpoints to be masked as a
     /// security and sanity precaution. Enforcing this requirement, which may be removed at some
     /// point may cause incompatibilities. If you need the extra security, set this to true.
+    /// When enforced, a server fails connections that send unmasked frames, closing them with
+    /// `CloseCode::Protocol`.
     /// Default: false
     pub masking_strict: bool,
+    /// The WebSocket protocol forbids server endpoints to mask their frames, so a client fails
+    /// connections that send masked frames, closing them with `CloseCode::Protocol`. Some
+    /// embedded servers mask their frames anyway; set this to true to unmask such frames
+    /// instead, so that a client can talk to them.
+    /// Default: false
+    pub allow_server_masking: bool,
+    /// Where the masking keys of the frames sent by client connections come from. The protocol
+    /// requires keys that the application can't predict, see `MaskingKeySource` before choosing
+    /// anything but the default.
+    /// Default: MaskingKeySource::Random
+    pub masking_key_source: MaskingKeySource,
     /// The WebSocket protocol requires clients to verify the key returned by a server to ensure
     /// that the server and all intermediaries can perform the protocol. Verifying the key will
     /// consume processing time and other resources with the benefit that we can fail the
@@ -221,21 +428,164 @@ pub struct Settings {
     /// requirement that handshakes begin with a GET method, set this to true.
     /// Default: false
     pub method_strict: bool,
+    /// Whether to answer a close frame from the other endpoint with a close frame right away, as
+    /// the WebSocket protocol requires. When this is false, the close is only passed to
+    /// `Handler::on_close`, and the closing handshake is completed once the handler calls
+    /// `Sender::close`, which allows a relay to forward the close to another connection and
+    /// echo the code that comes back. Until then, incoming data frames are ignored.
+    /// Default: true
+    pub auto_close_response: bool,
+    /// Whether server connections behind a reverse proxy should trust the `X-Forwarded-For` and
+    /// `X-Real-IP` headers to find the address of the client, see `Handshake::client_addr`. The
+    /// headers are only honored on connections from the proxies given to
+    /// `Builder::with_trusted_proxies`, since clients that connect directly could set them to
+    /// anything.
+    /// Default: false
+    pub trust_forwarded_for: bool,
+    /// Whether server connections begin with a PROXY protocol header, version 1 or 2, which load
+    /// balancers such as HAProxy or AWS NLB send to pass on the address of the client. The
+    /// address replaces the peer address of the connection in its `Handshake`. Connections
+    /// without a valid header are closed, so only enable this when every connection comes
+    /// through such a load balancer.
+    /// Default: false
+    pub accept_proxy_protocol: bool,
     /// Indicate whether server connections should use ssl encryption when accepting connections.
     /// Setting this to true means that clients should use the `wss` scheme to connect to this
     /// server. Note that using this flag will in general necessitate overriding the
-    /// `Handler::upgrade_ssl_server` method in order to provide the details of the ssl context. It may be
+    /// `Handler::upgrade_ssl_server` method in order to provide the details of the ssl context, or
+    /// the `Handler::build_server_tls_config` method when using the `tls-rustls` feature. It may be
     /// simpler for most users to use a reverse proxy such as nginx to provide server side
     /// encryption.
     ///
     /// Default: false
     pub encrypt_server: bool,
+    /// Whether server connections must present a client certificate during the TLS handshake.
+    /// Handshake requests on connections without one are rejected with a 403 response. The ssl
+    /// context must still be configured to ask for client certificates and to verify them
+    /// against the trusted certificate authorities, since this only checks that a verified
+    /// certificate was presented.
+    ///
+    /// Default: false
+    pub require_client_cert: bool,
     /// Disables Nagle's algorithm.
     /// Usually tcp socket tries to accumulate packets to send them all together (every 200ms).
     /// When enabled socket will try to send packet as fast as possible.
     ///
     /// Default: false
     pub tcp_nodelay: bool,
+    /// How long a connection may be idle before the operating system starts sending TCP
+    /// keepalive probes, which detect peers that went away without closing the connection. None
+    /// leaves keepalive disabled.
+    ///
+    /// Default: None
+    pub tcp_keepalive: Option<Duration>,
+    /// The size of the kernel send buffer of each socket, `SO_SNDBUF`. None leaves the size
+    /// chosen by the operating system.
+    ///
+    /// Default: None
+    pub tcp_send_buffer_size: Option<usize>,
+    /// The size of the kernel receive buffer of each socket, `SO_RCVBUF`. None leaves the size
+    /// chosen by the operating system.
+    ///
+    /// Default: None
+    pub tcp_recv_buffer_size: Option<usize>,
+    /// Whether a server listening on an IPv6 address only accepts IPv6 connections. When false,
+    /// a listener on `[::]` also accepts IPv4 connections as IPv4-mapped addresses, so it can't
+    /// be combined with a listener on `0.0.0.0` for the same port. This is always set explicitly
+    /// instead of relying on the default of the operating system.
+    ///
+    /// Default: false
+    pub ipv6_only: bool,
+    /// The interval at which each open connection will automatically send a ping to the other
+    /// endpoint. The other endpoint must answer each ping with a matching pong before the next
+    /// interval elapses, otherwise the ping is considered missed. Setting this to `None`
+    /// disables automatic pings.
+    ///
+    /// Default: None
+    pub ping_interval: Option<Duration>,
+    /// The number of consecutive automatic pings that may go unanswered before the connection is
+    /// considered dead. Once this limit is reached, the connection will be dropped and the
+    /// handler's `on_close` method will be called with `CloseCode::Abnormal`. This setting has no
+    /// effect unless `ping_interval` is set.
+    ///
+    /// Default: 3
+    pub max_missed_pongs: usize,
+    /// How long a connection may go without receiving a frame or sending a message before it is
+    /// closed with `CloseCode::Away`. If the other endpoint doesn't answer the close frame within
+    /// another `idle_timeout`, the connection is dropped. Automatic pings don't count as activity,
+    /// so this can be combined with `ping_interval`. Setting this to `None` disables the timeout.
+    ///
+    /// Default: None
+    pub idle_timeout: Option<Duration>,
+    /// How long a connection waits for the other endpoint to answer a close frame that this
+    /// endpoint sent. A connection that gets no answer in time is dropped and the handler's
+    /// `on_close` method is called with `CloseCode::Abnormal`, so connections aren't left stuck
+    /// in the closing handshake. Clients also stop waiting for the server to end the TCP connection
+    /// after the closing handshake. Setting this to `None` disables the timeout.
+    ///
+    /// Default: None
+    pub close_timeout: Option<Duration>,
+    /// How long a server connection may take, from being accepted, to complete the WebSocket
+    /// handshake, including the TLS handshake and any time spent deferring the response with
+    /// `Response::pending`. Connections that take longer are dropped after `on_error` is called
+    /// with a `Kind::Timeout` error. This protects against clients that hold connections open
+    /// without ever finishing the handshake. Setting this to `None` disables the timeout.
+    ///
+    /// Default: None
+    pub handshake_timeout: Option<Duration>,
+    /// How long a client connection may take, from starting to connect, to complete the
+    /// WebSocket handshake. Connections that take longer are dropped after `on_error` is called
+    /// with a `Kind::Timeout` error, and are reestablished if automatic reconnection is enabled.
+    /// Setting this to `None` disables the timeout.
+    ///
+    /// Default: None
+    pub connect_timeout: Option<Duration>,
+    /// The largest handshake request or response, in bytes, that a connection will read before
+    /// giving up on it. Servers reject larger requests with a 431 response, and clients fail the
+    /// connection with a `Kind::Protocol` error. This keeps a peer from making the connection
+    /// buffer an endless handshake.
+    ///
+    /// Default: 16384
+    pub max_handshake_size: usize,
+    /// The most extension offers, extension parameters and subprotocols that a handshake
+    /// request may list, counted across all of its `Sec-WebSocket-Extensions` and
+    /// `Sec-WebSocket-Protocol` headers. Servers reject requests that list more with a 400
+    /// response before any handler parses them, so that a peer can't make every connection
+    /// work through thousands of offers.
+    ///
+    /// Default: 64
+    pub max_handshake_tokens: usize,
+    /// The close code sent when a handler method returns a `Kind::Json` error, such as the
+    /// error of `Message::json` for a message that isn't the expected JSON. With `None` the
+    /// error is only passed to `Handler::on_error` and the connection stays open.
+    ///
+    /// Default: Some(CloseCode::Invalid)
+    #[cfg(feature = "json")]
+    pub json_close_code: Option<CloseCode>,
+    /// The oldest TLS protocol version that encrypted connections may negotiate. This is honored
+    /// by the default implementations of `Handler::upgrade_ssl_client` and
+    /// `Handler::build_client_tls_config`. Custom ssl contexts set up by overriding these methods
+    /// choose their own versions.
+    ///
+    /// Default: TlsVersion::Tls12
+    pub min_tls_version: TlsVersion,
+    /// The number of messages per second that each connection may receive from the other
+    /// endpoint. Up to this many messages may arrive in a burst, after which they are limited to
+    /// the rate. A fragmented message counts once, when its final frame arrives. Messages over
+    /// the limit are handled according to `rate_limit_action`. Setting this to `None` disables
+    /// the limit.
+    ///
+    /// Default: None
+    pub max_messages_per_second: Option<u32>,
+    /// What to do with messages that exceed `max_messages_per_second`.
+    ///
+    /// Default: RateLimitAction::Close
+    pub rate_limit_action: RateLimitAction,
+    /// What to do with server connections that arrive once `max_connections` connections are
+    /// open. Either way, `Factory::on_connection_limit` is called for each of them.
+    ///
+    /// Default: ConnectionLimitAction::Refuse
+    pub connection_limit_action: ConnectionLimitAction,
 }
 
 impl Default for Settings {
@@ -249,10 +599,22 @@ impl Default for Settings {
             fragments_grow: true,
             fragment_size: u16::max_value() as usize,
             max_fragment_size: usize::max_value(),
+            max_message_size: usize::MAX,
+            max_continuation_frames: None,
+            reject_client_data: false,
+            stream_messages: false,
             in_buffer_capacity: 2048,
             in_buffer_grow: true,
+            in_buffer_max_capacity: usize::MAX,
             out_buffer_capacity: 2048,
             out_buffer_grow: true,
+            out_buffer_max_capacity: usize::MAX,
+            high_water_mark: usize::MAX,
+            max_queued_messages: None,
+            overflow_policy: OverflowPolicy::Block,
+            coalesce_window: None,
+            prioritize_control_frames: true,
+            prioritize_close_frames: false,
             panic_on_internal: true,
             panic_on_capacity: false,
             panic_on_protocol: false,
@@ -262,14 +624,274 @@ impl Default for Settings {
             panic_on_timeout: false,
             shutdown_on_interrupt: true,
             masking_strict: false,
+            allow_server_masking: false,
+            masking_key_source: MaskingKeySource::Random,
             key_strict: false,
             method_strict: false,
+            auto_close_response: true,
+            trust_forwarded_for: false,
+            accept_proxy_protocol: false,
             encrypt_server: false,
+            require_client_cert: false,
             tcp_nodelay: false,
+            tcp_keepalive: None,
+            tcp_send_buffer_size: None,
+            tcp_recv_buffer_size: None,
+            ipv6_only: false,
+            ping_interval: None,
+            max_missed_pongs: 3,
+            idle_timeout: None,
+            close_timeout: None,
+            handshake_timeout: None,
+            connect_timeout: None,
+            max_handshake_size: 16 * 1024,
+            max_handshake_tokens: 64,
+            #[cfg(feature = "json")]
+            json_close_code: Some(CloseCode::Invalid),
+            min_tls_version: TlsVersion::Tls12,
+            max_messages_per_second: None,
+            rate_limit_action: RateLimitAction::Close,
+            connection_limit_action: ConnectionLimitAction::Refuse,
+        }
+    }
+}
+
+/// A version of the TLS protocol.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum TlsVersion {
+    /// TLS 1.0, which is deprecated and insecure.
+    Tls10,
+    /// TLS 1.1, which is deprecated and insecure.
+    Tls11,
+    /// TLS 1.2
+    Tls12,
+    /// TLS 1.3
+    Tls13,
+}
+
+/// Where the masking keys of the frames sent by client connections come from, see
+/// `Settings::masking_key_source`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MaskingKeySource {
+    /// A new random key for each frame, as the protocol requires.
+    Random,
+    /// A fixed sequence of keys generated from the seed, which makes the bytes written by a client
+    /// connection reproducible, so that tests can compare them with known frames. Each connection
+    /// starts the sequence over. Predictable keys defeat the purpose of masking, which keeps
+    /// scripts from choosing the bytes that intermediaries see, so this must never be used
+    /// outside of tests.
+    InsecureDeterministic(u64),
+}
+
+/// What to do with a message that arrives faster than `Settings::max_messages_per_second` allows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Close the connection with `CloseCode::Policy`.
+    Close,
+    /// Discard the message without passing it to the handler, and keep the connection open.
+    Drop,
+}
+
+/// What to do with an outgoing message when `Settings::max_queued_messages` messages are already
+/// held back by its connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Make `Sender::send` wait until there is room. Messages sent from the thread of the event
+    /// loop, such as those sent by handlers, can't wait and are held back beyond the limit.
+    Block,
+    /// Discard the oldest message that is held back to make room for the new one.
+    DropOldest,
+    /// Discard the new message.
+    DropNewest,
+    /// Close the connection with `CloseCode::Policy`, discarding the messages held back.
+    CloseConnection,
+}
+
+/// What to do with a server connection that arrives when `Settings::max_connections` connections
+/// are already open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionLimitAction {
+    /// Close the TCP connection as soon as it is accepted.
+    Refuse,
+    /// Read the handshake request and answer it with a 503 response, so that the client learns
+    /// why it was turned away. Up to `max_connections` further connections are accepted to be
+    /// answered this way, after which they are refused.
+    Reject,
+}
+
+/// Settings that only apply to client connections, i.e. connections created with
+/// `WebSocket::connect` or `Sender::connect`.
+#[derive(Debug, Clone)]
+pub struct ClientSettings {
+    /// Whether to automatically reconnect when a client connection is lost. The same handler
+    /// instance is kept across reconnections: the request is rebuilt with `build_request`, and
+    /// `on_open` is called again once the new handshake completes. A connection is not
+    /// reconnected if it was closed by this endpoint, or if the WebSocket is shutting down.
+    /// Messages sent while waiting to reconnect are delivered once the new connection is open.
+    /// Default: false
+    pub auto_reconnect: bool,
+    /// The delay before the first reconnection attempt. The delay doubles with each failed
+    /// attempt, up to `max_reconnect_delay`.
+    /// Default: 500 milliseconds
+    pub reconnect_delay: Duration,
+    /// The maximum delay between reconnection attempts.
+    /// Default: 30 seconds
+    pub max_reconnect_delay: Duration,
+    /// An upper bound on a random delay added to each reconnection attempt, this avoids many
+    /// clients reconnecting to a server at the same time.
+    /// Default: 100 milliseconds
+    pub reconnect_jitter: Duration,
+    /// The number of consecutive reconnection attempts to make before giving up on the
+    /// connection. The count is reset whenever a reconnection succeeds.
+    /// Default: 10
+    pub max_retries: u32,
+    /// An HTTP proxy to tunnel connections through. The client first asks the proxy to open a
+    /// tunnel to the server with a `CONNECT` request, and then performs the TLS and WebSocket
+    /// handshakes over that tunnel as usual.
+    /// Default: None
+    pub proxy: Option<ProxyConfig>,
+    /// The server name to send with the Server Name Indication extension and to verify the
+    /// certificate of the server against when connecting with the `wss` scheme. This is useful
+    /// when connecting to an ip address, or to a host fronting several domains. Setting this to
+    /// `None` uses the host of the url.
+    /// Default: None
+    pub sni_hostname: Option<String>,
+    /// The client certificate to present to servers that ask for one when connecting with the
+    /// `wss` scheme, for mutual TLS authentication. It is presented by the default
+    /// implementations of `Handler::upgrade_ssl_client` and `Handler::build_client_tls_config`.
+    /// Default: None
+    pub identity: Option<TlsIdentity>,
+    /// Where the random bytes of the `Sec-WebSocket-Key` of handshake requests, of the masking
+    /// keys of frames and of the reconnection jitter come from, for environments that only allow
+    /// an approved random number generator. `None` uses the generator of the `rand` crate, which
+    /// is seeded by the operating system. Keys made by `MaskingKeySource::InsecureDeterministic`
+    /// don't use it. It is part of the client settings because only clients need random bytes:
+    /// servers neither mask their frames, nor make handshake requests, nor reconnect.
+    /// Default: None
+    pub random_source: Option<RandomSource>,
+}
+
+impl Default for ClientSettings {
+    fn default() -> ClientSettings {
+        ClientSettings {
+            auto_reconnect: false,
+            reconnect_delay: Duration::from_millis(500),
+            max_reconnect_delay: Duration::from_secs(30),
+            reconnect_jitter: Duration::from_millis(100),
+            max_retries: 10,
+            proxy: None,
+            sni_hostname: None,
+            identity: None,
+            random_source: None,
         }
     }
 }
 
+/// A source of random bytes for client connections, see `ClientSettings::random_source`. The
+/// source is shared by all client connections, so a generator that needs exclusive access has
+/// to be locked:
+///
+/// ```ignore
+/// let rng = Mutex::new(ApprovedRng::new());
+/// let source = RandomSource::new(move |dest| rng.lock().unwrap().fill_bytes(dest));
+/// ```
+#[derive(Clone)]
+pub struct RandomSource(Arc<Fill>);
+
+type Fill = dyn Fn(&mut [u8]) + Send + Sync;
+
+impl RandomSource {
+    /// Create a source from a function that fills the buffer it is given with random bytes.
+    pub fn new<F>(fill: F) -> RandomSource
+    where
+        F: Fn(&mut [u8]) + Send + Sync + 'static,
+    {
+        RandomSource(Arc::new(fill))
+    }
+
+    /// Fill the buffer with random bytes.
+    pub fn fill(&self, dest: &mut [u8]) {
+        (self.0)(dest)
+    }
+}
+
+impl fmt::Debug for RandomSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RandomSource")
+    }
+}
+
+/// A certificate chain and private key that identify an endpoint of an encrypted connection.
+#[derive(Debug, Clone)]
+pub struct TlsIdentity {
+    /// The PEM encoded certificate chain, starting with the certificate of the endpoint.
+    pub cert_chain: Vec<u8>,
+    /// The PEM encoded PKCS #8 private key of the endpoint.
+    pub key: Vec<u8>,
+}
+
+impl TlsIdentity {
+    /// Create an identity from a PEM encoded certificate chain and private key.
+    pub fn from_pem<C, K>(cert_chain: C, key: K) -> TlsIdentity
+    where
+        C: Into<Vec<u8>>,
+        K: Into<Vec<u8>>,
+    {
+        TlsIdentity {
+            cert_chain: cert_chain.into(),
+            key: key.into(),
+        }
+    }
+
+    /// Decode the certificate chain and private key for use in a rustls configuration.
+    #[cfg(feature = "tls-rustls")]
+    pub fn to_rustls(&self) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>)> {
+        let invalid = |e: PemError| {
+            Error::new(
+                ErrorKind::Internal,
+                format!("Invalid TLS identity: {}", e),
+            )
+        };
+        let chain = CertificateDer::pem_slice_iter(&self.cert_chain)
+            .collect::<::std::result::Result<Vec<_>, _>>()
+            .map_err(invalid)?;
+        let key = PrivateKeyDer::from_pem_slice(&self.key).map_err(invalid)?;
+        Ok((chain, key))
+    }
+}
+
+/// The HTTP proxy to use for client connections.
+#[derive(Debug, Clone)]
+pub struct ProxyConfig {
+    /// The address of the proxy, as `host:port`.
+    pub addr: String,
+    /// The username and password to authenticate with, using Basic authentication.
+    pub credentials: Option<(String, String)>,
+}
+
+impl ProxyConfig {
+    /// Create a proxy configuration for the proxy at the given `host:port` address.
+    pub fn new<A>(addr: A) -> ProxyConfig
+    where
+        A: Into<String>,
+    {
+        ProxyConfig {
+            addr: addr.into(),
+            credentials: None,
+        }
+    }
+
+    /// Authenticate with the proxy using the given username and password.
+    pub fn with_credentials<U, P>(mut self, username: U, password: P) -> ProxyConfig
+    where
+        U: Into<String>,
+        P: Into<String>,
+    {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+}
+
 /// The WebSocket struct. A WebSocket can support multiple incoming and outgoing connections.
 pub struct WebSocket<F>
 where
@@ -288,6 +910,15 @@ where
         Builder::new().build(factory)
     }
 
+    /// Create a new WebSocket using the given Factory and run a single server connection over
+    /// the stream, see `serve_stream`.
+    pub fn from_stream<S>(stream: S, factory: F) -> Result<WebSocket<F>>
+    where
+        S: Read + Write + Send + 'static,
+    {
+        WebSocket::new(factory)?.serve_stream(stream)
+    }
+
     /// Consume the WebSocket and bind to the specified address.
     /// If the `addr_spec` yields multiple addresses this will return after the
     /// first successful bind. `local_addr` can be called to determine which
@@ -300,7 +931,7 @@ where
         let mut last_error = Error::new(ErrorKind::Internal, "No address given");
 
         for addr in addr_spec.to_socket_addrs()? {
-            if let Err(e) = self.handler.listen(&mut self.poll, &addr) {
+            if let Err(e) = self.handler.listen(&addr) {
                 error!("Unable to listen on {}", addr);
                 last_error = e;
             } else {
@@ -325,6 +956,106 @@ where
         self.bind(addr_spec).and_then(|server| server.run())
     }
 
+    /// Consume the WebSocket and bind to every one of the specified addresses, so that a single
+    /// event loop accepts connections on all of them, for example on both an IPv4 and an IPv6
+    /// address. Unlike `bind`, this fails if any of the addresses can't be bound.
+    /// After the server is successfully bound you should start it using `run`.
+    pub fn bind_multi(mut self, addrs: &[SocketAddr]) -> Result<WebSocket<F>> {
+        if addrs.is_empty() {
+            return Err(Error::new(ErrorKind::Internal, "No address given"));
+        }
+
+        for addr in addrs {
+            if let Err(e) = self.handler.listen(addr) {
+                error!("Unable to listen on {}", addr);
+                return Err(e);
+            }
+        }
+
+        for addr in self.handler.local_addrs()? {
+            info!("Listening for new connections on {}.", addr);
+        }
+
+        Ok(self)
+    }
+
+    /// Consume the WebSocket and listen for new connections on every one of the specified
+    /// addresses.
+    ///
+    /// # Safety
+    ///
+    /// This method will block until the event loop finishes running.
+    pub fn listen_multi(self, addrs: &[SocketAddr]) -> Result<WebSocket<F>> {
+        self.bind_multi(addrs).and_then(|server| server.run())
+    }
+
+    /// Consume the WebSocket and bind to a Unix domain socket at the specified path.
+    /// The path must not already exist. Connections accepted on a Unix domain socket have no ip
+    /// address, so the `peer_addr` and `local_addr` of their handshakes will be `None`.
+    /// After the server is successfully bound you should start it using `run`.
+    #[cfg(unix)]
+    pub fn bind_unix<P>(mut self, path: P) -> Result<WebSocket<F>>
+    where
+        P: AsRef<Path>,
+    {
+        self.handler.listen_unix(path.as_ref())?;
+        info!(
+            "Listening for new connections on {}.",
+            path.as_ref().display()
+        );
+        Ok(self)
+    }
+
+    /// Consume the WebSocket and listen for new connections on a Unix domain socket at the
+    /// specified path.
+    ///
+    /// # Safety
+    ///
+    /// This method will block until the event loop finishes running.
+    #[cfg(unix)]
+    pub fn listen_unix<P>(self, path: P) -> Result<WebSocket<F>>
+    where
+        P: AsRef<Path>,
+    {
+        self.bind_unix(path).and_then(|server| server.run())
+    }
+
+    /// Consume the WebSocket and run a single server connection over a stream provided by the
+    /// caller instead of a socket, such as an in-memory pipe for testing handlers. Reads from the
+    /// stream should block until data arrives.
+    ///
+    /// The stream can't be polled, so while the connection waits to read from it, messages sent
+    /// from other threads aren't written and timers don't fire. The encryption settings don't
+    /// apply to the stream.
+    ///
+    /// # Safety
+    ///
+    /// This method will block until the connection is closed.
+    pub fn serve_stream<S>(mut self, stream: S) -> Result<WebSocket<F>>
+    where
+        S: Read + Write + Send + 'static,
+    {
+        self.handler
+            .run_stream(&mut self.poll, Stream::custom(stream), None)?;
+        Ok(self)
+    }
+
+    /// Consume the WebSocket and run a single client connection to the url over a stream
+    /// provided by the caller instead of a socket, see `serve_stream`. The connection is not
+    /// reconnected when it is lost.
+    ///
+    /// # Safety
+    ///
+    /// This method will block until the connection is closed.
+    pub fn connect_stream<S>(mut self, stream: S, url: url::Url) -> Result<WebSocket<F>>
+    where
+        S: Read + Write + Send + 'static,
+    {
+        self.handler
+            .run_stream(&mut self.poll, Stream::custom(stream), Some(url))?;
+        Ok(self)
+    }
+
     /// Queue an outgoing connection on this WebSocket. This method may be called multiple times,
     /// but the actual connections will not be established until `run` is called.
     pub fn connect(&mut self, url: url::Url) -> Result<&mut WebSocket<F>> {
@@ -341,6 +1072,67 @@ where
         Ok(self)
     }
 
+    /// Handle the events of a poll owned by the caller instead of running the event loop, so
+    /// that the sockets of the WebSocket can share a thread with other event sources. After
+    /// calling `register`, pass the events of every poll to `ready`, and poll with a timeout no
+    /// longer than `poll_timeout`.
+    ///
+    /// The WebSocket uses the tokens below twice `Settings::max_connections` and the ten highest
+    /// tokens, so other event sources must be registered with the tokens in between. Events with
+    /// other tokens are ignored by `ready`.
+    ///
+    /// ```no_run
+    /// use ws::mio::{Events, Poll, PollOpt, Ready, Token};
+    /// use ws::mio::net::UdpSocket;
+    /// use ws::WebSocket;
+    ///
+    /// let mut ws = WebSocket::new(|out: ws::Sender| move |msg| out.send(msg))
+    ///     .unwrap()
+    ///     .bind("127.0.0.1:3012")
+    ///     .unwrap();
+    /// let mut poll = Poll::new().unwrap();
+    /// ws.register(&mut poll).unwrap();
+    ///
+    /// let udp = UdpSocket::bind(&"127.0.0.1:3013".parse().unwrap()).unwrap();
+    /// poll.register(&udp, Token(1 << 20), Ready::readable(), PollOpt::edge())
+    ///     .unwrap();
+    ///
+    /// let mut events = Events::with_capacity(1024);
+    /// loop {
+    ///     poll.poll(&mut events, ws.poll_timeout()).unwrap();
+    ///     for event in events.iter() {
+    ///         if event.token() == Token(1 << 20) {
+    ///             // read from the udp socket
+    ///         }
+    ///     }
+    ///     if !ws.ready(&mut poll, &events) {
+    ///         break;
+    ///     }
+    /// }
+    /// ws.deregister(&mut poll).unwrap();
+    /// ```
+    pub fn register(&mut self, poll: &mut Poll) -> Result<()> {
+        self.handler.register(poll)
+    }
+
+    /// Handle the events of a poll that was registered with `register`, returning whether the
+    /// WebSocket is still running. Once it returns false, the WebSocket has shut down and should
+    /// be deregistered.
+    pub fn ready(&mut self, poll: &mut Poll, events: &Events) -> bool {
+        self.handler.ready(poll, events)
+    }
+
+    /// The longest the registered poll may wait for events before `ready` is called again, or
+    /// `None` if the WebSocket has no deadline to meet.
+    pub fn poll_timeout(&self) -> Option<Duration> {
+        self.handler.poll_timeout()
+    }
+
+    /// Remove the sockets of the WebSocket from a poll that was registered with `register`.
+    pub fn deregister(&mut self, poll: &mut Poll) -> Result<()> {
+        self.handler.deregister(poll)
+    }
+
     /// Get a Sender that can be used to send messages on all connections.
     /// Calling `send` on this Sender is equivalent to calling `broadcast`.
     /// Calling `shutdown` on this Sender will shutdown the WebSocket even if no connections have
@@ -350,18 +1142,64 @@ where
         self.handler.sender()
     }
 
+    /// Get a handle that sends messages to, and closes, the connections of this WebSocket by
+    /// their token. The handle can be used from any thread once the WebSocket is running.
+    #[inline]
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle::new(self.handler.sender())
+    }
+
+    /// Close every open connection that the selector returns true for, with the given code and
+    /// reason, see `Sender::close_where`. Use the `broadcaster` to do this while the WebSocket
+    /// is running.
+    pub fn close_where<P, S>(&self, selector: P, code: CloseCode, reason: S) -> Result<()>
+    where
+        P: Fn(&ConnectionInfo) -> bool + Send + Sync + 'static,
+        S: Into<Cow<'static, str>>,
+    {
+        self.handler.sender().close_where(selector, code, reason)
+    }
+
+    /// The traffic of all the connections of this WebSocket so far.
+    ///
+    /// To read the totals while the WebSocket is running, use `stats` on the `broadcaster`.
+    #[inline]
+    pub fn stats(&self) -> Stats {
+        self.handler.stats()
+    }
+
+    /// The number of connections of this WebSocket in each state. The counts are kept up to date
+    /// as connections are accepted, opened and closed, so they are cheap to read.
+    ///
+    /// To read the counts while the WebSocket is running, use `connection_counts` on the
+    /// `broadcaster`.
+    #[inline]
+    pub fn connection_counts(&self) -> ConnectionCounts {
+        self.handler.connection_counts()
+    }
+
     /// Get the local socket address this socket is bound to. Will return an error
     /// if the backend returns an error. Will return a `NotFound` error if
-    /// this WebSocket is not a listening socket.
+    /// this WebSocket is not a listening socket. When bound to several addresses with
+    /// `bind_multi`, this is the first of them.
     pub fn local_addr(&self) -> ::std::io::Result<SocketAddr> {
         self.handler.local_addr()
     }
+
+    /// Get all the local socket addresses this socket is bound to, in the order they were bound.
+    pub fn local_addrs(&self) -> ::std::io::Result<Vec<SocketAddr>> {
+        self.handler.local_addrs()
+    }
 }
 
 /// Utility for constructing a WebSocket from various settings.
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone)]
 pub struct Builder {
     settings: Settings,
+    client_settings: ClientSettings,
+    allowed_origins: Option<Vec<String>>,
+    trusted_proxies: Option<Vec<IpAddr>>,
+    authenticator: Option<Arc<dyn Authenticator>>,
 }
 
 // TODO: add convenience methods for each setting
@@ -379,7 +1217,14 @@ impl Builder {
     {
         Ok(WebSocket {
             poll: Poll::new()?,
-            handler: io::Handler::new(factory, self.settings),
+            handler: io::Handler::new(
+                factory,
+                self.settings,
+                self.client_settings.clone(),
+                self.allowed_origins.clone().map(Arc::new),
+                self.trusted_proxies.clone().map(Arc::new),
+                self.authenticator.clone(),
+            ),
         })
     }
 
@@ -388,4 +1233,40 @@ impl Builder {
         self.settings = settings;
         self
     }
+
+    /// Set the settings to use for client connections.
+    pub fn with_client_settings(&mut self, settings: ClientSettings) -> &mut Builder {
+        self.client_settings = settings;
+        self
+    }
+
+    /// Only accept handshakes from the given origins. Requests with an `Origin` header that
+    /// doesn't match any of these will be rejected with a 403 response before the handler sees
+    /// them. Requests without an `Origin` header, which browsers always send, are not affected.
+    ///
+    /// An origin may be given in full, such as `https://example.com`, or as just a host, in which
+    /// case any scheme is accepted. A host of the form `*.example.com` matches any subdomain of
+    /// `example.com`, and `*` matches every origin.
+    pub fn with_allowed_origins(&mut self, origins: Vec<String>) -> &mut Builder {
+        self.allowed_origins = Some(origins);
+        self
+    }
+
+    /// Set the addresses of the reverse proxies whose `X-Forwarded-For` and `X-Real-IP` headers
+    /// are trusted when `Settings::trust_forwarded_for` is enabled.
+    pub fn with_trusted_proxies(&mut self, proxies: Vec<IpAddr>) -> &mut Builder {
+        self.trusted_proxies = Some(proxies);
+        self
+    }
+
+    /// Authenticate every handshake request with the authenticator before it is passed to
+    /// `Handler::on_request`. Requests that the authenticator doesn't accept are answered with
+    /// the response it decides on, and their connections are never opened.
+    pub fn with_authenticator<A>(&mut self, authenticator: A) -> &mut Builder
+    where
+        A: Authenticator + 'static,
+    {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
 }
@@ -3,6 +3,11 @@ use std::fmt;
 use std::result::Result as StdResult;
 use std::str::from_utf8;
 
+#[cfg(feature = "json")]
+use serde::de::{Deserialize, DeserializeOwned};
+#[cfg(feature = "json")]
+use serde_json;
+
 use protocol::OpCode;
 use result::Result;
 
@@ -99,6 +104,28 @@ impl Message {
             Binary(ref data) => Ok(from_utf8(data)?),
         }
     }
+
+    /// Parse the payload of the message as JSON, whether it is a text or a binary message. This
+    /// fails with a `Kind::Json` error if the payload isn't JSON of the type `T`, which closes
+    /// the connection when returned from a handler method, see `Settings::json_close_code`.
+    /// Wrap a handler in a `JsonHandler` to receive every message parsed this way.
+    ///
+    /// ```ignore
+    /// fn on_message(&mut self, msg: Message) -> Result<()> {
+    ///     let request: Request = msg.json()?;
+    ///     self.out.send_json(&self.answer(request))
+    /// }
+    /// ```
+    #[cfg(feature = "json")]
+    pub fn json<T>(&self) -> Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(match *self {
+            Text(ref string) => serde_json::from_str(string)?,
+            Binary(ref data) => serde_json::from_slice(data)?,
+        })
+    }
 }
 
 impl From<String> for Message {
@@ -125,6 +152,142 @@ impl From<Vec<u8>> for Message {
     }
 }
 
+/// A borrowed view of a WebSocket message, as passed to `Handler::on_message_ref`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub enum MessageRef<'a> {
+    /// A text WebSocket message
+    Text(&'a str),
+    /// A binary WebSocket message
+    Binary(&'a [u8]),
+}
+
+impl<'a> MessageRef<'a> {
+    /// Indicates whether a message is a text message.
+    pub fn is_text(&self) -> bool {
+        match *self {
+            MessageRef::Text(_) => true,
+            MessageRef::Binary(_) => false,
+        }
+    }
+
+    /// Indicates whether a message is a binary message.
+    pub fn is_binary(&self) -> bool {
+        match *self {
+            MessageRef::Text(_) => false,
+            MessageRef::Binary(_) => true,
+        }
+    }
+
+    /// Get the length of the WebSocket message.
+    pub fn len(&self) -> usize {
+        self.as_data().len()
+    }
+
+    /// Returns true if the WebSocket message has no content.
+    pub fn is_empty(&self) -> bool {
+        self.as_data().is_empty()
+    }
+
+    /// Get the WebSocket message as binary data.
+    pub fn as_data(&self) -> &'a [u8] {
+        match *self {
+            MessageRef::Text(string) => string.as_bytes(),
+            MessageRef::Binary(data) => data,
+        }
+    }
+
+    /// Attempt to get a &str from the WebSocket message,
+    /// this will try to convert binary data to utf8.
+    pub fn as_text(&self) -> Result<&'a str> {
+        match *self {
+            MessageRef::Text(string) => Ok(string),
+            MessageRef::Binary(data) => Ok(from_utf8(data)?),
+        }
+    }
+
+    /// Parse the payload of the message as JSON, see `Message::json`. The value may borrow
+    /// strings from the payload.
+    #[cfg(feature = "json")]
+    pub fn json<T>(&self) -> Result<T>
+    where
+        T: Deserialize<'a>,
+    {
+        Ok(serde_json::from_slice(self.as_data())?)
+    }
+
+    /// Copy the message into an owned `Message`.
+    pub fn into_owned(self) -> Message {
+        match self {
+            MessageRef::Text(string) => Message::text(string),
+            MessageRef::Binary(data) => Message::binary(data),
+        }
+    }
+}
+
+/// How an incoming message arrived over the wire, as passed to
+/// `Handler::on_message_with_metadata`.
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
+pub struct MessageMetadata {
+    opcode: OpCode,
+    frames: usize,
+    wire_len: usize,
+    compressed: bool,
+}
+
+impl MessageMetadata {
+    #[doc(hidden)]
+    pub fn new(
+        opcode: OpCode,
+        frames: usize,
+        wire_len: usize,
+        compressed: bool,
+    ) -> MessageMetadata {
+        MessageMetadata {
+            opcode,
+            frames,
+            wire_len,
+            compressed,
+        }
+    }
+
+    /// The opcode of the first frame of the message.
+    pub fn opcode(&self) -> OpCode {
+        self.opcode
+    }
+
+    /// The number of frames the message arrived in, not counting control frames sent in between.
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    /// Whether the message arrived in more than one frame.
+    pub fn is_fragmented(&self) -> bool {
+        self.frames > 1
+    }
+
+    /// The combined payload length of the frames of the message, as they arrived. For a
+    /// compressed message this is the compressed size, while the length of the message is the
+    /// uncompressed size.
+    pub fn wire_len(&self) -> usize {
+        self.wire_len
+    }
+
+    /// Whether the first frame of the message has the RSV1 bit set, which the permessage-deflate
+    /// extension uses to mark compressed messages.
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+}
+
+impl<'a> From<&'a Message> for MessageRef<'a> {
+    fn from(msg: &'a Message) -> MessageRef<'a> {
+        match *msg {
+            Text(ref string) => MessageRef::Text(string),
+            Binary(ref data) => MessageRef::Binary(data),
+        }
+    }
+}
+
 impl fmt::Display for Message {
     fn fmt(&self, f: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
         if let Ok(string) = self.as_text() {
@@ -164,6 +327,19 @@ mod test {
         assert!(msg.into_text().is_err());
     }
 
+    #[test]
+    fn message_ref() {
+        let msg = Message::binary(vec![6u8, 7, 8]);
+        let view = MessageRef::from(&msg);
+        assert!(view.is_binary());
+        assert_eq!(view.as_data(), &[6u8, 7, 8][..]);
+        assert_eq!(view.into_owned(), msg);
+
+        let view = MessageRef::Text("kiwotsukete");
+        assert_eq!(view.len(), 11);
+        assert_eq!(view.as_text().unwrap(), "kiwotsukete");
+    }
+
     #[test]
     fn text_convert() {
         let s = "kiwotsukete";
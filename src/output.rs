@@ -0,0 +1,383 @@
+use std::collections::VecDeque;
+use std::io::IoSlice;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use frame::Frame;
+use message::{Message, MessageRef};
+use protocol::OpCode;
+use result::Result;
+
+// Payloads at least this long are written out of the frame they came in rather than copied into
+// the buffer after their header.
+const OWNED_PAYLOAD: usize = 4096;
+
+// A message encoded once into the frames that are written to every connection that it is
+// broadcast to, instead of being copied and encoded for each of them.
+pub struct SharedFrames {
+    data: Arc<[u8]>,
+    frames: usize,
+}
+
+impl SharedFrames {
+    // Encode the message the way a connection does, fragmenting it at the fragment size.
+    pub fn encode(msg: &Message, fragment_size: usize) -> Result<SharedFrames> {
+        let payload = MessageRef::from(msg).as_data();
+        let mut data = Vec::with_capacity(payload.len() + 14);
+        let mut frames = 0;
+        if payload.len() > fragment_size {
+            let mut chunks = payload.chunks(fragment_size).peekable();
+            let mut opcode = msg.opcode();
+            while let Some(chunk) = chunks.next() {
+                let finished = chunks.peek().is_none();
+                Frame::message(Vec::from(chunk), opcode, finished).format(&mut data)?;
+                opcode = OpCode::Continue;
+                frames += 1;
+            }
+        } else {
+            Frame::message(Vec::from(payload), msg.opcode(), true).format(&mut data)?;
+            frames += 1;
+        }
+        Ok(SharedFrames {
+            data: data.into(),
+            frames,
+        })
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    // The number of frames the message was encoded into.
+    #[inline]
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+}
+
+// A buffer of output, either owned by the connection or shared with other connections.
+enum Buffer {
+    Owned(Vec<u8>),
+    Shared(Arc<[u8]>),
+}
+
+impl Buffer {
+    // The buffer as one that can be written to, copying it if it is shared.
+    fn to_mut(&mut self) -> &mut Vec<u8> {
+        if let Buffer::Shared(ref data) = *self {
+            *self = Buffer::Owned(data.to_vec());
+        }
+        match *self {
+            Buffer::Owned(ref mut data) => data,
+            Buffer::Shared(_) => unreachable!(),
+        }
+    }
+
+    fn clear(&mut self) {
+        match *self {
+            Buffer::Owned(ref mut data) => data.clear(),
+            Buffer::Shared(_) => *self = Buffer::Owned(Vec::new()),
+        }
+    }
+}
+
+impl Deref for Buffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Buffer::Owned(ref data) => data,
+            Buffer::Shared(ref data) => data,
+        }
+    }
+}
+
+// The output of a connection that is waiting to be written, as a series of buffers that can be
+// written with a single vectored write.
+//
+// Headers and small frames are copied into the last buffer, large payloads and broadcast
+// messages become buffers of their own, so the header and payload of a frame and any frames
+// queued behind it go out in the same call without copying the payload.
+pub struct OutputBuffer {
+    // the last buffer is always owned, frames are copied into it
+    buffers: VecDeque<Buffer>,
+    // how much of the first buffer has been written
+    position: usize,
+    // the number of bytes that haven't been written
+    len: usize,
+}
+
+impl OutputBuffer {
+    pub fn with_capacity(capacity: usize) -> OutputBuffer {
+        let mut buffers = VecDeque::new();
+        buffers.push_back(Buffer::Owned(Vec::with_capacity(capacity)));
+        OutputBuffer {
+            buffers,
+            position: 0,
+            len: 0,
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn clear(&mut self) {
+        self.buffers.truncate(1);
+        self.buffers[0].clear();
+        self.position = 0;
+        self.len = 0;
+    }
+
+    // Format a frame at the end of the output, returning the length of the frame.
+    pub fn push(&mut self, mut frame: Frame) -> Result<usize> {
+        let len = frame.len();
+        if self.buffers.len() == 1 && self.position > 0 {
+            let buf = self.buffers[0].to_mut();
+            if buf.len() + len > buf.capacity() {
+                // make room at the front rather than growing past the written output
+                buf.drain(..self.position);
+                self.position = 0;
+            }
+        }
+
+        if frame.payload().len() >= OWNED_PAYLOAD {
+            frame.format_head(self.last())?;
+            self.buffers.push_back(Buffer::Owned(frame.into_data()));
+            self.buffers.push_back(Buffer::Owned(Vec::new()));
+        } else {
+            frame.format(self.last())?;
+        }
+        self.len += len;
+        Ok(len)
+    }
+
+    // Queue frames that are shared with other connections at the end of the output.
+    pub fn push_shared(&mut self, frames: &SharedFrames) {
+        self.buffers.push_back(Buffer::Shared(frames.data.clone()));
+        self.buffers.push_back(Buffer::Owned(Vec::new()));
+        self.len += frames.len();
+    }
+
+    #[inline]
+    fn last(&mut self) -> &mut Vec<u8> {
+        self.buffers.back_mut().unwrap().to_mut()
+    }
+
+    // Format a frame into the output at an offset from the first byte that hasn't been written,
+    // which must be the boundary of a frame, returning the length of the frame.
+    pub fn insert(&mut self, at: usize, mut frame: Frame) -> Result<usize> {
+        debug_assert!(at <= self.len);
+        let mut data = Vec::with_capacity(frame.len());
+        frame.format(&mut data)?;
+        let len = data.len();
+
+        let mut offset = at + self.position;
+        let mut index = 0;
+        while offset > self.buffers[index].len() {
+            offset -= self.buffers[index].len();
+            index += 1;
+        }
+        if offset == 0 {
+            self.buffers.insert(index, Buffer::Owned(data));
+        } else if offset == self.buffers[index].len() && index + 1 < self.buffers.len() {
+            // between two buffers, which may be payloads that shouldn't be copied
+            self.buffers.insert(index + 1, Buffer::Owned(data));
+        } else {
+            self.buffers[index].to_mut().splice(offset..offset, data);
+        }
+        self.len += len;
+        Ok(len)
+    }
+
+    // Drop the output after the given number of bytes from the first byte that hasn't been
+    // written, which must be the boundary of a frame.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return;
+        }
+        let mut keep = len + self.position;
+        let mut index = 0;
+        while keep > self.buffers[index].len() {
+            keep -= self.buffers[index].len();
+            index += 1;
+        }
+        self.buffers.truncate(index + 1);
+        if keep < self.buffers[index].len() {
+            self.buffers[index].to_mut().truncate(keep);
+        }
+        if index > 0 {
+            // the last buffer may be a payload, which isn't copied into
+            self.buffers.push_back(Buffer::Owned(Vec::new()));
+        }
+        self.len = len;
+    }
+
+    // Fill the slices with the output from the first byte that hasn't been written, returning
+    // the number of slices that were filled.
+    pub fn slices<'a>(&'a self, slices: &mut [IoSlice<'a>]) -> usize {
+        let mut count = 0;
+        let mut position = self.position;
+        for buf in &self.buffers {
+            if count == slices.len() {
+                break;
+            }
+            if buf.len() > position {
+                slices[count] = IoSlice::new(&buf[position..]);
+                count += 1;
+            }
+            position = 0;
+        }
+        count
+    }
+
+    // Drop the output that has been written.
+    pub fn advance(&mut self, mut written: usize) {
+        debug_assert!(written <= self.len);
+        self.len -= written;
+        while self.buffers.len() > 1 && written >= self.buffers[0].len() - self.position {
+            written -= self.buffers[0].len() - self.position;
+            self.buffers.pop_front();
+            self.position = 0;
+        }
+        self.position += written;
+
+        if self.len == 0 {
+            // start over at the front of the last buffer
+            self.buffers[0].clear();
+            self.position = 0;
+        }
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+    use protocol::OpCode;
+
+    fn output(buffer: &OutputBuffer) -> Vec<u8> {
+        let mut slices = [IoSlice::new(&[]); 16];
+        let count = buffer.slices(&mut slices);
+        slices[..count].iter().flat_map(|slice| slice.iter().cloned()).collect()
+    }
+
+    fn slices(buffer: &OutputBuffer) -> usize {
+        buffer.slices(&mut [IoSlice::new(&[]); 16])
+    }
+
+    fn formatted(mut frame: Frame) -> Vec<u8> {
+        let mut buf = Vec::new();
+        frame.format(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn large_payloads_are_not_copied() {
+        let mut buffer = OutputBuffer::with_capacity(1024);
+        let small = Frame::message(vec![1; 10], OpCode::Binary, true);
+        let large = Frame::message(vec![2; OWNED_PAYLOAD], OpCode::Binary, true);
+        buffer.push(small.clone()).unwrap();
+        buffer.push(large.clone()).unwrap();
+        buffer.push(small.clone()).unwrap();
+
+        // the header and small frames before the payload, the payload and the small frame after
+        assert_eq!(slices(&buffer), 3);
+        let mut expected = formatted(small.clone());
+        expected.extend(formatted(large));
+        expected.extend(formatted(small));
+        assert_eq!(buffer.len(), expected.len());
+        assert_eq!(output(&buffer), expected);
+    }
+
+    #[test]
+    fn advance_across_buffers() {
+        let mut buffer = OutputBuffer::with_capacity(1024);
+        let large = Frame::message(vec![2; OWNED_PAYLOAD], OpCode::Binary, true);
+        buffer.push(large.clone()).unwrap();
+        buffer.push(Frame::ping(vec![3])).unwrap();
+        let expected = output(&buffer);
+
+        buffer.advance(2);
+        assert_eq!(output(&buffer), &expected[2..]);
+        buffer.advance(100);
+        assert_eq!(slices(&buffer), 2);
+        assert_eq!(output(&buffer), &expected[102..]);
+        buffer.advance(expected.len() - 102);
+        assert!(buffer.is_empty());
+        assert_eq!(slices(&buffer), 0);
+        assert_eq!(output(&buffer), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn insert_between_payloads() {
+        let mut buffer = OutputBuffer::with_capacity(1024);
+        let large = Frame::message(vec![2; OWNED_PAYLOAD], OpCode::Binary, true);
+        let head = formatted(large.clone()).len() - OWNED_PAYLOAD;
+        buffer.push(large.clone()).unwrap();
+        buffer.push(large.clone()).unwrap();
+
+        // after the first frame, which ends with the first payload
+        buffer.insert(head + OWNED_PAYLOAD, Frame::pong(vec![3])).unwrap();
+        let mut expected = formatted(large.clone());
+        expected.extend(formatted(Frame::pong(vec![3])));
+        expected.extend(formatted(large));
+        assert_eq!(output(&buffer), expected);
+    }
+
+    #[test]
+    fn truncate_after_payload() {
+        let mut buffer = OutputBuffer::with_capacity(1024);
+        let large = Frame::message(vec![2; OWNED_PAYLOAD], OpCode::Binary, true);
+        let small = Frame::message(vec![1; 10], OpCode::Binary, true);
+        buffer.push(large.clone()).unwrap();
+        buffer.push(large.clone()).unwrap();
+        buffer.advance(1);
+
+        let len = formatted(large.clone()).len();
+        buffer.truncate(len - 1);
+        buffer.push(small.clone()).unwrap();
+        let mut expected = formatted(large);
+        expected.extend(formatted(small));
+        assert_eq!(buffer.len(), expected.len() - 1);
+        assert_eq!(output(&buffer), &expected[1..]);
+    }
+
+    #[test]
+    fn encode_fragments() {
+        let msg = Message::binary(vec![5; 10]);
+        let frames = SharedFrames::encode(&msg, 4).unwrap();
+        assert_eq!(frames.frames(), 3);
+
+        let mut expected = formatted(Frame::message(vec![5; 4], OpCode::Binary, false));
+        expected.extend(formatted(Frame::message(vec![5; 4], OpCode::Continue, false)));
+        expected.extend(formatted(Frame::message(vec![5; 2], OpCode::Continue, true)));
+        assert_eq!(&frames.data[..], &expected[..]);
+    }
+
+    #[test]
+    fn shared_frames_around_control_frames() {
+        let frames = SharedFrames::encode(&Message::text("shared"), 1024).unwrap();
+        let mut buffer = OutputBuffer::with_capacity(1024);
+        buffer.push_shared(&frames);
+        buffer.insert(0, Frame::pong(vec![1])).unwrap();
+        let pong = formatted(Frame::pong(vec![1])).len();
+        buffer.insert(pong + frames.len(), Frame::pong(vec![2])).unwrap();
+
+        let mut expected = formatted(Frame::pong(vec![1]));
+        expected.extend(&frames.data[..]);
+        expected.extend(formatted(Frame::pong(vec![2])));
+        assert_eq!(output(&buffer), expected);
+
+        // the frames after the first pong are dropped, the shared ones only from this output
+        buffer.truncate(pong);
+        assert_eq!(output(&buffer), &expected[..pong]);
+        assert_eq!(&frames.data[..], &expected[pong..pong + frames.len()]);
+    }
+}
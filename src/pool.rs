@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use mio::Token;
+use url;
+
+use communication::Sender;
+use factory::Factory;
+use handler::Handler;
+use handshake::Handshake;
+use message::Message;
+use protocol::CloseCode;
+use result::{Error, Kind, Result};
+use Builder;
+
+// The idle connections of a pool by the url they are connected to.
+type Idle = Arc<Mutex<HashMap<String, Vec<Lease>>>>;
+
+// The state of a pooled connection, shared by its handler and whoever has checked it out.
+#[derive(Default)]
+struct Link {
+    messages: Option<mpsc::Sender<Message>>,
+    pongs: Option<mpsc::Sender<()>>,
+    open: bool,
+    // Counts the times the connection was returned to the pool, so that an idle timeout can tell
+    // whether the connection has been used since it was scheduled.
+    returned: usize,
+    idle: bool,
+}
+
+type Shared = Arc<Mutex<Link>>;
+
+// A handler that panicked while holding the lock doesn't leave the link in an inconsistent state.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// An open connection, as handed from its handler to the pool.
+struct Lease {
+    out: Sender,
+    link: Shared,
+}
+
+/// Keeps client connections open after they have been used, so that later requests to the same
+/// url can reuse them instead of connecting, and reestablishing TLS, again.
+///
+/// Each connection runs on a thread of its own. A connection returns to the pool when the
+/// `PooledConnection` that was checked out is dropped, and it is closed once it has been idle in
+/// the pool for longer than the idle TTL. Before an idle connection is handed out again, a ping
+/// verifies that the server is still there.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// use ws::{Builder, ClientPool};
+///
+/// let pool = ClientPool::new(&Builder::new(), Duration::from_secs(30));
+/// let url = "ws://127.0.0.1:3012".parse().unwrap();
+/// for _ in 0..10 {
+///     // all the requests share a single connection
+///     let conn = pool.checkout(&url, Duration::from_secs(5)).unwrap();
+///     conn.send("time").unwrap();
+///     println!("The time is {}", conn.recv(Duration::from_secs(5)).unwrap());
+/// }
+/// ```
+pub struct ClientPool {
+    builder: Builder,
+    idle_ttl: Duration,
+    idle: Idle,
+}
+
+impl ClientPool {
+    /// Create a ClientPool that opens connections with the settings of the builder, closing the
+    /// connections that have been idle for longer than `idle_ttl`.
+    pub fn new(builder: &Builder, idle_ttl: Duration) -> ClientPool {
+        ClientPool {
+            builder: builder.clone(),
+            idle_ttl,
+            idle: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Get a connection to the url, reusing an idle connection if there is one that still
+    /// answers a ping, or else opening a new one.
+    ///
+    /// This blocks until the connection is ready, failing with a timeout error if validating and
+    /// opening the connection takes longer than `timeout`.
+    pub fn checkout(&self, url: &url::Url, timeout: Duration) -> Result<PooledConnection> {
+        let deadline = Instant::now() + timeout;
+        let key = url.as_str().to_owned();
+
+        loop {
+            let lease = match lock(&self.idle).get_mut(&key).and_then(Vec::pop) {
+                Some(lease) => lease,
+                None => break,
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if let Some(conn) = self.validate(&key, lease, remaining) {
+                return Ok(conn);
+            }
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        self.open(url, key, remaining)
+    }
+
+    /// The number of idle connections in the pool.
+    pub fn idle(&self) -> usize {
+        lock(&self.idle).values().map(Vec::len).sum()
+    }
+
+    // Hand out an idle connection if it answers a ping in time, and close it otherwise.
+    fn validate(&self, key: &str, lease: Lease, timeout: Duration) -> Option<PooledConnection> {
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut link = lock(&lease.link);
+            if !link.open {
+                return None;
+            }
+            link.idle = false;
+            link.pongs = Some(tx);
+        }
+
+        if lease.out.ping(b"ws-rs pool".to_vec()).is_ok() && rx.recv_timeout(timeout).is_ok() {
+            let (tx, rx) = mpsc::channel();
+            {
+                let mut link = lock(&lease.link);
+                link.pongs = None;
+                link.messages = Some(tx);
+            }
+            Some(self.lease(key.into(), lease, rx))
+        } else {
+            debug!("Closing pooled connection that didn't answer a ping.");
+            lock(&lease.link).pongs = None;
+            let _ = lease.out.close(CloseCode::Away);
+            None
+        }
+    }
+
+    fn open(&self, url: &url::Url, key: String, timeout: Duration) -> Result<PooledConnection> {
+        let (tx, rx) = mpsc::channel();
+        let mut ws = self.builder.build(PoolFactory {
+            waiter: Some(tx),
+            key: key.clone(),
+            idle: self.idle.clone(),
+        })?;
+        ws.connect(url.clone())?;
+        thread::spawn(move || {
+            if let Err(err) = ws.run() {
+                error!("Pooled connection failed: {:?}", err);
+            }
+        });
+
+        match rx.recv_timeout(timeout) {
+            Ok(Ok((lease, messages))) => Ok(self.lease(key, lease, messages)),
+            Ok(Err(err)) => Err(err),
+            Err(_) => Err(Error::new(
+                Kind::Timeout,
+                format!("Unable to open a connection to {} in time.", url),
+            )),
+        }
+    }
+
+    fn lease(&self, key: String, lease: Lease, rx: mpsc::Receiver<Message>) -> PooledConnection {
+        PooledConnection {
+            key,
+            out: lease.out,
+            link: lease.link,
+            rx,
+            idle: self.idle.clone(),
+            idle_ttl: self.idle_ttl,
+            discard: false,
+        }
+    }
+}
+
+impl Drop for ClientPool {
+    fn drop(&mut self) {
+        for (_, leases) in lock(&self.idle).drain() {
+            for lease in leases {
+                let _ = lease.out.close(CloseCode::Away);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ClientPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ClientPool {{ idle_ttl: {:?}, idle: {} }}",
+            self.idle_ttl,
+            self.idle()
+        )
+    }
+}
+
+/// A connection checked out of a `ClientPool`. Dropping it returns the connection to the pool,
+/// unless the connection was closed.
+pub struct PooledConnection {
+    key: String,
+    out: Sender,
+    link: Shared,
+    rx: mpsc::Receiver<Message>,
+    idle: Idle,
+    idle_ttl: Duration,
+    discard: bool,
+}
+
+impl PooledConnection {
+    /// The sender of the connection.
+    pub fn sender(&self) -> &Sender {
+        &self.out
+    }
+
+    /// Send a message over the connection.
+    pub fn send<M>(&self, msg: M) -> Result<()>
+    where
+        M: Into<Message>,
+    {
+        self.out.send(msg)
+    }
+
+    /// Block until the next message arrives, failing with a timeout error if it doesn't arrive
+    /// in time, and with an io error if the connection is closed.
+    pub fn recv(&self, timeout: Duration) -> Result<Message> {
+        self.rx.recv_timeout(timeout).map_err(|err| match err {
+            mpsc::RecvTimeoutError::Timeout => {
+                Error::new(Kind::Timeout, "No message arrived in time.")
+            }
+            mpsc::RecvTimeoutError::Disconnected => Error::from(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "The pooled connection was closed.",
+            )),
+        })
+    }
+
+    /// Whether the connection is still open.
+    pub fn is_open(&self) -> bool {
+        lock(&self.link).open
+    }
+
+    /// Close the connection instead of returning it to the pool.
+    pub fn close(mut self, code: CloseCode) -> Result<()> {
+        self.discard = true;
+        self.out.close(code)
+    }
+}
+
+impl Drop for PooledConnection {
+    fn drop(&mut self) {
+        if self.discard {
+            return;
+        }
+        let returned = {
+            let mut link = lock(&self.link);
+            if !link.open {
+                return;
+            }
+            // messages that arrive while the connection is idle are dropped
+            link.messages = None;
+            link.idle = true;
+            link.returned += 1;
+            link.returned
+        };
+
+        let ttl = self.idle_ttl.as_secs() * 1000 + u64::from(self.idle_ttl.subsec_millis());
+        if self.out.timeout(ttl, Token(returned)).is_err() {
+            return;
+        }
+        lock(&self.idle)
+            .entry(self.key.clone())
+            .or_default()
+            .push(Lease {
+                out: self.out.clone(),
+                link: self.link.clone(),
+            });
+    }
+}
+
+impl fmt::Debug for PooledConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PooledConnection {{ url: {}, out: {:?} }}", self.key, self.out)
+    }
+}
+
+type Waiter = mpsc::Sender<Result<(Lease, mpsc::Receiver<Message>)>>;
+
+struct PoolFactory {
+    waiter: Option<Waiter>,
+    key: String,
+    idle: Idle,
+}
+
+impl Factory for PoolFactory {
+    type Handler = PoolHandler;
+
+    fn connection_made(&mut self, out: Sender) -> PoolHandler {
+        PoolHandler {
+            out,
+            waiter: self.waiter.take(),
+            key: self.key.clone(),
+            link: Arc::new(Mutex::new(Link::default())),
+            idle: self.idle.clone(),
+        }
+    }
+}
+
+struct PoolHandler {
+    out: Sender,
+    // Whoever is waiting for the connection to open.
+    waiter: Option<Waiter>,
+    key: String,
+    link: Shared,
+    idle: Idle,
+}
+
+impl PoolHandler {
+    fn closed(&mut self) {
+        {
+            let mut link = lock(&self.link);
+            link.open = false;
+            link.messages = None;
+            link.pongs = None;
+        }
+        if let Some(leases) = lock(&self.idle).get_mut(&self.key) {
+            leases.retain(|lease| lease.out != self.out);
+        }
+    }
+}
+
+impl Handler for PoolHandler {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut link = lock(&self.link);
+            link.open = true;
+            link.messages = Some(tx);
+        }
+        let lease = Lease {
+            out: self.out.clone(),
+            link: self.link.clone(),
+        };
+        match self.waiter.take() {
+            Some(waiter) if waiter.send(Ok((lease, rx))).is_ok() => Ok(()),
+            // the checkout gave up waiting
+            _ => self.out.close(CloseCode::Away),
+        }
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        if let Some(ref messages) = lock(&self.link).messages {
+            let _ = messages.send(msg);
+        }
+        Ok(())
+    }
+
+    fn on_pong(&mut self, _: &[u8]) -> Result<()> {
+        if let Some(ref pongs) = lock(&self.link).pongs {
+            let _ = pongs.send(());
+        }
+        Ok(())
+    }
+
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        let expired = {
+            let link = lock(&self.link);
+            link.idle && link.returned == event.0
+        };
+        if expired {
+            debug!("Closing pooled connection that was idle for too long.");
+            self.closed();
+            self.out.close(CloseCode::Away)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn on_close(&mut self, _: CloseCode, _: &str) {
+        self.closed()
+    }
+
+    fn on_error(&mut self, err: Error) {
+        self.closed();
+        match self.waiter.take() {
+            Some(waiter) => {
+                let _ = waiter.send(Err(err));
+            }
+            None => debug!("Pooled connection encountered an error: {:?}", err),
+        }
+    }
+}
+
+impl Drop for PoolHandler {
+    fn drop(&mut self) {
+        if let Some(waiter) = self.waiter.take() {
+            let _ = waiter.send(Err(Error::new(
+                Kind::Internal,
+                "The connection was lost before it opened.",
+            )));
+        }
+        self.closed();
+    }
+}
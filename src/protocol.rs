@@ -19,6 +19,10 @@ pub enum OpCode {
     Pong,
     /// Indicates an invalid opcode was received.
     Bad,
+    /// A reserved opcode, 3-7 for non-control frames and 11-15 for control frames, which is only
+    /// used for frames sent with `Sender::send_custom`. Frames with reserved opcodes that are
+    /// received are still rejected as `Bad`.
+    Reserved(u8),
 }
 
 impl OpCode {
@@ -26,9 +30,18 @@ impl OpCode {
     pub fn is_control(&self) -> bool {
         match *self {
             Text | Binary | Continue => false,
+            Reserved(byte) => byte >= 8,
             _ => true,
         }
     }
+
+    /// Test whether the opcode is one of the opcodes reserved for further frame types.
+    pub fn is_reserved(&self) -> bool {
+        match *self {
+            Reserved(_) => true,
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for OpCode {
@@ -41,6 +54,7 @@ impl fmt::Display for OpCode {
             Ping => write!(f, "PING"),
             Pong => write!(f, "PONG"),
             Bad => write!(f, "BAD"),
+            Reserved(byte) => write!(f, "RESERVED({})", byte),
         }
     }
 }
@@ -54,6 +68,7 @@ impl Into<u8> for OpCode {
             Close => 8,
             Ping => 9,
             Pong => 10,
+            Reserved(byte) => byte,
             Bad => {
                 debug_assert!(
                     false,
@@ -145,10 +160,27 @@ pub enum CloseCode {
     Tls,
     #[doc(hidden)]
     Empty,
-    #[doc(hidden)]
+    /// Any other close code. The range 3000-3999 is reserved for libraries, frameworks and
+    /// applications registered with IANA, and the range 4000-4999 is reserved for private use
+    /// by applications, for example `CloseCode::Other(4001)` for an expired session.
     Other(u16),
 }
 
+impl CloseCode {
+    /// Test whether the close code may be sent in a close frame. `Status`, `Abnormal` and `Tls`
+    /// only describe a closure to the handler, and codes below 1000, above 4999 or not assigned
+    /// in the range 1000-2999 must not be used at all. An endpoint that receives such a code
+    /// fails the connection.
+    pub fn is_allowed(&self) -> bool {
+        match *self {
+            Status | Abnormal | Tls | Empty => false,
+            // 1014 is registered for gateways that received an invalid response
+            Other(code) => code == 1014 || (3000..5000).contains(&code),
+            _ => true,
+        }
+    }
+}
+
 impl Into<u16> for CloseCode {
     fn into(self) -> u16 {
         match self {
@@ -224,4 +256,22 @@ mod test {
         let byte: u16 = text.into();
         assert_eq!(byte, 1001u16);
     }
+
+    #[test]
+    fn closecode_allowed() {
+        for &code in &[1000, 1001, 1003, 1011, 1012, 1013, 1014, 3000, 4999] {
+            assert!(CloseCode::from(code).is_allowed(), "{} is not allowed", code);
+        }
+        for &code in &[0, 999, 1004, 1005, 1006, 1015, 1016, 2999, 5000] {
+            assert!(!CloseCode::from(code).is_allowed(), "{} is allowed", code);
+        }
+    }
+
+    #[test]
+    fn closecode_other_roundtrip() {
+        let code = CloseCode::from(4001u16);
+        assert_eq!(code, CloseCode::Other(4001));
+        let byte: u16 = code.into();
+        assert_eq!(byte, 4001u16);
+    }
 }
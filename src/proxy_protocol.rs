@@ -0,0 +1,231 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::from_utf8;
+
+use result::{Error, Kind, Result};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// The longest possible v1 header, including the line ending.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The header that a load balancer speaking the PROXY protocol sends before the data of the
+/// connection, telling the server which client the connection was accepted from.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ProxyHeader {
+    /// The length of the header, after which the data of the connection begins.
+    pub len: usize,
+    /// The address of the client. This is `None` for connections that the proxy made on its own
+    /// behalf, such as health checks, and for addresses the header couldn't describe.
+    pub source: Option<SocketAddr>,
+}
+
+impl ProxyHeader {
+    /// Attempt to parse a PROXY protocol header of either version from the start of a buffer. If
+    /// the buffer does not contain the complete header yet, this will return `Ok(None)`.
+    pub fn parse(buf: &[u8]) -> Result<Option<ProxyHeader>> {
+        if starts_with(buf, V2_SIGNATURE) {
+            if buf.len() < V2_SIGNATURE.len() {
+                return Ok(None);
+            }
+            parse_v2(buf)
+        } else if starts_with(buf, V1_PREFIX) {
+            if buf.len() < V1_PREFIX.len() {
+                return Ok(None);
+            }
+            parse_v1(buf)
+        } else {
+            Err(Error::new(
+                Kind::Protocol,
+                "Connection did not begin with a PROXY protocol header.",
+            ))
+        }
+    }
+}
+
+// Whether the buffer starts with the prefix, or could once more data arrives.
+fn starts_with(buf: &[u8], prefix: &[u8]) -> bool {
+    let len = buf.len().min(prefix.len());
+    buf[..len] == prefix[..len]
+}
+
+fn parse_v1(buf: &[u8]) -> Result<Option<ProxyHeader>> {
+    let end = match buf.iter().take(V1_MAX_LEN).position(|&b| b == b'\n') {
+        Some(end) => end,
+        None if buf.len() < V1_MAX_LEN => return Ok(None),
+        None => {
+            return Err(Error::new(
+                Kind::Protocol,
+                "PROXY protocol header is too long.",
+            ))
+        }
+    };
+    let invalid = || Error::new(Kind::Protocol, "Invalid PROXY protocol v1 header.");
+
+    let line = &buf[..end];
+    if !line.ends_with(b"\r") {
+        return Err(invalid());
+    }
+    let line = from_utf8(&line[..line.len() - 1]).map_err(|_| invalid())?;
+    let mut fields = line.split(' ').skip(1);
+
+    let source = match fields.next() {
+        // the remainder of the line is to be ignored for unknown protocols
+        Some("UNKNOWN") => None,
+        Some(family @ "TCP4") | Some(family @ "TCP6") => {
+            let mut next = || fields.next().ok_or_else(invalid);
+            let src: IpAddr = next()?.parse().map_err(|_| invalid())?;
+            let _dst: IpAddr = next()?.parse().map_err(|_| invalid())?;
+            let src_port: u16 = next()?.parse().map_err(|_| invalid())?;
+            let _dst_port: u16 = next()?.parse().map_err(|_| invalid())?;
+            if fields.next().is_some() || src.is_ipv4() != (family == "TCP4") {
+                return Err(invalid());
+            }
+            Some(SocketAddr::new(src, src_port))
+        }
+        _ => return Err(invalid()),
+    };
+
+    Ok(Some(ProxyHeader {
+        len: end + 1,
+        source,
+    }))
+}
+
+fn parse_v2(buf: &[u8]) -> Result<Option<ProxyHeader>> {
+    const FIXED_LEN: usize = 16;
+    if buf.len() < FIXED_LEN {
+        return Ok(None);
+    }
+    let invalid = || Error::new(Kind::Protocol, "Invalid PROXY protocol v2 header.");
+
+    let version_command = buf[12];
+    let family = buf[13];
+    let addr_len = usize::from(u16::from_be_bytes([buf[14], buf[15]]));
+    if version_command >> 4 != 2 {
+        return Err(invalid());
+    }
+    let len = FIXED_LEN + addr_len;
+    if buf.len() < len {
+        return Ok(None);
+    }
+    let addr = &buf[FIXED_LEN..len];
+    let port = |at: usize| u16::from_be_bytes([addr[at], addr[at + 1]]);
+
+    let source = match version_command & 0x0F {
+        // LOCAL, the proxy connected on its own behalf
+        0 => None,
+        // PROXY
+        1 => match family {
+            // TCP over IPv4
+            0x11 => {
+                if addr.len() < 12 {
+                    return Err(invalid());
+                }
+                let ip = Ipv4Addr::new(addr[0], addr[1], addr[2], addr[3]);
+                Some(SocketAddr::new(IpAddr::V4(ip), port(8)))
+            }
+            // TCP over IPv6
+            0x21 => {
+                if addr.len() < 36 {
+                    return Err(invalid());
+                }
+                let mut octets = [0; 16];
+                octets.copy_from_slice(&addr[..16]);
+                Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port(32)))
+            }
+            // UNSPEC, UDP or unix sockets, which don't describe a TCP client
+            _ => None,
+        },
+        _ => return Err(invalid()),
+    };
+
+    Ok(Some(ProxyHeader { len, source }))
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    fn parse(buf: &[u8]) -> Option<ProxyHeader> {
+        ProxyHeader::parse(buf).unwrap()
+    }
+
+    #[test]
+    fn v1() {
+        let header = b"PROXY TCP4 192.168.0.1 192.168.0.11 56324 443\r\nGET /";
+        assert_eq!(
+            parse(header),
+            Some(ProxyHeader {
+                len: 47,
+                source: Some("192.168.0.1:56324".parse().unwrap()),
+            })
+        );
+        assert_eq!(parse(&header[..20]), None);
+        assert_eq!(parse(b"PRO"), None);
+
+        let header = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 80\r\n";
+        assert_eq!(
+            parse(header).unwrap().source,
+            Some("[2001:db8::1]:4000".parse().unwrap())
+        );
+
+        let header = b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\n";
+        assert_eq!(
+            parse(header),
+            Some(ProxyHeader {
+                len: header.len(),
+                source: None,
+            })
+        );
+
+        assert!(ProxyHeader::parse(b"PROXY TCP4 ::1 ::1 1 2\r\n").is_err());
+        assert!(ProxyHeader::parse(b"PROXY TCP4 1.1.1.1 2.2.2.2 1\r\n").is_err());
+        assert!(ProxyHeader::parse(b"PROXY TCP4 1.1.1.1 2.2.2.2 1 2\n").is_err());
+        assert!(ProxyHeader::parse(b"GET / HTTP/1.1\r\n").is_err());
+        assert!(ProxyHeader::parse(&[b'A'; V1_MAX_LEN]).is_err());
+    }
+
+    #[test]
+    fn v2() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend(&[0x21, 0x11, 0, 12]);
+        header.extend(&[10, 0, 0, 1, 10, 0, 0, 2, 0x1F, 0x90, 0, 80]);
+        header.extend(b"GET /");
+        assert_eq!(
+            parse(&header),
+            Some(ProxyHeader {
+                len: 28,
+                source: Some("10.0.0.1:8080".parse().unwrap()),
+            })
+        );
+        assert_eq!(parse(&header[..20]), None);
+        assert_eq!(parse(&header[..5]), None);
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend(&[0x21, 0x21, 0, 36]);
+        header.extend(&[0x20, 0x01, 0x0d, 0xb8]);
+        header.extend(&[0; 11]);
+        header.push(1);
+        header.extend(&[0; 16]);
+        header.extend(&[0, 1, 0, 2]);
+        assert_eq!(
+            parse(&header).unwrap().source,
+            Some("[2001:db8::1]:1".parse().unwrap())
+        );
+
+        // a health check from the proxy itself, with a TLV that is skipped
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend(&[0x20, 0x00, 0, 4, 0x04, 0, 1, 0]);
+        assert_eq!(
+            parse(&header),
+            Some(ProxyHeader {
+                len: 20,
+                source: None,
+            })
+        );
+
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend(&[0x11, 0x11, 0, 0]);
+        assert!(ProxyHeader::parse(&header).is_err());
+    }
+}
@@ -12,6 +12,10 @@ use mio;
 use openssl::ssl::{Error as SslError, HandshakeError as SslHandshakeError};
 #[cfg(feature = "nativetls")]
 use native_tls::{Error as SslError, HandshakeError as SslHandshakeError};
+#[cfg(feature = "tls-rustls")]
+use rustls::Error as SslError;
+#[cfg(feature = "json")]
+use serde_json;
 #[cfg(any(feature = "ssl", feature = "nativetls"))]
 type HandshakeError = SslHandshakeError<mio::tcp::TcpStream>;
 
@@ -46,6 +50,9 @@ pub enum Kind {
     /// This kind of error should only occur during a WebSocket Handshake, and a HTTP 500 response
     /// will be generated.
     Http(httparse::Error),
+    /// Indicates that the WebSocket handshake didn't complete within `Settings::handshake_timeout`
+    /// or `Settings::connect_timeout`. The connection is dropped.
+    Timeout,
     /// Indicates a failure to send a signal on the internal EventLoop channel. This means that
     /// the WebSocket is overloaded. In order to avoid this error, it is important to set
     /// `Settings::max_connections` and `Settings:queue_size` high enough to handle the load.
@@ -53,11 +60,17 @@ pub enum Kind {
     /// the queue may relieve the situation.
     Queue(mio::channel::SendError<Command>),
     /// Indicates a failure to perform SSL encryption.
-    #[cfg(any(feature = "ssl", feature = "nativetls"))]
+    #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
     Ssl(SslError),
     /// Indicates a failure to perform SSL encryption.
     #[cfg(any(feature = "ssl", feature = "nativetls"))]
     SslHandshake(HandshakeError),
+    /// Indicates a failure to parse a message as JSON, or to serialize a value to JSON.
+    /// If a handler method returns this error, the WebSocket will automatically attempt to send
+    /// the close code of `Settings::json_close_code`, Invalid Frame Payload Data (1007) by
+    /// default.
+    #[cfg(feature = "json")]
+    Json(serde_json::Error),
     /// A custom error kind for use by applications. This error kind involves extra overhead
     /// because it will allocate the memory on the heap. The WebSocket ignores such errors by
     /// default, simply passing them to the Connection Handler.
@@ -118,10 +131,13 @@ impl StdError for Error {
             Kind::Encoding(ref err) => err.description(),
             Kind::Io(ref err) => err.description(),
             Kind::Http(_) => "Unable to parse HTTP",
-            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            Kind::Timeout => "WebSocket Timeout",
+            #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
             Kind::Ssl(ref err) => err.description(),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Kind::SslHandshake(ref err) => err.description(),
+            #[cfg(feature = "json")]
+            Kind::Json(_) => "Invalid JSON",
             Kind::Queue(_) => "Unable to send signal on event loop",
             Kind::Custom(ref err) => err.description(),
         }
@@ -131,10 +147,12 @@ impl StdError for Error {
         match self.kind {
             Kind::Encoding(ref err) => Some(err),
             Kind::Io(ref err) => Some(err),
-            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            #[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
             Kind::Ssl(ref err) => Some(err),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Kind::SslHandshake(ref err) => err.cause(),
+            #[cfg(feature = "json")]
+            Kind::Json(ref err) => Some(err),
             Kind::Custom(ref err) => Some(err.as_ref()),
             _ => None,
         }
@@ -180,7 +198,7 @@ impl From<Utf8Error> for Error {
     }
 }
 
-#[cfg(any(feature = "ssl", feature = "nativetls"))]
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
 impl From<SslError> for Error {
     fn from(err: SslError) -> Error {
         Error::new(Kind::Ssl(err), "")
@@ -194,6 +212,15 @@ impl From<HandshakeError> for Error {
     }
 }
 
+#[cfg(feature = "json")]
+impl From<serde_json::Error> for Error {
+    fn from(err: serde_json::Error) -> Error {
+        // the position of the error tells what is wrong with a message
+        let details = err.to_string();
+        Error::new(Kind::Json(err), details)
+    }
+}
+
 impl<B> From<Box<B>> for Error
 where
     B: StdError + Send + Sync + 'static,
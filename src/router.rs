@@ -0,0 +1,377 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use url;
+
+use communication::{AckToken, Sender};
+use factory::Factory;
+use frame::{FragmentState, Frame};
+use handler::{Handler, PingAction};
+use handshake::{Handshake, Request, Response};
+use message::{Message, MessageMetadata, MessageRef};
+use protocol::{CloseCode, OpCode};
+use result::{Error, Result};
+use util::{Timeout, Token};
+
+// A factory whose handlers are boxed, so that routes may use different handler types.
+trait RouteFactory {
+    fn server_connected(&mut self, ws: Sender) -> Box<dyn Handler + Send>;
+    fn on_shutdown(&mut self);
+    fn on_drain_start(&mut self);
+}
+
+impl<F> RouteFactory for F
+where
+    F: Factory,
+    F::Handler: Send + 'static,
+{
+    fn server_connected(&mut self, ws: Sender) -> Box<dyn Handler + Send> {
+        Box::new(Factory::server_connected(self, ws))
+    }
+
+    fn on_shutdown(&mut self) {
+        Factory::on_shutdown(self)
+    }
+
+    fn on_drain_start(&mut self) {
+        Factory::on_drain_start(self)
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route {
+    pattern: Vec<Segment>,
+    factory: Box<dyn RouteFactory + Send>,
+}
+
+impl Route {
+    // Match the path of a resource against the pattern, returning the captured parameters.
+    fn matches(&self, resource: &str) -> Option<HashMap<String, String>> {
+        let path = resource.split('?').next().unwrap_or("");
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        if segments.len() != self.pattern.len() {
+            return None;
+        }
+
+        let mut params = HashMap::new();
+        for (segment, expected) in segments.iter().zip(self.pattern.iter()) {
+            match *expected {
+                Segment::Literal(ref literal) if literal == segment => (),
+                Segment::Param(ref name) if !segment.is_empty() => {
+                    params.insert(name.clone(), segment.to_string());
+                }
+                _ => return None,
+            }
+        }
+        Some(params)
+    }
+}
+
+/// A factory that hands each server connection to the factory of the route matching the
+/// resource of its handshake request.
+///
+/// Routes are tried in the order in which they were added. A segment of a pattern that starts
+/// with `:` matches any non-empty segment of the path, which is then available to the routed
+/// handler through `Handshake::param`. The query string is ignored when matching. Handshake
+/// requests that no route matches are answered with a 404 response.
+///
+/// ```no_run
+/// use ws::{Handler, Handshake, Result, Router, Sender};
+///
+/// struct Chat {
+///     out: Sender,
+/// }
+///
+/// impl Handler for Chat {
+///     fn on_open(&mut self, shake: Handshake) -> Result<()> {
+///         self.out.send(format!("Welcome to {}", shake.param("room").unwrap()))
+///     }
+/// }
+///
+/// let router = Router::new()
+///     .route("/chat/:room", |out| Chat { out })
+///     .route("/echo", |out: Sender| move |msg| out.send(msg));
+/// ws::WebSocket::new(router).unwrap().listen("127.0.0.1:3012").unwrap();
+/// ```
+///
+/// A connection is only routed once its handshake request arrives, so the methods of the
+/// `Handler` that run before that, such as the ones that set up encrypted connections, use
+/// their default implementations. Client connections are never routed. The handlers of closed
+/// connections are dropped instead of being passed to `Factory::connection_lost`.
+#[derive(Default)]
+pub struct Router {
+    routes: Arc<Mutex<Vec<Route>>>,
+}
+
+impl Router {
+    /// Create a router without any routes.
+    pub fn new() -> Router {
+        Router::default()
+    }
+
+    /// Add a route that hands the connections whose resource matches the pattern to the
+    /// factory.
+    pub fn route<F>(self, pattern: &str, factory: F) -> Router
+    where
+        F: Factory + Send + 'static,
+        F::Handler: Send + 'static,
+    {
+        let pattern = pattern
+            .trim_start_matches('/')
+            .split('/')
+            .map(|segment| {
+                if let Some(name) = segment.strip_prefix(':') {
+                    Segment::Param(name.into())
+                } else {
+                    Segment::Literal(segment.into())
+                }
+            })
+            .collect();
+        self.lock().push(Route {
+            pattern,
+            factory: Box::new(factory),
+        });
+        self
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<Route>> {
+        lock(&self.routes)
+    }
+}
+
+// A route factory that panicked doesn't leave the routes in an inconsistent state.
+fn lock(routes: &Mutex<Vec<Route>>) -> MutexGuard<'_, Vec<Route>> {
+    routes.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl fmt::Debug for Router {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Router {{ routes: {} }}", self.lock().len())
+    }
+}
+
+impl Factory for Router {
+    type Handler = RouterHandler;
+
+    fn connection_made(&mut self, out: Sender) -> RouterHandler {
+        RouterHandler {
+            out,
+            routes: self.routes.clone(),
+            inner: Box::new(Unrouted),
+            params: HashMap::new(),
+        }
+    }
+
+    fn on_shutdown(&mut self) {
+        for route in self.lock().iter_mut() {
+            route.factory.on_shutdown();
+        }
+    }
+
+    fn on_drain_start(&mut self) {
+        for route in self.lock().iter_mut() {
+            route.factory.on_drain_start();
+        }
+    }
+}
+
+// The handler of a connection that hasn't been routed yet.
+struct Unrouted;
+
+impl Handler for Unrouted {}
+
+/// The handler of a connection accepted by a `Router`, which passes the events of the
+/// connection on to the handler of its route.
+pub struct RouterHandler {
+    out: Sender,
+    routes: Arc<Mutex<Vec<Route>>>,
+    inner: Box<dyn Handler + Send>,
+    params: HashMap<String, String>,
+}
+
+impl fmt::Debug for RouterHandler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "RouterHandler {{ out: {:?}, params: {:?} }}",
+            self.out, self.params
+        )
+    }
+}
+
+impl Handler for RouterHandler {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let routed = {
+            let out = &self.out;
+            lock(&self.routes).iter_mut().find_map(|route| {
+                route
+                    .matches(req.resource())
+                    .map(|params| (route.factory.server_connected(out.clone()), params))
+            })
+        };
+
+        match routed {
+            Some((inner, params)) => {
+                self.inner = inner;
+                self.params = params;
+                // the handler of the route didn't exist when the request arrived
+                self.inner.on_handshake_request(req);
+                self.inner.on_request(req)
+            }
+            None => {
+                debug!("No route for resource {}.", req.resource());
+                Ok(Response::new(
+                    404,
+                    "Not Found",
+                    b"No route for the requested resource.".to_vec(),
+                ))
+            }
+        }
+    }
+
+    #[inline]
+    fn on_open(&mut self, mut shake: Handshake) -> Result<()> {
+        shake.set_params(self.params.clone());
+        self.inner.on_open(shake)
+    }
+
+    #[inline]
+    fn on_shutdown(&mut self) {
+        self.inner.on_shutdown()
+    }
+
+    #[inline]
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.inner.on_message(msg)
+    }
+
+    #[inline]
+    fn on_message_ref(&mut self, msg: MessageRef) -> Result<bool> {
+        self.inner.on_message_ref(msg)
+    }
+
+    #[inline]
+    fn on_message_with_metadata(&mut self, msg: Message, metadata: MessageMetadata) -> Result<()> {
+        self.inner.on_message_with_metadata(msg, metadata)
+    }
+
+    #[inline]
+    fn on_message_start(&mut self, opcode: OpCode) -> Result<()> {
+        self.inner.on_message_start(opcode)
+    }
+
+    #[inline]
+    fn on_message_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.inner.on_message_chunk(data)
+    }
+
+    #[inline]
+    fn on_message_end(&mut self) -> Result<()> {
+        self.inner.on_message_end()
+    }
+
+    #[inline]
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.inner.on_close(code, reason)
+    }
+
+    #[inline]
+    fn on_ping(&mut self, data: &[u8]) -> Result<PingAction> {
+        self.inner.on_ping(data)
+    }
+
+    #[inline]
+    fn on_pong(&mut self, data: &[u8]) -> Result<()> {
+        self.inner.on_pong(data)
+    }
+
+    #[inline]
+    fn on_buffer_drained(&mut self) {
+        self.inner.on_buffer_drained()
+    }
+
+    #[inline]
+    fn on_sent(&mut self, ack: AckToken) {
+        self.inner.on_sent(ack)
+    }
+
+    #[inline]
+    fn on_reconnect_attempt(&mut self, attempt: u32) {
+        self.inner.on_reconnect_attempt(attempt)
+    }
+
+    #[inline]
+    fn on_error(&mut self, err: Error) {
+        self.inner.on_error(err)
+    }
+
+    #[inline]
+    fn on_response(&mut self, res: &Response) -> Result<()> {
+        self.inner.on_response(res)
+    }
+
+    #[inline]
+    fn on_timeout(&mut self, event: Token) -> Result<()> {
+        self.inner.on_timeout(event)
+    }
+
+    #[inline]
+    fn on_new_timeout(&mut self, event: Token, timeout: Timeout) -> Result<()> {
+        self.inner.on_new_timeout(event, timeout)
+    }
+
+    #[inline]
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.inner.on_frame(frame)
+    }
+
+    #[inline]
+    fn on_frame_with_state(&mut self, frame: Frame, state: FragmentState) -> Result<Option<Frame>> {
+        self.inner.on_frame_with_state(frame, state)
+    }
+
+    #[inline]
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.inner.on_send_frame(frame)
+    }
+
+    #[inline]
+    fn share_broadcasts(&self) -> bool {
+        self.inner.share_broadcasts()
+    }
+
+    #[inline]
+    fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+        self.inner.build_request(url)
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    fn params(pattern: &str, resource: &str) -> Option<HashMap<String, String>> {
+        let router = Router::new().route(pattern, |_| Unrouted);
+        let routes = router.lock();
+        routes[0].matches(resource)
+    }
+
+    #[test]
+    fn route_matching() {
+        assert_eq!(params("/echo", "/echo"), Some(HashMap::new()));
+        assert_eq!(params("/echo", "/echo?token=abc"), Some(HashMap::new()));
+        assert_eq!(params("/echo", "/echo/more"), None);
+        assert_eq!(params("/echo", "/"), None);
+        assert_eq!(params("/", "/"), Some(HashMap::new()));
+
+        let room = params("/chat/:room", "/chat/rust?token=abc").unwrap();
+        assert_eq!(room.get("room").map(String::as_str), Some("rust"));
+        assert_eq!(params("/chat/:room", "/chat/"), None);
+        assert_eq!(params("/chat/:room", "/notifications/rust"), None);
+    }
+}
@@ -0,0 +1,250 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use communication::Sender;
+use message::Message;
+use result::{Error, Kind, Result};
+
+// The calls waiting for a reply by id, numbered so that a call that has already been answered
+// can't forget a later call with the same id.
+#[derive(Default)]
+struct Calls {
+    next: u64,
+    waiting: HashMap<String, (u64, mpsc::Sender<Message>)>,
+}
+
+type Pending = Arc<Mutex<Calls>>;
+
+type ReadId = dyn Fn(&Message) -> Option<String> + Send + Sync;
+
+// A handler that panicked while holding the lock doesn't leave the calls in an inconsistent state.
+fn lock(pending: &Pending) -> MutexGuard<'_, Calls> {
+    pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Sends requests over a connection and matches the replies to them by a correlation id, for
+/// protocols where each request is answered by a message that carries the id of the request.
+///
+/// The id is read from both the requests and the replies with the function passed to `new`.
+/// Pass the incoming messages of the connection to `resolve` from `Handler::on_message`, which
+/// hands the replies to the waiting callers and returns the other messages.
+///
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// use ws::{Handler, Handshake, Message, Result, RpcSender, Sender};
+///
+/// // messages look like "<id>:<payload>"
+/// fn id(msg: &Message) -> Option<String> {
+///     msg.as_text().ok()?.split_once(':').map(|(id, _)| id.into())
+/// }
+///
+/// struct Client {
+///     rpc: RpcSender,
+/// }
+///
+/// impl Handler for Client {
+///     fn on_open(&mut self, _: Handshake) -> Result<()> {
+///         let rpc = self.rpc.clone();
+///         // waiting for a reply blocks, so it mustn't happen on the event loop thread
+///         thread::spawn(move || {
+///             let reply = rpc.call("1:time").unwrap().wait(Duration::from_secs(5));
+///             println!("The time is {}", reply.unwrap());
+///         });
+///         Ok(())
+///     }
+///
+///     fn on_message(&mut self, msg: Message) -> Result<()> {
+///         if let Some(msg) = self.rpc.resolve(msg) {
+///             println!("Received a message that isn't a reply: {}", msg);
+///         }
+///         Ok(())
+///     }
+/// }
+///
+/// ws::connect("ws://127.0.0.1:3012", |out: Sender| Client {
+///     rpc: RpcSender::new(out, id),
+/// }).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct RpcSender {
+    out: Sender,
+    id: Arc<ReadId>,
+    pending: Pending,
+}
+
+impl RpcSender {
+    /// Create an `RpcSender` that sends requests over a connection, using a function that reads
+    /// the correlation id of a message.
+    pub fn new<F>(out: Sender, id: F) -> RpcSender
+    where
+        F: Fn(&Message) -> Option<String> + Send + Sync + 'static,
+    {
+        RpcSender {
+            out,
+            id: Arc::new(id),
+            pending: Arc::new(Mutex::new(Calls::default())),
+        }
+    }
+
+    /// Send a request, returning the reply that can be waited for.
+    ///
+    /// This fails with an internal error if the request has no id, or if a call with the same id
+    /// is still waiting for its reply.
+    pub fn call<M>(&self, msg: M) -> Result<Reply>
+    where
+        M: Into<Message>,
+    {
+        let msg = msg.into();
+        let id = (self.id)(&msg)
+            .ok_or_else(|| Error::new(Kind::Internal, "The request has no correlation id."))?;
+
+        let (tx, rx) = mpsc::channel();
+        let call = {
+            let mut calls = lock(&self.pending);
+            if calls.waiting.contains_key(&id) {
+                return Err(Error::new(
+                    Kind::Internal,
+                    format!("A call with the correlation id {} is already pending.", id),
+                ));
+            }
+            let call = calls.next;
+            calls.next += 1;
+            calls.waiting.insert(id.clone(), (call, tx));
+            call
+        };
+
+        let reply = Reply {
+            id,
+            call,
+            rx,
+            pending: self.pending.clone(),
+        };
+        // the reply removes the call again if the request can't be sent
+        self.out.send(msg)?;
+        Ok(reply)
+    }
+
+    /// Hand a reply to the call waiting for it. Messages that aren't the reply to a pending call
+    /// are returned so that they can be handled as usual.
+    pub fn resolve(&self, msg: Message) -> Option<Message> {
+        let waiting = (self.id)(&msg).and_then(|id| lock(&self.pending).waiting.remove(&id));
+        match waiting {
+            // the caller may have stopped waiting in the meantime
+            Some((_, tx)) => {
+                let _ = tx.send(msg);
+                None
+            }
+            None => Some(msg),
+        }
+    }
+
+    /// The number of calls that are waiting for their reply.
+    pub fn pending(&self) -> usize {
+        lock(&self.pending).waiting.len()
+    }
+}
+
+impl fmt::Debug for RpcSender {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "RpcSender {{ out: {:?}, pending: {} }}",
+            self.out,
+            self.pending()
+        )
+    }
+}
+
+/// The reply to a call made with `RpcSender::call`. Dropping it stops waiting for the reply.
+pub struct Reply {
+    id: String,
+    call: u64,
+    rx: mpsc::Receiver<Message>,
+    pending: Pending,
+}
+
+impl Reply {
+    /// The correlation id of the call.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Block until the reply arrives, failing with a timeout error if it doesn't arrive in time.
+    ///
+    /// This must not be called from a handler, because the reply is only received once the
+    /// handler returns to the event loop.
+    pub fn wait(self, timeout: Duration) -> Result<Message> {
+        self.rx.recv_timeout(timeout).map_err(|_| {
+            Error::new(
+                Kind::Timeout,
+                format!("No reply to the call with the correlation id {}.", self.id),
+            )
+        })
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        let mut calls = lock(&self.pending);
+        if calls.waiting.get(&self.id).map(|&(call, _)| call) == Some(self.call) {
+            calls.waiting.remove(&self.id);
+        }
+    }
+}
+
+impl fmt::Debug for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Reply {{ id: {:?} }}", self.id)
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    use mio;
+    use mio::Token;
+
+    fn id(msg: &Message) -> Option<String> {
+        msg.as_text().ok()?.split_once(':').map(|(id, _)| id.into())
+    }
+
+    #[test]
+    fn correlate_replies() {
+        let (tx, rx) = mio::channel::sync_channel(10);
+        let rpc = RpcSender::new(Sender::new(Token(0), tx, 0), id);
+
+        let first = rpc.call("1:first").unwrap();
+        let second = rpc.call("2:second").unwrap();
+        assert!(rpc.call("1:again").is_err());
+        assert!(rpc.call("no id").is_err());
+        assert_eq!(rpc.pending(), 2);
+
+        assert_eq!(rpc.resolve("2:reply".into()), None);
+        assert_eq!(rpc.resolve("3:unrelated".into()), Some("3:unrelated".into()));
+        let reply = second.wait(Duration::from_secs(1)).unwrap();
+        assert_eq!(reply, Message::from("2:reply"));
+        assert_eq!(rpc.pending(), 1);
+
+        // the call is forgotten once it times out
+        assert!(first.wait(Duration::from_millis(10)).is_err());
+        assert_eq!(rpc.pending(), 0);
+        assert_eq!(rpc.resolve("1:late".into()), Some("1:late".into()));
+
+        // dropping the reply forgets the call too
+        drop(rpc.call("4:dropped").unwrap());
+        assert_eq!(rpc.pending(), 0);
+
+        // an answered call doesn't forget a later call with the same id
+        let answered = rpc.call("5:first").unwrap();
+        assert_eq!(rpc.resolve("5:reply".into()), None);
+        let later = rpc.call("5:second").unwrap();
+        drop(answered);
+        assert_eq!(rpc.pending(), 1);
+    }
+}
@@ -1,18 +1,30 @@
+#[cfg(any(feature = "ssl", feature = "nativetls"))]
+use std::cmp;
 use std::io;
 use std::io::ErrorKind::WouldBlock;
+use std::io::IoSlice;
 #[cfg(any(feature = "ssl", feature = "nativetls"))]
 use std::mem::replace;
 use std::net::SocketAddr;
 
 use bytes::{Buf, BufMut};
+use iovec::IoVec;
 use mio::tcp::TcpStream;
+use mio::{Evented, Poll, PollOpt, Ready, Token};
+#[cfg(unix)]
+use mio_uds::UnixStream;
 #[cfg(feature = "nativetls")]
 use native_tls::{
     HandshakeError, MidHandshakeTlsStream as MidHandshakeSslStream, TlsStream as SslStream,
 };
 #[cfg(feature = "ssl")]
 use openssl::ssl::{ErrorCode as SslErrorCode, HandshakeError, MidHandshakeSslStream, SslStream};
+#[cfg(feature = "ssl")]
+use openssl::x509::X509NameRef;
+#[cfg(feature = "tls-rustls")]
+use rustls::{Connection as RustlsConnection, ProtocolVersion};
 
+use handshake::TlsInfo;
 use result::{Error, Kind, Result};
 
 fn map_non_block<T>(res: io::Result<T>) -> io::Result<Option<T>> {
@@ -63,16 +75,134 @@ pub trait TryWriteBuf: io::Write {
 
         res
     }
+
+    fn try_write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<Option<usize>>
+    where
+        Self: Sized,
+    {
+        map_non_block(self.write_vectored(bufs))
+    }
+}
+
+// The largest record a TLS stream writes at once.
+#[cfg(any(feature = "ssl", feature = "nativetls"))]
+const TLS_RECORD: usize = 16384;
+
+// The slices as mio takes them for a vectored write, which can't be empty.
+fn io_vecs<'a>(bufs: &'a [IoSlice]) -> Vec<&'a IoVec> {
+    bufs.iter().filter_map(|buf| IoVec::from_bytes(buf)).collect()
+}
+
+// Write the slices to a stream that encrypts each write into a record of its own, copying small
+// slices into one record instead of sending a record for each.
+#[cfg(any(feature = "ssl", feature = "nativetls"))]
+fn write_coalesced<W: io::Write>(w: &mut W, bufs: &[IoSlice]) -> io::Result<usize> {
+    let first = bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |buf| &buf[..]);
+    if first.len() >= TLS_RECORD {
+        return w.write(first);
+    }
+    let mut record = Vec::with_capacity(TLS_RECORD);
+    for buf in bufs {
+        let len = cmp::min(buf.len(), TLS_RECORD - record.len());
+        record.extend_from_slice(&buf[..len]);
+    }
+    w.write(&record)
+}
+
+#[cfg(unix)]
+fn no_ip_addr() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        "Unix domain sockets do not have an ip address",
+    )
+}
+
+// Format the entries of a certificate name the way OpenSSL prints them on one line.
+#[cfg(feature = "ssl")]
+fn subject_line(name: &X509NameRef) -> String {
+    name.entries()
+        .map(|entry| {
+            let key = entry.object().nid().short_name().unwrap_or("UNDEF");
+            match entry.data().as_utf8() {
+                Ok(value) => format!("{}={}", key, value),
+                Err(_) => key.into(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn no_addr() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        "Streams provided by the user do not have an address",
+    )
 }
 
 impl<T: io::Read> TryReadBuf for T {}
 impl<T: io::Write> TryWriteBuf for T {}
 
+/// A stream that a connection can run over.
+pub trait ReadWrite: io::Read + io::Write + Send {}
+
+impl<T: io::Read + io::Write + Send> ReadWrite for T {}
+
+// A stream provided by the user, which mio can't poll. Reads on it may block, so only one is
+// made each time the connection is given the chance to read.
+pub struct CustomStream {
+    sock: Box<dyn ReadWrite>,
+    can_read: bool,
+}
+
+impl io::Read for CustomStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.can_read {
+            return Err(io::Error::new(WouldBlock, "Already read from the stream"));
+        }
+        self.can_read = false;
+        self.sock.read(buf)
+    }
+}
+
+impl io::Write for CustomStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sock.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.sock.write_vectored(bufs)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+// Registering the stream does nothing, it is driven without being polled.
+impl Evented for CustomStream {
+    fn register(&self, _: &Poll, _: Token, _: Ready, _: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reregister(&self, _: &Poll, _: Token, _: Ready, _: PollOpt) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn deregister(&self, _: &Poll) -> io::Result<()> {
+        Ok(())
+    }
+}
+
 use self::Stream::*;
 pub enum Stream {
     Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
     #[cfg(any(feature = "ssl", feature = "nativetls"))]
     Tls(TlsStream),
+    #[cfg(feature = "tls-rustls")]
+    Rustls(RustlsStream<TcpStream>),
+    Custom(CustomStream),
 }
 
 impl Stream {
@@ -80,6 +210,28 @@ impl Stream {
         Tcp(stream)
     }
 
+    #[cfg(unix)]
+    pub fn unix(stream: UnixStream) -> Stream {
+        Unix(stream)
+    }
+
+    pub fn custom<S>(stream: S) -> Stream
+    where
+        S: ReadWrite + 'static,
+    {
+        Custom(CustomStream {
+            sock: Box::new(stream),
+            can_read: false,
+        })
+    }
+
+    // Allow the next read from a stream provided by the user.
+    pub fn allow_read(&mut self) {
+        if let Custom(ref mut sock) = *self {
+            sock.can_read = true;
+        }
+    }
+
     #[cfg(any(feature = "ssl", feature = "nativetls"))]
     pub fn tls(stream: MidHandshakeSslStream<TcpStream>) -> Stream {
         Tls(TlsStream::Handshake {
@@ -93,27 +245,73 @@ impl Stream {
         Tls(TlsStream::Live(stream))
     }
 
+    #[cfg(feature = "tls-rustls")]
+    pub fn tls_live(stream: RustlsStream<TcpStream>) -> Stream {
+        Rustls(stream)
+    }
+
     #[cfg(any(feature = "ssl", feature = "nativetls"))]
     pub fn is_tls(&self) -> bool {
         match *self {
-            Tcp(_) => false,
             Tls(_) => true,
+            _ => false,
         }
     }
 
-    pub fn evented(&self) -> &TcpStream {
+    #[cfg(feature = "tls-rustls")]
+    pub fn is_tls(&self) -> bool {
+        match *self {
+            Rustls(_) => true,
+            _ => false,
+        }
+    }
+
+    pub fn tcp_stream(&self) -> Option<&TcpStream> {
+        match *self {
+            Tcp(ref sock) => Some(sock),
+            #[cfg(unix)]
+            Unix(_) => None,
+            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            Tls(ref inner) => Some(inner.evented()),
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref inner) => Some(inner.get_ref()),
+            Custom(_) => None,
+        }
+    }
+
+    pub fn evented(&self) -> &dyn Evented {
         match *self {
             Tcp(ref sock) => sock,
+            #[cfg(unix)]
+            Unix(ref sock) => sock,
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(ref inner) => inner.evented(),
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref inner) => inner.get_ref(),
+            Custom(ref sock) => sock,
         }
     }
 
     pub fn is_negotiating(&self) -> bool {
         match *self {
             Tcp(_) => false,
+            #[cfg(unix)]
+            Unix(_) => false,
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(ref inner) => inner.is_negotiating(),
+            // rustls drives the handshake from any read or write
+            #[cfg(feature = "tls-rustls")]
+            Rustls(_) => false,
+            Custom(_) => false,
+        }
+    }
+
+    // Whether encrypted data is waiting for the socket to become writable again.
+    pub fn wants_write(&self) -> bool {
+        match *self {
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref inner) => inner.conn.wants_write(),
+            _ => false,
         }
     }
 
@@ -123,24 +321,92 @@ impl Stream {
                 Kind::Internal,
                 "Attempted to clear negotiating flag on non ssl connection.",
             )),
+            #[cfg(unix)]
+            Unix(_) => Err(Error::new(
+                Kind::Internal,
+                "Attempted to clear negotiating flag on non ssl connection.",
+            )),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(ref mut inner) => inner.clear_negotiating(),
+            #[cfg(feature = "tls-rustls")]
+            Rustls(_) => Err(Error::new(
+                Kind::Internal,
+                "Attempted to clear negotiating flag on rustls connection.",
+            )),
+            Custom(_) => Err(Error::new(
+                Kind::Internal,
+                "Attempted to clear negotiating flag on non ssl connection.",
+            )),
+        }
+    }
+
+    // Whether the other endpoint presented a certificate during the TLS handshake.
+    pub fn has_peer_certificate(&self) -> bool {
+        match *self {
+            #[cfg(feature = "ssl")]
+            Tls(TlsStream::Live(ref sock)) => sock.ssl().peer_certificate().is_some(),
+            #[cfg(feature = "nativetls")]
+            Tls(TlsStream::Live(ref sock)) => match sock.peer_certificate() {
+                Ok(cert) => cert.is_some(),
+                Err(_) => false,
+            },
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref sock) => sock.conn.peer_certificates().is_some(),
+            _ => false,
+        }
+    }
+
+    // The details of the TLS session, once the TLS handshake has completed.
+    pub fn tls_info(&self) -> Option<TlsInfo> {
+        match *self {
+            #[cfg(feature = "ssl")]
+            Tls(TlsStream::Live(ref sock)) => {
+                let ssl = sock.ssl();
+                Some(TlsInfo {
+                    version: ssl.version_str().into(),
+                    cipher: ssl.current_cipher()?.name().into(),
+                    peer_subject: ssl
+                        .peer_certificate()
+                        .map(|cert| subject_line(cert.subject_name())),
+                })
+            }
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref sock) => Some(TlsInfo {
+                version: match sock.conn.protocol_version()? {
+                    ProtocolVersion::TLSv1_2 => "TLSv1.2".into(),
+                    ProtocolVersion::TLSv1_3 => "TLSv1.3".into(),
+                    version => format!("{:?}", version),
+                },
+                cipher: format!("{:?}", sock.conn.negotiated_cipher_suite()?.suite()),
+                peer_subject: None,
+            }),
+            _ => None,
         }
     }
 
     pub fn peer_addr(&self) -> io::Result<SocketAddr> {
         match *self {
             Tcp(ref sock) => sock.peer_addr(),
+            #[cfg(unix)]
+            Unix(_) => Err(no_ip_addr()),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(ref inner) => inner.peer_addr(),
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref inner) => inner.get_ref().peer_addr(),
+            Custom(_) => Err(no_addr()),
         }
     }
 
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         match *self {
             Tcp(ref sock) => sock.local_addr(),
+            #[cfg(unix)]
+            Unix(_) => Err(no_ip_addr()),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(ref inner) => inner.local_addr(),
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref inner) => inner.get_ref().local_addr(),
+            Custom(_) => Err(no_addr()),
         }
     }
 }
@@ -149,8 +415,13 @@ impl io::Read for Stream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match *self {
             Tcp(ref mut sock) => sock.read(buf),
+            #[cfg(unix)]
+            Unix(ref mut sock) => sock.read(buf),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(TlsStream::Live(ref mut sock)) => sock.read(buf),
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref mut sock) => sock.read(buf),
+            Custom(ref mut sock) => sock.read(buf),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(ref mut tls_stream) => {
                 trace!("Attempting to read ssl handshake.");
@@ -217,8 +488,13 @@ impl io::Write for Stream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
         match *self {
             Tcp(ref mut sock) => sock.write(buf),
+            #[cfg(unix)]
+            Unix(ref mut sock) => sock.write(buf),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(TlsStream::Live(ref mut sock)) => sock.write(buf),
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref mut sock) => sock.write(buf),
+            Custom(ref mut sock) => sock.write(buf),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(ref mut tls_stream) => {
                 trace!("Attempting to write ssl handshake.");
@@ -282,15 +558,42 @@ impl io::Write for Stream {
         }
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        match *self {
+            // mio writes the slices of its sockets with writev, but doesn't do so from
+            // `write_vectored`
+            Tcp(ref sock) => match io_vecs(bufs) {
+                ref vecs if vecs.is_empty() => Ok(0),
+                vecs => sock.write_bufs(&vecs),
+            },
+            #[cfg(unix)]
+            Unix(ref sock) => match io_vecs(bufs) {
+                ref vecs if vecs.is_empty() => Ok(0),
+                vecs => sock.write_bufs(&vecs),
+            },
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref mut sock) => sock.write_vectored(bufs),
+            Custom(ref mut sock) => sock.write_vectored(bufs),
+            // a stream that is still negotiating completes the handshake in `write`
+            #[cfg(any(feature = "ssl", feature = "nativetls"))]
+            Tls(_) => write_coalesced(self, bufs),
+        }
+    }
+
     fn flush(&mut self) -> io::Result<()> {
         match *self {
             Tcp(ref mut sock) => sock.flush(),
+            #[cfg(unix)]
+            Unix(ref mut sock) => sock.flush(),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(TlsStream::Live(ref mut sock)) => sock.flush(),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(TlsStream::Handshake { ref mut sock, .. }) => sock.get_mut().flush(),
             #[cfg(any(feature = "ssl", feature = "nativetls"))]
             Tls(TlsStream::Upgrading) => panic!("Tried to access actively upgrading TlsStream"),
+            #[cfg(feature = "tls-rustls")]
+            Rustls(ref mut sock) => sock.flush(),
+            Custom(ref mut sock) => sock.flush(),
         }
     }
 }
@@ -356,3 +659,106 @@ impl TlsStream {
         }
     }
 }
+
+/// A non-blocking stream encrypted by rustls.
+///
+/// The TLS handshake is performed as the stream is read from and written to, so a new stream
+/// can be used right away.
+#[cfg(feature = "tls-rustls")]
+pub struct RustlsStream<S> {
+    // boxed to keep the size of a `Stream` close to that of a socket
+    conn: Box<RustlsConnection>,
+    sock: S,
+}
+
+#[cfg(feature = "tls-rustls")]
+impl<S: io::Read + io::Write> RustlsStream<S> {
+    /// Wrap a socket with a rustls client or server connection.
+    pub fn new<C: Into<RustlsConnection>>(conn: C, sock: S) -> RustlsStream<S> {
+        RustlsStream {
+            conn: Box::new(conn.into()),
+            sock,
+        }
+    }
+
+    /// Get a reference to the rustls connection.
+    pub fn get_connection(&self) -> &RustlsConnection {
+        &self.conn
+    }
+
+    /// Get a reference to the underlying socket.
+    pub fn get_ref(&self) -> &S {
+        &self.sock
+    }
+
+    // Write out as much encrypted data as the socket will take, the rest is written once the
+    // socket becomes writable again.
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            if map_non_block(self.conn.write_tls(&mut self.sock))?.is_none() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "tls-rustls")]
+impl<S: io::Read + io::Write> io::Read for RustlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.conn.reader().read(buf) {
+                Err(ref err) if err.kind() == WouldBlock => (),
+                res => return res,
+            }
+
+            if self.conn.read_tls(&mut self.sock)? == 0 {
+                return Ok(0);
+            }
+            let processed = self.conn.process_new_packets();
+            // alerts and handshake messages may need to be sent in response
+            self.write_tls()?;
+            if let Err(err) = processed {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, err));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "tls-rustls")]
+impl<S: io::Read + io::Write> io::Write for RustlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_tls()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = self.conn.writer().write(buf)?;
+        self.write_tls()?;
+        if len == 0 {
+            // the buffer limit of the connection has been reached
+            Err(io::Error::new(WouldBlock, "TLS buffer is full"))
+        } else {
+            Ok(len)
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        self.write_tls()?;
+        if bufs.iter().all(|buf| buf.is_empty()) {
+            return Ok(0);
+        }
+        let len = self.conn.writer().write_vectored(bufs)?;
+        self.write_tls()?;
+        if len == 0 {
+            // the buffer limit of the connection has been reached
+            Err(io::Error::new(WouldBlock, "TLS buffer is full"))
+        } else {
+            Ok(len)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_tls()?;
+        self.sock.flush()
+    }
+}
@@ -0,0 +1,271 @@
+//! Tools for unit-testing handlers without sockets or an event loop.
+//!
+//! A `MockConnection` drives a handler through the events of a connection, while its
+//! `MockSender` records everything the handler sends so that it can be asserted upon.
+//!
+//! ```
+//! use ws::test::{MockConnection, Sent};
+//! use ws::{CloseCode, Handler, Message, Result, Sender};
+//!
+//! struct Echo {
+//!     out: Sender,
+//! }
+//!
+//! impl Handler for Echo {
+//!     fn on_message(&mut self, msg: Message) -> Result<()> {
+//!         if msg.as_text()? == "bye" {
+//!             self.out.close(CloseCode::Normal)
+//!         } else {
+//!             self.out.send(msg)
+//!         }
+//!     }
+//! }
+//!
+//! let mut conn = MockConnection::new(|out| Echo { out });
+//! conn.open().unwrap();
+//! conn.message("hello").unwrap();
+//! assert_eq!(conn.sender().messages(), vec![Message::from("hello")]);
+//!
+//! conn.message("bye").unwrap();
+//! match conn.sender().sent().as_slice() {
+//!     [Sent::Close(CloseCode::Normal, _)] => (),
+//!     sent => panic!("Unexpected output {:?}", sent),
+//! }
+//! ```
+
+use std::time::Duration;
+
+use mio;
+use mio::Token;
+use url;
+
+use communication::{Command, Sender, Signal};
+use frame::Frame;
+use handler::Handler;
+use handshake::{Handshake, Request, Response};
+use io::ALL;
+use message::Message;
+use protocol::CloseCode;
+use result::{Error, Result};
+
+// The number of sends that can be recorded between two calls of `MockSender::sent`.
+const QUEUE_SIZE: usize = 1024;
+
+/// Something that was sent through the `Sender` of a `MockSender`.
+#[derive(Debug)]
+pub enum Sent {
+    /// A message for the other endpoint, from `Sender::send`.
+    Message(Message),
+    /// A message for several connections, from `Sender::broadcast` or
+    /// `Sender::broadcast_filter`.
+    Broadcast(Message),
+    /// A frame for the other endpoint, from `Sender::send_frame`, `Sender::send_custom` or a
+    /// `MessageWriter`.
+    Frame(Frame),
+    /// A close code and reason, from `Sender::close` or `Sender::close_with_reason`.
+    Close(CloseCode, String),
+    /// A close code and reason for the selected connections, from `Sender::close_where`.
+    CloseWhere(CloseCode, String),
+    /// The data of a ping, from `Sender::ping`.
+    Ping(Vec<u8>),
+    /// The data of a pong, from `Sender::pong`.
+    Pong(Vec<u8>),
+    /// A deferred handshake response, from `Sender::complete_handshake`.
+    Handshake(Response),
+    /// A url to open a new connection to, from `Sender::connect`.
+    Connect(url::Url),
+    /// A request to shut down the WebSocket, with the timeout for a graceful shutdown if one was
+    /// given.
+    Shutdown(Option<Duration>),
+    /// A request to start draining the WebSocket, with the longest time to drain.
+    Drain(Duration),
+    /// A timeout scheduled with `Sender::timeout`.
+    Timeout {
+        /// The delay in milliseconds.
+        delay: u64,
+        /// The token the timeout was scheduled with.
+        token: Token,
+    },
+    /// A timeout cancelled with `Sender::cancel`.
+    Cancel,
+}
+
+/// A `Sender` that records what is sent through it instead of passing it to an event loop.
+pub struct MockSender {
+    sender: Sender,
+    rx: mio::channel::Receiver<Command>,
+}
+
+impl MockSender {
+    /// Create a MockSender.
+    pub fn new() -> MockSender {
+        let (tx, rx) = mio::channel::sync_channel(QUEUE_SIZE);
+        MockSender {
+            sender: Sender::new(Token(0), tx, 0),
+            rx,
+        }
+    }
+
+    /// Get a `Sender` whose output is recorded. Sending blocks once 1024 sends are waiting to be
+    /// taken with `sent`.
+    pub fn sender(&self) -> Sender {
+        self.sender.clone()
+    }
+
+    /// Take everything that was sent since the last call, in the order it was sent.
+    pub fn sent(&self) -> Vec<Sent> {
+        let mut sent = Vec::new();
+        while let Ok(cmd) = self.rx.try_recv() {
+            let broadcast = cmd.token() == ALL;
+            sent.push(match cmd.into_signal() {
+                Signal::Message(msg, _, _) if broadcast => Sent::Broadcast(msg),
+                Signal::Message(msg, _, _) | Signal::Routed(msg) => Sent::Message(msg),
+                Signal::Broadcast(msg, _, _) => Sent::Broadcast(msg),
+                Signal::Fragment(frame) | Signal::Frame(frame) => Sent::Frame(frame),
+                Signal::Handshake(response) => Sent::Handshake(response),
+                Signal::Close(code, reason) => Sent::Close(code, reason.into_owned()),
+                Signal::CloseWhere(_, code, reason) => Sent::CloseWhere(code, reason.into_owned()),
+                Signal::Ping(data) => Sent::Ping(data),
+                Signal::Pong(data) => Sent::Pong(data),
+                Signal::Connect(url) => Sent::Connect(url),
+                Signal::Shutdown => Sent::Shutdown(None),
+                Signal::ShutdownGraceful(timeout) => Sent::Shutdown(Some(timeout)),
+                Signal::Drain(max) => Sent::Drain(max),
+                Signal::Timeout { delay, token } => Sent::Timeout { delay, token },
+                Signal::Cancel(_) => Sent::Cancel,
+            });
+        }
+        sent
+    }
+
+    /// Take everything that was sent since the last call like `sent`, keeping only the messages
+    /// for the other endpoint.
+    pub fn messages(&self) -> Vec<Message> {
+        self.sent()
+            .into_iter()
+            .filter_map(|sent| match sent {
+                Sent::Message(msg) => Some(msg),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+impl Default for MockSender {
+    fn default() -> MockSender {
+        MockSender::new()
+    }
+}
+
+/// Drives a handler through the events of a connection without a socket. The handler is built
+/// from a `Sender` whose output is recorded by the `MockSender` of the connection.
+///
+/// The mock doesn't implement the protocol, so sending a close frame doesn't call `on_close`,
+/// and messages are handed to the handler whether or not the connection was opened.
+pub struct MockConnection<H: Handler> {
+    handler: H,
+    out: MockSender,
+}
+
+impl<H: Handler> MockConnection<H> {
+    /// Create a MockConnection, building the handler like a `Factory` would.
+    pub fn new<F>(factory: F) -> MockConnection<H>
+    where
+        F: FnOnce(Sender) -> H,
+    {
+        let out = MockSender::new();
+        MockConnection {
+            handler: factory(out.sender()),
+            out,
+        }
+    }
+
+    /// Get the handler of the connection.
+    pub fn handler(&mut self) -> &mut H {
+        &mut self.handler
+    }
+
+    /// Get the MockSender that records the output of the handler.
+    pub fn sender(&self) -> &MockSender {
+        &self.out
+    }
+
+    /// Open the connection with the handshake of a client connecting to `ws://127.0.0.1/`.
+    pub fn open(&mut self) -> Result<()> {
+        let url = url::Url::parse("ws://127.0.0.1/").unwrap();
+        let request = Request::from_url(&url)?;
+        let response = Response::from_request(&request)?;
+        self.open_with(Handshake::new(request, response, None, None))
+    }
+
+    /// Open the connection with the given handshake.
+    pub fn open_with(&mut self, shake: Handshake) -> Result<()> {
+        self.handler.on_open(shake)
+    }
+
+    /// Hand a message from the other endpoint to the handler.
+    pub fn message<M>(&mut self, msg: M) -> Result<()>
+    where
+        M: Into<Message>,
+    {
+        self.handler.on_message(msg.into())
+    }
+
+    /// Close the connection as if the other endpoint had sent a close frame.
+    pub fn close(&mut self, code: CloseCode, reason: &str) {
+        self.handler.on_close(code, reason)
+    }
+
+    /// Hand an error to the handler.
+    pub fn error(&mut self, err: Error) {
+        self.handler.on_error(err)
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code, clippy::module_inception)]
+    use super::*;
+
+    use std::sync::mpsc;
+
+    struct Chat {
+        out: Sender,
+        closed: mpsc::Sender<(CloseCode, String)>,
+    }
+
+    impl Handler for Chat {
+        fn on_open(&mut self, _: Handshake) -> Result<()> {
+            self.out.send("welcome")
+        }
+
+        fn on_message(&mut self, msg: Message) -> Result<()> {
+            self.out.broadcast(msg)?;
+            self.out.ping(b"still there?".to_vec())
+        }
+
+        fn on_close(&mut self, code: CloseCode, reason: &str) {
+            self.closed.send((code, reason.into())).unwrap();
+        }
+    }
+
+    #[test]
+    fn record_output() {
+        let (tx, rx) = mpsc::channel();
+        let mut conn = MockConnection::new(|out| Chat { out, closed: tx });
+        conn.open().unwrap();
+        assert_eq!(conn.sender().messages(), vec![Message::from("welcome")]);
+
+        conn.message("hi all").unwrap();
+        match conn.sender().sent().as_slice() {
+            [Sent::Broadcast(msg), Sent::Ping(data)] => {
+                assert_eq!(msg, &Message::from("hi all"));
+                assert_eq!(data, b"still there?");
+            }
+            sent => panic!("Unexpected output {:?}", sent),
+        }
+        assert!(conn.sender().sent().is_empty());
+
+        conn.close(CloseCode::Away, "gone");
+        assert_eq!(rx.try_recv().unwrap(), (CloseCode::Away, "gone".into()));
+    }
+}
@@ -0,0 +1,106 @@
+use std::mem::replace;
+use std::str::from_utf8;
+
+use result::{Error, Result};
+
+/// Validates the fragments of a text message as they arrive, so that a message that turns out to
+/// be invalid UTF-8 is rejected without waiting for the rest of it.
+#[derive(Debug, Default)]
+pub struct Utf8Validator {
+    // the start of a code point that was split between fragments
+    partial: [u8; 4],
+    len: usize,
+}
+
+impl Utf8Validator {
+    /// Validate the next fragment of the message.
+    pub fn feed(&mut self, mut data: &[u8]) -> Result<()> {
+        if self.len > 0 {
+            let need = width(self.partial[0]) - self.len;
+            let take = need.min(data.len());
+            self.partial[self.len..self.len + take].copy_from_slice(&data[..take]);
+            self.len += take;
+            data = &data[take..];
+
+            match from_utf8(&self.partial[..self.len]) {
+                Ok(_) => self.len = 0,
+                Err(err) => {
+                    if err.error_len().is_some() {
+                        return Err(Error::from(err));
+                    }
+                    // the code point is still incomplete, so the fragment was used up
+                    return Ok(());
+                }
+            }
+        }
+
+        match from_utf8(data) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                if err.error_len().is_some() {
+                    return Err(Error::from(err));
+                }
+                let rest = &data[err.valid_up_to()..];
+                self.partial[..rest.len()].copy_from_slice(rest);
+                self.len = rest.len();
+                Ok(())
+            }
+        }
+    }
+
+    /// Check that the message didn't end in the middle of a code point, and get ready for the
+    /// next message.
+    pub fn finish(&mut self) -> Result<()> {
+        let len = replace(&mut self.len, 0);
+        // a code point that is still incomplete fails here
+        from_utf8(&self.partial[..len])?;
+        Ok(())
+    }
+}
+
+// The length of the code point that starts with a byte, which has been checked to be a valid
+// leading byte.
+fn width(first: u8) -> usize {
+    match first {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        _ => 4,
+    }
+}
+
+mod test {
+    #![allow(unused_imports, unused_variables, dead_code)]
+    use super::*;
+
+    fn validate(fragments: &[&[u8]]) -> Result<()> {
+        let mut validator = Utf8Validator::default();
+        for fragment in fragments {
+            validator.feed(fragment)?;
+        }
+        validator.finish()
+    }
+
+    #[test]
+    fn split_code_points() {
+        let text = "κόσμε 🦀".as_bytes();
+        for i in 0..text.len() {
+            assert!(validate(&[&text[..i], &text[i..]]).is_ok());
+        }
+        // a four byte code point spread over four fragments
+        let crab = "🦀".as_bytes();
+        assert!(validate(&[&crab[..1], &crab[1..2], &crab[2..3], &crab[3..]]).is_ok());
+        assert!(validate(&[&crab[..1], b"", &crab[1..]]).is_ok());
+    }
+
+    #[test]
+    fn invalid_fragments() {
+        let crab = "🦀".as_bytes();
+        assert!(validate(&[b"valid", b"\xff"]).is_err());
+        assert!(validate(&[&crab[..2], b"a"]).is_err());
+        assert!(validate(&[&crab[..2]]).is_err());
+
+        // the error is found as soon as the invalid fragment arrives
+        let mut validator = Utf8Validator::default();
+        assert!(validator.feed(b"\xed\xa0").is_err());
+    }
+}
@@ -4,6 +4,9 @@
 pub use mio::Token;
 /// A handle to a specific timeout.
 pub use mio_extras::timer::Timeout;
-#[cfg(any(feature = "ssl", feature = "nativetls"))]
+#[cfg(any(feature = "ssl", feature = "nativetls", feature = "tls-rustls"))]
 /// TcpStream underlying the WebSocket
 pub use mio::tcp::TcpStream;
+#[cfg(feature = "tls-rustls")]
+/// Encrypted stream returned by the rustls `Handler::upgrade_ssl_*` methods
+pub use stream::RustlsStream;
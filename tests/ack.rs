@@ -0,0 +1,96 @@
+extern crate url;
+extern crate ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::{AckToken, Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+
+struct Client {
+    out: Sender,
+    sent: Vec<AckToken>,
+    acked: Vec<AckToken>,
+    echoes: usize,
+    done: Channel<(Vec<AckToken>, Vec<AckToken>)>,
+}
+
+impl Client {
+    fn finish(&mut self) -> Result<()> {
+        if self.acked.len() == 3 && self.echoes == 4 {
+            self.done
+                .send((self.sent.clone(), self.acked.clone()))
+                .unwrap();
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.sent.push(self.out.send_with_ack("first")?);
+        // a message that takes several writes to go out
+        self.sent.push(self.out.send_with_ack(vec![7; 1 << 20])?);
+        self.out.send("unacknowledged")?;
+        self.sent.push(self.out.send_with_ack("last")?);
+        Ok(())
+    }
+
+    fn on_sent(&mut self, ack: AckToken) {
+        self.acked.push(ack);
+        if self.acked.len() == 3 {
+            // nothing is left to write once the last message is out
+            assert_eq!(self.out.pending(), 0);
+        }
+        self.finish().unwrap();
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.echoes += 1;
+        self.finish()
+    }
+}
+
+#[test]
+fn send_with_ack() {
+    let server = Builder::new()
+        .build(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:3073")
+        .unwrap();
+    let handle = server.broadcaster();
+    assert!(handle.send_with_ack("to everyone").is_err());
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        let mut client = Builder::new()
+            .with_settings(Settings {
+                tcp_send_buffer_size: Some(4096),
+                ..Settings::default()
+            })
+            .build(move |out| Client {
+                out,
+                sent: Vec::new(),
+                acked: Vec::new(),
+                echoes: 0,
+                done: tx.clone(),
+            })
+            .unwrap();
+        client
+            .connect(url::Url::parse("ws://127.0.0.1:3073").unwrap())
+            .unwrap();
+        client.run().unwrap();
+    });
+
+    let (sent, acked) = rx.recv_timeout(Duration::from_secs(10)).unwrap();
+    assert_eq!(sent, acked);
+    assert!(sent[0] != sent[1] && sent[1] != sent[2]);
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
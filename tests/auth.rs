@@ -0,0 +1,65 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use ws::{BasicAuthAuthenticator, Builder, Handler, Request, Response, Result};
+
+// Only sees the requests that were authenticated.
+struct Server;
+
+impl Handler for Server {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        assert!(req.header("authorization").is_some());
+        Response::from_request(req)
+    }
+}
+
+fn handshake(authorization: Option<&str>) -> String {
+    let mut stream = TcpStream::connect("127.0.0.1:3067").unwrap();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         Host: 127.0.0.1:3067\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n"
+    ).unwrap();
+    if let Some(authorization) = authorization {
+        write!(stream, "Authorization: {}\r\n", authorization).unwrap();
+    }
+    write!(stream, "\r\n").unwrap();
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).unwrap();
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[test]
+fn basic_auth() {
+    let server = Builder::new()
+        .with_authenticator(BasicAuthAuthenticator::new(
+            "chat",
+            vec![("alice".into(), "open sesame".into())],
+        ))
+        .build(|_| Server)
+        .unwrap()
+        .bind("127.0.0.1:3067")
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let response = handshake(None);
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized"));
+    assert!(response.contains("WWW-Authenticate: Basic realm=\"chat\", charset=\"UTF-8\"\r\n"));
+    // alice:guess
+    assert!(handshake(Some("Basic YWxpY2U6Z3Vlc3M=")).starts_with("HTTP/1.1 401"));
+    // alice:open sesame
+    assert!(handshake(Some("Basic YWxpY2U6b3BlbiBzZXNhbWU=")).starts_with("HTTP/1.1 101"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -0,0 +1,300 @@
+//! Cases from the Autobahn Testsuite that can be checked without the fuzzingclient. The full
+//! suite is run by `scripts/autobahn.sh`.
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Message, Sender, WebSocket};
+
+const ADDR: &str = "127.0.0.1:3092";
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+const MASK: [u8; 4] = [0x37, 0xfa, 0x21, 0x3d];
+
+// A frame as the client sends it, masked.
+fn frame(head: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![head];
+    if payload.len() < 126 {
+        frame.push(0x80 | payload.len() as u8);
+    } else if payload.len() <= 0xffff {
+        frame.push(0x80 | 126);
+        frame.extend_from_slice(&[(payload.len() >> 8) as u8, payload.len() as u8]);
+    } else {
+        frame.push(0x80 | 127);
+        frame.extend((0..8).rev().map(|i| (payload.len() >> (i * 8)) as u8));
+    }
+    frame.extend_from_slice(&MASK);
+    frame.extend(payload.iter().zip(MASK.iter().cycle()).map(|(b, m)| b ^ m));
+    frame
+}
+
+fn close(code: u16, reason: &[u8]) -> Vec<u8> {
+    let mut payload = vec![(code >> 8) as u8, code as u8];
+    payload.extend_from_slice(reason);
+    frame(0x88, &payload)
+}
+
+// Read a frame the server sent, or all frames of a message, if it hasn't closed the connection.
+fn read_frame(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)> {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).ok()?;
+    assert_eq!(head[1] & 0x80, 0, "The server masked a frame.");
+    let len = match head[1] & 0x7f {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            (len[0] as usize) << 8 | len[1] as usize
+        }
+        127 => {
+            let mut len = [0; 8];
+            stream.read_exact(&mut len).unwrap();
+            len.iter().fold(0, |len, &byte| len << 8 | byte as usize)
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    if head[0] & 0x80 == 0 {
+        // the server may fragment a message it echoes
+        let (_, rest) = read_frame(stream)?;
+        payload.extend(rest);
+    }
+    Some((head[0] | 0x80, payload))
+}
+
+// Open a connection and send it the frames in one write. After the server has answered with the
+// given number of frames, close the connection and return all frames the server sent.
+fn exchange(frames: &[Vec<u8>], replies: usize) -> Vec<(u8, Vec<u8>)> {
+    let mut stream = TcpStream::connect(ADDR).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream.write_all(REQUEST).unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    assert!(head.starts_with(b"HTTP/1.1 101"));
+
+    // the server may fail the connection before it reads everything
+    let _ = stream.write_all(&frames.concat());
+    let mut received: Vec<_> = (0..replies)
+        .filter_map(|_| read_frame(&mut stream))
+        .collect();
+    let _ = stream.write_all(&close(1000, b""));
+    while let Some(frame) = read_frame(&mut stream) {
+        received.push(frame);
+    }
+    received
+}
+
+// The close code the server fails the connection with, after any other frames.
+#[track_caller]
+fn close_code(frames: &[Vec<u8>]) -> u16 {
+    let received = exchange(frames, 0);
+    let &(head, ref payload) = received.last().expect("The server sent no close frame.");
+    assert_eq!(head, 0x88);
+    u16::from(payload[0]) << 8 | u16::from(payload[1])
+}
+
+#[test]
+fn autobahn() {
+    let server = WebSocket::new(|out: Sender| move |msg: Message| out.send(msg))
+        .unwrap()
+        .bind(ADDR)
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // 1.1, 1.2: echo of text and binary messages
+    for &len in &[0, 125, 126, 127, 128, 65535, 65536] {
+        let payload = vec![b'*'; len];
+        for &opcode in &[0x81, 0x82] {
+            assert_eq!(
+                exchange(&[frame(opcode, &payload)], 1),
+                vec![(opcode, payload.clone()), (0x88, vec![0x03, 0xe8])]
+            );
+        }
+    }
+
+    // 2.1 - 2.4: pings are answered with their payload
+    for &len in &[0, 10, 125] {
+        let payload = vec![0xfe; len];
+        assert_eq!(
+            exchange(&[frame(0x89, &payload)], 1),
+            vec![(0x8a, payload), (0x88, vec![0x03, 0xe8])]
+        );
+    }
+    // 2.5: a ping with too much payload
+    assert_eq!(close_code(&[frame(0x89, &[0; 126])]), 1002);
+    // 2.6: unsolicited pongs are ignored
+    assert_eq!(
+        exchange(&[frame(0x8a, b"pong")], 0),
+        vec![(0x88, vec![0x03, 0xe8])]
+    );
+    // 2.10: pings are answered in order
+    let pings: Vec<_> = (0..10u8).map(|i| frame(0x89, &[i])).collect();
+    let mut expected: Vec<_> = (0..10u8).map(|i| (0x8a, vec![i])).collect();
+    expected.push((0x88, vec![0x03, 0xe8]));
+    assert_eq!(exchange(&pings, 10), expected);
+
+    // 3.1 - 3.7: the reserved bits must not be set without an extension
+    for &rsv in &[0x40, 0x20, 0x10, 0x70] {
+        assert_eq!(close_code(&[frame(0x81 | rsv, b"rsv")]), 1002);
+        assert_eq!(close_code(&[frame(0x89 | rsv, b"")]), 1002);
+    }
+    // 3.2: the echo of the message before the faulty frame is queued behind the failure, which
+    // the suite accepts as non-strict
+    assert_eq!(
+        close_code(&[frame(0x81, b"first"), frame(0xa1, b"rsv")]),
+        1002
+    );
+
+    // 4.1, 4.2: reserved opcodes
+    for opcode in (3..8).chain(0xb..0x10) {
+        assert_eq!(close_code(&[frame(0x80 | opcode, b"reserved")]), 1002);
+    }
+
+    // 5.1 - 5.2: control frames must not be fragmented
+    assert_eq!(close_code(&[frame(0x09, b"a"), frame(0x80, b"b")]), 1002);
+    assert_eq!(close_code(&[frame(0x0a, b"a"), frame(0x80, b"b")]), 1002);
+    // 5.3 - 5.5: fragmented messages
+    assert_eq!(
+        exchange(
+            &[
+                frame(0x01, b"frag"),
+                frame(0x00, b"men"),
+                frame(0x80, b"ted"),
+            ],
+            1
+        ),
+        vec![(0x81, b"fragmented".to_vec()), (0x88, vec![0x03, 0xe8])]
+    );
+    // 5.6 - 5.8: pings in the middle of a fragmented message
+    assert_eq!(
+        exchange(
+            &[
+                frame(0x01, b"frag"),
+                frame(0x89, b"ping"),
+                frame(0x80, b"ment"),
+            ],
+            2
+        ),
+        vec![
+            (0x8a, b"ping".to_vec()),
+            (0x81, b"fragment".to_vec()),
+            (0x88, vec![0x03, 0xe8]),
+        ]
+    );
+    // 5.9 - 5.14: continuation frames without a message to continue
+    assert_eq!(close_code(&[frame(0x80, b"continue")]), 1002);
+    assert_eq!(
+        close_code(&[frame(0x00, b"con"), frame(0x80, b"tinue")]),
+        1002
+    );
+    // 5.18: a new message before the last one is finished
+    assert_eq!(
+        close_code(&[frame(0x01, b"first"), frame(0x81, b"second")]),
+        1002
+    );
+    // 5.19, 5.20: a fragmented message with pings and pongs between the fragments
+    assert_eq!(
+        exchange(
+            &[
+                frame(0x01, b"a"),
+                frame(0x00, b"b"),
+                frame(0x89, b"1"),
+                frame(0x8a, b""),
+                frame(0x00, b"c"),
+                frame(0x89, b"2"),
+                frame(0x80, b"d"),
+            ],
+            3
+        ),
+        vec![
+            (0x8a, b"1".to_vec()),
+            (0x8a, b"2".to_vec()),
+            (0x81, b"abcd".to_vec()),
+            (0x88, vec![0x03, 0xe8]),
+        ]
+    );
+
+    // 6.1, 6.2: valid utf-8, also when a code point is split between fragments
+    let text = "κόσμε".as_bytes();
+    assert_eq!(
+        exchange(&[frame(0x01, &text[..3]), frame(0x80, &text[3..])], 1),
+        vec![(0x81, text.to_vec()), (0x88, vec![0x03, 0xe8])]
+    );
+    assert_eq!(
+        exchange(&[frame(0x81, b"")], 1),
+        vec![(0x81, vec![]), (0x88, vec![0x03, 0xe8])]
+    );
+    // 6.3 - 6.21: invalid utf-8
+    for invalid in &[
+        &b"\xce\xba\xe1\xbd\xb9\xcf\x83\xce\xbc\xce\xb5\xed\xa0\x80\x65\x64\x69\x74\x65\x64"[..],
+        &b"\xc0\xaf"[..],
+        &b"\xed\xa0\x80"[..],
+        &b"\xf4\x90\x80\x80"[..],
+        &b"\xfe"[..],
+        &b"\x80"[..],
+    ] {
+        assert_eq!(close_code(&[frame(0x81, invalid)]), 1007);
+        let (first, second) = invalid.split_at(invalid.len() / 2);
+        assert_eq!(close_code(&[frame(0x01, first), frame(0x80, second)]), 1007);
+    }
+    // 6.4: invalid utf-8 fails the connection before the message is finished
+    assert_eq!(
+        close_code(&[
+            frame(0x01, b"\xce\xba\xe1\xbd\xb9\xcf\x83\xce\xbc\xce\xb5"),
+            frame(0x00, b"\xf4\x90\x80\x80")
+        ]),
+        1007
+    );
+
+    // 7.1: nothing is answered after the close frame
+    assert_eq!(
+        exchange(&[close(1000, b""), frame(0x81, b"after the close")], 0),
+        vec![(0x88, vec![0x03, 0xe8])]
+    );
+    // 7.3: close frames with a payload of 0, 1 or 125 bytes
+    assert_eq!(exchange(&[frame(0x88, b"")], 0), vec![(0x88, vec![])]);
+    assert_eq!(close_code(&[frame(0x88, b"\x03")]), 1002);
+    assert_eq!(close_code(&[close(1000, &[b'*'; 123])]), 1000);
+    assert_eq!(close_code(&[frame(0x88, &[0x03; 126])]), 1002);
+    // 7.5: a reason that is not valid utf-8
+    assert_eq!(
+        close_code(&[close(
+            1000,
+            b"\xce\xba\xe1\xbd\xb9\xcf\x83\xce\xbc\xce\xb5\xed\xa0\x80"
+        )]),
+        1007
+    );
+    // 7.7: valid close codes are echoed
+    for &code in &[
+        1000, 1001, 1002, 1003, 1007, 1008, 1009, 1010, 1011, 1012, 1013, 1014, 3000, 3999, 4000,
+        4999,
+    ] {
+        assert_eq!(close_code(&[close(code, b"")]), code);
+    }
+    // 7.9: invalid close codes
+    for &code in &[
+        0, 999, 1004, 1005, 1006, 1015, 1016, 1100, 2000, 2999, 5000, 65535,
+    ] {
+        assert_eq!(close_code(&[close(code, b"")]), 1002, "close code {}", code);
+    }
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
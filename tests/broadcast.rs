@@ -0,0 +1,98 @@
+extern crate ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Frame, Handler, Handshake, Message, Result, Settings};
+
+const CLIENTS: usize = 4;
+const LEN: usize = 200 * 1024;
+
+// Reports its connection opening, and every frame it is asked to send, unless it shares the
+// messages that are broadcast.
+struct Server {
+    share: bool,
+    open: Channel<()>,
+    frames: Channel<bool>,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.open.send(()).unwrap();
+        Ok(())
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.frames.send(self.share).unwrap();
+        Ok(Some(frame))
+    }
+
+    fn share_broadcasts(&self) -> bool {
+        self.share
+    }
+}
+
+#[test]
+fn broadcast_to_all() {
+    let (open_tx, open_rx) = channel();
+    let (frames_tx, frames_rx) = channel();
+    let mut connections = 0;
+    let server = Builder::new()
+        .with_settings(Settings {
+            fragment_size: 64 * 1024,
+            ..Settings::default()
+        })
+        .build(move |_| {
+            connections += 1;
+            Server {
+                share: connections % 2 == 0,
+                open: open_tx.clone(),
+                frames: frames_tx.clone(),
+            }
+        })
+        .unwrap()
+        .bind("127.0.0.1:3102")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let clients: Vec<_> = (0..CLIENTS)
+        .map(|_| {
+            let tx = tx.clone();
+            thread::spawn(move || {
+                ws::connect("ws://127.0.0.1:3102", |out| {
+                    let tx = tx.clone();
+                    move |msg: Message| {
+                        tx.send(msg.into_data()).unwrap();
+                        out.close(CloseCode::Normal)
+                    }
+                }).unwrap();
+            })
+        })
+        .collect();
+    for _ in 0..CLIENTS {
+        open_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    }
+
+    let data: Vec<u8> = (0..LEN).map(|i| i as u8).collect();
+    handle.send(data.clone()).unwrap();
+    for _ in 0..CLIENTS {
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), data);
+    }
+    for client in clients {
+        client.join().unwrap();
+    }
+
+    // the frames of the message only passed through the handlers that don't share it, the rest
+    // are the close frames of every connection
+    let frames: Vec<bool> = frames_rx.try_iter().collect();
+    assert_eq!(frames.iter().filter(|&&share| !share).count(), CLIENTS / 2 * 2);
+    assert_eq!(frames.iter().filter(|&&share| share).count(), CLIENTS / 2);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
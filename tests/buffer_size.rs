@@ -0,0 +1,149 @@
+extern crate url;
+extern crate ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+
+const MESSAGES: usize = 100;
+
+// Answers each message with its length.
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg.len().to_string())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Event {
+    Message(String),
+    Error(String),
+    Close(CloseCode),
+}
+
+struct Client {
+    out: Sender,
+    sizes: Vec<usize>,
+    received: usize,
+    events: Channel<Event>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        for &size in &self.sizes {
+            self.out.send(vec![0u8; size])?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received += 1;
+        self.events.send(Event::Message(msg.into_text()?)).unwrap();
+        if self.received == MESSAGES {
+            // a frame larger than the buffer of the server
+            self.out.send(vec![0u8; 2000])?;
+        }
+        Ok(())
+    }
+
+    fn on_error(&mut self, err: ws::Error) {
+        self.events
+            .send(Event::Error(format!("{:?}", err.kind)))
+            .unwrap();
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.events.send(Event::Close(code)).unwrap();
+    }
+}
+
+fn server(addr: &'static str) -> (Sender, thread::JoinHandle<()>) {
+    let server = Builder::new()
+        .with_settings(Settings {
+            in_buffer_capacity: 256,
+            in_buffer_max_capacity: 1024,
+            ..Settings::default()
+        })
+        .build(|out| Server { out })
+        .unwrap()
+        .bind(addr)
+        .unwrap();
+    let handle = server.broadcaster();
+    let thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+    (handle, thread)
+}
+
+fn client(settings: Settings, addr: &'static str, sizes: Vec<usize>) -> Vec<Event> {
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        let mut client = Builder::new()
+            .with_settings(settings)
+            .build(move |out| Client {
+                out,
+                sizes: sizes.clone(),
+                received: 0,
+                events: tx.clone(),
+            })
+            .unwrap();
+        client
+            .connect(url::Url::parse(&format!("ws://{}", addr)).unwrap())
+            .unwrap();
+        client.run().unwrap();
+    });
+
+    // the events up to the close of the connection
+    let mut events = Vec::new();
+    loop {
+        let event = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        let closed = match event {
+            Event::Close(_) => true,
+            _ => false,
+        };
+        events.push(event);
+        if closed {
+            break;
+        }
+    }
+    client.join().unwrap();
+    events
+}
+
+#[test]
+fn in_buffer_max_capacity() {
+    let (handle, server_thread) = server("127.0.0.1:3077");
+
+    // far more than the buffer holds at once
+    let events = client(Settings::default(), "127.0.0.1:3077", vec![500; MESSAGES]);
+    let mut expected = (0..MESSAGES)
+        .map(|_| Event::Message("500".into()))
+        .collect::<Vec<_>>();
+    expected.push(Event::Close(CloseCode::Size));
+    assert_eq!(events, expected);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn out_buffer_max_capacity() {
+    let (handle, server_thread) = server("127.0.0.1:3078");
+
+    let settings = Settings {
+        out_buffer_max_capacity: 1024,
+        ..Settings::default()
+    };
+    let events = client(settings, "127.0.0.1:3078", vec![500, 2000]);
+    assert!(events.contains(&Event::Error("Capacity".into())));
+    assert_eq!(events.last(), Some(&Event::Close(CloseCode::Size)));
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,52 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{CloseCode, Handler, Handshake, Result, Sender, WebSocket};
+
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.close_with_reason(CloseCode::Other(4001), "session expired")
+    }
+}
+
+struct Client {
+    closed: std::sync::mpsc::Sender<(CloseCode, String)>,
+}
+
+impl Handler for Client {
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.closed.send((code, reason.into())).unwrap();
+    }
+}
+
+#[test]
+fn application_close_code() {
+    let server = WebSocket::new(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:3019")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3019", |_| Client { closed: tx.clone() }).unwrap();
+    });
+
+    let (code, reason) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(code, CloseCode::Other(4001));
+    assert_eq!(reason, "session expired");
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
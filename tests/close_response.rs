@@ -0,0 +1,80 @@
+extern crate url;
+extern crate ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Result, Sender, Settings};
+
+// Answers a close later on and with a code of its own, as a relay would once the close has made
+// it through to the other side.
+struct Relay {
+    out: Sender,
+}
+
+impl Handler for Relay {
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        assert_eq!(code, CloseCode::Normal);
+        let out = self.out.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            out.close(CloseCode::Away).unwrap();
+        });
+    }
+}
+
+struct Client {
+    out: Sender,
+    closed: Channel<CloseCode>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.close(CloseCode::Normal)
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+#[test]
+fn manual_close_response() {
+    let server = Builder::new()
+        .with_settings(Settings {
+            auto_close_response: false,
+            ..Settings::default()
+        })
+        .build(|out| Relay { out })
+        .unwrap()
+        .bind("127.0.0.1:3066")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        let mut client = Builder::new()
+            .build(move |out| Client {
+                out,
+                closed: tx.clone(),
+            })
+            .unwrap();
+        client
+            .connect(url::Url::parse("ws://127.0.0.1:3066").unwrap())
+            .unwrap();
+        client.run().unwrap();
+    });
+
+    assert_eq!(
+        rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        CloseCode::Away
+    );
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
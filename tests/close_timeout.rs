@@ -0,0 +1,70 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Builder, CloseCode, Handler, Handshake, Result, Sender, Settings};
+
+// Starts the closing handshake as soon as the connection is open.
+struct Server {
+    out: Sender,
+    closed: std::sync::mpsc::Sender<CloseCode>,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.close(CloseCode::Normal)
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+#[test]
+fn close_timeout() {
+    let (tx, rx) = channel();
+    let server = Builder::new()
+        .with_settings(Settings {
+            close_timeout: Some(Duration::from_millis(200)),
+            ..Settings::default()
+        })
+        .build(move |out| Server {
+            out,
+            closed: tx.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:3079")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // A client that never answers the close frame.
+    let start = Instant::now();
+    let mut stream = TcpStream::connect("127.0.0.1:3079").unwrap();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+    ).unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    assert!(received.starts_with(b"HTTP/1.1 101"));
+    // the close frame with the Normal close code
+    assert!(received.ends_with(&[0x88, 0x02, 0x03, 0xe8]));
+
+    let code = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(code, CloseCode::Abnormal);
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
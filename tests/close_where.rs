@@ -0,0 +1,110 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{CloseCode, Handler, Message, Result, Sender, WebSocket};
+
+// The room a connection has joined.
+struct Room(String);
+
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.set_state(Room(msg.into_text()?));
+        self.out.send("joined")
+    }
+}
+
+struct Client {
+    out: Sender,
+    room: &'static str,
+    events: std::sync::mpsc::Sender<(&'static str, Option<CloseCode>)>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: ws::Handshake) -> Result<()> {
+        self.out.send(self.room)
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.events.send((self.room, None)).unwrap();
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.events.send((self.room, Some(code))).unwrap();
+    }
+}
+
+#[test]
+fn close_selected_connections() {
+    let server = WebSocket::new(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:3058")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let mut client = WebSocket::new(move |out: Sender| {
+        let room = match out.connection_id() {
+            0 | 1 => "lobby",
+            _ => "games",
+        };
+        Client {
+            out,
+            room,
+            events: tx.clone(),
+        }
+    }).unwrap();
+    for _ in 0..3 {
+        client
+            .connect("ws://127.0.0.1:3058".parse().unwrap())
+            .unwrap();
+    }
+    let closer = client.broadcaster();
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    let mut joined: Vec<_> = (0..3)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    joined.sort_by_key(|&(room, _)| room);
+    assert_eq!(
+        joined,
+        vec![("games", None), ("lobby", None), ("lobby", None)]
+    );
+
+    handle
+        .close_where(
+            |info| {
+                assert!(info.peer_addr().is_some());
+                info.state::<Room>().map_or(false, |room| room.0 == "lobby")
+            },
+            CloseCode::Policy,
+            "The lobby is closed.",
+        )
+        .unwrap();
+    for _ in 0..2 {
+        let closed = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(closed, ("lobby", Some(CloseCode::Policy)));
+    }
+    assert!(rx.recv_timeout(Duration::from_millis(200)).is_err());
+
+    closer.close(CloseCode::Normal).unwrap();
+    assert_eq!(
+        rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        ("games", Some(CloseCode::Normal))
+    );
+    client_thread.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
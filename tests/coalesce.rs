@@ -0,0 +1,94 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+
+const WINDOW: Duration = Duration::from_millis(300);
+
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        // messages sent in quick succession, but not all at once
+        let out = self.out.clone();
+        thread::spawn(move || {
+            for msg in &["a", "b", "c"] {
+                out.send(*msg).unwrap();
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+        Ok(())
+    }
+}
+
+struct Client {
+    out: Sender,
+    start: Instant,
+    arrived: std::sync::mpsc::Sender<(&'static str, Duration)>,
+    received: usize,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.start = Instant::now();
+        self.out.ping(Vec::new())?;
+        self.out.send("go")
+    }
+
+    fn on_pong(&mut self, _: &[u8]) -> Result<()> {
+        self.arrived.send(("pong", self.start.elapsed())).unwrap();
+        Ok(())
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.arrived.send(("message", self.start.elapsed())).unwrap();
+        self.received += 1;
+        if self.received == 3 {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn coalesce_window() {
+    let server = Builder::new()
+        .with_settings(Settings {
+            coalesce_window: Some(WINDOW),
+            ..Settings::default()
+        })
+        .build(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:3047")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    ws::connect("ws://127.0.0.1:3047", |out| Client {
+        out,
+        start: Instant::now(),
+        arrived: tx.clone(),
+        received: 0,
+    }).unwrap();
+
+    let arrived: Vec<(&str, Duration)> = rx.try_iter().collect();
+    // the pong isn't held back
+    assert_eq!(arrived[0].0, "pong");
+    assert!(arrived[0].1 < WINDOW);
+    // the messages are held back for the window and then arrive together
+    let messages: Vec<Duration> = arrived[1..].iter().map(|&(_, at)| at).collect();
+    assert_eq!(messages.len(), 3);
+    assert!(messages[0] >= WINDOW - Duration::from_millis(50));
+    assert!(messages[2] - messages[0] < Duration::from_millis(15));
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,40 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{connect_with_sender, CloseCode, Message, Sender, WebSocket};
+
+#[test]
+fn send_before_open() {
+    let server = WebSocket::new(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:3060")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let (out, client) = connect_with_sender("ws://127.0.0.1:3060", move |_| {
+        let tx = tx.clone();
+        move |msg| {
+            tx.send(msg).unwrap();
+            Ok(())
+        }
+    }).unwrap();
+    // sent from this thread before the handshake has completed
+    out.send("early").unwrap();
+    let msg: Message = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(msg, Message::from("early"));
+
+    out.close(CloseCode::Normal).unwrap();
+    client.join().unwrap().unwrap();
+
+    assert!(connect_with_sender("not a url", |_| |_| Ok(())).is_err());
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
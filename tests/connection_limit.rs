@@ -0,0 +1,92 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use ws::{Builder, ConnectionLimitAction, Factory, Handler, Sender, Settings};
+
+struct Server;
+impl Handler for Server {}
+
+struct ServerFactory {
+    limited: Arc<AtomicUsize>,
+}
+
+impl Factory for ServerFactory {
+    type Handler = Server;
+
+    fn connection_made(&mut self, _: Sender) -> Server {
+        Server
+    }
+
+    fn on_connection_limit(&mut self) {
+        self.limited.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+// Send a handshake request, returning the stream and the response, which is empty if the
+// connection was closed without one.
+fn handshake(addr: &str) -> (TcpStream, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        addr
+    ).unwrap();
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).unwrap_or(0);
+    let response = String::from_utf8_lossy(&buf[..len]).into_owned();
+    (stream, response)
+}
+
+// Open a connection to a server that allows a single connection, then try a second one.
+fn second_response(port: u16, action: ConnectionLimitAction) -> (String, usize) {
+    let limited = Arc::new(AtomicUsize::new(0));
+    let server = Builder::new()
+        .with_settings(Settings {
+            max_connections: 1,
+            connection_limit_action: action,
+            ..Settings::default()
+        })
+        .build(ServerFactory {
+            limited: limited.clone(),
+        })
+        .unwrap()
+        .bind(("127.0.0.1", port))
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let addr = format!("127.0.0.1:{}", port);
+    let (_first, response) = handshake(&addr);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    let (_second, response) = handshake(&addr);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+    (response, limited.load(Ordering::SeqCst))
+}
+
+#[test]
+fn refuse_connections() {
+    let (response, limited) = second_response(3035, ConnectionLimitAction::Refuse);
+    assert_eq!(response, "");
+    assert_eq!(limited, 1);
+}
+
+#[test]
+fn reject_connections() {
+    let (response, limited) = second_response(3036, ConnectionLimitAction::Reject);
+    assert!(response.starts_with("HTTP/1.1 503"));
+    assert_eq!(limited, 1);
+}
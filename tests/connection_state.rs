@@ -0,0 +1,61 @@
+extern crate ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{connect_with_sender, CloseCode, ConnectionState, Handler, Handshake, Result, Sender,
+         WebSocket};
+
+// Hands its sender out once the connection is open.
+struct Server {
+    out: Sender,
+    opened: Channel<Sender>,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.opened.send(self.out.clone()).unwrap();
+        Ok(())
+    }
+}
+
+fn wait_for(out: &Sender, state: ConnectionState) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while out.connection_state() != state {
+        assert!(Instant::now() < deadline, "{:?} never became {:?}", out, state);
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn connection_state() {
+    let (tx, rx) = channel();
+    let server = WebSocket::new(move |out| Server {
+        out,
+        opened: tx.clone(),
+    })
+    .unwrap()
+    .bind("127.0.0.1:3069")
+    .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (client, client_thread) =
+        connect_with_sender("ws://127.0.0.1:3069", |_| |_| Ok(())).unwrap();
+    let server_side = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(server_side.is_open());
+    wait_for(&client, ConnectionState::Open);
+
+    client.close(CloseCode::Normal).unwrap();
+    wait_for(&server_side, ConnectionState::Closed);
+    client_thread.join().unwrap().unwrap();
+    assert_eq!(client.connection_state(), ConnectionState::Closed);
+    assert!(!server_side.is_open());
+
+    assert!(handle.is_open());
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
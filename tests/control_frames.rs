@@ -0,0 +1,62 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use ws::{Message, Sender, WebSocket};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+// Open a connection and send it a frame, returning the close code the server answers with.
+fn close_code_for(frame: &[u8]) -> u16 {
+    let mut stream = TcpStream::connect("127.0.0.1:3061").unwrap();
+    stream.write_all(REQUEST).unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+
+    stream.write_all(frame).unwrap();
+    let mut close = [0; 4];
+    stream.read_exact(&mut close).unwrap();
+    assert_eq!(close[0], 0x88);
+    u16::from(close[2]) << 8 | u16::from(close[3])
+}
+
+#[test]
+fn reject_invalid_control_frames() {
+    let server = WebSocket::new(|out: Sender| move |msg: Message| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:3061")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // a ping with 126 bytes of payload, masked with an empty mask
+    let mut ping = vec![0x89, 0x80 | 126, 0, 126, 0, 0, 0, 0];
+    ping.extend_from_slice(&[0; 126]);
+    assert_eq!(close_code_for(&ping), 1002);
+
+    // the first fragment of a close frame
+    assert_eq!(
+        close_code_for(&[0x08, 0x82, 0, 0, 0, 0, 0x03, 0xe8]),
+        1002
+    );
+
+    // close codes that only describe a closure to the handler: 1005, 1006 and 1015
+    for &code in &[0xed, 0xee, 0xf7] {
+        assert_eq!(close_code_for(&[0x88, 0x82, 0, 0, 0, 0, 0x03, code]), 1002);
+    }
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
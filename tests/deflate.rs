@@ -3,8 +3,13 @@ extern crate env_logger;
 extern crate url;
 extern crate ws;
 
-use ws::deflate::DeflateHandler;
-use ws::{Builder, Message, Sender, Settings, WebSocket};
+use std::io::Write;
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+
+use ws::deflate::{DeflateBuilder, DeflateHandler, DeflateSettings};
+use ws::{Builder, Frame, Handler, Handshake, Message, MessageMetadata, OpCode, Request, Response,
+         Result, Sender, Settings, WebSocket};
 
 #[test]
 fn round_trip() {
@@ -76,3 +81,399 @@ fn fragment() {
 
     ws.listen("127.0.0.1:3024").unwrap();
 }
+
+#[test]
+fn stream() {
+    const MESSAGE: &'static str = "this message is written to the stream in pieces";
+
+    let mut name = "Client";
+
+    let mut ws = Builder::new()
+        .with_settings(Settings {
+            fragment_size: 4,
+            ..Default::default()
+        })
+        .build(|output: Sender| {
+            if name == "Client" {
+                let mut writer = output.stream(OpCode::Text);
+                for word in MESSAGE.split(' ') {
+                    write!(writer, "{} ", word).unwrap();
+                }
+                writer.finish().unwrap();
+            }
+
+            let handler = move |msg: Message| {
+                if name == "Server" {
+                    output.send(msg)
+                } else {
+                    assert_eq!(msg.as_text().unwrap(), format!("{} ", MESSAGE));
+                    output.shutdown()
+                }
+            };
+
+            name = "Server";
+
+            DeflateHandler::new(handler)
+        })
+        .unwrap();
+
+    let url = url::Url::parse("ws://127.0.0.1:3027").unwrap();
+
+    ws.connect(url).unwrap();
+
+    ws.listen("127.0.0.1:3027").unwrap();
+}
+
+struct Takeover {
+    out: Sender,
+    client: bool,
+    received: usize,
+}
+
+impl Handler for Takeover {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        if self.client {
+            let extensions = shake.response.extensions()?;
+            assert!(extensions[0].contains("; client_no_context_takeover"));
+            assert!(extensions[0].contains("; server_no_context_takeover"));
+            self.out.send(MESSAGE)?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        if self.client {
+            assert_eq!(msg.as_text()?, MESSAGE);
+            self.received += 1;
+            if self.received == 3 {
+                return self.out.shutdown();
+            }
+        }
+        // every message is compressed from scratch, so repeating it works the same
+        self.out.send(msg)
+    }
+}
+
+const MESSAGE: &'static str = "this message is compressed without the context of the last one";
+
+#[test]
+fn no_context_takeover() {
+    let mut client = true;
+    let mut ws = WebSocket::new(|out: Sender| {
+        let handler = Takeover {
+            out,
+            client,
+            received: 0,
+        };
+        client = false;
+        DeflateBuilder::new()
+            .with_settings(DeflateSettings {
+                client_no_context_takeover: true,
+                server_no_context_takeover: true,
+                ..DeflateSettings::default()
+            })
+            .build(handler)
+    }).unwrap();
+
+    let url = url::Url::parse("ws://127.0.0.1:3043").unwrap();
+
+    ws.connect(url).unwrap();
+
+    ws.listen("127.0.0.1:3043").unwrap();
+}
+
+// The client sends a compressible message, an incompressible one and the first one again, which
+// the server echoes.
+struct Mixed {
+    out: Sender,
+    client: bool,
+    received: Vec<Message>,
+}
+
+impl Handler for Mixed {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        if self.client {
+            self.out.send(text())?;
+            self.out.send(noise())?;
+            self.out.send(text())?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        if !self.client {
+            return self.out.send(msg);
+        }
+        self.received.push(msg);
+        if self.received.len() == 3 {
+            assert_eq!(
+                self.received,
+                vec![Message::text(text()), Message::binary(noise()), Message::text(text())]
+            );
+            self.out.shutdown()?;
+        }
+        Ok(())
+    }
+}
+
+// Checks that only the incompressible message leaves the deflate handler uncompressed.
+struct Wire(DeflateHandler<Mixed>);
+
+impl Handler for Wire {
+    fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+        self.0.build_request(url)
+    }
+
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        self.0.on_request(req)
+    }
+
+    fn on_response(&mut self, res: &Response) -> Result<()> {
+        self.0.on_response(res)
+    }
+
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.0.on_open(shake)
+    }
+
+    fn on_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        self.0.on_frame(frame)
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.0.on_message(msg)
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        let frame = self.0.on_send_frame(frame)?;
+        if let Some(ref frame) = frame {
+            if !frame.is_control() {
+                assert_eq!(frame.has_rsv1(), frame.payload() != &noise()[..]);
+            }
+        }
+        Ok(frame)
+    }
+}
+
+fn text() -> String {
+    "compressible ".repeat(100)
+}
+
+// Pseudo-random bytes, which deflate can't shrink.
+fn noise() -> Vec<u8> {
+    let mut state = 0x2545_f491_u32;
+    (0..1000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect()
+}
+
+#[test]
+fn skip_incompressible() {
+    let mut client = true;
+    let mut ws = WebSocket::new(|out: Sender| {
+        let handler = Mixed {
+            out,
+            client,
+            received: Vec::new(),
+        };
+        client = false;
+        Wire(
+            DeflateBuilder::new()
+                .with_settings(DeflateSettings {
+                    skip_incompressible_threshold: Some(0),
+                    ..DeflateSettings::default()
+                })
+                .build(handler),
+        )
+    }).unwrap();
+
+    let url = url::Url::parse("ws://127.0.0.1:3065").unwrap();
+
+    ws.connect(url).unwrap();
+
+    ws.listen("127.0.0.1:3065").unwrap();
+}
+
+// The server limits the sliding windows, which the client with the default settings agrees to.
+struct Window {
+    out: Sender,
+    client: bool,
+}
+
+impl Handler for Window {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        if self.client {
+            let extensions = shake.response.extensions()?;
+            assert!(extensions[0].contains("; client_max_window_bits=10"));
+            assert!(extensions[0].contains("; server_max_window_bits=10"));
+            // repeats further apart than the window reaches
+            self.out.send([noise(), noise()].concat())?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        if self.client {
+            assert_eq!(msg.into_data(), [noise(), noise()].concat());
+            self.out.shutdown()
+        } else {
+            self.out.send(msg)
+        }
+    }
+}
+
+#[test]
+fn server_max_window_bits() {
+    let mut client = true;
+    let mut ws = WebSocket::new(|out: Sender| {
+        let settings = if client {
+            DeflateSettings::default()
+        } else {
+            DeflateSettings {
+                max_window_bits: 10,
+                ..DeflateSettings::default()
+            }
+        };
+        let handler = Window { out, client };
+        client = false;
+        DeflateBuilder::new().with_settings(settings).build(handler)
+    }).unwrap();
+
+    let url = url::Url::parse("ws://127.0.0.1:3082").unwrap();
+
+    ws.connect(url).unwrap();
+
+    ws.listen("127.0.0.1:3082").unwrap();
+}
+
+// A client without the extension, which sends its own offers and reports the extensions of the
+// response, along with the echo of a message if the server declined them.
+struct Offers {
+    out: Sender,
+    offers: &'static str,
+    events: Channel<String>,
+}
+
+impl Handler for Offers {
+    fn build_request(&mut self, url: &url::Url) -> Result<Request> {
+        let mut req = Request::from_url(url)?;
+        req.add_extension(self.offers);
+        Ok(req)
+    }
+
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        let extensions = shake.response.extensions()?.join(", ");
+        self.events.send(extensions.clone()).unwrap();
+        if extensions.is_empty() {
+            self.out.send("plain")
+        } else {
+            // compressed messages are beyond this client
+            self.out.close(ws::CloseCode::Normal)
+        }
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.events.send(msg.into_text()?).unwrap();
+        self.out.close(ws::CloseCode::Normal)
+    }
+}
+
+fn negotiate(addr: &'static str, offers: &'static str) -> Vec<String> {
+    // the deflate handlers stay on the thread of the server
+    let (handle_tx, handle_rx) = channel();
+    let server_thread = thread::spawn(move || {
+        let server = WebSocket::new(|out: Sender| DeflateHandler::new(move |msg| out.send(msg)))
+            .unwrap()
+            .bind(addr)
+            .unwrap();
+        handle_tx.send(server.broadcaster()).unwrap();
+        server.run().unwrap();
+    });
+    let handle = handle_rx.recv().unwrap();
+
+    let (tx, rx) = channel();
+    ws::connect(format!("ws://{}", addr), |out| Offers {
+        out,
+        offers,
+        events: tx.clone(),
+    }).unwrap();
+    let events = rx.try_iter().collect();
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+    events
+}
+
+#[test]
+fn decline_unsupported_offers() {
+    let events = negotiate(
+        "127.0.0.1:3083",
+        "permessage-deflate; server_max_window_bits=20, \
+         permessage-deflate; unknown_param, \
+         permessage-deflate; client_no_context_takeover; client_no_context_takeover, \
+         x-permessage-deflate",
+    );
+    // the connection opens without compression
+    assert_eq!(events, vec!["", "plain"]);
+}
+
+#[test]
+fn accept_later_offer() {
+    let events = negotiate(
+        "127.0.0.1:3084",
+        "permessage-deflate; client_no_context_takeover; unknown_param, \
+         permessage-deflate; client_max_window_bits=\"10\"",
+    );
+    // the parameters of the skipped offer are not applied
+    assert_eq!(
+        events,
+        vec!["permessage-deflate; client_max_window_bits=10; server_max_window_bits=15"]
+    );
+}
+
+// The client checks that the echo of a compressible message arrived compressed.
+struct Sizes {
+    out: Sender,
+    client: bool,
+}
+
+impl Handler for Sizes {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        if self.client {
+            self.out.send(text())?;
+        }
+        Ok(())
+    }
+
+    fn on_message_with_metadata(&mut self, msg: Message, metadata: MessageMetadata) -> Result<()> {
+        if self.client {
+            assert!(metadata.is_compressed());
+            assert!(metadata.wire_len() < msg.len());
+            assert_eq!(msg.into_text()?, text());
+            self.out.shutdown()
+        } else {
+            self.out.send(msg)
+        }
+    }
+}
+
+#[test]
+fn compressed_metadata() {
+    let mut client = true;
+    let mut ws = WebSocket::new(|out: Sender| {
+        let handler = Sizes { out, client };
+        client = false;
+        DeflateHandler::new(handler)
+    }).unwrap();
+
+    let url = url::Url::parse("ws://127.0.0.1:3086").unwrap();
+
+    ws.connect(url).unwrap();
+
+    ws.listen("127.0.0.1:3086").unwrap();
+}
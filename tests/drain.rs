@@ -0,0 +1,181 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender};
+
+const MESSAGES: usize = 4;
+
+// Only sends the next message once the previous one has been written out.
+struct Producer {
+    out: Sender,
+    sent: usize,
+    pending: std::sync::mpsc::Sender<usize>,
+}
+
+impl Producer {
+    fn produce(&mut self) -> Result<()> {
+        self.sent += 1;
+        self.out.send(vec![0; 256 * 1024])
+    }
+}
+
+impl Handler for Producer {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.produce()
+    }
+
+    fn on_buffer_drained(&mut self) {
+        self.pending.send(self.out.pending()).unwrap();
+        if self.sent < MESSAGES {
+            self.produce().unwrap();
+        } else {
+            self.out.close(CloseCode::Normal).unwrap();
+        }
+    }
+}
+
+struct Consumer {
+    received: std::sync::mpsc::Sender<usize>,
+}
+
+impl Handler for Consumer {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received.send(msg.len()).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn buffer_drained() {
+    let (pending_tx, pending_rx) = channel();
+    let server = Builder::new()
+        .build(move |out| Producer {
+            out,
+            sent: 0,
+            pending: pending_tx.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:3042")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3042", |_| Consumer {
+            received: tx.clone(),
+        }).unwrap();
+    });
+
+    for _ in 0..MESSAGES {
+        let len = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(len, 256 * 1024);
+    }
+    client.join().unwrap();
+
+    // nothing was left waiting to be written whenever the buffer drained
+    let pending: Vec<usize> = pending_rx.try_iter().collect();
+    assert!(pending.len() >= MESSAGES);
+    assert!(pending.iter().all(|&pending| pending == 0));
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+struct Opened {
+    open: std::sync::mpsc::Sender<()>,
+}
+
+impl Handler for Opened {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.open.send(()).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn broadcast_pending() {
+    let (open_tx, open_rx) = channel();
+    let server = Builder::new()
+        .build(move |_| Opened {
+            open: open_tx.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:3098")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3098", |out| {
+            let tx = tx.clone();
+            move |msg: Message| {
+                tx.send(msg.len()).unwrap();
+                out.close(CloseCode::Normal)
+            }
+        }).unwrap();
+    });
+
+    open_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    handle.send(vec![0; 1024]).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1024);
+    client.join().unwrap();
+
+    // the broadcast is no longer pending once it was handed to the connections
+    assert_eq!(handle.pending(), 0);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+// Broadcasts a message to every connection as soon as its own connection opens.
+struct Announcer {
+    out: Sender,
+}
+
+impl Handler for Announcer {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.broadcast(vec![0; 1024])
+    }
+}
+
+#[test]
+fn connection_broadcast_pending() {
+    let server = Builder::new()
+        .build(|out| Announcer { out })
+        .unwrap()
+        .bind("127.0.0.1:3101")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3101", |out| {
+            let tx = tx.clone();
+            move |msg: Message| {
+                tx.send(msg.len()).unwrap();
+                out.close(CloseCode::Normal)
+            }
+        }).unwrap();
+    });
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), 1024);
+    client.join().unwrap();
+
+    // a broadcast from a connection was never pending for the broadcaster
+    assert_eq!(handle.pending(), 0);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
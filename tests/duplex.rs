@@ -0,0 +1,47 @@
+#![cfg(unix)]
+extern crate url;
+extern crate ws;
+
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc::channel;
+use std::thread;
+
+use ws::{CloseCode, Handler, Handshake, Message, Result, Sender, WebSocket};
+
+struct Client {
+    out: Sender,
+    received: std::sync::mpsc::Sender<String>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("ping")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received.send(msg.into_text()?).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn duplex_pipe() {
+    let (server_end, client_end) = UnixStream::pair().unwrap();
+
+    let server = thread::spawn(move || {
+        WebSocket::from_stream(server_end, |out: Sender| {
+            move |msg: Message| out.send(format!("{} pong", msg))
+        }).unwrap();
+    });
+
+    let (tx, rx) = channel();
+    WebSocket::new(move |out| Client {
+        out,
+        received: tx.clone(),
+    }).unwrap()
+        .connect_stream(client_end, url::Url::parse("ws://localhost/").unwrap())
+        .unwrap();
+
+    assert_eq!(rx.try_recv().unwrap(), "ping pong");
+    server.join().unwrap();
+}
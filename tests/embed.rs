@@ -0,0 +1,89 @@
+extern crate ws;
+
+use std::net::UdpSocket as StdUdpSocket;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::mio::net::UdpSocket;
+use ws::mio::{Events, Poll, PollOpt, Ready, Token};
+use ws::{CloseCode, Handler, Handshake, Message, Result, Sender, WebSocket};
+
+const UDP: Token = Token(1 << 20);
+
+struct Echo {
+    out: Sender,
+}
+
+impl Handler for Echo {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+}
+
+struct Client {
+    out: Sender,
+    received: std::sync::mpsc::Sender<Message>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("from a shared reactor")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received.send(msg).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn external_poll() {
+    let mut server = WebSocket::new(|out| Echo { out })
+        .unwrap()
+        .bind("127.0.0.1:3053")
+        .unwrap();
+    let handle = server.broadcaster();
+    let (datagrams, datagram) = channel();
+    let server_thread = thread::spawn(move || {
+        let mut poll = Poll::new().unwrap();
+        server.register(&mut poll).unwrap();
+        let udp = UdpSocket::bind(&"127.0.0.1:3054".parse().unwrap()).unwrap();
+        poll.register(&udp, UDP, Ready::readable(), PollOpt::level())
+            .unwrap();
+
+        let mut events = Events::with_capacity(1024);
+        loop {
+            poll.poll(&mut events, server.poll_timeout()).unwrap();
+            for event in events.iter() {
+                if event.token() == UDP {
+                    let mut buf = [0; 16];
+                    let len = udp.recv(&mut buf).unwrap();
+                    datagrams.send(buf[..len].to_vec()).unwrap();
+                }
+            }
+            if !server.ready(&mut poll, &events) {
+                break;
+            }
+        }
+        server.deregister(&mut poll).unwrap();
+    });
+
+    let (tx, rx) = channel();
+    ws::connect("ws://127.0.0.1:3053", |out| Client {
+        out,
+        received: tx.clone(),
+    }).unwrap();
+    let msg = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(msg, Message::from("from a shared reactor"));
+
+    let udp = StdUdpSocket::bind("127.0.0.1:0").unwrap();
+    udp.send_to(b"ping", "127.0.0.1:3054").unwrap();
+    assert_eq!(
+        datagram.recv_timeout(Duration::from_secs(5)).unwrap(),
+        b"ping".to_vec()
+    );
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,168 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Frame, Handler, Handshake, Message, MessageMetadata, OpCode, Result,
+         Sender};
+
+// Sends a message as hand-built frames, with a ping between its fragments.
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        let mut first = Frame::message(b"hel".to_vec(), OpCode::Text, false);
+        first.set_rsv2(true);
+        self.out.send_frame(first)?;
+        self.out.send_frame(Frame::ping(b"between".to_vec()))?;
+        self.out
+            .send_frame(Frame::message(b"lo".to_vec(), OpCode::Continue, true))
+    }
+
+    fn on_send_frame(&mut self, frame: Frame) -> Result<Option<Frame>> {
+        // allow the reserved bit
+        Ok(Some(frame))
+    }
+}
+
+struct Client {
+    out: Sender,
+    events: std::sync::mpsc::Sender<String>,
+}
+
+impl Handler for Client {
+    fn on_frame(&mut self, mut frame: Frame) -> Result<Option<Frame>> {
+        self.events
+            .send(format!(
+                "{:?} rsv2={} {}",
+                frame.opcode(),
+                frame.has_rsv2(),
+                String::from_utf8_lossy(frame.payload())
+            ))
+            .unwrap();
+        // the reserved bit would otherwise fail the connection
+        frame.set_rsv2(false);
+        Ok(Some(frame))
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.events.send(msg.into_text()?).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn send_raw_frames() {
+    let server = Builder::new()
+        .build(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:3038")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3038", |out| Client {
+            out,
+            events: tx.clone(),
+        }).unwrap();
+    });
+
+    let events: Vec<String> = (0..4)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            "Text rsv2=true hel",
+            "Ping rsv2=false between",
+            "Continue rsv2=false lo",
+            "hello",
+        ]
+    );
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+// Sends a message in three fragments, then one in a single frame.
+struct Fragmenting {
+    out: Sender,
+}
+
+impl Handler for Fragmenting {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out
+            .send_frame(Frame::message(b"fr".to_vec(), OpCode::Binary, false))?;
+        self.out
+            .send_frame(Frame::message(b"agm".to_vec(), OpCode::Continue, false))?;
+        self.out.send_frame(Frame::ping(Vec::new()))?;
+        self.out
+            .send_frame(Frame::message(b"ented".to_vec(), OpCode::Continue, true))?;
+        self.out.send("whole")
+    }
+}
+
+struct Metadata {
+    out: Sender,
+    events: std::sync::mpsc::Sender<(OpCode, usize, bool, usize, usize)>,
+}
+
+impl Handler for Metadata {
+    fn on_message_with_metadata(&mut self, msg: Message, metadata: MessageMetadata) -> Result<()> {
+        self.events
+            .send((
+                metadata.opcode(),
+                metadata.frames(),
+                metadata.is_fragmented(),
+                metadata.wire_len(),
+                msg.len(),
+            ))
+            .unwrap();
+        assert!(!metadata.is_compressed());
+        if msg.is_text() {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn message_metadata() {
+    let server = Builder::new()
+        .build(|out| Fragmenting { out })
+        .unwrap()
+        .bind("127.0.0.1:3085")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3085", |out| Metadata {
+            out,
+            events: tx.clone(),
+        }).unwrap();
+    });
+
+    let events: Vec<_> = (0..2)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    assert_eq!(
+        events,
+        vec![(OpCode::Binary, 3, true, 10, 10), (OpCode::Text, 1, false, 5, 5)]
+    );
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
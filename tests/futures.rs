@@ -0,0 +1,43 @@
+#![cfg(feature = "futures")]
+extern crate futures;
+extern crate ws;
+
+use std::thread;
+
+use futures::executor::block_on;
+use futures::{SinkExt, StreamExt};
+
+use ws::{Message, Sender, WebSocket};
+
+#[test]
+fn connect_async() {
+    let server = WebSocket::new(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:3094")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (mut sink, mut stream) = ws::connect_async("ws://127.0.0.1:3094").unwrap();
+    block_on(sink.send("first".into())).unwrap();
+    block_on(sink.send(Message::binary(vec![1, 2, 3]))).unwrap();
+    let received = block_on(stream.by_ref().take(2).collect::<Vec<_>>());
+    assert_eq!(
+        received.into_iter().map(|msg| msg.unwrap()).collect::<Vec<_>>(),
+        vec![Message::from("first"), Message::binary(vec![1, 2, 3])]
+    );
+    // the stream ends with the connection
+    block_on(sink.close()).unwrap();
+    assert!(block_on(stream.next()).is_none());
+
+    // nothing listens on this port
+    let (_, mut stream) = ws::connect_async("ws://127.0.0.1:3095").unwrap();
+    assert!(block_on(stream.next()).unwrap().is_err());
+    assert!(block_on(stream.next()).is_none());
+    assert!(ws::connect_async("not a url").is_err());
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,261 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, Handler, Request, Response, Result, Sender};
+
+// Decides on the handshake in another thread, as if it were waiting for an external service.
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let authorized = req.resource().ends_with("?token=secret");
+        let accepted = Response::from_request(req)?;
+        let out = self.out.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            let response = if authorized {
+                accepted
+            } else {
+                Response::new(401, "Unauthorized", b"Invalid token.".to_vec())
+            };
+            out.complete_handshake(response).unwrap();
+        });
+        Ok(Response::pending())
+    }
+}
+
+fn handshake(addr: &str, resource: &str, version: u8) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: {}\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        resource, addr, version
+    ).unwrap();
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).unwrap();
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[test]
+fn deferred_handshake() {
+    let server = Builder::new()
+        .build(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:3037")
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let response = handshake("127.0.0.1:3037", "/?token=secret", 13);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+    assert!(handshake("127.0.0.1:3037", "/?token=guess", 13).starts_with("HTTP/1.1 401"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn unsupported_version() {
+    let server = Builder::new()
+        .build(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:3059")
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let response = handshake("127.0.0.1:3059", "/?token=secret", 8);
+    assert!(response.starts_with("HTTP/1.1 426 Upgrade Required"));
+    assert!(response.contains("Sec-WebSocket-Version: 13\r\n"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+// Establishes a session while accepting the connection.
+struct Session;
+
+impl Handler for Session {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let mut res = Response::from_request(req)?;
+        res.add_header("Set-Cookie", "session=1234; HttpOnly")?;
+        res.add_header("Set-Cookie", "theme=dark")?;
+        assert!(res.add_header("X-Injected", "1\r\nX-Other: 2").is_err());
+        assert!(res.add_header("Bad Name", "1").is_err());
+        Ok(res)
+    }
+}
+
+#[test]
+fn response_headers() {
+    let server = Builder::new()
+        .build(|_| Session)
+        .unwrap()
+        .bind("127.0.0.1:3062")
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let response = handshake("127.0.0.1:3062", "/", 13);
+    assert!(response.starts_with("HTTP/1.1 101"));
+    assert!(response.contains("Set-Cookie: session=1234; HttpOnly\r\n"));
+    assert!(response.contains("Set-Cookie: theme=dark\r\n"));
+    assert!(!response.contains("X-"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+// Records the resources of the handshake requests it sees, leaving the responses to the default.
+struct Observer {
+    seen: std::sync::mpsc::Sender<String>,
+}
+
+impl Handler for Observer {
+    fn on_handshake_request(&mut self, req: &Request) {
+        self.seen.send(req.resource().into()).unwrap();
+    }
+}
+
+#[test]
+fn observe_handshake_request() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let server = Builder::new()
+        .build(move |_| Observer { seen: tx.clone() })
+        .unwrap()
+        .bind("127.0.0.1:3076")
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    assert!(handshake("127.0.0.1:3076", "/accepted", 13).starts_with("HTTP/1.1 101"));
+    assert!(handshake("127.0.0.1:3076", "/rejected", 8).starts_with("HTTP/1.1 426"));
+    let seen = rx.try_iter().collect::<Vec<_>>();
+    assert_eq!(seen, vec!["/accepted", "/rejected"]);
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+// Send a handshake request with the given Connection header.
+fn upgrade(addr: &str, connection: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         {}\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        connection
+    ).unwrap();
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).unwrap();
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[test]
+fn connection_header_tokens() {
+    let server = Builder::new()
+        .build(|_| Session)
+        .unwrap()
+        .bind("127.0.0.1:3081")
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    for connection in &[
+        "Connection: Upgrade",
+        "Connection: keep-alive, Upgrade",
+        "connection: keep-alive,upgrade",
+        "CONNECTION: UPGRADE",
+        "Connection: keep-alive\r\nConnection: Upgrade",
+    ] {
+        let response = upgrade("127.0.0.1:3081", connection);
+        assert!(response.starts_with("HTTP/1.1 101"), "{:?} was rejected", connection);
+    }
+    for connection in &["Connection: keep-alive", "Connection: Upgraded", "X-Missing: 1"] {
+        let response = upgrade("127.0.0.1:3081", connection);
+        assert!(response.starts_with("HTTP/1.1 426"), "{:?} was accepted", connection);
+    }
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+// Rejects handshakes without a token, explaining why in the body of the response.
+struct Gatekeeper;
+
+impl Handler for Gatekeeper {
+    fn on_request(&mut self, req: &Request) -> Result<Response> {
+        let mut res = Response::from_request(req)?;
+        if !req.resource().contains("token=") {
+            res.set_status(401);
+            res.set_reason("Unauthorized");
+            res.add_header("Content-Type", "application/json")?;
+            res.set_body(br#"{"error": "missing token"}"#.to_vec());
+        }
+        Ok(res)
+    }
+}
+
+#[test]
+fn rejection_body() {
+    let server = Builder::new()
+        .build(|_| Gatekeeper)
+        .unwrap()
+        .bind("127.0.0.1:3093")
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut stream = TcpStream::connect("127.0.0.1:3093").unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\n\
+              Connection: Upgrade\r\n\
+              Upgrade: websocket\r\n\
+              Sec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .unwrap();
+    // the server closes the connection after the body
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 401 Unauthorized\r\n"));
+    assert!(response.contains("Content-Type: application/json\r\n"));
+    assert!(response.contains("Content-Length: 26\r\n"));
+    assert!(response.contains("Connection: close\r\n"));
+    assert!(!response.contains("Upgrade"));
+    assert!(!response.contains("Sec-WebSocket-Accept"));
+    assert!(response.ends_with("\r\n\r\n{\"error\": \"missing token\"}"));
+
+    assert!(handshake("127.0.0.1:3093", "/?token=secret", 13).starts_with("HTTP/1.1 101"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -0,0 +1,142 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, Error, ErrorKind, Handler, Settings};
+
+fn settings() -> Settings {
+    Settings {
+        max_handshake_size: 1024,
+        ..Settings::default()
+    }
+}
+
+// Reports whether its connection failed with a protocol error.
+struct Failed {
+    errors: std::sync::mpsc::Sender<bool>,
+}
+
+impl Handler for Failed {
+    fn on_error(&mut self, err: Error) {
+        let protocol = match err.kind {
+            ErrorKind::Protocol => true,
+            _ => false,
+        };
+        self.errors.send(protocol).unwrap();
+    }
+}
+
+#[test]
+fn request_too_large() {
+    let (tx, _rx) = channel();
+    let server = Builder::new()
+        .with_settings(settings())
+        .build(move |_| Failed { errors: tx.clone() })
+        .unwrap()
+        .bind("127.0.0.1:3050")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // a request whose headers don't end within the limit
+    let mut stream = TcpStream::connect("127.0.0.1:3050").unwrap();
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nUpgrade: websocket\r\n")
+        .unwrap();
+    stream
+        .write_all(format!("X-Padding: {}\r\n", "a".repeat(1024)).as_bytes())
+        .unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 431"));
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.contains("\r\nConnection: close\r\n"), "{}", response);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn too_many_extensions() {
+    let (tx, _rx) = channel();
+    let server = Builder::new()
+        .with_settings(settings())
+        .build(move |_| Failed { errors: tx.clone() })
+        .unwrap()
+        .bind("127.0.0.1:3087")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let request = |extensions: &str| {
+        let mut stream = TcpStream::connect("127.0.0.1:3087").unwrap();
+        write!(
+            stream,
+            "GET / HTTP/1.1\r\n\
+             Connection: Upgrade\r\n\
+             Upgrade: websocket\r\n\
+             Sec-WebSocket-Version: 13\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+             Sec-WebSocket-Extensions: {}\r\n\r\n",
+            extensions
+        ).unwrap();
+        let mut response = [0; 12];
+        stream.read_exact(&mut response).unwrap();
+        response
+    };
+
+    // a list of empty offers, which fits in the handshake size limit
+    assert_eq!(&request(&",".repeat(512)), b"HTTP/1.1 400");
+    // as do many parameters of a single offer
+    assert_eq!(&request(&"x;".repeat(100)), b"HTTP/1.1 400");
+    assert_eq!(
+        &request("permessage-deflate; client_max_window_bits"),
+        b"HTTP/1.1 101"
+    );
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn response_too_large() {
+    // A server that answers the handshake request with headers that exceed the limit.
+    let listener = TcpListener::bind("127.0.0.1:3051").unwrap();
+    let server_thread = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.read(&mut [0; 1024]).unwrap();
+        stream
+            .write_all(b"HTTP/1.1 101 Switching Protocols\r\n")
+            .unwrap();
+        stream
+            .write_all(format!("X-Padding: {}\r\n", "a".repeat(1024)).as_bytes())
+            .unwrap();
+        // keep the connection open until the client gives up on it
+        let _ = stream.read(&mut [0; 16]);
+    });
+
+    let (tx, rx) = channel();
+    let mut client = Builder::new()
+        .with_settings(settings())
+        .build(move |_| Failed { errors: tx.clone() })
+        .unwrap();
+    client
+        .connect("ws://127.0.0.1:3051".parse().unwrap())
+        .unwrap();
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+
+    client_thread.join().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,74 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use ws::{Factory, Handler, Request, Response, Sender, WebSocket};
+
+struct Server;
+
+impl Handler for Server {}
+
+// Answers the health checks of a load balancer on the WebSocket port.
+struct Healthy;
+
+impl Factory for Healthy {
+    type Handler = Server;
+
+    fn connection_made(&mut self, _: Sender) -> Server {
+        Server
+    }
+
+    fn on_non_upgrade(&mut self, req: &Request) -> Response {
+        if req.resource() == "/health" {
+            Response::new(200, "OK", b"OK".to_vec())
+        } else {
+            Response::new(404, "Not Found", Vec::new())
+        }
+    }
+}
+
+// Send a request and read the response until the server closes the connection.
+fn request(headers: &str) -> String {
+    let mut stream = TcpStream::connect("127.0.0.1:3068").unwrap();
+    write!(stream, "{}\r\n", headers).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    response
+}
+
+#[test]
+fn health_check() {
+    let server = WebSocket::new(Healthy)
+        .unwrap()
+        .bind("127.0.0.1:3068")
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    assert_eq!(
+        request("GET /health HTTP/1.1\r\nHost: 127.0.0.1:3068\r\n"),
+        "HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nOK"
+    );
+    assert!(request("GET / HTTP/1.1\r\n").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+    // handshakes are unaffected
+    let mut stream = TcpStream::connect("127.0.0.1:3068").unwrap();
+    write!(
+        stream,
+        "GET /health HTTP/1.1\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+    ).unwrap();
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).unwrap();
+    assert!(buf[..len].starts_with(b"HTTP/1.1 101"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
@@ -0,0 +1,91 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Sender, Settings};
+
+struct Server {
+    closed: std::sync::mpsc::Sender<CloseCode>,
+}
+
+impl Handler for Server {
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+fn server(addr: &str, closed: std::sync::mpsc::Sender<CloseCode>) -> Sender {
+    let socket = Builder::new()
+        .with_settings(Settings {
+            ping_interval: Some(Duration::from_millis(100)),
+            max_missed_pongs: 1,
+            ..Settings::default()
+        })
+        .build(move |_| Server {
+            closed: closed.clone(),
+        })
+        .unwrap()
+        .bind(addr)
+        .unwrap();
+    let handle = socket.broadcaster();
+    thread::spawn(move || {
+        socket.run().unwrap();
+    });
+    handle
+}
+
+#[test]
+fn missed_pongs() {
+    let (tx, rx) = channel();
+    let handle = server("127.0.0.1:3013", tx);
+
+    // A raw client that completes the handshake but never answers pings
+    let mut stream = TcpStream::connect("127.0.0.1:3013").unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\n\
+              Host: 127.0.0.1:3013\r\n\
+              Connection: Upgrade\r\n\
+              Upgrade: websocket\r\n\
+              Sec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .unwrap();
+    let mut buf = [0; 1024];
+    assert!(stream.read(&mut buf).unwrap() > 0);
+
+    let code = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(code, CloseCode::Abnormal);
+
+    handle.shutdown().unwrap();
+}
+
+#[test]
+fn answered_pings() {
+    let (tx, rx) = channel();
+    let handle = server("127.0.0.1:3014", tx);
+
+    // The client answers pings automatically, so the connection should stay alive until it is
+    // closed normally.
+    let client = thread::spawn(|| {
+        ws::connect("ws://127.0.0.1:3014", |out| {
+            let timer = out.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(500));
+                timer.close(CloseCode::Normal).unwrap();
+            });
+            |_| Ok(())
+        })
+        .unwrap();
+    });
+
+    let code = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(code, CloseCode::Normal);
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+}
@@ -0,0 +1,49 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Builder, CloseCode, Handler, Settings};
+
+struct Server {
+    closed: std::sync::mpsc::Sender<CloseCode>,
+}
+
+impl Handler for Server {
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+#[test]
+fn idle_timeout() {
+    let (tx, rx) = channel();
+    let server = Builder::new()
+        .with_settings(Settings {
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..Settings::default()
+        })
+        .build(move |_| Server { closed: tx.clone() })
+        .unwrap()
+        .bind("127.0.0.1:3022")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // A client that stays connected but never sends anything.
+    let start = Instant::now();
+    let client = thread::spawn(|| {
+        ws::connect("ws://127.0.0.1:3022", |_| |_| Ok(())).unwrap();
+    });
+
+    let code = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(code, CloseCode::Away);
+    assert!(start.elapsed() >= Duration::from_millis(300));
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,129 @@
+#![cfg(feature = "json")]
+#[macro_use]
+extern crate serde_derive;
+extern crate ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::{CloseCode, Handler, Handshake, JsonHandler, Message, OnJson, Result, Sender, WebSocket};
+
+#[derive(Serialize, Deserialize)]
+struct Add {
+    a: u32,
+    b: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Sum {
+    sum: u32,
+}
+
+// Answers each request with its sum, and fails the connection on anything else.
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let add: Add = msg.json()?;
+        self.out.send_json(&Sum { sum: add.a + add.b })
+    }
+}
+
+struct Client {
+    out: Sender,
+    events: Channel<String>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send_json(&Add { a: 1, b: 2 })
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        assert_eq!(msg.json::<Sum>()?, Sum { sum: 3 });
+        self.events.send(msg.into_text()?).unwrap();
+        self.out.send(r#"{"a": 1}"#)
+    }
+
+    fn on_close(&mut self, code: CloseCode, reason: &str) {
+        self.events
+            .send(format!("{:?} {}", code, reason))
+            .unwrap();
+    }
+}
+
+#[test]
+fn json_messages() {
+    let server = WebSocket::new(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:3088")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3088", |out| Client {
+            out,
+            events: tx.clone(),
+        }).unwrap();
+    });
+
+    let events: Vec<_> = (0..2)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    assert_eq!(events[0], r#"{"sum":3}"#);
+    assert!(events[1].starts_with("Invalid Invalid JSON: missing field `b`"));
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+// Answers each request with its sum as it is handed over by `JsonHandler`.
+struct Adder {
+    out: Sender,
+}
+
+impl Handler for Adder {}
+
+impl OnJson<Add> for Adder {
+    fn on_json(&mut self, add: Add) -> Result<()> {
+        self.out.send_json(&Sum { sum: add.a + add.b })
+    }
+}
+
+#[test]
+fn json_handler() {
+    let server = WebSocket::new(|out| JsonHandler::new(Adder { out }))
+        .unwrap()
+        .bind("127.0.0.1:3103")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3103", |out| Client {
+            out,
+            events: tx.clone(),
+        }).unwrap();
+    });
+
+    let events: Vec<_> = (0..2)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    assert_eq!(events[0], r#"{"sum":3}"#);
+    assert!(events[1].starts_with("Invalid Invalid JSON: missing field `b`"));
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
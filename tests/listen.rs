@@ -0,0 +1,78 @@
+extern crate ws;
+
+use std::net::SocketAddr;
+use std::sync::mpsc::channel;
+use std::thread;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+
+struct Client {
+    out: Sender,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("hello")
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+fn settings(ipv6_only: bool) -> Settings {
+    Settings {
+        ipv6_only,
+        ..Settings::default()
+    }
+}
+
+#[test]
+fn listen_multi() {
+    let addrs: Vec<SocketAddr> = vec![
+        "0.0.0.0:3044".parse().unwrap(),
+        "[::]:3044".parse().unwrap(),
+    ];
+
+    let (tx, rx) = channel();
+    let server = Builder::new()
+        .with_settings(settings(true))
+        .build(move |out: Sender| {
+            let tx = tx.clone();
+            move |msg: Message| {
+                tx.send(()).unwrap();
+                out.send(msg)
+            }
+        })
+        .unwrap()
+        .bind_multi(&addrs)
+        .unwrap();
+    assert_eq!(server.local_addrs().unwrap(), addrs);
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    for url in &["ws://127.0.0.1:3044", "ws://[::1]:3044"] {
+        ws::connect(*url, |out| Client { out }).unwrap();
+    }
+    assert_eq!(rx.try_iter().count(), 2);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn dual_stack_conflict() {
+    // without ipv6_only the IPv6 listener also claims the port for IPv4
+    let addrs: Vec<SocketAddr> = vec![
+        "0.0.0.0:3045".parse().unwrap(),
+        "[::]:3045".parse().unwrap(),
+    ];
+    let result = Builder::new()
+        .with_settings(settings(false))
+        .build(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind_multi(&addrs);
+    assert!(result.is_err());
+}
@@ -0,0 +1,296 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, ClientSettings, CloseCode, Error, ErrorKind, Handler, Handshake,
+         MaskingKeySource, Message, RandomSource, Result, Sender, Settings};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+
+const RESPONSE: &[u8] = b"HTTP/1.1 101 Switching Protocols\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n";
+
+fn settings() -> Settings {
+    Settings {
+        masking_strict: true,
+        ..Settings::default()
+    }
+}
+
+// Read the head of an http response or request.
+fn read_head(stream: &mut TcpStream) -> Vec<u8> {
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    head
+}
+
+struct Echo {
+    out: Sender,
+}
+
+impl Handler for Echo {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+}
+
+#[test]
+fn server_rejects_unmasked_frames() {
+    let server = Builder::new()
+        .with_settings(settings())
+        .build(|out| Echo { out })
+        .unwrap()
+        .bind("127.0.0.1:3055")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut stream = TcpStream::connect("127.0.0.1:3055").unwrap();
+    stream.write_all(REQUEST).unwrap();
+    read_head(&mut stream);
+
+    // an unmasked text frame
+    stream.write_all(&[0x81, 0x02, b'h', b'i']).unwrap();
+    let mut frame = [0; 4];
+    stream.read_exact(&mut frame).unwrap();
+    // a close frame with the protocol error code
+    assert_eq!(frame[0], 0x88);
+    assert_eq!(u16::from(frame[2]) << 8 | u16::from(frame[3]), 1002);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+struct Client {
+    errors: std::sync::mpsc::Sender<bool>,
+}
+
+impl Handler for Client {
+    fn on_error(&mut self, err: Error) {
+        let protocol = match err.kind {
+            ErrorKind::Protocol => true,
+            _ => false,
+        };
+        self.errors.send(protocol).unwrap();
+    }
+}
+
+#[test]
+fn client_rejects_masked_frames() {
+    let listener = TcpListener::bind("127.0.0.1:3056").unwrap();
+    let server_thread = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        read_head(&mut stream);
+        stream.write_all(RESPONSE).unwrap();
+        // a masked text frame with an empty mask
+        stream
+            .write_all(&[0x81, 0x82, 0, 0, 0, 0, b'h', b'i'])
+            .unwrap();
+        // keep the connection open until the client gives up on it
+        let _ = stream.read(&mut [0; 16]);
+    });
+
+    let (tx, rx) = channel();
+    let mut client = Builder::new()
+        .with_settings(settings())
+        .build(move |_| Client { errors: tx.clone() })
+        .unwrap();
+    client
+        .connect("ws://127.0.0.1:3056".parse().unwrap())
+        .unwrap();
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+
+    client_thread.join().unwrap();
+    server_thread.join().unwrap();
+}
+
+struct Hello {
+    out: Sender,
+}
+
+impl Handler for Hello {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("hello")?;
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn deterministic_masking_keys() {
+    let listener = TcpListener::bind("127.0.0.1:3072").unwrap();
+    let server_thread = thread::spawn(move || {
+        let mut frames = Vec::new();
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_head(&mut stream);
+            stream.write_all(RESPONSE).unwrap();
+            // the text frame and the close frame with its code
+            let mut frame = [0; 11 + 8];
+            stream.read_exact(&mut frame).unwrap();
+            frames.push(frame);
+        }
+        frames
+    });
+
+    let mut client = Builder::new()
+        .with_settings(Settings {
+            masking_key_source: MaskingKeySource::InsecureDeterministic(7),
+            ..Settings::default()
+        })
+        .build(|out| Hello { out })
+        .unwrap();
+    for _ in 0..2 {
+        client
+            .connect("ws://127.0.0.1:3072".parse().unwrap())
+            .unwrap();
+    }
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    // every connection writes the same bytes
+    let frames = server_thread.join().unwrap();
+    assert_eq!(&frames[0][..], &frames[1][..]);
+    let frame = frames[0];
+    assert_eq!(
+        &frame[..],
+        &[
+            0x81, 0x85, 0x59, 0x32, 0x0d, 0xd7, 0x31, 0x57, 0x61, 0xbb, 0x36, 0x88, 0x82, 0xf4,
+            0x3c, 0x66, 0x1c, 0xf7, 0xd4,
+        ][..]
+    );
+    let payload: Vec<u8> = frame[6..11]
+        .iter()
+        .zip(frame[2..6].iter().cycle())
+        .map(|(byte, mask)| byte ^ mask)
+        .collect();
+    assert_eq!(payload, b"hello");
+
+    client_thread.join().unwrap();
+}
+
+#[test]
+fn random_source() {
+    let listener = TcpListener::bind("127.0.0.1:3091").unwrap();
+    let server_thread = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let head = String::from_utf8(read_head(&mut stream)).unwrap();
+        assert!(head.contains("Sec-WebSocket-Key: q6urq6urq6urq6urq6urqw==\r\n"));
+        stream
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\n\
+                  Connection: Upgrade\r\n\
+                  Upgrade: websocket\r\n\
+                  Sec-WebSocket-Accept: 5jSu99N61NkfGRivKp2L1TNc+X8=\r\n\r\n",
+            )
+            .unwrap();
+        let mut frame = [0; 11];
+        stream.read_exact(&mut frame).unwrap();
+        frame
+    });
+
+    let mut client = Builder::new()
+        .with_client_settings(ClientSettings {
+            random_source: Some(RandomSource::new(|dest| {
+                for byte in dest.iter_mut() {
+                    *byte = 0xab;
+                }
+            })),
+            ..ClientSettings::default()
+        })
+        .build(|out| Hello { out })
+        .unwrap();
+    client
+        .connect("ws://127.0.0.1:3091".parse().unwrap())
+        .unwrap();
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    // both the key of the handshake and the masking key come from the source
+    let frame = server_thread.join().unwrap();
+    assert_eq!(&frame[..6], &[0x81, 0x85, 0xab, 0xab, 0xab, 0xab][..]);
+    let payload: Vec<u8> = frame[6..].iter().map(|byte| byte ^ 0xab).collect();
+    assert_eq!(payload, b"hello");
+
+    client_thread.join().unwrap();
+}
+
+// Reports the messages and protocol errors of a connection.
+struct Report {
+    events: std::sync::mpsc::Sender<String>,
+}
+
+impl Handler for Report {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.events.send(msg.into_text()?).unwrap();
+        Ok(())
+    }
+
+    fn on_error(&mut self, err: Error) {
+        if let ErrorKind::Protocol = err.kind {
+            self.events.send("protocol error".into()).unwrap();
+        }
+    }
+}
+
+#[test]
+fn allow_server_masking() {
+    let listener = TcpListener::bind("127.0.0.1:3097").unwrap();
+    let server_thread = thread::spawn(move || {
+        for _ in 0..2 {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_head(&mut stream);
+            stream.write_all(RESPONSE).unwrap();
+            // a text frame masked with 1, 2, 3, 4
+            stream
+                .write_all(&[0x81, 0x82, 1, 2, 3, 4, b'h' ^ 1, b'i' ^ 2])
+                .unwrap();
+            let _ = stream.read(&mut [0; 16]);
+        }
+    });
+
+    for &(allow, expected) in &[(false, "protocol error"), (true, "hi")] {
+        let (tx, rx) = channel();
+        let mut client = Builder::new()
+            .with_settings(Settings {
+                allow_server_masking: allow,
+                ..Settings::default()
+            })
+            .build(move |_| Report { events: tx.clone() })
+            .unwrap();
+        client
+            .connect("ws://127.0.0.1:3097".parse().unwrap())
+            .unwrap();
+        let sender = client.broadcaster();
+        let client_thread = thread::spawn(move || {
+            client.run().unwrap();
+        });
+
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), expected);
+        sender.shutdown().unwrap();
+        client_thread.join().unwrap();
+    }
+
+    server_thread.join().unwrap();
+}
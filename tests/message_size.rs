@@ -0,0 +1,143 @@
+extern crate url;
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Message, Result, Sender, Settings};
+
+struct Client {
+    out: Sender,
+    closed: std::sync::mpsc::Sender<CloseCode>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: ws::Handshake) -> Result<()> {
+        self.out.send(vec![0u8; 100])
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+struct Server;
+
+impl Handler for Server {
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        panic!("Oversized message was accepted.")
+    }
+}
+
+#[test]
+fn fragmented_message_too_large() {
+    let server = Builder::new()
+        .with_settings(Settings {
+            max_message_size: 50,
+            ..Settings::default()
+        })
+        .build(|_| Server)
+        .unwrap()
+        .bind("127.0.0.1:3015")
+        .unwrap();
+    let server_handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let mut client = Builder::new()
+        .with_settings(Settings {
+            // each fragment is allowed on its own, only the whole message is too large
+            fragment_size: 10,
+            ..Settings::default()
+        })
+        .build(move |out| Client {
+            out,
+            closed: tx.clone(),
+        })
+        .unwrap();
+    client
+        .connect(url::Url::parse("ws://127.0.0.1:3015").unwrap())
+        .unwrap();
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    let code = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(code, CloseCode::Size);
+
+    client_thread.join().unwrap();
+    server_handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+// Sends a message within the limit on continuation frames and then one over it.
+struct Fragmenter {
+    out: Sender,
+    received: std::sync::mpsc::Sender<Message>,
+    closed: std::sync::mpsc::Sender<CloseCode>,
+}
+
+impl Handler for Fragmenter {
+    fn on_open(&mut self, _: ws::Handshake) -> Result<()> {
+        // the first frame and five continuation frames
+        self.out.send(vec![0u8; 60])
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received.send(msg).unwrap();
+        self.out.send(vec![0u8; 100])
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+#[test]
+fn too_many_continuation_frames() {
+    let server = Builder::new()
+        .with_settings(Settings {
+            max_continuation_frames: Some(5),
+            ..Settings::default()
+        })
+        .build(|out: Sender| move |msg: Message| out.send(msg.len().to_string()))
+        .unwrap()
+        .bind("127.0.0.1:3074")
+        .unwrap();
+    let server_handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let (closed_tx, closed_rx) = channel();
+    let mut client = Builder::new()
+        .with_settings(Settings {
+            fragment_size: 10,
+            ..Settings::default()
+        })
+        .build(move |out| Fragmenter {
+            out,
+            received: tx.clone(),
+            closed: closed_tx.clone(),
+        })
+        .unwrap();
+    client
+        .connect(url::Url::parse("ws://127.0.0.1:3074").unwrap())
+        .unwrap();
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    let code = closed_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(code, CloseCode::Policy);
+    // only the message within the limit was accepted
+    assert_eq!(rx.try_iter().collect::<Vec<_>>(), vec![Message::text("60")]);
+
+    client_thread.join().unwrap();
+    server_handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,49 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use ws::{Builder, Handler};
+
+struct Server;
+impl Handler for Server {}
+
+fn handshake(addr: &str, origin: &str) -> String {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "GET / HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Origin: {}\r\n\
+         Connection: Upgrade\r\n\
+         Upgrade: websocket\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        addr, origin
+    ).unwrap();
+    let mut buf = [0; 1024];
+    let len = stream.read(&mut buf).unwrap();
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+#[test]
+fn allowed_origins() {
+    let server = Builder::new()
+        .with_allowed_origins(vec!["https://example.com".into(), "*.example.org".into()])
+        .build(|_| Server)
+        .unwrap()
+        .bind("127.0.0.1:3017")
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    assert!(handshake("127.0.0.1:3017", "https://example.com").starts_with("HTTP/1.1 101"));
+    assert!(handshake("127.0.0.1:3017", "http://www.example.org").starts_with("HTTP/1.1 101"));
+    assert!(handshake("127.0.0.1:3017", "https://evil.com").starts_with("HTTP/1.1 403"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
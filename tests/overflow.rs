@@ -0,0 +1,90 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+
+use ws::{Builder, CloseCode, Handler, Message, OverflowPolicy, Result, Sender, Settings};
+
+// Sends a burst of messages with the policy named by the client, more than the connection
+// holds back before any of them can be written.
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        let out = match msg.as_text()? {
+            "block" => self.out.clone(),
+            "drop oldest" => self.out.with_overflow_policy(OverflowPolicy::DropOldest),
+            "drop newest" => self.out.with_overflow_policy(OverflowPolicy::DropNewest),
+            _ => self.out.with_overflow_policy(OverflowPolicy::CloseConnection),
+        };
+        for i in 0..10 {
+            out.send(i.to_string())?;
+        }
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+struct Client {
+    out: Sender,
+    policy: &'static str,
+    received: Vec<String>,
+    result: std::sync::mpsc::Sender<(Vec<String>, CloseCode)>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: ws::Handshake) -> Result<()> {
+        self.out.send(self.policy)
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received.push(msg.into_text()?);
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.result.send((self.received.clone(), code)).unwrap();
+    }
+}
+
+#[test]
+fn overflow_policies() {
+    let server = Builder::new()
+        .with_settings(Settings {
+            max_queued_messages: Some(2),
+            ..Settings::default()
+        })
+        .build(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:3046")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let run = |policy: &'static str| {
+        let (tx, rx) = channel();
+        ws::connect("ws://127.0.0.1:3046", |out| Client {
+            out,
+            policy,
+            received: Vec::new(),
+            result: tx.clone(),
+        }).unwrap();
+        let (received, code) = rx.recv().unwrap();
+        (received.join(" "), code)
+    };
+
+    // handlers can't wait for room, so nothing is lost
+    assert_eq!(
+        run("block"),
+        ("0 1 2 3 4 5 6 7 8 9".into(), CloseCode::Normal)
+    );
+    assert_eq!(run("drop oldest"), ("0 8 9".into(), CloseCode::Normal));
+    assert_eq!(run("drop newest"), ("0 1 2".into(), CloseCode::Normal));
+    assert_eq!(run("close"), ("0".into(), CloseCode::Policy));
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
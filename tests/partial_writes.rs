@@ -0,0 +1,119 @@
+extern crate url;
+extern crate ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+
+const MESSAGES: usize = 100;
+// The messages sent ahead of the echoes, so that frames queue up behind the ones being written.
+const WINDOW: usize = 4;
+
+// Send buffers far smaller than the frames, so that each frame takes many writes to go out. The
+// receive buffers are left alone, shrinking them after the connection is established can stall the
+// TCP stream itself.
+fn settings() -> Settings {
+    Settings {
+        tcp_send_buffer_size: Some(4096),
+        ..Settings::default()
+    }
+}
+
+// A message whose size and contents depend on its index, so that a byte written twice, dropped or
+// taken from the wrong offset shows up as a mismatch.
+fn message(i: usize) -> Vec<u8> {
+    let len = 1 + i * 7919 % 200_000;
+    (0..len).map(|j| (i * 31 + j * 7 + j / 251) as u8).collect()
+}
+
+struct Echo {
+    out: Sender,
+}
+
+impl Handler for Echo {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+}
+
+struct Client {
+    out: Sender,
+    sent: usize,
+    received: usize,
+    done: Channel<usize>,
+}
+
+impl Client {
+    fn send_next(&mut self) -> Result<()> {
+        if self.sent < MESSAGES {
+            self.sent += 1;
+            self.out.send(message(self.sent - 1))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        for _ in 0..WINDOW {
+            self.send_next()?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        assert!(
+            msg.into_data() == message(self.received),
+            "message {} was corrupted",
+            self.received
+        );
+        self.received += 1;
+        eprintln!("got {}", self.received);
+        if self.received == MESSAGES {
+            self.done.send(self.received).unwrap();
+            self.out.close(CloseCode::Normal)
+        } else {
+            self.send_next()
+        }
+    }
+}
+
+#[test]
+fn partial_writes() {
+    let server = Builder::new()
+        .with_settings(settings())
+        .build(|out| Echo { out })
+        .unwrap()
+        .bind("127.0.0.1:3070")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        let mut client = Builder::new()
+            .with_settings(settings())
+            .build(move |out| Client {
+                out,
+                sent: 0,
+                received: 0,
+                done: tx.clone(),
+            })
+            .unwrap();
+        client
+            .connect(url::Url::parse("ws://127.0.0.1:3070").unwrap())
+            .unwrap();
+        client.run().unwrap();
+    });
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(60)).unwrap(), MESSAGES);
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
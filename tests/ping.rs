@@ -0,0 +1,220 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Handshake, PingAction, Result, Sender, Settings};
+
+struct Server;
+
+impl Handler for Server {
+    fn on_ping(&mut self, data: &[u8]) -> Result<PingAction> {
+        Ok(match data {
+            b"replace" => PingAction::Pong(b"replaced".to_vec()),
+            b"ignore" => PingAction::None,
+            _ => PingAction::Default,
+        })
+    }
+}
+
+struct Client {
+    out: Sender,
+    pongs: std::sync::mpsc::Sender<Vec<u8>>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.ping(b"replace".to_vec())?;
+        self.out.ping(b"ignore".to_vec())?;
+        self.out.ping(b"echo".to_vec())
+    }
+
+    fn on_pong(&mut self, data: &[u8]) -> Result<()> {
+        self.pongs.send(data.to_vec()).unwrap();
+        if data == b"echo" {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn ping_actions() {
+    let server = Builder::new()
+        .build(|_| Server)
+        .unwrap()
+        .bind("127.0.0.1:3039")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3039", |out| Client {
+            out,
+            pongs: tx.clone(),
+        }).unwrap();
+    });
+
+    client.join().unwrap();
+    let pongs: Vec<Vec<u8>> = rx.try_iter().collect();
+    assert_eq!(pongs, vec![b"replaced".to_vec(), b"echo".to_vec()]);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+const BACKLOG: usize = 16;
+
+// Saturates each connection with messages before pinging it.
+struct Flood {
+    out: Sender,
+}
+
+impl Handler for Flood {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        for _ in 0..BACKLOG {
+            self.out.send(vec![7u8; 1 << 20])?;
+        }
+        self.out.ping(b"alive".to_vec())
+    }
+}
+
+// Complete the opening handshake with a server as a client that reads the frames itself.
+fn open(addr: &str) -> TcpStream {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\n\
+              Connection: Upgrade\r\n\
+              Upgrade: websocket\r\n\
+              Sec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .unwrap();
+    let mut head = Vec::new();
+    let mut byte = [0];
+    while !head.ends_with(b"\r\n\r\n") {
+        stream.read_exact(&mut byte).unwrap();
+        head.push(byte[0]);
+    }
+    // let the server buffer everything before reading any of it
+    thread::sleep(Duration::from_millis(200));
+    stream
+}
+
+// Read the first byte of a frame's header and its payload.
+fn read_frame(stream: &mut TcpStream) -> (u8, Vec<u8>) {
+    let mut head = [0; 2];
+    stream.read_exact(&mut head).unwrap();
+    let len = match head[1] {
+        126 => {
+            let mut len = [0; 2];
+            stream.read_exact(&mut len).unwrap();
+            (len[0] as usize) << 8 | len[1] as usize
+        }
+        127 => {
+            let mut len = [0; 8];
+            stream.read_exact(&mut len).unwrap();
+            len.iter().fold(0, |len, &byte| len << 8 | byte as usize)
+        }
+        len => len as usize,
+    };
+    let mut payload = vec![0; len];
+    stream.read_exact(&mut payload).unwrap();
+    (head[0], payload)
+}
+
+#[test]
+fn ping_ahead_of_backlog() {
+    let server = Builder::new()
+        .build(|out| Flood { out })
+        .unwrap()
+        .bind("127.0.0.1:3096")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut stream = open("127.0.0.1:3096");
+    let mut data = 0;
+    let mut ping_after = None;
+    while data < BACKLOG << 20 {
+        let (head, payload) = read_frame(&mut stream);
+        let len = payload.len();
+        if head == 0x89 {
+            assert_eq!(payload, b"alive");
+            ping_after = Some(data);
+        } else {
+            assert!(payload.iter().all(|&byte| byte == 7));
+            data += len;
+        }
+    }
+    // the ping went out well before the data that was queued ahead of it
+    assert!(ping_after.unwrap() < BACKLOG << 19);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+// Saturates each connection with messages before closing it.
+struct FloodClose {
+    out: Sender,
+}
+
+impl Handler for FloodClose {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        for _ in 0..BACKLOG {
+            self.out.send(vec![7u8; 1 << 20])?;
+        }
+        self.out.close(CloseCode::Away)
+    }
+}
+
+#[test]
+fn close_ahead_of_backlog() {
+    let server = Builder::new()
+        .with_settings(Settings {
+            prioritize_close_frames: true,
+            ..Settings::default()
+        })
+        .build(|out| FloodClose { out })
+        .unwrap()
+        .bind("127.0.0.1:3100")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut stream = open("127.0.0.1:3100");
+    let mut data = 0;
+    loop {
+        let (head, payload) = read_frame(&mut stream);
+        if head == 0x88 {
+            assert_eq!(payload, [0x03, 0xe9]);
+            break;
+        }
+        assert!(payload.iter().all(|&byte| byte == 7));
+        data += payload.len();
+    }
+    // the close went out well before the data that was queued ahead of it, which was dropped
+    assert!(data < BACKLOG << 19);
+
+    // confirm the close with a masked close frame, then nothing but the end of the stream follows
+    stream
+        .write_all(&[0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe9])
+        .unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert!(rest.is_empty());
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
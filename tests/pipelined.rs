@@ -0,0 +1,95 @@
+extern crate url;
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{connect, CloseCode, Message, WebSocket};
+
+// A masked text frame carrying "hello".
+const MASKED_HELLO: &[u8] = &[
+    0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x5f, 0x9f, 0x4d, 0x51, 0x58,
+];
+
+// An unmasked text frame carrying "hello".
+const HELLO: &[u8] = &[0x81, 0x05, b'h', b'e', b'l', b'l', b'o'];
+
+#[test]
+fn frame_after_request() {
+    let server = WebSocket::new(|out: ws::Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:3063")
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut stream = TcpStream::connect("127.0.0.1:3063").unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    let mut request = b"GET / HTTP/1.1\r\n\
+        Host: 127.0.0.1:3063\r\n\
+        Connection: Upgrade\r\n\
+        Upgrade: websocket\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        .to_vec();
+    request.extend_from_slice(MASKED_HELLO);
+    stream.write_all(&request).unwrap();
+
+    // the echo may arrive along with the response
+    let mut received = Vec::new();
+    let mut buf = [0; 1024];
+    while !received.ends_with(HELLO) {
+        let len = stream.read(&mut buf).unwrap();
+        assert!(len > 0, "connection closed before the echo arrived");
+        received.extend_from_slice(&buf[..len]);
+    }
+    assert!(received.starts_with(b"HTTP/1.1 101"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+}
+
+#[test]
+fn frame_after_response() {
+    let listener = TcpListener::bind("127.0.0.1:3064").unwrap();
+    let server = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let len = stream.read(&mut buf).unwrap();
+            request.extend_from_slice(&buf[..len]);
+        }
+        let mut response = b"HTTP/1.1 101 Switching Protocols\r\n\
+            Connection: Upgrade\r\n\
+            Upgrade: websocket\r\n\
+            Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"
+            .to_vec();
+        response.extend_from_slice(HELLO);
+        stream.write_all(&response).unwrap();
+        // wait for the close frame of the client and then hang up
+        let _ = stream.read(&mut buf);
+    });
+
+    let (tx, rx) = channel();
+    connect("ws://127.0.0.1:3064", |out| {
+        let tx = tx.clone();
+        move |msg: Message| {
+            tx.send(msg).unwrap();
+            out.close(CloseCode::Normal)
+        }
+    }).unwrap();
+
+    assert_eq!(
+        rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+        Message::text("hello")
+    );
+    server.join().unwrap();
+}
@@ -0,0 +1,70 @@
+extern crate ws;
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, ClientPool, CloseCode, Handler, Handshake, Message, Result, Sender};
+
+struct Echo {
+    out: Sender,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Handler for Echo {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.connections.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+}
+
+#[test]
+fn reuse_idle_connections() {
+    let connections = Arc::new(AtomicUsize::new(0));
+    let counter = connections.clone();
+    let server = Builder::new()
+        .build(move |out| Echo {
+            out,
+            connections: counter.clone(),
+        })
+        .unwrap()
+        .bind("127.0.0.1:3052")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let timeout = Duration::from_secs(5);
+    let url = "ws://127.0.0.1:3052".parse().unwrap();
+    let pool = ClientPool::new(&Builder::new(), Duration::from_millis(200));
+
+    for text in &["first", "second"] {
+        let conn = pool.checkout(&url, timeout).unwrap();
+        conn.send(*text).unwrap();
+        assert_eq!(conn.recv(timeout).unwrap(), Message::from(*text));
+    }
+    assert_eq!(connections.load(Ordering::SeqCst), 1);
+    assert_eq!(pool.idle(), 1);
+
+    // a closed connection isn't returned to the pool
+    let conn = pool.checkout(&url, timeout).unwrap();
+    conn.close(CloseCode::Normal).unwrap();
+    assert_eq!(pool.idle(), 0);
+    drop(pool.checkout(&url, timeout).unwrap());
+    assert_eq!(connections.load(Ordering::SeqCst), 2);
+
+    // connections that stay idle beyond the ttl are closed
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(pool.idle(), 0);
+    drop(pool.checkout(&url, timeout).unwrap());
+    assert_eq!(connections.load(Ordering::SeqCst), 3);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,100 @@
+extern crate url;
+extern crate ws;
+
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, ClientSettings, CloseCode, Handler, Handshake, Message, ProxyConfig, Result,
+         Sender, WebSocket};
+
+// A minimal HTTP proxy that accepts a single CONNECT request and then relays the traffic.
+fn proxy(listener: TcpListener, requests: std::sync::mpsc::Sender<String>) {
+    let (mut client, _) = listener.accept().unwrap();
+
+    let mut request = Vec::new();
+    let mut buf = [0; 1];
+    while !request.ends_with(b"\r\n\r\n") {
+        assert_eq!(client.read(&mut buf).unwrap(), 1);
+        request.push(buf[0]);
+    }
+    let request = String::from_utf8(request).unwrap();
+    let target = request.split(' ').nth(1).unwrap().to_owned();
+    requests.send(request).unwrap();
+
+    let mut server = TcpStream::connect(target).unwrap();
+    client
+        .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+        .unwrap();
+
+    let mut client_read = client.try_clone().unwrap();
+    let mut server_write = server.try_clone().unwrap();
+    thread::spawn(move || {
+        let _ = io::copy(&mut client_read, &mut server_write);
+    });
+    let _ = io::copy(&mut server, &mut client);
+    let _ = client.shutdown(Shutdown::Both);
+}
+
+struct Client {
+    out: Sender,
+    echoed: std::sync::mpsc::Sender<String>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("hello")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.echoed.send(msg.into_text()?).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn connect_through_proxy() {
+    let server = WebSocket::new(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:3021")
+        .unwrap();
+    let server_handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (requests_tx, requests) = channel();
+    let listener = TcpListener::bind("127.0.0.1:3020").unwrap();
+    thread::spawn(move || proxy(listener, requests_tx));
+
+    let (tx, rx) = channel();
+    let mut client = Builder::new()
+        .with_client_settings(ClientSettings {
+            proxy: Some(ProxyConfig::new("127.0.0.1:3020").with_credentials("user", "pass")),
+            ..ClientSettings::default()
+        })
+        .build(move |out| Client {
+            out,
+            echoed: tx.clone(),
+        })
+        .unwrap();
+    client
+        .connect(url::Url::parse("ws://127.0.0.1:3021").unwrap())
+        .unwrap();
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    let request = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert!(request.starts_with("CONNECT 127.0.0.1:3021 HTTP/1.1\r\n"));
+    assert!(request.contains("Proxy-Authorization: Basic dXNlcjpwYXNz\r\n"));
+
+    let echoed = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(echoed, "hello");
+
+    client_thread.join().unwrap();
+    server_handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
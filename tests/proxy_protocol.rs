@@ -0,0 +1,68 @@
+extern crate ws;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, Handler, Handshake, Result, Settings};
+
+const REQUEST: &[u8] = b"GET / HTTP/1.1\r\n\
+    Connection: Upgrade\r\n\
+    Upgrade: websocket\r\n\
+    Sec-WebSocket-Version: 13\r\n\
+    Sec-WebSocket-Key: q16eN37NCfVwUChPvBdk4g==\r\n\r\n";
+
+struct Server {
+    peers: std::sync::mpsc::Sender<Option<SocketAddr>>,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.peers.send(shake.peer_addr).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn proxy_protocol_peer_addr() {
+    let (tx, rx) = channel();
+    let server = Builder::new()
+        .with_settings(Settings {
+            accept_proxy_protocol: true,
+            ..Settings::default()
+        })
+        .build(move |_| Server { peers: tx.clone() })
+        .unwrap()
+        .bind("127.0.0.1:3048")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // the header may arrive separately from the request
+    let mut stream = TcpStream::connect("127.0.0.1:3048").unwrap();
+    stream
+        .write_all(b"PROXY TCP4 203.0.113.7 127.0.0.1 4242 3048\r\n")
+        .unwrap();
+    thread::sleep(Duration::from_millis(50));
+    stream.write_all(REQUEST).unwrap();
+    let peer = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(peer, Some("203.0.113.7:4242".parse().unwrap()));
+    let mut response = [0; 12];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(&response, b"HTTP/1.1 101");
+
+    // connections without the header are rejected
+    let mut stream = TcpStream::connect("127.0.0.1:3048").unwrap();
+    stream.write_all(REQUEST).unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).unwrap();
+    assert!(response.starts_with(b"HTTP/1.1 400"));
+    assert!(rx.try_recv().is_err());
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,87 @@
+extern crate url;
+extern crate ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+
+// Pushes a message to each client as it connects.
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("pushed")
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        panic!("The server received a message.");
+    }
+}
+
+struct Client {
+    out: Sender,
+    events: Channel<String>,
+}
+
+impl Handler for Client {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.events.send(msg.into_text()?).unwrap();
+        // pings are still allowed
+        self.out.ping(b"ping".to_vec())
+    }
+
+    fn on_pong(&mut self, data: &[u8]) -> Result<()> {
+        self.events
+            .send(String::from_utf8(data.to_vec()).unwrap())
+            .unwrap();
+        self.out.send("reply")
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.events.send(format!("{:?}", code)).unwrap();
+    }
+}
+
+#[test]
+fn reject_client_data() {
+    let server = Builder::new()
+        .with_settings(Settings {
+            reject_client_data: true,
+            ..Settings::default()
+        })
+        .build(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:3075")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        let mut client = Builder::new()
+            .build(move |out| Client {
+                out,
+                events: tx.clone(),
+            })
+            .unwrap();
+        client
+            .connect(url::Url::parse("ws://127.0.0.1:3075").unwrap())
+            .unwrap();
+        client.run().unwrap();
+    });
+
+    let events = (0..3)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(events, vec!["pushed", "ping", "Policy"]);
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,92 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::util::Token;
+use ws::{Builder, CloseCode, Handler, Handshake, Message, RateLimitAction, Result, Sender,
+         Settings};
+
+const LATE: Token = Token(1);
+
+struct Client {
+    out: Sender,
+    echoed: Vec<String>,
+    done: std::sync::mpsc::Sender<(Vec<String>, CloseCode)>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        for i in 0..5 {
+            self.out.send(i.to_string())?;
+        }
+        // by then the bucket has been refilled
+        self.out.timeout(600, LATE)
+    }
+
+    fn on_timeout(&mut self, _: Token) -> Result<()> {
+        self.out.send("late")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.echoed.push(msg.into_text()?);
+        if self.echoed.last().unwrap() == "late" {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.done.send((self.echoed.clone(), code)).unwrap();
+    }
+}
+
+fn run(port: u16, action: RateLimitAction) -> (Vec<String>, CloseCode) {
+    let server = Builder::new()
+        .with_settings(Settings {
+            max_messages_per_second: Some(2),
+            rate_limit_action: action,
+            ..Settings::default()
+        })
+        .build(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind(("127.0.0.1", port))
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let url = format!("ws://127.0.0.1:{}", port);
+    let client = thread::spawn(move || {
+        ws::connect(url, |out| Client {
+            out,
+            echoed: Vec::new(),
+            done: tx.clone(),
+        }).unwrap();
+    });
+
+    let result = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+    result
+}
+
+#[test]
+fn rate_limit_close() {
+    // the echoes are queued behind the close frame, so they may be lost
+    let (echoed, code) = run(3031, RateLimitAction::Close);
+    assert!(echoed.len() <= 2);
+    assert_eq!(code, CloseCode::Policy);
+}
+
+#[test]
+fn rate_limit_drop() {
+    let (echoed, code) = run(3032, RateLimitAction::Drop);
+    assert_eq!(echoed, vec!["0", "1", "late"]);
+    assert_eq!(code, CloseCode::Normal);
+}
@@ -0,0 +1,109 @@
+extern crate url;
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, ClientSettings, CloseCode, Handler, Handshake, Result, Sender};
+
+struct Server {
+    out: Sender,
+    drop: bool,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        if self.drop {
+            self.out.close(CloseCode::Away)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+enum Event {
+    Open,
+    Attempt(u32),
+}
+
+struct Client {
+    out: Sender,
+    opens: usize,
+    events: std::sync::mpsc::Sender<Event>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.opens += 1;
+        self.events.send(Event::Open).unwrap();
+        if self.opens == 3 {
+            self.out.close(CloseCode::Normal)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn on_reconnect_attempt(&mut self, attempt: u32) {
+        self.events.send(Event::Attempt(attempt)).unwrap();
+    }
+}
+
+#[test]
+fn reconnect_after_server_close() {
+    // The server closes the first two connections as soon as they are established.
+    let mut accepted = 0;
+    let server = Builder::new()
+        .build(move |out| {
+            accepted += 1;
+            Server {
+                out,
+                drop: accepted <= 2,
+            }
+        })
+        .unwrap()
+        .bind("127.0.0.1:3018")
+        .unwrap();
+    let server_handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let mut client = Builder::new()
+        .with_client_settings(ClientSettings {
+            auto_reconnect: true,
+            reconnect_delay: Duration::from_millis(10),
+            reconnect_jitter: Duration::from_millis(10),
+            ..ClientSettings::default()
+        })
+        .build(move |out| Client {
+            out,
+            opens: 0,
+            events: tx.clone(),
+        })
+        .unwrap();
+    client
+        .connect(url::Url::parse("ws://127.0.0.1:3018").unwrap())
+        .unwrap();
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    // The same handler sees every reconnection, and the attempt count is reset once the
+    // connection has been reestablished.
+    let mut attempts = Vec::new();
+    let mut opens = 0;
+    while opens < 3 {
+        match rx.recv_timeout(Duration::from_secs(5)).unwrap() {
+            Event::Open => opens += 1,
+            Event::Attempt(attempt) => attempts.push(attempt),
+        }
+    }
+    assert_eq!(attempts, vec![1, 1]);
+
+    // Closing from the client side doesn't trigger another reconnection.
+    client_thread.join().unwrap();
+    server_handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,73 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{CloseCode, Error, Handler, Handshake, Message, Result, Router, Sender, WebSocket};
+
+struct Chat {
+    out: Sender,
+}
+
+impl Handler for Chat {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        self.out.send(format!("Welcome to {}", shake.param("room").unwrap()))
+    }
+}
+
+struct Client {
+    out: Sender,
+    received: std::sync::mpsc::Sender<Option<String>>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("ping")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received.send(Some(msg.into_text()?)).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+
+    fn on_error(&mut self, _: Error) {
+        let _ = self.received.send(None);
+    }
+}
+
+fn request(resource: &str) -> Option<String> {
+    let (tx, rx) = channel();
+    let url = format!("ws://127.0.0.1:3033{}", resource);
+    let client = thread::spawn(move || {
+        ws::connect(url, |out| Client {
+            out,
+            received: tx.clone(),
+        }).unwrap();
+    });
+    let received = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    client.join().unwrap();
+    received
+}
+
+#[test]
+fn route_by_path() {
+    let router = Router::new()
+        .route("/chat/:room", |out| Chat { out })
+        .route("/echo", |out: Sender| move |msg| out.send(msg));
+    let server = WebSocket::new(router)
+        .unwrap()
+        .bind("127.0.0.1:3033")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    assert_eq!(request("/chat/rust"), Some("Welcome to rust".into()));
+    assert_eq!(request("/echo?token=abc"), Some("ping".into()));
+    assert_eq!(request("/missing"), None);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,210 @@
+#![cfg(feature = "tls-rustls")]
+extern crate rustls;
+extern crate url;
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::crypto::ring;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+use ws::{Builder, ClientSettings, CloseCode, Error, Handler, Handshake, Message, Result, Sender,
+         Settings, TlsIdentity};
+
+const CA: &[u8] = include_bytes!("certs/ca.pem");
+const CERT: &[u8] = include_bytes!("certs/cert.pem");
+const KEY: &[u8] = include_bytes!("certs/key.pem");
+const CLIENT_CERT: &[u8] = include_bytes!("certs/client.pem");
+const CLIENT_KEY: &[u8] = include_bytes!("certs/client-key.pem");
+
+fn roots() -> RootCertStore {
+    let mut roots = RootCertStore::empty();
+    roots.add(CertificateDer::from_pem_slice(CA).unwrap()).unwrap();
+    roots
+}
+
+fn certified_key() -> CertifiedKey {
+    let chain = vec![CertificateDer::from_pem_slice(CERT).unwrap()];
+    let key = PrivateKeyDer::from_pem_slice(KEY).unwrap();
+    CertifiedKey::from_der(chain, key, &ring::default_provider()).unwrap()
+}
+
+// Records the server names sent by clients.
+#[derive(Debug)]
+struct Resolver {
+    key: Arc<CertifiedKey>,
+    names: Mutex<Vec<Option<String>>>,
+}
+
+impl ResolvesServerCert for Resolver {
+    fn resolve(&self, hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let name = hello.server_name().map(String::from);
+        self.names.lock().unwrap().push(name);
+        Some(self.key.clone())
+    }
+}
+
+struct Server {
+    out: Sender,
+    resolver: Arc<Resolver>,
+}
+
+impl Handler for Server {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+
+    fn build_server_tls_config(&mut self) -> Result<Arc<ServerConfig>> {
+        // client certificates are verified if they are presented, but they are not required
+        let verifier = WebPkiClientVerifier::builder(Arc::new(roots()))
+            .allow_unauthenticated()
+            .build()
+            .unwrap();
+        let config = ServerConfig::builder()
+            .with_client_cert_verifier(verifier)
+            .with_cert_resolver(self.resolver.clone());
+        Ok(Arc::new(config))
+    }
+}
+
+struct Client {
+    out: Sender,
+    identity: Option<TlsIdentity>,
+    echoed: std::sync::mpsc::Sender<Option<String>>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        let tls = shake.tls_info().unwrap();
+        assert_eq!(tls.version, "TLSv1.3");
+        assert!(tls.cipher.starts_with("TLS13_"), "{}", tls.cipher);
+        assert_eq!(tls.peer_subject, None);
+        self.out.send("hello")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.echoed.send(Some(msg.into_text()?)).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+
+    fn on_error(&mut self, _: Error) {
+        let _ = self.echoed.send(None);
+    }
+
+    fn build_client_tls_config(&mut self) -> Result<Arc<ClientConfig>> {
+        let builder = ClientConfig::builder().with_root_certificates(roots());
+        let config = match self.identity {
+            Some(ref identity) => {
+                let (chain, key) = identity.to_rustls()?;
+                builder.with_client_auth_cert(chain, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+// Echo a message through an encrypted server, and return the echoed message and the server
+// names sent by the client.
+fn echo(
+    port: u16,
+    settings: Settings,
+    client_settings: ClientSettings,
+) -> (Option<String>, Vec<Option<String>>) {
+    let resolver = Arc::new(Resolver {
+        key: Arc::new(certified_key()),
+        names: Mutex::new(Vec::new()),
+    });
+    let server_resolver = resolver.clone();
+    let server = Builder::new()
+        .with_settings(Settings {
+            encrypt_server: true,
+            ..settings
+        })
+        .build(move |out| Server {
+            out,
+            resolver: server_resolver.clone(),
+        })
+        .unwrap()
+        .bind(("127.0.0.1", port))
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let identity = client_settings.identity.clone();
+    let mut client = Builder::new()
+        .with_client_settings(client_settings)
+        .build(move |out| Client {
+            out,
+            identity: identity.clone(),
+            echoed: tx.clone(),
+        })
+        .unwrap();
+    client
+        .connect(url::Url::parse(&format!("wss://127.0.0.1:{}", port)).unwrap())
+        .unwrap();
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    let echoed = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    client_thread.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+
+    let names = resolver.names.lock().unwrap();
+    (echoed, names.clone())
+}
+
+#[test]
+fn rustls_echo() {
+    // No server name is sent when connecting to an ip address.
+    let (echoed, names) = echo(3023, Settings::default(), ClientSettings::default());
+    assert_eq!(echoed, Some("hello".into()));
+    assert_eq!(names, vec![None]);
+}
+
+#[test]
+fn sni_hostname() {
+    let (echoed, names) = echo(
+        3024,
+        Settings::default(),
+        ClientSettings {
+            sni_hostname: Some("localhost".into()),
+            ..ClientSettings::default()
+        },
+    );
+    assert_eq!(echoed, Some("hello".into()));
+    assert_eq!(names, vec![Some("localhost".into())]);
+}
+
+#[test]
+fn client_certificate() {
+    let settings = Settings {
+        require_client_cert: true,
+        ..Settings::default()
+    };
+    let (echoed, _) = echo(
+        3025,
+        settings,
+        ClientSettings {
+            identity: Some(TlsIdentity::from_pem(CLIENT_CERT, CLIENT_KEY)),
+            ..ClientSettings::default()
+        },
+    );
+    assert_eq!(echoed, Some("hello".into()));
+
+    // A client without a certificate is refused.
+    let (echoed, _) = echo(3026, settings, ClientSettings::default());
+    assert_eq!(echoed, None);
+}
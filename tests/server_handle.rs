@@ -0,0 +1,153 @@
+extern crate ws;
+
+use std::sync::mpsc::{channel, Sender as Channel};
+use std::thread;
+use std::time::Duration;
+
+use ws::util::Token;
+use ws::{CloseCode, Handler, Handshake, Message, Result, Sender, WebSocket};
+
+// Hands out the token and id of each connection, leaving the routing to the test.
+struct Server {
+    out: Sender,
+    opened: Channel<(Token, u32)>,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.opened
+            .send((self.out.token(), self.out.connection_id()))
+            .unwrap();
+        Ok(())
+    }
+}
+
+struct Client {
+    events: Channel<String>,
+}
+
+impl Handler for Client {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.events.send(msg.into_text()?).unwrap();
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.events.send(format!("{:?}", code)).unwrap();
+    }
+}
+
+#[test]
+fn send_to_and_close() {
+    let (tx, rx) = channel();
+    let server = WebSocket::new(move |out| Server {
+        out,
+        opened: tx.clone(),
+    })
+    .unwrap()
+    .bind("127.0.0.1:3080")
+    .unwrap();
+    let handle = server.handle();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut clients = Vec::new();
+    let mut events = Vec::new();
+    for _ in 0..2 {
+        let (events_tx, events_rx) = channel();
+        clients.push(thread::spawn(move || {
+            ws::connect("ws://127.0.0.1:3080", |_| Client {
+                events: events_tx.clone(),
+            })
+            .unwrap();
+        }));
+        events.push(events_rx);
+    }
+    let first = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    let second = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    // from another thread, as a worker would
+    let worker = handle.clone();
+    thread::spawn(move || {
+        worker.send_to(first.0, first.1, "to the first").unwrap();
+        worker.send_to(second.0, second.1, "to the second").unwrap();
+        worker.close(first.0, first.1, CloseCode::Normal).unwrap();
+        worker.close(second.0, second.1, CloseCode::Away).unwrap();
+    })
+    .join()
+    .unwrap();
+
+    let mut received = events
+        .iter()
+        .map(|events| {
+            (0..2)
+                .map(|_| events.recv_timeout(Duration::from_secs(5)).unwrap())
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+    received.sort();
+    assert_eq!(
+        received,
+        vec![vec!["to the first", "Normal"], vec!["to the second", "Away"]]
+    );
+
+    for client in clients {
+        client.join().unwrap();
+    }
+    handle.broadcaster().shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn reused_token() {
+    let (tx, rx) = channel();
+    let server = WebSocket::new(move |out| Server {
+        out,
+        opened: tx.clone(),
+    })
+    .unwrap()
+    .bind("127.0.0.1:3099")
+    .unwrap();
+    let handle = server.handle();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let connect = || {
+        let (events_tx, events_rx) = channel();
+        let client = thread::spawn(move || {
+            ws::connect("ws://127.0.0.1:3099", |_| Client {
+                events: events_tx.clone(),
+            })
+            .unwrap();
+        });
+        (client, events_rx)
+    };
+
+    let (client, events) = connect();
+    let old = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    handle.close(old.0, old.1, CloseCode::Normal).unwrap();
+    assert_eq!(events.recv_timeout(Duration::from_secs(5)).unwrap(), "Normal");
+    client.join().unwrap();
+
+    // the new connection is given the token of the closed one
+    let (client, events) = connect();
+    let new = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(new.0, old.0);
+    assert!(new.1 != old.1);
+
+    handle.send_to(old.0, old.1, "to the old").unwrap();
+    handle.send_to(new.0, new.1, "to the new").unwrap();
+    handle.close(new.0, new.1, CloseCode::Normal).unwrap();
+    assert_eq!(
+        (0..2)
+            .map(|_| events.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect::<Vec<_>>(),
+        vec!["to the new", "Normal"]
+    );
+
+    client.join().unwrap();
+    handle.broadcaster().shutdown().unwrap();
+    server_thread.join().unwrap();
+}
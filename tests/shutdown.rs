@@ -1,5 +1,6 @@
 extern crate ws;
 
+use std::io::{Read, Write};
 use std::sync::mpsc::channel;
 use std::thread;
 use std::time::Duration;
@@ -39,3 +40,184 @@ fn shutdown_before_connections() {
 
     assert!(t.join().is_ok());
 }
+
+#[test]
+fn graceful_shutdown_delivers_queued_messages() {
+    struct Server {
+        out: ws::Sender,
+    }
+
+    impl ws::Handler for Server {
+        fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+            self.out.send("last words")?;
+            self.out.shutdown_graceful(Duration::from_secs(10))
+        }
+    }
+
+    struct Client {
+        received: std::sync::mpsc::Sender<String>,
+    }
+
+    impl ws::Handler for Client {
+        fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+            self.received.send(msg.into_text()?).unwrap();
+            Ok(())
+        }
+
+        fn on_close(&mut self, code: ws::CloseCode, _: &str) {
+            self.received.send(format!("{:?}", code)).unwrap();
+        }
+    }
+
+    let socket = ws::Builder::new()
+        .build(|out| Server { out })
+        .unwrap()
+        .bind("127.0.0.1:3016")
+        .unwrap();
+
+    let t = thread::spawn(move || {
+        socket.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3016", |_| Client {
+            received: tx.clone(),
+        })
+        .unwrap();
+    });
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "last words");
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "Away");
+
+    // the server exits as soon as the closing handshake completes, well before the timeout
+    assert!(t.join().is_ok());
+    assert!(client.join().is_ok());
+}
+
+struct Echo {
+    out: ws::Sender,
+}
+
+impl ws::Handler for Echo {
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        self.out.send(msg)
+    }
+}
+
+// Reports when draining starts.
+struct Draining {
+    events: std::sync::mpsc::Sender<String>,
+}
+
+impl ws::Factory for Draining {
+    type Handler = Echo;
+
+    fn connection_made(&mut self, out: ws::Sender) -> Echo {
+        Echo { out }
+    }
+
+    fn on_drain_start(&mut self) {
+        self.events.send("draining".into()).unwrap();
+    }
+}
+
+// Hands out its sender, and reports the messages and the close of its connection.
+struct Open {
+    out: ws::Sender,
+    events: std::sync::mpsc::Sender<String>,
+    opened: std::sync::mpsc::Sender<ws::Sender>,
+}
+
+impl ws::Handler for Open {
+    fn on_open(&mut self, _: ws::Handshake) -> ws::Result<()> {
+        self.opened.send(self.out.clone()).unwrap();
+        Ok(())
+    }
+
+    fn on_message(&mut self, msg: ws::Message) -> ws::Result<()> {
+        self.events.send(msg.into_text()?).unwrap();
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: ws::CloseCode, _: &str) {
+        self.events.send(format!("{:?}", code)).unwrap();
+    }
+}
+
+fn start_draining(
+    addr: &'static str,
+    max: Duration,
+) -> (
+    ws::Sender,
+    std::sync::mpsc::Receiver<String>,
+    std::sync::mpsc::Receiver<String>,
+    thread::JoinHandle<()>,
+    thread::JoinHandle<()>,
+) {
+    let (server_tx, server_rx) = channel();
+    let socket = ws::Builder::new()
+        .build(Draining { events: server_tx })
+        .unwrap()
+        .bind(addr)
+        .unwrap();
+    let handle = socket.handle();
+    let server = thread::spawn(move || {
+        socket.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let (opened_tx, opened_rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect(format!("ws://{}", addr), |out| Open {
+            out,
+            events: tx.clone(),
+            opened: opened_tx.clone(),
+        }).unwrap();
+    });
+    let out = opened_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+    handle.start_draining(max).unwrap();
+    assert_eq!(server_rx.recv_timeout(Duration::from_secs(5)).unwrap(), "draining");
+    (out, rx, server_rx, client, server)
+}
+
+#[test]
+fn drain() {
+    let (out, rx, _, client, server) = start_draining("127.0.0.1:3089", Duration::from_secs(10));
+
+    // new clients are turned away
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:3089").unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\n\
+              Connection: Upgrade\r\n\
+              Upgrade: websocket\r\n\
+              Sec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .unwrap();
+    let mut response = [0; 12];
+    stream.read_exact(&mut response).unwrap();
+    assert_eq!(&response, b"HTTP/1.1 503");
+
+    // while the open connection carries on
+    out.send("still here").unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "still here");
+    out.close(ws::CloseCode::Normal).unwrap();
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "Normal");
+    drop(stream);
+
+    // and the server stops once it has closed
+    client.join().unwrap();
+    server.join().unwrap();
+}
+
+#[test]
+fn drain_timeout() {
+    let (_, rx, _, client, server) = start_draining("127.0.0.1:3090", Duration::from_millis(200));
+
+    assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), "Away");
+    client.join().unwrap();
+    server.join().unwrap();
+}
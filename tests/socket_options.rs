@@ -0,0 +1,76 @@
+extern crate ws;
+
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, Handler, Handshake, Message, Result, Sender, Settings};
+
+fn settings() -> Settings {
+    Settings {
+        tcp_nodelay: true,
+        tcp_keepalive: Some(Duration::from_secs(30)),
+        tcp_send_buffer_size: Some(64 * 1024),
+        tcp_recv_buffer_size: Some(64 * 1024),
+        ..Settings::default()
+    }
+}
+
+struct Echo {
+    out: Sender,
+}
+
+impl Handler for Echo {
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.out.send(msg)
+    }
+}
+
+struct Client {
+    out: Sender,
+    received: std::sync::mpsc::Sender<Message>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("over a configured socket")
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received.send(msg).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn configured_sockets() {
+    let server = Builder::new()
+        .with_settings(settings())
+        .build(|out| Echo { out })
+        .unwrap()
+        .bind("127.0.0.1:3049")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let mut client = Builder::new()
+        .with_settings(settings())
+        .build(move |out| Client {
+            out,
+            received: tx.clone(),
+        })
+        .unwrap();
+    client
+        .connect("ws://127.0.0.1:3049".parse().unwrap())
+        .unwrap();
+    client.run().unwrap();
+
+    let msg = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(msg, Message::from("over a configured socket"));
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,120 @@
+extern crate ws;
+
+use std::net::TcpStream;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{CloseCode, ConnectionCounts, Handler, Handshake, Message, Result, Sender, Stats,
+         WebSocket};
+
+struct Client {
+    out: Sender,
+    stats: std::sync::mpsc::Sender<Stats>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        self.out.send("hello")
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.stats.send(self.out.stats()).unwrap();
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn connection_stats() {
+    let server = WebSocket::new(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:3030")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3030", |out| Client {
+            out,
+            stats: tx.clone(),
+        }).unwrap();
+    });
+
+    let stats = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(stats.messages_out, 1);
+    assert_eq!(stats.messages_in, 1);
+    assert_eq!(stats.frames_out, 1);
+    assert_eq!(stats.frames_in, 1);
+    // the handshake and the framing are counted as well
+    assert!(stats.bytes_written > "hello".len() as u64);
+    assert!(stats.bytes_read > "hello".len() as u64);
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    let server = server_thread.join().unwrap();
+
+    // the echoed message and the close frames
+    let totals = server.stats();
+    assert_eq!(totals, handle.stats());
+    assert_eq!(totals.messages_in, 1);
+    assert_eq!(totals.messages_out, 1);
+    assert_eq!(totals.frames_in, 2);
+    assert_eq!(totals.frames_out, 2);
+    // the client's close frame is a masked header of 6 bytes and a 2 byte close code
+    assert_eq!(totals.bytes_read, stats.bytes_written + 6 + 2);
+}
+
+fn wait_for(out: &Sender, counts: ConnectionCounts) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while out.connection_counts() != counts {
+        assert!(
+            Instant::now() < deadline,
+            "{:?} never became {:?}",
+            out.connection_counts(),
+            counts
+        );
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn connection_counts() {
+    let server = WebSocket::new(|_| |_| Ok(()))
+        .unwrap()
+        .bind("127.0.0.1:3071")
+        .unwrap();
+    assert_eq!(server.connection_counts(), ConnectionCounts::default());
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || server.run().unwrap());
+
+    // a connection that hasn't sent its handshake yet
+    let stream = TcpStream::connect("127.0.0.1:3071").unwrap();
+    wait_for(
+        &handle,
+        ConnectionCounts {
+            connecting: 1,
+            ..ConnectionCounts::default()
+        },
+    );
+
+    let (client, client_thread) =
+        ws::connect_with_sender("ws://127.0.0.1:3071", |_| |_| Ok(())).unwrap();
+    wait_for(
+        &handle,
+        ConnectionCounts {
+            connecting: 1,
+            open: 1,
+            closing: 0,
+        },
+    );
+
+    client.close(CloseCode::Normal).unwrap();
+    client_thread.join().unwrap().unwrap();
+    drop(stream);
+    wait_for(&handle, ConnectionCounts::default());
+
+    handle.shutdown().unwrap();
+    let server = server_thread.join().unwrap();
+    assert_eq!(server.connection_counts(), ConnectionCounts::default());
+}
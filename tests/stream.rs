@@ -0,0 +1,233 @@
+extern crate url;
+extern crate ws;
+
+use std::io::Write;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Builder, CloseCode, FragmentState, Frame, Handler, Handshake, Message, OpCode, Result,
+         Sender, Settings, WebSocket};
+
+struct Client {
+    out: Sender,
+    received: std::sync::mpsc::Sender<Message>,
+    count: usize,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        let mut writer = self.out.stream(OpCode::Binary);
+        writer.write_all(&[1; 10])?;
+        // sent after the streamed message even though it is queued in the middle of it
+        self.out.send("between")?;
+        self.out.ping(b"still alive".to_vec())?;
+        writer.write_all(&[2; 10])?;
+        writer.finish()
+    }
+
+    fn on_message(&mut self, msg: Message) -> Result<()> {
+        self.received.send(msg).unwrap();
+        self.count += 1;
+        if self.count == 2 {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn stream_message() {
+    let server = WebSocket::new(|out: Sender| move |msg| out.send(msg))
+        .unwrap()
+        .bind("127.0.0.1:3028")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        let mut client = Builder::new()
+            .with_settings(Settings {
+                fragment_size: 4,
+                ..Settings::default()
+            })
+            .build(move |out| Client {
+                out,
+                received: tx.clone(),
+                count: 0,
+            })
+            .unwrap();
+        client
+            .connect(url::Url::parse("ws://127.0.0.1:3028").unwrap())
+            .unwrap();
+        client.run().unwrap();
+    });
+
+    let mut expected = vec![1; 10];
+    expected.extend_from_slice(&[2; 10]);
+    let streamed = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(streamed, Message::Binary(expected));
+    let between = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(between, Message::text("between"));
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+// Records the reassembly state of each data frame and closes once a message is complete.
+struct Server {
+    out: Sender,
+    states: std::sync::mpsc::Sender<(usize, usize)>,
+}
+
+impl Handler for Server {
+    fn on_frame_with_state(&mut self, frame: Frame, state: FragmentState) -> Result<Option<Frame>> {
+        if !frame.is_control() {
+            self.states.send((state.frames(), state.payload_len())).unwrap();
+        }
+        Ok(Some(frame))
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.out.close(CloseCode::Normal)
+    }
+}
+
+#[test]
+fn fragment_state() {
+    let (tx, rx) = channel();
+    let server = WebSocket::new(move |out| Server {
+        out,
+        states: tx.clone(),
+    })
+    .unwrap()
+    .bind("127.0.0.1:3029")
+    .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let client = thread::spawn(move || {
+        let mut client = Builder::new()
+            .with_settings(Settings {
+                fragment_size: 4,
+                ..Settings::default()
+            })
+            .build(|out: Sender| {
+                out.send(vec![0; 10]).unwrap();
+                |_| Ok(())
+            })
+            .unwrap();
+        client
+            .connect(url::Url::parse("ws://127.0.0.1:3029").unwrap())
+            .unwrap();
+        client.run().unwrap();
+    });
+
+    let states: Vec<_> = (0..3)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    assert_eq!(states, vec![(0, 0), (1, 4), (2, 8)]);
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+// Records the streaming handler calls and closes after the second message.
+struct Receiver {
+    out: Sender,
+    events: std::sync::mpsc::Sender<String>,
+    ended: usize,
+}
+
+impl Handler for Receiver {
+    fn on_message_start(&mut self, opcode: OpCode) -> Result<()> {
+        self.events.send(format!("start {:?}", opcode)).unwrap();
+        Ok(())
+    }
+
+    fn on_message_chunk(&mut self, data: &[u8]) -> Result<()> {
+        self.events.send(format!("chunk {:?}", data)).unwrap();
+        Ok(())
+    }
+
+    fn on_message_end(&mut self) -> Result<()> {
+        self.events.send("end".into()).unwrap();
+        self.ended += 1;
+        if self.ended == 2 {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.events.send("message".into()).unwrap();
+        Ok(())
+    }
+}
+
+#[test]
+fn stream_incoming_messages() {
+    let (tx, rx) = channel();
+    let server = Builder::new()
+        .with_settings(Settings {
+            stream_messages: true,
+            ..Settings::default()
+        })
+        .build(move |out| Receiver {
+            out,
+            events: tx.clone(),
+            ended: 0,
+        })
+        .unwrap()
+        .bind("127.0.0.1:3057")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let client = thread::spawn(move || {
+        let mut client = Builder::new()
+            .with_settings(Settings {
+                fragment_size: 4,
+                ..Settings::default()
+            })
+            .build(|out: Sender| {
+                out.send(vec![1, 2, 3, 4, 5, 6]).unwrap();
+                out.send("hi").unwrap();
+                |_| Ok(())
+            })
+            .unwrap();
+        client
+            .connect(url::Url::parse("ws://127.0.0.1:3057").unwrap())
+            .unwrap();
+        client.run().unwrap();
+    });
+
+    let events: Vec<_> = (0..7)
+        .map(|_| rx.recv_timeout(Duration::from_secs(5)).unwrap())
+        .collect();
+    assert_eq!(
+        events,
+        vec![
+            "start Binary",
+            "chunk [1, 2, 3, 4]",
+            "chunk [5, 6]",
+            "end",
+            "start Text",
+            "chunk [104, 105]",
+            "end",
+        ]
+    );
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
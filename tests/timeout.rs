@@ -0,0 +1,81 @@
+extern crate ws;
+
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use ws::{Builder, Error, ErrorKind, Handler, Settings};
+
+// Reports whether its connection failed with a timeout.
+struct TimedOut {
+    errors: std::sync::mpsc::Sender<bool>,
+}
+
+impl Handler for TimedOut {
+    fn on_error(&mut self, err: Error) {
+        let timed_out = match err.kind {
+            ErrorKind::Timeout => true,
+            _ => false,
+        };
+        self.errors.send(timed_out).unwrap();
+    }
+}
+
+#[test]
+fn handshake_timeout() {
+    let (tx, rx) = channel();
+    let server = Builder::new()
+        .with_settings(Settings {
+            handshake_timeout: Some(Duration::from_millis(300)),
+            ..Settings::default()
+        })
+        .build(move |_| TimedOut { errors: tx.clone() })
+        .unwrap()
+        .bind("127.0.0.1:3040")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    // A client that connects but never sends its handshake request.
+    let start = Instant::now();
+    let mut stream = TcpStream::connect("127.0.0.1:3040").unwrap();
+    assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert!(start.elapsed() >= Duration::from_millis(300));
+    assert_eq!(stream.read(&mut [0; 16]).unwrap(), 0);
+
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
+
+#[test]
+fn connect_timeout() {
+    // A server that accepts connections but never answers the handshake request.
+    let listener = TcpListener::bind("127.0.0.1:3041").unwrap();
+    let server_thread = thread::spawn(move || listener.accept().unwrap());
+
+    let (tx, rx) = channel();
+    let mut client = Builder::new()
+        .with_settings(Settings {
+            connect_timeout: Some(Duration::from_millis(300)),
+            ..Settings::default()
+        })
+        .build(move |_| TimedOut { errors: tx.clone() })
+        .unwrap();
+    client
+        .connect("ws://127.0.0.1:3041".parse().unwrap())
+        .unwrap();
+    let start = Instant::now();
+    let client_thread = thread::spawn(move || {
+        client.run().unwrap();
+    });
+
+    assert!(rx.recv_timeout(Duration::from_secs(5)).unwrap());
+    assert!(start.elapsed() >= Duration::from_millis(300));
+
+    client_thread.join().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,66 @@
+#![cfg(unix)]
+extern crate ws;
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::Duration;
+
+use ws::{Handler, Handshake, Result, Sender, WebSocket};
+
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, shake: Handshake) -> Result<()> {
+        assert!(shake.peer_addr.is_none());
+        assert!(shake.local_addr.is_none());
+        self.out.send("hello")
+    }
+}
+
+#[test]
+fn listen_unix() {
+    let path = env::temp_dir().join(format!("ws-rs-test-{}.sock", std::process::id()));
+    let _ = fs::remove_file(&path);
+
+    let server = WebSocket::new(|out| Server { out })
+        .unwrap()
+        .bind_unix(&path)
+        .unwrap();
+    let handle = server.broadcaster();
+    let t = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let mut stream = UnixStream::connect(&path).unwrap();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+    stream
+        .write_all(
+            b"GET / HTTP/1.1\r\n\
+              Host: localhost\r\n\
+              Connection: Upgrade\r\n\
+              Upgrade: websocket\r\n\
+              Sec-WebSocket-Version: 13\r\n\
+              Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n",
+        )
+        .unwrap();
+
+    let mut received = Vec::new();
+    let mut buf = [0; 1024];
+    while !received.ends_with(b"hello") {
+        let len = stream.read(&mut buf).unwrap();
+        assert!(len > 0);
+        received.extend_from_slice(&buf[..len]);
+    }
+    assert!(received.starts_with(b"HTTP/1.1 101"));
+
+    handle.shutdown().unwrap();
+    t.join().unwrap();
+    fs::remove_file(&path).unwrap();
+}
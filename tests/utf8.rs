@@ -0,0 +1,57 @@
+extern crate ws;
+
+use std::io::Write;
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Duration;
+
+use ws::{CloseCode, Handler, Handshake, MessageWriter, OpCode, Result, Sender, WebSocket};
+
+struct Client {
+    out: Sender,
+    writer: Option<MessageWriter>,
+    closed: std::sync::mpsc::Sender<CloseCode>,
+}
+
+impl Handler for Client {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        // send the start of a text message that is already invalid, and never finish it
+        let mut writer = self.out.stream(OpCode::Text);
+        writer.write_all(b"invalid \xff")?;
+        writer.flush()?;
+        self.writer = Some(writer);
+        Ok(())
+    }
+
+    fn on_close(&mut self, code: CloseCode, _: &str) {
+        self.closed.send(code).unwrap();
+    }
+}
+
+#[test]
+fn invalid_fragment() {
+    let server = WebSocket::new(|_| |_| Ok(()))
+        .unwrap()
+        .bind("127.0.0.1:3034")
+        .unwrap();
+    let handle = server.broadcaster();
+    let server_thread = thread::spawn(move || {
+        server.run().unwrap();
+    });
+
+    let (tx, rx) = channel();
+    let client = thread::spawn(move || {
+        ws::connect("ws://127.0.0.1:3034", |out| Client {
+            out,
+            writer: None,
+            closed: tx.clone(),
+        }).unwrap();
+    });
+
+    let code = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+    assert_eq!(code, CloseCode::Invalid);
+
+    client.join().unwrap();
+    handle.shutdown().unwrap();
+    server_thread.join().unwrap();
+}
@@ -0,0 +1,101 @@
+#![cfg(unix)]
+extern crate url;
+extern crate ws;
+
+use std::io::{self, IoSlice, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ws::{CloseCode, Handler, Handshake, Message, Result, Sender, WebSocket};
+
+const LARGE: usize = 4;
+const SMALL: usize = 50;
+
+// A stream that records the bytes of every vectored write made to it.
+struct Counted {
+    sock: UnixStream,
+    writes: Arc<Mutex<Vec<usize>>>,
+}
+
+impl Read for Counted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.sock.read(buf)
+    }
+}
+
+impl Write for Counted {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.sock.write(buf)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+        let len = self.sock.write_vectored(bufs)?;
+        self.writes.lock().unwrap().push(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.sock.flush()
+    }
+}
+
+struct Server {
+    out: Sender,
+}
+
+impl Handler for Server {
+    fn on_open(&mut self, _: Handshake) -> Result<()> {
+        for _ in 0..LARGE {
+            self.out.send(vec![1u8; 8192])?;
+        }
+        for _ in 0..SMALL {
+            self.out.send("small")?;
+        }
+        Ok(())
+    }
+}
+
+struct Client {
+    out: Sender,
+    received: usize,
+}
+
+impl Handler for Client {
+    fn on_message(&mut self, _: Message) -> Result<()> {
+        self.received += 1;
+        if self.received == LARGE + SMALL {
+            self.out.close(CloseCode::Normal)?;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn queued_frames_in_one_write() {
+    let (server_end, client_end) = UnixStream::pair().unwrap();
+    let writes = Arc::new(Mutex::new(Vec::new()));
+
+    let stream = Counted {
+        sock: server_end,
+        writes: writes.clone(),
+    };
+    let server = thread::spawn(move || {
+        WebSocket::new(|out| Server { out })
+            .unwrap()
+            .serve_stream(stream)
+            .unwrap();
+    });
+
+    WebSocket::new(|out| Client { out, received: 0 })
+        .unwrap()
+        .connect_stream(client_end, url::Url::parse("ws://localhost/").unwrap())
+        .unwrap();
+    server.join().unwrap();
+
+    // the headers and payloads of every message, then the frame that confirms the close
+    let messages = LARGE * (4 + 8192) + SMALL * (2 + 5);
+    let writes = writes.lock().unwrap();
+    assert_eq!(writes[0], messages);
+    assert_eq!(writes.len(), 2);
+}